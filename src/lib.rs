@@ -0,0 +1,11173 @@
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use csv::ReaderBuilder;
+use flate2::read::{GzDecoder, MultiGzDecoder};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressState, ProgressStyle,
+};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use regex::Regex;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, FROM, IF_RANGE,
+    LAST_MODIFIED, RANGE, RETRY_AFTER,
+};
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tar::{Archive, Builder};
+use taxonomy::ncbi::load;
+use taxonomy::{GeneralTaxonomy, TaxRank, Taxonomy};
+
+const TAXDUMP_URL: &str = "https://ftp.ncbi.nih.gov/pub/taxonomy/taxdump.tar.gz";
+const NEW_TAXDUMP_URL: &str =
+    "https://ftp.ncbi.nih.gov/pub/taxonomy/new_taxdump/new_taxdump.tar.gz";
+
+// citations, delnodes, division, gencode, images, merged, names, nodes, gc.prt and readme.txt
+const TAXDUMP_N_ENTRIES: u64 = 10;
+
+// written to the taxdump directory once it's been extracted, since the extracted files keep the
+// mtimes they had in the archive
+const TAXDUMP_EXTRACTED_STAMP: &str = ".gdl-extracted";
+
+// written to the taxdump directory while it's being extracted, so that an extraction that failed
+// (e.g. when the disk filled up) or was killed partway is never taken for a usable taxdump
+const TAXDUMP_EXTRACTING_MARKER: &str = ".gdl-extracting";
+
+const DATASETS_API_URL: &str = "https://api.ncbi.nlm.nih.gov/datasets/v2";
+
+const GTDB_TAXONOMY_URLS: [&str; 2] = [
+    "https://data.gtdb.ecogenomic.org/releases/latest/bac120_taxonomy.tsv.gz",
+    "https://data.gtdb.ecogenomic.org/releases/latest/ar53_taxonomy.tsv.gz",
+];
+
+const PB_DOWNLOAD_TEMPLATE: &str =
+    "[{elapsed:.cyan}] {msg} [{bar:.green}] {bytes:.blue}/{total_bytes:.blue}";
+const PB_PROGRESS_TEMPLATE: &str =
+    "[{elapsed:.cyan}] {msg} [{bar:.green}] {percent:.blue}% (ETA: {eta})";
+// {throughput} is filled in by the assembly downloads
+const PB_ASSEMBLIES_TEMPLATE: &str =
+    "[{elapsed:.cyan}] {msg} [{bar:.green}] {percent:.blue}% ({throughput}, ETA: {eta})";
+const PB_EXTRACT_TEMPLATE: &str = "[{elapsed:.cyan}] {msg} [{bar:.green}] {pos}/{len}";
+const PB_SPINNER_TEMPLATE: &str = "[{elapsed:.cyan}] {msg}";
+const PROGRESS_CHARS: &str = "█░ ";
+
+#[derive(Parser, Debug)]
+#[command(group(
+        ArgGroup::new("tax_id_or_name")
+        .args(&["tax_id", "tax_name", "tax_name_glob", "tax_name_regex"])
+), group(
+        ArgGroup::new("selection")
+        .required(true)
+        .multiple(true)
+        .args(&[
+            "tax_id",
+            "tax_name",
+            "tax_name_glob",
+            "tax_name_regex",
+            "accession",
+            "accession_file",
+            "verify_only",
+            "assembly_summary_columns",
+            "version_info",
+        ])
+), group(
+        ArgGroup::new("checkpointed")
+        .multiple(true)
+        .args(&["manifest", "state"])
+), subcommand_negates_reqs = true)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// path to extracted taxdump.tar.gz. Can also be the taxdump.tar.gz itself, which is then
+    /// extracted next to it (e.g. to taxdump/ for taxdump.tar.gz)
+    #[clap(long, default_value = "taxdump")]
+    taxdump_path: String,
+
+    /// extract the taxdump from this taxdump.tar.gz instead of downloading it, if --taxdump-path
+    /// doesn't have one yet. By default, an archive at --taxdump-path with .tar.gz added is used
+    /// when there is one
+    #[clap(long)]
+    taxdump_archive: Option<String>,
+
+    /// where to download the taxdump from (default depends on --taxdump-format)
+    #[clap(long)]
+    taxdump_url: Option<String>,
+
+    /// download the taxdump again when NCBI has published a newer one than the one in
+    /// --taxdump-path. By default, there's only a warning
+    #[clap(long, default_value = "false")]
+    refresh_taxdump_if_stale: bool,
+
+    /// which NCBI taxonomy dump to use. The new format includes ranked lineages, which are used
+    /// to show lineages without walking the taxonomy
+    #[clap(value_enum, long, default_value_t = TaxdumpFormat::Classic)]
+    taxdump_format: TaxdumpFormat,
+
+    /// directory to keep downloaded assembly summaries in, default=pwd
+    #[clap(long, default_value = ".")]
+    cache_dir: String,
+
+    /// taxonomy used to resolve --tax-id/--tax-name and their descendants
+    #[clap(value_enum, long, default_value_t = TaxonomyBackend::Ncbi)]
+    taxonomy: TaxonomyBackend,
+
+    /// path to a GTDB *_taxonomy.tsv file or a directory of them (used with --taxonomy gtdb)
+    #[clap(long, default_value = "gtdb")]
+    gtdb_path: String,
+
+    /// show the estimated download size and ask before downloading anything. This happens
+    /// anyway in a terminal when more than 1000 assemblies match
+    #[clap(long, default_value = "false", conflicts_with = "yes")]
+    confirm: bool,
+
+    /// don't ask before downloading, however many assemblies match
+    #[clap(short = 'y', long, default_value = "false")]
+    yes: bool,
+
+    /// do not actually download anything
+    #[clap(long, default_value = "false")]
+    dry_run: bool,
+
+    /// print what gdl would do with these options (where the summaries and the taxonomy come
+    /// from, the resolved taxon, the filters, the output layout and the concurrency), then exit
+    /// without downloading or filtering anything
+    #[clap(long, default_value = "false")]
+    print_plan: bool,
+
+    /// print the gdl version and the data sources it would use (the NCBI URLs, when the cached
+    /// taxdump was extracted, and each summary's Last-Modified and number of rows) for bug
+    /// reports, then exit without downloading anything
+    #[clap(
+        long,
+        default_value = "false",
+        alias = "print-version-and-source-info",
+        conflicts_with = "print_plan"
+    )]
+    version_info: bool,
+
+    /// print the tax IDs that --tax-id/--tax-name resolve to (including descendants) with their
+    /// rank and lineage, then exit without looking at any assemblies
+    #[clap(
+        long,
+        default_value = "false",
+        requires = "tax_id_or_name",
+        conflicts_with = "dry_run"
+    )]
+    print_taxa: bool,
+
+    /// write the tax IDs that --tax-id/--tax-name resolve to (including descendants) to this
+    /// file, one per line, before downloading
+    #[clap(long, value_name = "PATH", requires = "tax_id_or_name")]
+    tax_id_output: Option<String>,
+
+    /// also write the name, rank and lineage of each tax ID to --tax-id-output, like --print-taxa
+    #[clap(long, default_value = "false", requires = "tax_id_output")]
+    tax_id_output_names: bool,
+
+    /// print how many of the matched assemblies there are per taxon at this rank (e.g. genus or
+    /// species) as a TSV sorted by count, then exit without downloading anything
+    #[clap(long, value_parser = parse_rank, conflicts_with = "print_taxa")]
+    count_by: Option<TaxRank>,
+
+    /// stop at the first line of the assembly summary that can't be parsed, instead of skipping it
+    /// (and any others) and reporting how many were skipped
+    #[clap(long, default_value = "false")]
+    strict_parse: bool,
+
+    /// print the columns of the assembly summary with their indices, and whether gdl uses them,
+    /// then exit. Useful when NCBI changes the layout of the summary
+    #[clap(long, default_value = "false", conflicts_with = "stream_summary")]
+    assembly_summary_columns: bool,
+
+    /// print the matched assemblies to stdout as a JSON array (requires --dry-run)
+    #[clap(long, default_value = "false", requires = "dry_run")]
+    json: bool,
+
+    /// print the URL of every file that would be downloaded to stdout, one per line (requires
+    /// --dry-run)
+    #[clap(long, default_value = "false", requires = "dry_run")]
+    print_urls: bool,
+
+    /// print each matched assembly to stdout as a line of JSON as soon as it is found (requires
+    /// --dry-run)
+    #[clap(
+        long,
+        default_value = "false",
+        requires = "dry_run",
+        conflicts_with = "json"
+    )]
+    jsonl: bool,
+
+    /// re-fetch assembly_summary.txt and taxdump
+    #[clap(long, default_value = "false")]
+    no_cache: bool,
+
+    /// never use the network: the summaries and taxonomy have to be cached (or given) already,
+    /// and so do the files of every matched assembly, which are then only listed as with
+    /// --dry-run. Fails naming whatever is missing
+    #[clap(
+        long,
+        default_value = "false",
+        conflicts_with_all = [
+            "no_cache", "stream_summary", "refresh_taxdump_if_stale", "verify",
+            "list_formats_available", "only_formats_present", "tar", "stdout", "seqid2taxid"
+        ]
+    )]
+    offline: bool,
+
+    /// keep the assemblies matched by the last run's filters in --cache-dir, and use them
+    /// instead of filtering the assembly summary again when the filters and the summary haven't
+    /// changed
+    #[clap(long, default_value = "false", conflicts_with = "stream_summary")]
+    filter_cache: bool,
+
+    /// number of assemblies to download concurrently (default is the number of CPUs, up to 8)
+    #[clap(short = 'j', long, visible_alias = "jobs", default_value_t = default_parallel())]
+    parallel: usize,
+
+    /// download the assemblies grouped by tax ID and ordered by ftp_path, so that each thread
+    /// works through neighbouring NCBI directories in turn
+    #[clap(long, default_value = "false", conflicts_with = "sort")]
+    group_by_taxid: bool,
+
+    /// the order the matched assemblies are listed, downloaded and logged in: by tax ID,
+    /// accession, assembly level (most complete first, see --prefer-assembly-level) or as in the
+    /// assembly summary (none)
+    #[clap(value_enum, long, default_value_t = SortOrder::None)]
+    sort: SortOrder,
+
+    /// number of files to hash concurrently with --verify-only (default is all CPUs). Hashing is
+    /// limited by the disk and CPU rather than the network, so this is separate from --parallel
+    #[clap(long, requires = "verify_only")]
+    verify_parallel: Option<usize>,
+
+    /// number of times to retry a download after a connection error or server error (5xx)
+    #[clap(long, default_value = "3")]
+    retries: u32,
+
+    /// download everything possible when some downloads fail, then report the failures and exit
+    /// with an error (the default)
+    #[clap(long, default_value = "false", overrides_with = "strict")]
+    keep_going: bool,
+
+    /// stop the whole run at the first download that fails (after retries)
+    #[clap(long, default_value = "false", overrides_with = "keep_going")]
+    strict: bool,
+
+    /// write every failed download to this file as a JSON object per line (accession, url, kind,
+    /// http_status, retries and error)
+    #[clap(long)]
+    json_errors: Option<String>,
+
+    /// stop starting new downloads once the filesystem of --out-dir has less than this much free
+    /// space (e.g. 50G), keeping what has been downloaded so far
+    #[clap(long, value_parser = parse_size)]
+    min_free_space: Option<u64>,
+
+    /// stop starting new downloads once gdl has been running for this long (e.g. 90m or 2h 30m),
+    /// letting the ones in progress finish, so that a job with a time limit can be resumed with
+    /// --state instead of being killed halfway through a file
+    #[clap(long, value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+
+    /// limit the combined download speed of all --parallel downloads to this many bytes per
+    /// second (e.g. 10MB/s or 500k)
+    #[clap(long, value_parser = parse_bandwidth)]
+    max_bandwidth: Option<u64>,
+
+    /// download files of at least --chunked-threshold over several connections at once, each
+    /// fetching a different range of the file
+    #[clap(long, default_value = "false")]
+    chunked: bool,
+
+    /// how large a file has to be for --chunked to kick in (e.g. 500M, 2G)
+    #[clap(long, value_parser = parse_size, default_value = "1G", requires = "chunked")]
+    chunked_threshold: u64,
+
+    /// number of connections to download each file with when using --chunked
+    #[clap(long, default_value = "4", requires = "chunked")]
+    chunked_connections: usize,
+
+    /// skip any file larger than this (e.g. 2G), going by the size the server reports before
+    /// downloading it, to leave out the occasional giant genome
+    #[clap(long, value_parser = parse_size, conflicts_with = "stdout")]
+    max_file_size: Option<u64>,
+
+    /// comma-separated files to save alongside the downloaded formats of each assembly, e.g. for
+    /// provenance: md5 (md5checksums.txt) and stats (_assembly_stats.txt). Failing to fetch them
+    /// is only a warning
+    #[clap(value_enum, long, value_delimiter = ',')]
+    extra_files: Vec<ExtraFile>,
+
+    /// fetch each assembly's md5checksums.txt first and only download the requested formats that
+    /// it lists, reporting the assemblies that don't have them instead of failing on a 404
+    #[clap(long, default_value = "false")]
+    list_formats_available: bool,
+
+    /// print which formats each matched assembly has (going by its md5checksums.txt) as a TSV
+    /// with a true/false column per format, then exit without downloading anything
+    #[clap(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["print_taxa", "count_by"]
+    )]
+    only_formats_present: bool,
+
+    /// append `|taxid=<tax ID>|<lineage>` to the header of every sequence in downloaded fasta
+    /// files (fna, faa, cds, rna), e.g. to build a taxonomically labelled reference database
+    #[clap(long, default_value = "false")]
+    annotate_headers: bool,
+
+    /// check downloaded files against the assembly's md5checksums.txt, and skip files that are
+    /// already in --out-dir and match it
+    #[clap(long, default_value = "false")]
+    verify: bool,
+
+    /// check that the bases in each downloaded fna add up to the total length in the assembly's
+    /// _assembly_stats.txt (or the summary's genome_size), to catch a file that downloaded fine
+    /// but isn't the assembly it should be
+    #[clap(long, default_value = "false", conflicts_with = "stdout")]
+    check_genome_length: bool,
+
+    /// decompress every downloaded .gz file to the end to check that it isn't truncated or
+    /// corrupted, downloading it again if it is. Needs no checksums, unlike --verify
+    #[clap(long, default_value = "false", conflicts_with = "stdout")]
+    verify_gzip_integrity: bool,
+
+    /// run this shell command for every file once it's downloaded (and verified), with {} replaced
+    /// by the file's path, e.g. `--exec 'seqkit stats {}'`. A failing command is a warning unless
+    /// --strict
+    #[clap(long, value_name = "CMD", value_parser = parse_exec, conflicts_with = "stdout")]
+    exec: Option<String>,
+
+    /// contact email sent to NCBI with every request, as asked for by their guidelines for bulk
+    /// downloads
+    #[clap(long, env = "NCBI_EMAIL", value_parser = parse_email)]
+    email: Option<String>,
+
+    /// when progress bars are hidden (e.g. output is not a terminal), print a status line to
+    /// stderr every <PROGRESS_INTERVAL> seconds while downloading
+    #[clap(long)]
+    progress_interval: Option<u64>,
+
+    /*
+    OUTPUT OPTIONS
+    */
+    /// file format(s) to download, can be provided multiple times (default is fna)
+    #[clap(value_enum, long)]
+    format: Vec<AssemblyFormat>,
+
+    /// comma-separated formats to try in order when an assembly doesn't have one of the
+    /// requested formats (e.g. gbff when there's no gff), downloading the first one it has
+    #[clap(value_enum, long, value_delimiter = ',', conflicts_with = "stdout")]
+    format_fallback: Vec<AssemblyFormat>,
+
+    /// download a predefined set of formats for each assembly (in addition to --format)
+    #[clap(value_enum, long)]
+    bundle: Option<Bundle>,
+
+    /// recompress downloaded sequences (fna, faa, cds, rna) with bgzip so they can be indexed by
+    /// e.g. samtools faidx
+    #[clap(long, default_value = "false")]
+    bgzip: bool,
+
+    /// decompress downloaded .gz files and re-encode them in this format (e.g. zstd, written as
+    /// .zst), which takes less space
+    #[clap(value_enum, long, conflicts_with = "bgzip")]
+    recompress: Option<Recompression>,
+
+    /// keep downloaded sequences (fna, faa, cds, rna) gzipped and also write a decompressed copy
+    /// next to each one (e.g. genome.fna next to genome.fna.gz)
+    #[clap(long, default_value = "false", conflicts_with_all = ["recompress", "tar"])]
+    both: bool,
+
+    /// how hard to compress the files gdl writes with --bgzip, --recompress or
+    /// --annotate-headers: 0-9 for gzip and bgzip (default 6), 1-22 for zstd (default 3)
+    #[clap(long)]
+    compression_level: Option<u32>,
+
+    /// name files after the accession only (e.g. GCF_000005845.2.fna.gz) instead of NCBI's
+    /// {accession}_{assembly name} prefix
+    #[clap(long, default_value = "false")]
+    flat_filenames: bool,
+
+    /// name files after a template instead, e.g. "{organism}_{accession}" for
+    /// Escherichia_coli_str._K-12_substr._MG1655_GCF_000005845.2.fna.gz. The placeholders are
+    /// {accession}, {asm_name}, {organism} and {taxid}; the format's extension is added
+    #[clap(long, value_parser = parse_filename_template, conflicts_with_all = ["flat_filenames", "preserve_directory_structure"])]
+    filename_template: Option<String>,
+
+    /// what replaces the characters in --filename-template values that aren't safe in filenames on
+    /// Linux, macOS and Windows (anything but letters, digits, ., -, _ and +). Can be empty
+    #[clap(long, default_value = "_", value_parser = parse_name_delimiter, requires = "filename_template")]
+    name_delimiter: String,
+
+    /// keep the files of every format in --out-dir itself. By default, when more than one format
+    /// is downloaded, each format's files go in their own subdirectory (e.g. fna/, gff/)
+    #[clap(long, default_value = "false")]
+    no_format_subdirs: bool,
+
+    /// with --source all, put the RefSeq and GenBank assemblies in refseq/ and genbank/
+    /// subdirectories of --out-dir
+    #[clap(long, default_value = "false")]
+    source_subdirs: bool,
+
+    /// spread the assemblies over numbered subdirectories of --out-dir (000/, 001/, ...) with at
+    /// most this many assemblies each, filled in order of accession
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["preserve_directory_structure", "stdout"])]
+    shard_size: Option<u64>,
+
+    /// mirror NCBI's directory layout under --out-dir (e.g.
+    /// genomes/all/GCF/000/005/845/GCF_000005845.2_ASM584v2/), keeping NCBI's filenames
+    #[clap(long, default_value = "false", conflicts_with_all = ["flat_filenames", "source_subdirs"])]
+    preserve_directory_structure: bool,
+
+    /// output directory, default=pwd
+    #[clap(long)]
+    out_dir: Option<String>,
+
+    /// write the assembly to stdout instead of a file, for use in pipelines. Exactly one assembly
+    /// has to match and only one --format can be given
+    #[clap(
+        long,
+        default_value = "false",
+        conflicts_with_all = [
+            "tar", "json", "jsonl", "print_urls", "manifest", "verify", "bgzip",
+            "recompress", "annotate_headers", "both"
+        ]
+    )]
+    stdout: bool,
+
+    /// with --stdout, decompress the .gz file while writing it
+    #[clap(long, default_value = "false", requires = "stdout")]
+    decompress: bool,
+
+    /// write downloaded files into a single tar archive at this path instead of --out-dir (gzipped
+    /// if the path ends in .tar.gz or .tgz)
+    #[clap(long)]
+    tar: Option<String>,
+
+    /// record completed assemblies in this file and skip the ones it already lists, so that an
+    /// interrupted run can be resumed by running the same command again
+    #[clap(long)]
+    state: Option<String>,
+
+    /// rewrite --manifest and sync --state to disk every <CHECKPOINT_EVERY> completed assemblies,
+    /// so that a crash loses at most that many records. By default, the manifest is only written
+    /// at the end
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "checkpointed",
+        conflicts_with = "dry_run"
+    )]
+    checkpoint_every: Option<u64>,
+
+    /// write a TSV listing every downloaded file to this path
+    #[clap(long)]
+    manifest: Option<String>,
+
+    /// comma-separated columns to write to --manifest, in order
+    #[clap(
+        value_enum,
+        long,
+        value_delimiter = ',',
+        requires = "manifest",
+        default_value = "accession,taxid,organism_name,assembly_level,format,path"
+    )]
+    manifest_columns: Vec<ManifestColumn>,
+
+    /// comma-separated columns of the assembly summary, by their name in its header, to copy
+    /// into --manifest unchanged after the --manifest-columns (e.g. bioproject,asm_name)
+    #[clap(
+        long,
+        value_delimiter = ',',
+        requires = "manifest",
+        conflicts_with = "filter_cache"
+    )]
+    summary_columns_passthrough: Vec<String>,
+
+    /// comma-separated checksums of every downloaded file to add to --manifest. NCBI only
+    /// publishes md5s, so sha256 is computed locally for your own records
+    #[clap(value_enum, long, value_delimiter = ',', requires = "manifest")]
+    checksum_algorithm: Vec<ChecksumAlgorithm>,
+
+    /// don't download anything. Instead, re-hash the files listed in --manifest (which needs the
+    /// path and md5 columns) within --out-dir and report any that are missing or don't match,
+    /// failing if there are any
+    #[clap(
+        long,
+        default_value = "false",
+        requires = "manifest",
+        conflicts_with_all = ["tax_id", "tax_name", "accession", "accession_file"]
+    )]
+    verify_only: bool,
+
+    /// write a headerless TSV mapping each matched assembly's accession to its lineage (or tax
+    /// ID, with --name-map-format taxid) to this path, for tools like GTDB-Tk or kraken2
+    #[clap(long)]
+    name_map: Option<String>,
+
+    /// what --name-map maps accessions to
+    #[clap(value_enum, long, default_value_t = NameMapFormat::Lineage, requires = "name_map")]
+    name_map_format: NameMapFormat,
+
+    /// write a headerless TSV mapping the ID of every sequence in the downloaded FASTA files to
+    /// its assembly's tax ID to this path, like kraken2's seqid2taxid.map
+    #[clap(long, conflicts_with_all = ["dry_run", "stdout"])]
+    seqid2taxid: Option<String>,
+
+    /// write a JSON record of how this set of assemblies was selected to this path
+    #[clap(long)]
+    provenance: Option<String>,
+
+    /// download assemblies from a mirror by replacing the <FROM> prefix of each URL with <TO>,
+    /// e.g. --rebase-url https://ftp.ncbi.nlm.nih.gov/genomes https://mirror.example.org/ncbi
+    #[clap(long, num_args = 2, value_names = ["FROM", "TO"])]
+    rebase_url: Option<Vec<String>>,
+
+    /// how to download assemblies: from the FTP paths in the assembly summary, or as one NCBI
+    /// Datasets package (a zip) per assembly
+    #[clap(value_enum, long, default_value_t = Backend::Ftp)]
+    backend: Backend,
+
+    /// base URL of the NCBI Datasets API (used with --backend datasets)
+    #[clap(long, default_value = DATASETS_API_URL)]
+    datasets_url: String,
+
+    /// NCBI API key sent with requests to the Datasets API, which raises how many requests NCBI
+    /// allows per second
+    #[clap(long, env = "NCBI_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// force downloads to use this URL scheme. By default ftp:// paths are fetched over https://
+    #[clap(value_enum, long)]
+    scheme: Option<Scheme>,
+
+    /*
+    FILTERING PARAMETERS
+    */
+    /// where to fetch assemblies from (default is RefSeq). all filters both the RefSeq and the
+    /// GenBank summaries
+    #[clap(value_enum, long, default_value_t = AssemblySource::Refseq)]
+    source: AssemblySource,
+
+    /// also filter the historical summary of --source, which lists the assemblies that have been
+    /// replaced or suppressed since
+    #[clap(
+        long,
+        default_value = "false",
+        conflicts_with = "assembly_summary_path"
+    )]
+    include_historical: bool,
+
+    /// path to assembly_summary.txt, or - to read it from stdin. Needed with --source none
+    #[clap(long, required_if_eq("source", "none"))]
+    assembly_summary_path: Option<String>,
+
+    /// where to download the assembly summary from (default depends on --source)
+    #[clap(long)]
+    assembly_summary_url: Option<String>,
+
+    /// download each assembly as soon as it's matched instead of filtering the whole summary
+    /// first, so the matches are never all held in memory. The number of assemblies isn't known
+    /// up front, so options that need all of them (e.g. --unique-taxid, --sort or --limit) can't
+    /// be used
+    #[clap(
+        long,
+        alias = "chunk-summary-by-taxid",
+        default_value = "false",
+        conflicts_with_all = [
+            "unique_taxid", "max_per_taxid", "cluster_file", "sort", "limit", "shard_size",
+            "dry_run", "json", "jsonl", "manifest", "tar", "state", "stdout", "verify",
+            "format_fallback", "list_formats_available", "seqid2taxid", "name_map",
+            "annotate_headers", "exec", "count_by", "only_formats_present", "print_taxa",
+            "offline", "filter_cache", "provenance", "include_accession_file",
+            "exclude_accession_file", "since_accession", "only_missing", "confirm", "strict",
+            "max_runtime", "min_free_space", "json_errors", "extra_files", "print_urls",
+            "validate_accessions"
+        ]
+    )]
+    stream_downloads: bool,
+
+    /// filter the assembly summary while it downloads instead of saving it to disk first
+    #[clap(
+        long,
+        default_value = "false",
+        conflicts_with = "assembly_summary_path"
+    )]
+    stream_summary: bool,
+
+    /// keep the assembly summaries in --cache-dir gzipped (e.g. assembly_summary_refseq.txt.gz),
+    /// which takes about a quarter of the space
+    #[clap(long, default_value = "false", conflicts_with = "stream_summary")]
+    assembly_summary_gz: bool,
+
+    /// warn when a cached assembly summary was downloaded longer ago than this (e.g. 30d),
+    /// without downloading it again. That's still up to --no-cache or gdl update
+    #[clap(long, value_parser = parse_duration, conflicts_with_all = ["no_cache", "stream_summary"])]
+    summary_max_age_warn: Option<Duration>,
+
+    /// tax_id to download assemblies for (includes descendants unless --no-children or
+    /// --species-exact is enabled). A number like 562, or a GTDB taxon with --taxonomy gtdb. Can
+    /// be provided multiple times or comma-separated
+    #[clap(long, value_delimiter = ',', conflicts_with = "tax_name")]
+    tax_id: Vec<String>,
+
+    /// do not include child taxa of --tax-id/--tax-name. Strains, subspecies and other taxa below
+    /// the species level still roll up to their species, so for a species (or anything below one)
+    /// its descendants are still included
+    #[clap(long, default_value = "false", conflicts_with = "species_exact")]
+    no_children: bool,
+
+    /// only download assemblies with exactly the tax ID of --tax-id/--tax-name, leaving out
+    /// every descendant (including strains of a species)
+    #[clap(long, default_value = "false", requires = "tax_id_or_name")]
+    species_exact: bool,
+
+    /// only include the taxa of --tax-id/--tax-name (and their descendants) that are at or below
+    /// this rank, e.g. species for species and strains but not the clades above them. Taxa
+    /// without a rank count as below the closest ranked taxon above them
+    #[clap(long, value_parser = parse_rank, requires = "tax_id_or_name")]
+    include_rank_below: Option<TaxRank>,
+
+    /// only include the taxa of --tax-id/--tax-name (and their descendants) that are at or above
+    /// this rank, e.g. species to leave out strains. Can be combined with --include-rank-below
+    #[clap(long, value_parser = parse_rank, requires = "tax_id_or_name")]
+    include_rank_above: Option<TaxRank>,
+
+    /// tax_name to download assemblies for (includes descendants unless --no-children or
+    /// --species-exact is enabled). Can be provided multiple times
+    #[clap(long)]
+    tax_name: Vec<String>,
+
+    /// with several --tax-id/--tax-name, skip the ones that don't exist or are ambiguous (with a
+    /// warning) and download the rest, instead of stopping
+    #[clap(long, default_value = "false", requires = "tax_id_or_name")]
+    continue_on_taxon_error: bool,
+
+    /// the kind of name --tax-name is: scientific (falling back to synonyms when nothing
+    /// matches), synonym, common, authority, or any (scientific, then any kind of name)
+    #[clap(value_enum, long, default_value_t = NameClass::Scientific)]
+    name_class: NameClass,
+
+    /// like --tax-name, but download assemblies for every taxon whose name matches this glob
+    /// (e.g. 'Strepto*', with * and ? as wildcards)
+    #[clap(long)]
+    tax_name_glob: Option<String>,
+
+    /// like --tax-name-glob, but with a regular expression (e.g. '^Strepto(coccus|myces)$')
+    #[clap(long)]
+    tax_name_regex: Option<String>,
+
+    /// only include the assembly with this accession (e.g. GCF_000005845.2, or GCF_000005845 for
+    /// any version). Can be provided multiple times, and --tax-id/--tax-name are optional with it
+    #[clap(long)]
+    accession: Vec<String>,
+
+    /// like --accession, but read accessions from this file (one per line)
+    #[clap(long)]
+    accession_file: Option<String>,
+
+    /// list the accessions from --accession, --accession-file and --include-accession-file that
+    /// didn't match any assembly, and stop before downloading anything if there are any
+    #[clap(long, default_value = "false")]
+    validate_accessions: bool,
+
+    /// what an accession without a version matches in --accession, --accession-file and
+    /// --include-accession-file: every version (any), only the highest one (latest), or nothing,
+    /// since the version has to be given (exact)
+    #[clap(value_enum, long, default_value_t = AccessionVersionPolicy::Any)]
+    accession_version_policy: AccessionVersionPolicy,
+
+    /// after all other filters, only keep assemblies with an accession listed in this file (one
+    /// per line)
+    #[clap(long)]
+    include_accession_file: Option<String>,
+
+    /// after all other filters, drop assemblies with an accession listed in this file (one per
+    /// line), e.g. a list of known contaminated genomes
+    #[clap(long)]
+    exclude_accession_file: Option<String>,
+
+    /// only keep assemblies that aren't in this --manifest from a previous run, or that have a
+    /// higher accession version than it lists (e.g. GCF_000005845.3 when it has
+    /// GCF_000005845.2), to keep a local copy up to date
+    #[clap(long)]
+    since_accession: Option<String>,
+
+    /// only download assemblies that don't have all of their files in --out-dir yet, going by
+    /// the filenames they'd be saved as. Unlike --since-accession or --state, this needs nothing
+    /// from a previous run
+    #[clap(long, default_value = "false", conflicts_with_all = ["tar", "stdout"])]
+    only_missing: bool,
+
+    /// include assemblies that match this assembly level. By default, all assembly_levels are
+    /// included
+    #[clap(long)]
+    assembly_level: Option<Vec<String>>,
+
+    /// only include assemblies released on or after this date (YYYY/MM/DD or YYYY-MM-DD)
+    #[clap(long, value_parser = parse_release_date)]
+    after_date: Option<ReleaseDate>,
+
+    /// only include assemblies released on or before this date (YYYY/MM/DD or YYYY-MM-DD)
+    #[clap(long, value_parser = parse_release_date)]
+    before_date: Option<ReleaseDate>,
+
+    /// exclude assemblies whose excluded_from_refseq column lists this reason (e.g. "derived
+    /// from metagenome"). Can be provided multiple times
+    #[clap(long)]
+    exclude_flag: Vec<String>,
+
+    /// exclude assemblies that have any excluded_from_refseq reason set
+    #[clap(long, default_value = "false")]
+    clean_only: bool,
+
+    /// exclude metagenome-assembled genomes (MAGs) and assemblies from environmental samples
+    #[clap(long, default_value = "false", conflicts_with = "mags_only")]
+    exclude_mags: bool,
+
+    /// only include metagenome-assembled genomes (MAGs) and assemblies from environmental
+    /// samples
+    #[clap(long, default_value = "false")]
+    mags_only: bool,
+
+    /// only include assemblies matching this expression of summary columns, e.g.
+    /// "assembly_level == 'Complete Genome' && refseq_category != 'na'"
+    #[clap(long, alias = "summary-filter-expr", value_parser = parse_filter_expr)]
+    filter: Option<FilterExpr>,
+
+    /// include assemblies that NCBI has suppressed (version_status is "suppressed"), which are
+    /// excluded by default
+    #[clap(long, default_value = "false")]
+    include_suppressed: bool,
+
+    /// only include assemblies derived from type material (relation_to_type_material is set)
+    #[clap(long, default_value = "false")]
+    type_material_only: bool,
+
+    /// only include assemblies that are in both GenBank and RefSeq (gbrs_paired_asm is set)
+    #[clap(long, default_value = "false", conflicts_with = "unpaired_only")]
+    paired_only: bool,
+
+    /// only include assemblies that are only in GenBank or only in RefSeq (gbrs_paired_asm is
+    /// "na")
+    #[clap(long, default_value = "false")]
+    unpaired_only: bool,
+
+    /// only include assemblies whose submitter contains this (case-insensitive), e.g. "Sanger"
+    #[clap(long)]
+    submitter: Option<String>,
+
+    /// only include assemblies whose organism_name or infraspecific_name contains this
+    /// (case-insensitive), e.g. "O157". Can be given multiple times to match any of them
+    #[clap(long, alias = "organism-name-filter")]
+    organism_name_contains: Vec<String>,
+
+    /// only include assemblies with a genome_size of at least this many bases (e.g. 200kb, 5Mb)
+    #[clap(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// only include assemblies with a genome_size of at most this many bases (e.g. 200kb, 5Mb)
+    #[clap(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// keep only one assembly per tax ID, preferring the latest version and then the most
+    /// complete assembly level
+    #[clap(long, default_value = "false")]
+    unique_taxid: bool,
+
+    /// keep at most this many assemblies per tax ID, picked the same way as --unique-taxid
+    #[clap(
+        long,
+        visible_alias = "max-assemblies-per-taxid",
+        conflicts_with = "unique_taxid"
+    )]
+    max_per_taxid: Option<usize>,
+
+    /// keep one assembly per cluster of this precomputed accession → cluster ID file (two
+    /// whitespace-separated columns, e.g. from dRep or skani), picked the same way as
+    /// --unique-taxid. Assemblies that aren't in the file are left out unless --keep-unclustered
+    #[clap(long, alias = "dedup-by-ani-cluster", value_name = "PATH")]
+    cluster_file: Option<String>,
+
+    /// keep the assemblies that aren't in --cluster-file, each as a cluster of its own
+    #[clap(long, default_value = "false", requires = "cluster_file")]
+    keep_unclustered: bool,
+
+    /// comma-separated assembly levels from most to least preferred, used to pick between
+    /// assemblies of the same tax ID once their versions are equal. Levels that aren't listed come
+    /// last
+    #[clap(
+        long,
+        value_delimiter = ',',
+        default_value = "Complete Genome,Chromosome,Scaffold,Contig"
+    )]
+    prefer_assembly_level: Vec<String>,
+
+    /// only keep the first <LIMIT> matched assemblies, in the order given by --sort (or the best
+    /// <LIMIT>, with --assembly-level-priority)
+    #[clap(long)]
+    limit: Option<usize>,
+
+    /// with --limit, keep the best assemblies rather than the first ones: the most complete
+    /// assembly level (see --prefer-assembly-level), then latest versions, then reference and
+    /// representative genomes, then the most recently released
+    #[clap(long, default_value = "false", requires = "limit")]
+    assembly_level_priority: bool,
+}
+
+// downloads are network-bound so there's little point in going much higher than this by default
+const MAX_DEFAULT_PARALLEL: usize = 8;
+
+fn default_parallel() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(MAX_DEFAULT_PARALLEL)
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// print a shell completion script to stdout
+    #[command(hide = true)]
+    Completions { shell: Shell },
+    /// remove the cached assembly summaries, the taxdump and files left behind by interrupted
+    /// downloads. Without any flags, all of them are removed
+    Clean {
+        /// remove the assembly summaries (and filter cache) in --cache-dir
+        #[clap(long, default_value = "false")]
+        summaries: bool,
+
+        /// remove the extracted taxdump at --taxdump-path
+        #[clap(long, default_value = "false")]
+        taxdump: bool,
+
+        /// remove partial (.part) and temporary (.tmp) files in --out-dir
+        #[clap(long, default_value = "false")]
+        partial: bool,
+    },
+    /// download the taxonomy and the assembly summary of --source again, check them and exit
+    /// without downloading any assemblies, e.g. to keep the caches of a mirror fresh
+    #[command(alias = "refresh")]
+    Update,
+    /// print how many assemblies the assembly summary of --source has per assembly level, RefSeq
+    /// category and group (e.g. bacteria) as a TSV, then exit without downloading any assemblies
+    Stats,
+    /// print the assembly summaries each --source filters as a TSV, including the historical ones
+    /// that --include-historical adds, then exit
+    ListSources,
+}
+
+#[derive(Debug, Default, Clone, serde::Deserialize, serde::Serialize)]
+struct NCBIAssembly {
+    // the first column, e.g. GCF_000005845.2. This is what identifies an assembly everywhere
+    // (dedup, --state, GTDB lookups, output) rather than anything parsed out of ftp_path
+    #[serde(rename = "#assembly_accession", alias = "assembly_accession")]
+    assembly_accession: String,
+    taxid: String,
+    organism_name: String,
+    ftp_path: String,
+    // asm_name: String,
+    assembly_level: String,
+    seq_rel_date: String,
+    excluded_from_refseq: String,
+    version_status: String,
+    // only in newer versions of the assembly summary
+    #[serde(default)]
+    genome_size: String,
+    #[serde(default)]
+    relation_to_type_material: String,
+    #[serde(default)]
+    refseq_category: String,
+    // the sequencing center or consortium, e.g. "Wellcome Sanger Institute". The column is
+    // called asm_submitter in NCBI's summaries
+    #[serde(default, alias = "asm_submitter")]
+    submitter: String,
+    // e.g. strain=K-12
+    #[serde(default)]
+    infraspecific_name: String,
+    // roughly the domain or kingdom, e.g. bacteria, viral or plant
+    #[serde(default)]
+    group: String,
+    // the accession of the same assembly in the other database (GenBank for a RefSeq assembly and
+    // vice versa), or "na" when there isn't one
+    #[serde(default)]
+    gbrs_paired_asm: String,
+    // whether the paired assembly is "identical" or "different", "na" when there isn't one
+    #[serde(default)]
+    paired_asm_comp: String,
+    // which --source's summary the assembly is from, e.g. refseq
+    #[serde(skip)]
+    source: &'static str,
+    // the values of the --summary-columns-passthrough columns, in order
+    #[serde(skip)]
+    passthrough: Vec<String>,
+}
+
+// --organism-name-contains. A strain is often only named in infraspecific_name (strain=...), so
+// that's searched as well as organism_name
+fn matches_organism_name(assembly: &NCBIAssembly, filter: &AssemblyFilter) -> bool {
+    let organism_name = assembly.organism_name.to_lowercase();
+    let infraspecific_name = assembly.infraspecific_name.to_lowercase();
+    filter
+        .organism_names
+        .iter()
+        .any(|name| organism_name.contains(name) || infraspecific_name.contains(name))
+}
+
+// excluded_from_refseq reasons that mark an assembly as a metagenome-assembled genome (MAG) or
+// as otherwise assembled from an environmental sample rather than an isolate
+const METAGENOME_EXCLUSION_REASONS: [&str; 2] = [
+    "derived from metagenome",
+    "derived from environmental source",
+];
+
+impl NCBIAssembly {
+    // excluded_from_refseq holds a semicolon-separated list of reasons and is empty (or "na")
+    // for assemblies that NCBI has no complaints about
+    fn exclusion_reasons(&self) -> Vec<&str> {
+        self.excluded_from_refseq
+            .split(';')
+            .map(str::trim)
+            .filter(|reason| !reason.is_empty() && *reason != "na")
+            .collect()
+    }
+
+    // relation_to_type_material is e.g. "assembly from type material" and empty (or "na") for
+    // everything else
+    fn is_type_material(&self) -> bool {
+        let relation = self.relation_to_type_material.trim();
+        !relation.is_empty() && relation != "na"
+    }
+
+    // whether the assembly is in both GenBank and RefSeq. gbrs_paired_asm is empty in summaries
+    // that don't have the column
+    fn is_paired(&self) -> bool {
+        let paired = self.gbrs_paired_asm.trim();
+        !paired.is_empty() && paired != "na"
+    }
+
+    // assemblies from type material are never treated as MAGs, whatever excluded_from_refseq says
+    fn is_metagenome_derived(&self) -> bool {
+        !self.is_type_material()
+            && self.exclusion_reasons().iter().any(|reason| {
+                METAGENOME_EXCLUSION_REASONS
+                    .iter()
+                    .any(|mag_reason| mag_reason.eq_ignore_ascii_case(reason))
+            })
+    }
+
+    // NCBI suppresses assemblies it considers problematic (e.g. contaminated) without removing
+    // them from the summary
+    fn is_suppressed(&self) -> bool {
+        self.version_status.eq_ignore_ascii_case("suppressed")
+    }
+
+    // the version is the suffix of the accession, e.g. GCF_000005845.2 is version 2
+    fn accession_version(&self) -> u32 {
+        self.assembly_accession
+            .rsplit_once('.')
+            .and_then(|(_, version)| version.parse().ok())
+            .unwrap_or(0)
+    }
+
+    // higher is better. Used to pick a representative when there's more than one assembly to
+    // choose from: latest versions come first, then more recent accession versions, then more
+    // complete assembly levels
+    fn quality_key(&self, level_preference: &[String]) -> (bool, u32, usize) {
+        (
+            self.version_status.is_empty() || self.version_status == "latest",
+            self.accession_version(),
+            assembly_level_rank(&self.assembly_level, level_preference),
+        )
+    }
+
+    // higher is better. Used by --assembly-level-priority to rank assemblies of any tax ID: the
+    // assembly level comes first, then whether it's the latest version, then reference genomes
+    // ahead of representative genomes ahead of everything else, then the release date
+    fn priority_key(&self, level_preference: &[String]) -> (usize, bool, u8, Option<ReleaseDate>) {
+        (
+            assembly_level_rank(&self.assembly_level, level_preference),
+            self.version_status.is_empty() || self.version_status == "latest",
+            match self.refseq_category.to_lowercase().as_str() {
+                "reference genome" => 2,
+                "representative genome" => 1,
+                _ => 0,
+            },
+            parse_release_date(&self.seq_rel_date).ok(),
+        )
+    }
+
+    // the value of a FILTER_COLUMNS column, by its name in the summary
+    fn column(&self, name: &str) -> Option<&str> {
+        let value = match name {
+            "assembly_accession" => &self.assembly_accession,
+            "taxid" => &self.taxid,
+            "organism_name" => &self.organism_name,
+            "ftp_path" => &self.ftp_path,
+            "assembly_level" => &self.assembly_level,
+            "refseq_category" => &self.refseq_category,
+            "seq_rel_date" => &self.seq_rel_date,
+            "excluded_from_refseq" => &self.excluded_from_refseq,
+            "version_status" => &self.version_status,
+            "genome_size" => &self.genome_size,
+            "relation_to_type_material" => &self.relation_to_type_material,
+            "submitter" => &self.submitter,
+            "infraspecific_name" => &self.infraspecific_name,
+            "gbrs_paired_asm" => &self.gbrs_paired_asm,
+            "paired_asm_comp" => &self.paired_asm_comp,
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+// what gets written for each assembly by --json
+#[derive(Debug, serde::Serialize)]
+struct AssemblyRecord<'a> {
+    assembly_accession: &'a str,
+    taxid: &'a str,
+    organism_name: &'a str,
+    assembly_level: &'a str,
+    ftp_path: &'a str,
+}
+
+impl<'a> From<&'a NCBIAssembly> for AssemblyRecord<'a> {
+    fn from(assembly: &'a NCBIAssembly) -> Self {
+        AssemblyRecord {
+            assembly_accession: &assembly.assembly_accession,
+            taxid: &assembly.taxid,
+            organism_name: &assembly.organism_name,
+            assembly_level: &assembly.assembly_level,
+            ftp_path: &assembly.ftp_path,
+        }
+    }
+}
+
+// higher is better. `level_preference` is --prefer-assembly-level, most preferred first, and levels
+// that aren't in it come last
+fn assembly_level_rank(assembly_level: &str, level_preference: &[String]) -> usize {
+    level_preference
+        .iter()
+        .position(|level| level.eq_ignore_ascii_case(assembly_level))
+        .map_or(0, |position| level_preference.len() - position)
+}
+
+// keeps the best assembly (by NCBIAssembly::quality_key) for each tax ID. Ties go to whichever
+// assembly comes first in the summary.
+fn unique_by_taxid(
+    assemblies: Vec<NCBIAssembly>,
+    level_preference: &[String],
+) -> Vec<NCBIAssembly> {
+    let mut kept: Vec<NCBIAssembly> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for assembly in assemblies {
+        match positions.get(&assembly.taxid) {
+            Some(&i) => {
+                if assembly.quality_key(level_preference) > kept[i].quality_key(level_preference) {
+                    kept[i] = assembly;
+                }
+            }
+            None => {
+                positions.insert(assembly.taxid.clone(), kept.len());
+                kept.push(assembly);
+            }
+        }
+    }
+
+    kept
+}
+
+// like unique_by_taxid, but keeps up to `max` of each tax ID. The kept assemblies stay in the
+// order of the summary
+fn max_per_taxid(
+    assemblies: Vec<NCBIAssembly>,
+    max: usize,
+    level_preference: &[String],
+) -> Vec<NCBIAssembly> {
+    let mut by_taxid: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, assembly) in assemblies.iter().enumerate() {
+        by_taxid.entry(&assembly.taxid).or_default().push(i);
+    }
+
+    let mut keep = vec![false; assemblies.len()];
+    for indices in by_taxid.values_mut() {
+        // stable, so ties go to whichever comes first in the summary
+        indices.sort_by_key(|&i| std::cmp::Reverse(assemblies[i].quality_key(level_preference)));
+        for &i in indices.iter().take(max) {
+            keep[i] = true;
+        }
+    }
+
+    assemblies
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(assembly, keep)| keep.then_some(assembly))
+        .collect()
+}
+
+// --cluster-file: the cluster ID of each accession. Lines starting with # and blank lines are
+// skipped, like in accession files
+fn read_cluster_file(path: &str) -> HashMap<String, String> {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Unable to read cluster file {} ({})", path, e));
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut columns = line.split_whitespace();
+            match (columns.next(), columns.next()) {
+                (Some(accession), Some(cluster)) => (accession.to_string(), cluster.to_string()),
+                _ => panic!(
+                    "Cluster file {} has a line without a cluster ID: {}",
+                    path, line
+                ),
+            }
+        })
+        .collect()
+}
+
+// like unique_by_taxid, but by the cluster of each assembly in `clusters`, where an accession
+// without a version stands for every version. Returns the kept assemblies, in the order of the
+// summary, along with how many weren't in any cluster (which are kept as their own cluster with
+// `keep_unclustered` and left out otherwise)
+fn unique_by_cluster(
+    assemblies: Vec<NCBIAssembly>,
+    clusters: &HashMap<String, String>,
+    keep_unclustered: bool,
+    level_preference: &[String],
+) -> (Vec<NCBIAssembly>, usize) {
+    let mut kept: Vec<NCBIAssembly> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut n_unclustered = 0;
+
+    for assembly in assemblies {
+        let accession = assembly.assembly_accession.as_str();
+        let cluster = clusters.get(accession).or_else(|| {
+            accession
+                .rsplit_once('.')
+                .and_then(|(unversioned, _)| clusters.get(unversioned))
+        });
+        let Some(cluster) = cluster else {
+            n_unclustered += 1;
+            if keep_unclustered {
+                kept.push(assembly);
+            }
+            continue;
+        };
+        match positions.get(cluster) {
+            Some(&i) => {
+                if assembly.quality_key(level_preference) > kept[i].quality_key(level_preference) {
+                    kept[i] = assembly;
+                }
+            }
+            None => {
+                positions.insert(cluster.clone(), kept.len());
+                kept.push(assembly);
+            }
+        }
+    }
+
+    (kept, n_unclustered)
+}
+
+// --limit: the first `limit` assemblies or, with --assembly-level-priority (`priority` is then
+// --prefer-assembly-level), the best `limit` of them by NCBIAssembly::priority_key. Either way
+// they stay in the order they're in, and ties go to whichever comes first
+fn limit_assemblies(
+    assemblies: Vec<NCBIAssembly>,
+    limit: usize,
+    priority: Option<&[String]>,
+) -> Vec<NCBIAssembly> {
+    let Some(level_preference) = priority else {
+        return assemblies.into_iter().take(limit).collect();
+    };
+
+    let mut ranked: Vec<usize> = (0..assemblies.len()).collect();
+    // stable, which is what keeps ties in order
+    ranked.sort_by_key(|&i| std::cmp::Reverse(assemblies[i].priority_key(level_preference)));
+    let mut keep = vec![false; assemblies.len()];
+    for &i in ranked.iter().take(limit) {
+        keep[i] = true;
+    }
+
+    assemblies
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(assembly, keep)| keep.then_some(assembly))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ReleaseDate {
+    year: u16,
+    month: u8,
+    day: u8,
+}
+
+// NCBI has used YYYY/MM/DD for seq_rel_date for most of its history but older mirrors and
+// hand-edited summaries show up with ISO dates, compact YYYYMMDD dates and trailing timestamps.
+fn parse_release_date(s: &str) -> Result<ReleaseDate, String> {
+    let date = s
+        .trim()
+        .split(|c: char| c == 'T' || c.is_whitespace())
+        .next()
+        .unwrap_or_default();
+
+    let parts: Vec<&str> = if date.contains('/') {
+        date.split('/').collect()
+    } else if date.contains('-') {
+        date.split('-').collect()
+    } else if date.len() == 8 && date.chars().all(|c| c.is_ascii_digit()) {
+        vec![&date[0..4], &date[4..6], &date[6..8]]
+    } else {
+        vec![date]
+    };
+
+    let invalid = || format!("Invalid date `{}` (expected YYYY/MM/DD or YYYY-MM-DD)", s);
+
+    match parts.as_slice() {
+        [year, month, day] if year.len() == 4 => {
+            let year: u16 = year.parse().map_err(|_| invalid())?;
+            let month: u8 = month.parse().map_err(|_| invalid())?;
+            let day: u8 = day.parse().map_err(|_| invalid())?;
+            if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+                return Err(invalid());
+            }
+            Ok(ReleaseDate { year, month, day })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// parses sizes like `5Mb`, `200kb`, `1.5G` or `1000`. Units are decimal (1kb = 1000) since these
+// are mostly used for genome sizes in bases
+fn parse_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid size `{}` (expected e.g. 200kb, 5Mb or 1000)", s);
+
+    let lower = s.trim().to_lowercase();
+    let number = lower
+        .strip_suffix("bp")
+        .or_else(|| lower.strip_suffix('b'))
+        .unwrap_or(&lower);
+
+    let (number, multiplier) = match number.chars().last() {
+        Some('k') => (&number[..number.len() - 1], 1e3),
+        Some('m') => (&number[..number.len() - 1], 1e6),
+        Some('g') => (&number[..number.len() - 1], 1e9),
+        Some('t') => (&number[..number.len() - 1], 1e12),
+        _ => (number, 1.0),
+    };
+
+    let number: f64 = number.trim().parse().map_err(|_| invalid())?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(invalid());
+    }
+
+    Ok((number * multiplier).round() as u64)
+}
+
+// a --max-bandwidth like `10MB/s` or `500k`, in bytes per second
+fn parse_bandwidth(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let rate = parse_size(trimmed.strip_suffix("/s").unwrap_or(trimmed))
+        .map_err(|_| format!("Invalid bandwidth `{}` (expected e.g. 10MB/s or 500k)", s))?;
+    if rate == 0 {
+        return Err(format!(
+            "--max-bandwidth has to be more than 0, got `{}`",
+            s
+        ));
+    }
+    Ok(rate)
+}
+
+// a --max-runtime like `90m` or `2h 30m`, or a --summary-max-age-warn like `30d`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let duration = humantime::parse_duration(s.trim()).map_err(|e| {
+        format!(
+            "Invalid duration `{}` ({}, expected e.g. 90m or 2h 30m)",
+            s, e
+        )
+    })?;
+    if duration.is_zero() {
+        return Err(format!("The duration has to be more than 0, got `{}`", s));
+    }
+    Ok(duration)
+}
+
+const FILENAME_PLACEHOLDERS: [&str; 4] = ["accession", "asm_name", "organism", "taxid"];
+
+// every {...} in a --filename-template has to be one of FILENAME_PLACEHOLDERS
+fn parse_filename_template(s: &str) -> Result<String, String> {
+    let mut rest = s;
+    while let Some(start) = rest.find(['{', '}']) {
+        let end = match rest[start..].find('}') {
+            Some(end) if rest[start..].starts_with('{') => start + end,
+            _ => return Err(format!("Unbalanced braces in --filename-template `{}`", s)),
+        };
+        let placeholder = &rest[start + 1..end];
+        if !FILENAME_PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "Unknown placeholder {{{}}} in --filename-template (expected one of {})",
+                placeholder,
+                FILENAME_PLACEHOLDERS
+                    .map(|name| format!("{{{}}}", name))
+                    .join(", ")
+            ));
+        }
+        rest = &rest[end + 1..];
+    }
+    if s.contains(['/', '\\']) {
+        return Err(format!(
+            "--filename-template can't contain a directory, got `{}`",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+fn parse_name_delimiter(s: &str) -> Result<String, String> {
+    if s.chars().all(is_filename_safe) {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "--name-delimiter can only contain letters, digits, ., -, _ and +, got `{}`",
+            s
+        ))
+    }
+}
+
+fn parse_exec(s: &str) -> Result<String, String> {
+    if s.contains(EXEC_PLACEHOLDER) {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "--exec has to contain {} for the path of the file, got `{}`",
+            EXEC_PLACEHOLDER, s
+        ))
+    }
+}
+
+// NCBI tax IDs are non-negative integers, but are kept as strings like the taxonomy's own keys.
+// Leading zeros are dropped so that e.g. 0562 still finds 562
+fn parse_tax_id(s: &str) -> Result<String, String> {
+    s.trim().parse::<u32>().map(|tax_id| tax_id.to_string()).map_err(|_| {
+        format!(
+            "Invalid tax ID `{}` (expected a number like 562, use --tax-name to look up a taxon by name)",
+            s
+        )
+    })
+}
+
+// an NCBI rank name, for --count-by, --include-rank-below and --include-rank-above
+fn parse_rank(s: &str) -> Result<TaxRank, String> {
+    s.parse()
+        .map_err(|_| format!("Unknown rank `{}` (expected e.g. genus or species)", s))
+}
+
+// only checks the basic shape (something@domain.tld) to catch typos like a missing @
+fn parse_email(s: &str) -> Result<String, String> {
+    let invalid = || format!("Invalid email address `{}`", s);
+
+    let (local, domain) = s.trim().split_once('@').ok_or_else(invalid)?;
+    let valid_domain = domain
+        .split_once('.')
+        .is_some_and(|(name, tld)| !name.is_empty() && !tld.is_empty() && !tld.ends_with('.'));
+    if local.is_empty() || !valid_domain || s.trim().contains(char::is_whitespace) {
+        return Err(invalid());
+    }
+
+    Ok(s.trim().to_string())
+}
+
+// NCBI asks bulk users to identify themselves so that they can be contacted before being
+// throttled, so the email goes in the User-Agent and the From header
+fn build_client(email: Option<&str>) -> Client {
+    let mut user_agent = format!("gdl/{}", env!("CARGO_PKG_VERSION"));
+    let mut headers = HeaderMap::new();
+
+    if let Some(email) = email {
+        user_agent.push_str(&format!(" (mailto:{})", email));
+        headers.insert(
+            FROM,
+            HeaderValue::from_str(email).expect("Invalid email address"),
+        );
+    }
+
+    Client::builder()
+        .user_agent(user_agent)
+        .default_headers(headers)
+        .build()
+        .expect("Unable to build HTTP client")
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum AssemblyFormat {
+    Fna,
+    Faa,
+    Gbff,
+    Gff,
+    // the nucleotide sequences of the annotated CDS and RNA features
+    Cds,
+    Rna,
+    #[value(name = "feature_table")]
+    FeatureTable,
+    #[value(name = "assembly_report")]
+    AssemblyReport,
+}
+
+impl AssemblyFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AssemblyFormat::Fna => "fna",
+            AssemblyFormat::Faa => "faa",
+            AssemblyFormat::Gbff => "gbff",
+            AssemblyFormat::Gff => "gff",
+            AssemblyFormat::Cds => "cds",
+            AssemblyFormat::Rna => "rna",
+            AssemblyFormat::FeatureTable => "feature_table",
+            AssemblyFormat::AssemblyReport => "assembly_report",
+        }
+    }
+
+    // what NCBI appends to the assembly name to get the file name, e.g.
+    // GCF_000005845.2_ASM584v2 + _genomic.fna.gz
+    fn file_suffix(&self) -> &'static str {
+        match self {
+            AssemblyFormat::Fna => "_genomic.fna.gz",
+            AssemblyFormat::Faa => "_protein.faa.gz",
+            AssemblyFormat::Gbff => "_genomic.gbff.gz",
+            AssemblyFormat::Gff => "_genomic.gff.gz",
+            AssemblyFormat::Cds => "_cds_from_genomic.fna.gz",
+            AssemblyFormat::Rna => "_rna_from_genomic.fna.gz",
+            AssemblyFormat::FeatureTable => "_feature_table.txt.gz",
+            AssemblyFormat::AssemblyReport => "_assembly_report.txt",
+        }
+    }
+
+    // formats that tools like samtools faidx can index once they're bgzipped
+    fn is_sequence(&self) -> bool {
+        matches!(
+            self,
+            AssemblyFormat::Fna | AssemblyFormat::Faa | AssemblyFormat::Cds | AssemblyFormat::Rna
+        )
+    }
+
+    // what the Datasets API calls this format, if it has it
+    fn datasets_annotation_type(&self) -> Option<&'static str> {
+        match self {
+            AssemblyFormat::Fna => Some("GENOME_FASTA"),
+            AssemblyFormat::Faa => Some("PROT_FASTA"),
+            AssemblyFormat::Gbff => Some("GENOME_GBFF"),
+            AssemblyFormat::Gff => Some("GENOME_GFF"),
+            AssemblyFormat::Cds => Some("CDS_FASTA"),
+            // the Datasets API's RNA_FASTA holds transcripts (_rna.fna), not _rna_from_genomic
+            AssemblyFormat::Rna | AssemblyFormat::FeatureTable | AssemblyFormat::AssemblyReport => {
+                None
+            }
+        }
+    }
+
+    // what gdl appends to the assembly name to get the local file name
+    fn extension(&self) -> &'static str {
+        match self {
+            AssemblyFormat::Fna => "fna.gz",
+            AssemblyFormat::Faa => "faa.gz",
+            AssemblyFormat::Gbff => "gbff.gz",
+            AssemblyFormat::Gff => "gff.gz",
+            AssemblyFormat::Cds => "cds_from_genomic.fna.gz",
+            AssemblyFormat::Rna => "rna_from_genomic.fna.gz",
+            AssemblyFormat::FeatureTable => "feature_table.txt.gz",
+            AssemblyFormat::AssemblyReport => "assembly_report.txt",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+#[clap(rename_all = "lowercase")]
+enum Bundle {
+    Annotation,
+    Genome,
+}
+
+impl Bundle {
+    fn formats(&self) -> Vec<AssemblyFormat> {
+        match self {
+            Bundle::Annotation => vec![
+                AssemblyFormat::Gff,
+                AssemblyFormat::Faa,
+                AssemblyFormat::FeatureTable,
+            ],
+            Bundle::Genome => vec![AssemblyFormat::Fna, AssemblyFormat::AssemblyReport],
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum Scheme {
+    Https,
+    Http,
+}
+
+impl Scheme {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Https => "https",
+            Scheme::Http => "http",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum Backend {
+    Ftp,
+    Datasets,
+}
+
+// --name-map
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum NameMapFormat {
+    /// the assembly's lineage, e.g. `Bacteria; Pseudomonadota; ...; Escherichia coli` (or
+    /// `d__Bacteria;p__Pseudomonadota;...;s__Escherichia coli` with --taxonomy gtdb)
+    Lineage,
+    /// the assembly's NCBI tax ID
+    Taxid,
+}
+
+// columns of the assembly summary, plus a few about the downloaded file, that can be written to
+// --manifest
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "snake_case")]
+enum ManifestColumn {
+    #[value(alias = "assembly_accession")]
+    Accession,
+    Taxid,
+    OrganismName,
+    FtpPath,
+    AssemblyLevel,
+    SeqRelDate,
+    ExcludedFromRefseq,
+    VersionStatus,
+    GenomeSize,
+    RelationToTypeMaterial,
+    // the format of the file, e.g. fna
+    Format,
+    // where the file is, relative to --out-dir (or within --tar)
+    Path,
+    // where the file was downloaded from
+    Url,
+    // which --source's summary the assembly is from, e.g. refseq
+    Source,
+    Md5,
+    Sha256,
+}
+
+impl ManifestColumn {
+    fn name(&self) -> &'static str {
+        match self {
+            ManifestColumn::Accession => "accession",
+            ManifestColumn::Taxid => "taxid",
+            ManifestColumn::OrganismName => "organism_name",
+            ManifestColumn::FtpPath => "ftp_path",
+            ManifestColumn::AssemblyLevel => "assembly_level",
+            ManifestColumn::SeqRelDate => "seq_rel_date",
+            ManifestColumn::ExcludedFromRefseq => "excluded_from_refseq",
+            ManifestColumn::VersionStatus => "version_status",
+            ManifestColumn::GenomeSize => "genome_size",
+            ManifestColumn::RelationToTypeMaterial => "relation_to_type_material",
+            ManifestColumn::Format => "format",
+            ManifestColumn::Path => "path",
+            ManifestColumn::Url => "url",
+            ManifestColumn::Source => "source",
+            ManifestColumn::Md5 => "md5",
+            ManifestColumn::Sha256 => "sha256",
+        }
+    }
+
+    // computed from the downloaded file rather than taken from the summary
+    fn is_checksum(&self) -> bool {
+        matches!(self, ManifestColumn::Md5 | ManifestColumn::Sha256)
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    fn manifest_column(&self) -> ManifestColumn {
+        match self {
+            ChecksumAlgorithm::Md5 => ManifestColumn::Md5,
+            ChecksumAlgorithm::Sha256 => ManifestColumn::Sha256,
+        }
+    }
+}
+
+// what --recompress turns NCBI's gzip into
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum Recompression {
+    Zstd,
+}
+
+// the --compression-level values that the files gdl writes can use: zstd with --recompress, gzip
+// or bgzip otherwise
+fn compression_levels(recompress: Option<&Recompression>) -> RangeInclusive<u32> {
+    match recompress {
+        Some(Recompression::Zstd) => 1..=22,
+        None => 0..=9,
+    }
+}
+
+// which of the name classes in names.dmp --tax-name is matched against
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum NameClass {
+    // scientific names, then synonyms when nothing matches
+    Scientific,
+    Synonym,
+    // common and GenBank common names, e.g. "human"
+    Common,
+    // names with their authority, e.g. "Escherichia coli (Migula 1895) Castellani and Chalmers"
+    Authority,
+    // scientific names, then any other class when nothing matches
+    Any,
+}
+
+impl NameClass {
+    fn includes(&self, class: &str) -> bool {
+        match self {
+            NameClass::Scientific => {
+                matches!(class, "scientific name" | "synonym" | "equivalent name")
+            }
+            NameClass::Synonym => matches!(class, "synonym" | "equivalent name"),
+            NameClass::Common => matches!(class, "common name" | "genbank common name"),
+            NameClass::Authority => class == "authority",
+            NameClass::Any => true,
+        }
+    }
+}
+
+// files from the assembly's directory that --extra-files saves next to the downloaded formats
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum ExtraFile {
+    Md5,
+    Stats,
+}
+
+impl ExtraFile {
+    // the file's name in the assembly's directory on NCBI
+    fn remote_filename(&self, assembly_name: &str) -> String {
+        match self {
+            ExtraFile::Md5 => "md5checksums.txt".to_string(),
+            ExtraFile::Stats => format!("{}_assembly_stats.txt", assembly_name),
+        }
+    }
+
+    // what gdl appends to the assembly name to get the local file name, like
+    // AssemblyFormat::extension
+    fn extension(&self) -> &'static str {
+        match self {
+            ExtraFile::Md5 => "md5checksums.txt",
+            ExtraFile::Stats => "assembly_stats.txt",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum TaxdumpFormat {
+    Classic,
+    New,
+}
+
+impl TaxdumpFormat {
+    fn url(&self) -> &'static str {
+        match self {
+            TaxdumpFormat::Classic => TAXDUMP_URL,
+            TaxdumpFormat::New => NEW_TAXDUMP_URL,
+        }
+    }
+
+    // the files load_taxonomy (and, for the new format, lineage display) needs
+    fn required_files(&self) -> &'static [&'static str] {
+        match self {
+            TaxdumpFormat::Classic => &["nodes.dmp", "names.dmp"],
+            TaxdumpFormat::New => &["nodes.dmp", "names.dmp", RANKED_LINEAGE_FILENAME],
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+enum TaxonomyBackend {
+    #[default]
+    Ncbi,
+    Gtdb,
+}
+
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+#[clap(rename_all = "lowercase")]
+enum SortOrder {
+    Taxid,
+    Accession,
+    Level,
+    None,
+}
+
+// ties are broken by accession, so the order never depends on the summary's
+fn sort_assemblies(
+    assemblies: &mut [NCBIAssembly],
+    order: &SortOrder,
+    level_preference: &[String],
+) {
+    match order {
+        SortOrder::Taxid => assemblies.sort_by(|a, b| {
+            let taxid = |assembly: &NCBIAssembly| assembly.taxid.parse::<u64>().unwrap_or(u64::MAX);
+            (taxid(a), &a.taxid, &a.assembly_accession).cmp(&(
+                taxid(b),
+                &b.taxid,
+                &b.assembly_accession,
+            ))
+        }),
+        SortOrder::Accession => {
+            assemblies.sort_by(|a, b| a.assembly_accession.cmp(&b.assembly_accession))
+        }
+        SortOrder::Level => assemblies.sort_by(|a, b| {
+            let rank = |assembly: &NCBIAssembly| {
+                std::cmp::Reverse(assembly_level_rank(
+                    &assembly.assembly_level,
+                    level_preference,
+                ))
+            };
+            (rank(a), &a.assembly_accession).cmp(&(rank(b), &b.assembly_accession))
+        }),
+        SortOrder::None => {}
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+enum AccessionVersionPolicy {
+    Exact,
+    Latest,
+    #[default]
+    Any,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+enum AssemblySource {
+    Genbank,
+    Refseq,
+    // both RefSeq and GenBank
+    All,
+    // no summary of NCBI's, the one given with --assembly-summary-path is used instead
+    None,
+    // the assemblies that are no longer in the main summaries, for --include-historical
+    #[value(skip)]
+    RefseqHistorical,
+    #[value(skip)]
+    GenbankHistorical,
+}
+
+impl AssemblySource {
+    // the summaries that are filtered, RefSeq first and each followed by its historical summary.
+    // There are none for --source none
+    fn summaries(&self, include_historical: bool) -> Vec<SummarySource> {
+        let sources = match self {
+            AssemblySource::All => vec![AssemblySource::Refseq, AssemblySource::Genbank],
+            source => vec![source.clone()],
+        };
+        sources
+            .into_iter()
+            .flat_map(|source| {
+                let historical = include_historical.then(|| source.historical()).flatten();
+                iter::once(source).chain(historical)
+            })
+            .filter_map(|source| source.summary())
+            .collect()
+    }
+
+    fn historical(&self) -> Option<AssemblySource> {
+        match self {
+            AssemblySource::Genbank => Some(AssemblySource::GenbankHistorical),
+            AssemblySource::Refseq => Some(AssemblySource::RefseqHistorical),
+            _ => None,
+        }
+    }
+
+    // e.g. refseq_historical. All and None don't have a summary of their own
+    fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AssemblySource::Genbank => Some("genbank"),
+            AssemblySource::Refseq => Some("refseq"),
+            AssemblySource::GenbankHistorical => Some("genbank_historical"),
+            AssemblySource::RefseqHistorical => Some("refseq_historical"),
+            AssemblySource::All | AssemblySource::None => None,
+        }
+    }
+
+    fn url(&self) -> Option<&'static str> {
+        match self {
+            AssemblySource::Genbank => Some(
+                "https://ftp.ncbi.nlm.nih.gov/genomes/ASSEMBLY_REPORTS/assembly_summary_genbank.txt",
+            ),
+            AssemblySource::Refseq => Some(
+                "https://ftp.ncbi.nlm.nih.gov/genomes/ASSEMBLY_REPORTS/assembly_summary_refseq.txt",
+            ),
+            AssemblySource::GenbankHistorical => Some(
+                "https://ftp.ncbi.nlm.nih.gov/genomes/ASSEMBLY_REPORTS/assembly_summary_genbank_historical.txt",
+            ),
+            AssemblySource::RefseqHistorical => Some(
+                "https://ftp.ncbi.nlm.nih.gov/genomes/ASSEMBLY_REPORTS/assembly_summary_refseq_historical.txt",
+            ),
+            AssemblySource::All | AssemblySource::None => None,
+        }
+    }
+
+    fn summary(&self) -> Option<SummarySource> {
+        let historical = matches!(
+            self,
+            AssemblySource::GenbankHistorical | AssemblySource::RefseqHistorical
+        );
+        // what the assemblies in the summary are tagged with, so historical ones go with the rest
+        let origin = match self {
+            AssemblySource::GenbankHistorical => AssemblySource::Genbank.as_str(),
+            AssemblySource::RefseqHistorical => AssemblySource::Refseq.as_str(),
+            source => source.as_str(),
+        };
+        Some(SummarySource {
+            name: self.as_str()?,
+            origin: origin?,
+            url: self.url()?,
+            historical,
+        })
+    }
+}
+
+// `gdl list-sources`: a header, then a (source, summary, historical, url) row per summary of each
+// --source. --source none has a single row for the --assembly-summary-path it needs instead
+fn source_rows() -> Vec<[String; 4]> {
+    let mut rows = vec![["source", "summary", "historical", "url"].map(str::to_string)];
+    for source in AssemblySource::value_variants() {
+        let Some(value) = source.to_possible_value() else {
+            continue;
+        };
+        let summaries = source.summaries(true);
+        if summaries.is_empty() {
+            rows.push([
+                value.get_name().to_string(),
+                "--assembly-summary-path".to_string(),
+                "false".to_string(),
+                String::new(),
+            ]);
+        }
+        for summary in summaries {
+            rows.push([
+                value.get_name().to_string(),
+                summary.name.to_string(),
+                summary.historical.to_string(),
+                summary.url.to_string(),
+            ]);
+        }
+    }
+    rows
+}
+
+// one of NCBI's assembly summaries, e.g. the historical RefSeq one
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SummarySource {
+    // also names the cached summary, e.g. assembly_summary_refseq_historical.txt
+    name: &'static str,
+    // e.g. refseq for refseq_historical
+    origin: &'static str,
+    url: &'static str,
+    historical: bool,
+}
+
+#[derive(Debug, Default)]
+struct DownloadOptions {
+    retries: u32,
+    scheme: Option<Scheme>,
+    // (from, to) prefixes set by --rebase-url
+    rebase_url: Option<(String, String)>,
+    bgzip: bool,
+    recompress: Option<Recompression>,
+    // --compression-level, already checked against compression_levels
+    compression_level: Option<u32>,
+    // --both
+    decompressed_copy: bool,
+    // --check-genome-length
+    check_genome_length: bool,
+    // --verify-gzip-integrity
+    verify_gzip_integrity: bool,
+    flat_filenames: bool,
+    // --filename-template and --name-delimiter
+    filename_template: Option<String>,
+    name_delimiter: String,
+    // put each format's files in its own directory (e.g. fna/, gff/)
+    format_subdirs: bool,
+    // --source-subdirs
+    source_subdirs: bool,
+    // the --shard-size subdirectory of each accession, e.g. 003
+    shards: HashMap<String, String>,
+    // --api-key, only sent to the Datasets API
+    api_key: Option<String>,
+    // --preserve-directory-structure
+    preserve_directory_structure: bool,
+    // --chunked
+    chunked: Option<ChunkedDownload>,
+    // --max-file-size
+    max_file_size: Option<u64>,
+    // --max-bandwidth, shared by every download
+    throttle: Option<Throttle>,
+    // every byte received so far, for the throughput shown while downloading
+    bytes_received: Arc<AtomicU64>,
+    // the sizes of the files started so far, for the ETA
+    expected_bytes: Arc<ExpectedBytes>,
+    // how many assemblies can be downloaded at once, which goes down when NCBI rate limits us
+    slots: DownloadSlots,
+}
+
+// 429s in a row (from any download) before fewer assemblies are downloaded at once
+const RATE_LIMITED_BEFORE_SLOWING: usize = 3;
+
+// limits how many assemblies are downloaded at once. Starts at --parallel and is halved (down to
+// one) whenever NCBI keeps answering with 429 Too Many Requests
+#[derive(Debug)]
+struct DownloadSlots {
+    // (allowed, in use)
+    slots: Mutex<(usize, usize)>,
+    freed: Condvar,
+    // 429s in a row since the last successful request
+    n_rate_limited: AtomicUsize,
+}
+
+// unlimited, for everything but the assembly downloads themselves
+impl Default for DownloadSlots {
+    fn default() -> Self {
+        DownloadSlots::new(usize::MAX)
+    }
+}
+
+impl DownloadSlots {
+    fn new(allowed: usize) -> Self {
+        DownloadSlots {
+            slots: Mutex::new((allowed.max(1), 0)),
+            freed: Condvar::new(),
+            n_rate_limited: AtomicUsize::new(0),
+        }
+    }
+
+    // waits until fewer than the allowed number of downloads are running
+    fn acquire(&self) -> DownloadSlot<'_> {
+        let mut slots = self
+            .freed
+            .wait_while(self.slots.lock().unwrap(), |(allowed, in_use)| {
+                *in_use >= *allowed
+            })
+            .unwrap();
+        slots.1 += 1;
+        DownloadSlot(self)
+    }
+
+    // returns the new limit when this was one 429 too many
+    fn rate_limited(&self) -> Option<usize> {
+        if self.n_rate_limited.fetch_add(1, Ordering::Relaxed) + 1 < RATE_LIMITED_BEFORE_SLOWING {
+            return None;
+        }
+        self.n_rate_limited.store(0, Ordering::Relaxed);
+        let mut slots = self.slots.lock().unwrap();
+        let allowed = (slots.0.min(slots.1) / 2).max(1);
+        (allowed < slots.0).then(|| {
+            slots.0 = allowed;
+            allowed
+        })
+    }
+
+    fn succeeded(&self) {
+        self.n_rate_limited.store(0, Ordering::Relaxed);
+    }
+}
+
+struct DownloadSlot<'a>(&'a DownloadSlots);
+
+impl Drop for DownloadSlot<'_> {
+    fn drop(&mut self) {
+        self.0.slots.lock().unwrap().1 -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[derive(Debug)]
+struct ChunkedDownload {
+    // files smaller than this are downloaded over a single connection
+    threshold: u64,
+    connections: usize,
+}
+
+fn bytes_per_second(bytes: u64, elapsed: Duration) -> u64 {
+    if elapsed.is_zero() {
+        return 0;
+    }
+    (bytes as f64 / elapsed.as_secs_f64()) as u64
+}
+
+// the Content-Length of every file whose download has started (when the server sent one)
+#[derive(Debug, Default)]
+struct ExpectedBytes {
+    bytes: AtomicU64,
+    n_files: AtomicU64,
+}
+
+impl ExpectedBytes {
+    fn add(&self, size: Option<u64>) {
+        if let Some(size) = size {
+            self.bytes.fetch_add(size, Ordering::Relaxed);
+            self.n_files.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// the ETA of the assembly downloads, from the bytes still to come at the throughput so far rather
+// than from the number of assemblies left, since files range from a few KB (plasmids) to several
+// GB. Files that haven't started yet are taken to be as big as the average of those that have, or
+// as the estimate shown before downloading until one has. None until anything has been received
+struct ByteEta {
+    bytes_received: Arc<AtomicU64>,
+    expected_bytes: Arc<ExpectedBytes>,
+    n_files: u64,
+    estimated_file_size: Option<u64>,
+}
+
+impl ByteEta {
+    fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        byte_eta(
+            self.bytes_received.load(Ordering::Relaxed),
+            self.expected_bytes.bytes.load(Ordering::Relaxed),
+            self.expected_bytes.n_files.load(Ordering::Relaxed),
+            self.n_files,
+            self.estimated_file_size,
+            elapsed,
+        )
+    }
+}
+
+fn byte_eta(
+    bytes_received: u64,
+    bytes_expected: u64,
+    n_started: u64,
+    n_files: u64,
+    estimated_file_size: Option<u64>,
+    elapsed: Duration,
+) -> Option<Duration> {
+    let rate = bytes_per_second(bytes_received, elapsed);
+    if rate == 0 {
+        return None;
+    }
+    let file_size = match n_started {
+        0 => estimated_file_size?,
+        n_started => bytes_expected / n_started,
+    };
+    let total = bytes_expected + n_files.saturating_sub(n_started) * file_size;
+    Some(Duration::from_secs(
+        total.saturating_sub(bytes_received) / rate,
+    ))
+}
+
+// a token bucket of bytes. Each write takes its size from the bucket, which refills at
+// `bytes_per_second` and holds at most a second's worth. Writers that take more than is left
+// sleep until the bucket has caught up, so the total rate stays under the limit however many
+// downloads share it
+#[derive(Debug)]
+struct Throttle {
+    bytes_per_second: f64,
+    // (tokens, when they were last topped up). Tokens go negative while writers are waiting
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second as f64;
+        Throttle {
+            bytes_per_second,
+            bucket: Mutex::new((bytes_per_second, Instant::now())),
+        }
+    }
+
+    fn consume(&self, n_bytes: usize) {
+        let wait = {
+            let mut bucket = self
+                .bucket
+                .lock()
+                .expect("Unable to lock bandwidth throttle");
+            let (tokens, updated) = &mut *bucket;
+            let now = Instant::now();
+            let refill = now.duration_since(*updated).as_secs_f64() * self.bytes_per_second;
+            *tokens = (*tokens + refill).min(self.bytes_per_second) - n_bytes as f64;
+            *updated = now;
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / self.bytes_per_second))
+        };
+        if let Some(wait) = wait {
+            thread::sleep(wait);
+        }
+    }
+}
+
+// where downloads are written to. Counts the bytes received and holds them to --max-bandwidth, if
+// there is one
+struct DownloadWriter<'a, W: Write> {
+    inner: W,
+    options: &'a DownloadOptions,
+}
+
+impl<'a, W: Write> DownloadWriter<'a, W> {
+    fn new(inner: W, options: &'a DownloadOptions) -> Self {
+        DownloadWriter { inner, options }
+    }
+}
+
+impl<W: Write> Write for DownloadWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n_written = self.inner.write(buf)?;
+        self.options
+            .bytes_received
+            .fetch_add(n_written as u64, Ordering::Relaxed);
+        if let Some(throttle) = &self.options.throttle {
+            throttle.consume(n_written);
+        }
+        Ok(n_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// how much of the body of an error response is shown to the user
+const ERROR_BODY_SNIPPET_LEN: usize = 200;
+
+/// What can go wrong while fetching and saving an assembly or looking up a taxon. These end up in
+/// the message printed for each failed download, so they keep enough detail to tell e.g. a 404
+/// from a 503 from a DNS failure
+#[derive(Debug, thiserror::Error)]
+pub enum GdlError {
+    // a response that wasn't a success, with the start of its body
+    #[error("{url} returned {status}{}", body_snippet(.body))]
+    Http {
+        url: String,
+        status: StatusCode,
+        body: String,
+    },
+    // no response at all (DNS, refused connections, timeouts) or one that broke off halfway
+    #[error("Error fetching data from {url} ({source})")]
+    Request { url: String, source: reqwest::Error },
+    // a 429, with how long the server asked us to wait before trying again
+    #[error("{url} returned 429 Too Many Requests")]
+    RateLimited {
+        url: String,
+        retry_after: Option<Duration>,
+    },
+    #[error("Unable to {action} {} ({source})", .path.display())]
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    // a URL or filename that can't be worked out from the assembly summary
+    #[error("{0}")]
+    Parse(String),
+    #[error("Unable to find a tax ID: {0}")]
+    TaxonNotFound(String),
+    // a taxdump that's incomplete or corrupted, e.g. by an earlier interrupted download
+    #[error("Unable to load taxdump from {} ({message})", .path.display())]
+    TaxonomyLoad { path: PathBuf, message: String },
+    #[error("Unable to {action} {} ({source})", .path.display())]
+    Decompress {
+        action: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    // something other than what was asked for, e.g. an error page served with a 200 or a file
+    // that doesn't match its checksum
+    #[error("{0}")]
+    InvalidDownload(String),
+    // a file over --max-file-size, which is skipped rather than failed
+    #[error("{url} is {} (over --max-file-size {})", HumanBytes(*.size), HumanBytes(*.max_file_size))]
+    TooLarge {
+        url: String,
+        size: u64,
+        max_file_size: u64,
+    },
+    // an --exec command that exited with an error or was killed
+    #[error("--exec failed for {} ({status})", .path.display())]
+    Exec {
+        path: PathBuf,
+        status: std::process::ExitStatus,
+    },
+}
+
+impl GdlError {
+    fn io(action: &'static str, path: &Path) -> impl FnOnce(io::Error) -> GdlError {
+        let path = path.to_path_buf();
+        move |source| GdlError::Io {
+            action,
+            path,
+            source,
+        }
+    }
+
+    fn request(url: &str) -> impl FnOnce(reqwest::Error) -> GdlError {
+        let url = url.to_string();
+        move |source| GdlError::Request { url, source }
+    }
+
+    // connection errors and server errors (5xx) might go away when tried again
+    fn is_retryable(&self) -> bool {
+        match self {
+            GdlError::Http { status, .. } => status.is_server_error(),
+            GdlError::Request { .. } | GdlError::RateLimited { .. } => true,
+            _ => false,
+        }
+    }
+
+    // the variant, as written to --json-errors
+    fn kind(&self) -> &'static str {
+        match self {
+            GdlError::Http { .. } => "http",
+            GdlError::Request { .. } => "request",
+            GdlError::RateLimited { .. } => "rate_limited",
+            GdlError::Io { .. } => "io",
+            GdlError::Parse(_) => "parse",
+            GdlError::TaxonNotFound(_) => "taxon_not_found",
+            GdlError::TaxonomyLoad { .. } => "taxonomy_load",
+            GdlError::Decompress { .. } => "decompress",
+            GdlError::InvalidDownload(_) => "invalid_download",
+            GdlError::TooLarge { .. } => "too_large",
+            GdlError::Exec { .. } => "exec",
+        }
+    }
+
+    fn url(&self) -> Option<&str> {
+        match self {
+            GdlError::Http { url, .. }
+            | GdlError::Request { url, .. }
+            | GdlError::RateLimited { url, .. }
+            | GdlError::TooLarge { url, .. } => Some(url),
+            _ => None,
+        }
+    }
+
+    fn http_status(&self) -> Option<u16> {
+        match self {
+            GdlError::Http { status, .. } => Some(status.as_u16()),
+            GdlError::Request { source, .. } => source.status().map(|status| status.as_u16()),
+            GdlError::RateLimited { .. } => Some(StatusCode::TOO_MANY_REQUESTS.as_u16()),
+            _ => None,
+        }
+    }
+}
+
+// the URL and filename helpers describe what's wrong with the assembly summary as a String
+impl From<String> for GdlError {
+    fn from(message: String) -> Self {
+        GdlError::Parse(message)
+    }
+}
+
+// error pages are often HTML, so whitespace is collapsed to keep them on one line
+fn body_snippet(body: &str) -> String {
+    let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match body.char_indices().nth(ERROR_BODY_SNIPPET_LEN) {
+        _ if body.is_empty() => String::new(),
+        Some((end, _)) => format!(": {}…", &body[..end]),
+        None => format!(": {}", body),
+    }
+}
+
+fn rebase_url(url: &str, from: &str, to: &str) -> Result<String, String> {
+    let rebased = match url.strip_prefix(from) {
+        Some(rest) => format!("{}{}", to, rest),
+        None => return Ok(url.to_string()),
+    };
+    Url::parse(&rebased)
+        .map(|_| rebased.clone())
+        .map_err(|e| format!("Rebased URL {} is not valid: {}", rebased, e))
+}
+
+static FTP_REWRITE_LOGGED: AtomicBool = AtomicBool::new(false);
+
+// reqwest can't fetch ftp:// URLs, but NCBI serves the same paths over https:// from the same
+// host so those are rewritten. --scheme forces a scheme for every URL.
+fn normalize_scheme(url: &str, scheme: Option<&Scheme>) -> String {
+    let (current_scheme, rest) = match url.split_once("://") {
+        Some((current_scheme, rest)) => (current_scheme, rest),
+        None => return url.to_string(),
+    };
+
+    match scheme {
+        Some(scheme) => format!("{}://{}", scheme.as_str(), rest),
+        None if current_scheme.eq_ignore_ascii_case("ftp") => {
+            if !FTP_REWRITE_LOGGED.swap(true, Ordering::Relaxed) {
+                eprintln!("Rewriting ftp:// URLs to https:// (use --scheme to override)");
+            }
+            format!("https://{}", rest)
+        }
+        None => url.to_string(),
+    }
+}
+
+// the last part of the FTP path, e.g. GCF_000005845.2_ASM584v2, which NCBI uses as the prefix of
+// every file in the assembly's directory. The summary uses `na` for assemblies that don't have
+// any files (yet).
+fn assembly_name(ftp_path: &str) -> Result<&str, String> {
+    let trimmed = ftp_path.trim().trim_end_matches('/');
+
+    if trimmed.is_empty() || trimmed == "na" {
+        return Err(format!("Assembly has no FTP path ({:?})", ftp_path));
+    }
+
+    match trimmed.rsplit_once('/') {
+        Some((_, last_part)) if !last_part.is_empty() && !last_part.ends_with(':') => Ok(last_part),
+        _ => Err(format!(
+            "Failed to get the filename from FTP path {}",
+            ftp_path
+        )),
+    }
+}
+
+fn assembly_file_url(
+    ftp_path: &str,
+    format: &AssemblyFormat,
+    scheme: Option<&Scheme>,
+) -> Result<String, String> {
+    let last_part = assembly_name(ftp_path)?;
+    let base = normalize_scheme(ftp_path.trim().trim_end_matches('/'), scheme);
+
+    let url = format!("{}/{}{}", base, last_part, format.file_suffix());
+    Url::parse(&url).map_err(|e| format!("Invalid URL {} ({})", url, e))?;
+
+    Ok(url)
+}
+
+fn assembly_filename(ftp_path: &str, format: &AssemblyFormat) -> Result<String, String> {
+    Ok(format!(
+        "{}.{}",
+        assembly_name(ftp_path)?,
+        format.extension()
+    ))
+}
+
+// with --flat-filenames, files are named after the accession alone (e.g. GCF_000005845.2.fna.gz).
+// The path is relative to --out-dir
+fn local_filename(
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    options: &DownloadOptions,
+) -> Result<String, String> {
+    if options.preserve_directory_structure {
+        return mirrored_path(&assembly.ftp_path, &remote_filename(assembly, format)?);
+    }
+    let filename = if let Some(template) = &options.filename_template {
+        format!(
+            "{}.{}",
+            templated_name(template, assembly, &options.name_delimiter)?,
+            format.extension()
+        )
+    } else if options.flat_filenames {
+        format!("{}.{}", assembly.assembly_accession, format.extension())
+    } else {
+        assembly_filename(&assembly.ftp_path, format)?
+    };
+
+    if options.format_subdirs {
+        Ok(in_source_subdir(
+            assembly,
+            format!("{}/{}", format.as_str(), filename),
+            options,
+        ))
+    } else {
+        Ok(in_source_subdir(assembly, filename, options))
+    }
+}
+
+// with --shard-size, the accessions are split into runs of `shard_size` in sorted order, so the
+// same selection always lands in the same shards however it was sorted or filtered afterwards
+fn assign_shards(assemblies: &[NCBIAssembly], shard_size: usize) -> HashMap<String, String> {
+    let mut accessions: Vec<&str> = assemblies
+        .iter()
+        .map(|assembly| assembly.assembly_accession.as_str())
+        .collect();
+    accessions.sort_unstable();
+    accessions.dedup();
+    let last_shard = accessions.len().saturating_sub(1) / shard_size;
+    let width = last_shard.to_string().len().max(3);
+    accessions
+        .iter()
+        .enumerate()
+        .map(|(i, accession)| {
+            (
+                accession.to_string(),
+                format!("{:0width$}", i / shard_size, width = width),
+            )
+        })
+        .collect()
+}
+
+// with --filename-template, e.g. {organism}_{accession} makes
+// Escherichia_coli_str._K-12_substr._MG1655_GCF_000005845.2 (without the extension)
+fn templated_name(
+    template: &str,
+    assembly: &NCBIAssembly,
+    delimiter: &str,
+) -> Result<String, String> {
+    let asm_name = assembly_name(&assembly.ftp_path)?;
+    let asm_name = asm_name
+        .strip_prefix(&format!("{}_", assembly.assembly_accession))
+        .unwrap_or(asm_name);
+    let mut name = template.to_string();
+    for (placeholder, value) in [
+        ("{accession}", assembly.assembly_accession.as_str()),
+        ("{asm_name}", asm_name),
+        ("{organism}", assembly.organism_name.as_str()),
+        ("{taxid}", assembly.taxid.as_str()),
+    ] {
+        name = name.replace(placeholder, &sanitize_filename_part(value, delimiter));
+    }
+    Ok(name)
+}
+
+// ASCII letters, digits, ., -, _ and + mean the same thing on every filesystem
+fn is_filename_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+')
+}
+
+// replaces each run of unsafe characters (spaces, slashes, colons, parentheses, non-ASCII, ...)
+// with --name-delimiter, never at either end, so `Escherichia coli O157:H7 (strain Sakai)`
+// becomes `Escherichia_coli_O157_H7_strain_Sakai`
+fn sanitize_filename_part(value: &str, delimiter: &str) -> String {
+    let mut sanitized = String::new();
+    let mut pending_delimiter = false;
+    for c in value.chars() {
+        if is_filename_safe(c) {
+            if pending_delimiter && !sanitized.is_empty() {
+                sanitized.push_str(delimiter);
+            }
+            pending_delimiter = false;
+            sanitized.push(c);
+        } else {
+            pending_delimiter = true;
+        }
+    }
+    sanitized
+}
+
+// with --preserve-directory-structure, files are where NCBI keeps them, e.g.
+// genomes/all/GCF/000/005/845/GCF_000005845.2_ASM584v2/GCF_000005845.2_ASM584v2_genomic.fna.gz
+fn mirrored_path(ftp_path: &str, filename: &str) -> Result<String, String> {
+    let url = Url::parse(ftp_path.trim())
+        .map_err(|e| format!("Invalid FTP path {} ({})", ftp_path, e))?;
+    Ok(format!("{}/{}", url.path().trim_matches('/'), filename))
+}
+
+// with --source-subdirs, e.g. refseq/GCF_000005845.2_ASM584v2.fna.gz. The --shard-size
+// subdirectory goes above that, e.g. 003/refseq/GCF_000005845.2_ASM584v2.fna.gz
+fn in_source_subdir(assembly: &NCBIAssembly, path: String, options: &DownloadOptions) -> String {
+    let path = if options.source_subdirs && !assembly.source.is_empty() {
+        format!("{}/{}", assembly.source, path)
+    } else {
+        path
+    };
+    match options.shards.get(&assembly.assembly_accession) {
+        Some(shard) => format!("{}/{}", shard, path),
+        None => path,
+    }
+}
+
+// saved as {accession}.zip since the package isn't tied to the assembly's FTP directory
+fn datasets_package_filename(assembly: &NCBIAssembly, options: &DownloadOptions) -> String {
+    in_source_subdir(
+        assembly,
+        format!("{}.zip", assembly.assembly_accession),
+        options,
+    )
+}
+
+// for --only-missing. Each file is looked for where it'd end up, i.e. after --recompress. An
+// assembly whose filenames can't be worked out counts as missing
+fn is_already_downloaded(
+    assembly: &NCBIAssembly,
+    formats: &[AssemblyFormat],
+    out_path: &Path,
+    options: &DownloadOptions,
+    datasets: bool,
+) -> bool {
+    let filenames: Result<Vec<String>, String> = match datasets {
+        true => Ok(vec![datasets_package_filename(assembly, options)]),
+        false => formats
+            .iter()
+            .map(|format| local_filename(assembly, format, options))
+            .collect(),
+    };
+    filenames.is_ok_and(|filenames| {
+        filenames.iter().all(|filename| match datasets {
+            true => out_path.join(filename).exists(),
+            false => out_path.join(final_filename(filename, options)).exists(),
+        })
+    })
+}
+
+// how many of the colliding filenames are listed before giving up
+const COLLISIONS_SHOWN: usize = 5;
+
+// the files (relative to --out-dir) that more than one assembly would be written to with the
+// naming in `options`, each with the accessions of those assemblies, sorted by filename. Names
+// that can't be worked out are left for the download itself to fail on
+fn colliding_filenames<'a>(
+    assemblies: &'a [NCBIAssembly],
+    formats: &[AssemblyFormat],
+    options: &DownloadOptions,
+    datasets: bool,
+) -> Vec<(String, Vec<&'a str>)> {
+    let mut owners: HashMap<String, Vec<&str>> = HashMap::new();
+    for assembly in assemblies {
+        let filenames = match datasets {
+            true => vec![datasets_package_filename(assembly, options)],
+            false => formats
+                .iter()
+                .filter_map(|format| local_filename(assembly, format, options).ok())
+                .collect(),
+        };
+        for filename in filenames {
+            owners
+                .entry(filename)
+                .or_default()
+                .push(&assembly.assembly_accession);
+        }
+    }
+
+    let mut collisions: Vec<(String, Vec<&str>)> = owners
+        .into_iter()
+        .filter(|(_, accessions)| accessions.len() > 1)
+        .collect();
+    collisions.sort();
+    collisions
+}
+
+// used both to download assemblies and to print them with --print-urls
+fn assembly_url(
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    options: &DownloadOptions,
+) -> Result<String, String> {
+    let url = assembly_file_url(&assembly.ftp_path, format, options.scheme.as_ref())?;
+
+    match &options.rebase_url {
+        Some((from, to)) => rebase_url(&url, from, to),
+        None => Ok(url),
+    }
+}
+
+// the md5 published by NCBI for each file in an assembly's directory
+fn assembly_checksums_url(
+    assembly: &NCBIAssembly,
+    options: &DownloadOptions,
+) -> Result<String, String> {
+    assembly_dir_url(assembly, "md5checksums.txt", options)
+}
+
+// any other file in the assembly's directory
+fn assembly_dir_url(
+    assembly: &NCBIAssembly,
+    filename: &str,
+    options: &DownloadOptions,
+) -> Result<String, String> {
+    assembly_name(&assembly.ftp_path)?;
+    let base = normalize_scheme(
+        assembly.ftp_path.trim().trim_end_matches('/'),
+        options.scheme.as_ref(),
+    );
+
+    let url = format!("{}/{}", base, filename);
+    Url::parse(&url).map_err(|e| format!("Invalid URL {} ({})", url, e))?;
+
+    match &options.rebase_url {
+        Some((from, to)) => rebase_url(&url, from, to),
+        None => Ok(url),
+    }
+}
+
+// md5checksums.txt has one `<md5>  ./<filename>` line per file
+fn parse_md5_checksums(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(md5, filename)| {
+            let filename = filename.trim();
+            (
+                filename.strip_prefix("./").unwrap_or(filename).to_string(),
+                md5.to_lowercase(),
+            )
+        })
+        .collect()
+}
+
+// the name of a format's file in the assembly's directory on NCBI
+fn remote_filename(assembly: &NCBIAssembly, format: &AssemblyFormat) -> Result<String, String> {
+    Ok(format!(
+        "{}{}",
+        assembly_name(&assembly.ftp_path)?,
+        format.file_suffix()
+    ))
+}
+
+// md5checksums.txt lists every file in the assembly's directory, so a format that isn't in it
+// doesn't exist for that assembly. Assemblies without a usable ftp_path are left for the download
+// to report
+fn is_format_available(
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    checksums: &HashMap<String, String>,
+) -> bool {
+    remote_filename(assembly, format).map_or(true, |filename| checksums.contains_key(&filename))
+}
+
+// for --only-formats-present, whether each of AssemblyFormat::value_variants is in the assembly's
+// directory
+fn formats_present(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    options: &DownloadOptions,
+) -> Result<Vec<bool>, GdlError> {
+    let checksums = fetch_md5_checksums(client, assembly, options)?;
+    Ok(AssemblyFormat::value_variants()
+        .iter()
+        .map(|format| is_format_available(assembly, format, &checksums))
+        .collect())
+}
+
+// assemblies whose files couldn't be listed are left out, with a warning
+fn print_formats_present(assemblies: &[NCBIAssembly], present: Vec<Result<Vec<bool>, GdlError>>) {
+    let mut stdout = io::stdout().lock();
+    let header: Vec<&str> = iter::once("accession")
+        .chain(
+            AssemblyFormat::value_variants()
+                .iter()
+                .map(AssemblyFormat::as_str),
+        )
+        .collect();
+    writeln!(stdout, "{}", header.join("\t")).expect("Unable to write formats");
+    for (assembly, present) in assemblies.iter().zip(present) {
+        match present {
+            Ok(present) => {
+                let columns: Vec<String> = present.iter().map(bool::to_string).collect();
+                writeln!(
+                    stdout,
+                    "{}\t{}",
+                    assembly.assembly_accession,
+                    columns.join("\t")
+                )
+                .expect("Unable to write formats");
+            }
+            Err(e) => eprintln!(
+                "Warning: unable to list the files of {}: {}",
+                assembly.assembly_accession, e
+            ),
+        }
+    }
+}
+
+fn fetch_md5_checksums(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    options: &DownloadOptions,
+) -> Result<HashMap<String, String>, GdlError> {
+    let url = assembly_checksums_url(assembly, options)?;
+    let contents = get_with_retries(client, &url, options)?
+        .text()
+        .map_err(GdlError::request(&url))?;
+
+    Ok(parse_md5_checksums(&contents))
+}
+
+fn file_md5(path: &Path) -> io::Result<String> {
+    let mut context = md5::Context::new();
+    io::copy(&mut File::open(path)?, &mut context)?;
+    Ok(format!("{:x}", context.compute()))
+}
+
+// only for --checksum-algorithm sha256, so it isn't cached like the md5s
+fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            n => hasher.update(&buffer[..n]),
+        }
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+const CHECKSUM_CACHE_FILENAME: &str = ".gdl-md5-cache.tsv";
+
+#[derive(Debug, Clone, PartialEq)]
+struct CachedChecksum {
+    size: u64,
+    // nanoseconds since the Unix epoch
+    mtime: u128,
+    md5: String,
+    // the md5 the file had as it was downloaded, when it's been changed since (e.g. by
+    // --recompress), so that --verify can still tell it matches the published checksum
+    download_md5: Option<String>,
+}
+
+// (size, mtime) used to tell whether a file has changed since its checksum was cached
+fn file_stamp(path: &Path) -> io::Result<(u64, u128)> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    Ok((metadata.len(), mtime))
+}
+
+// a sidecar index of checksums in --out-dir so that files which haven't changed since they were
+// last verified don't have to be hashed again on every re-run
+struct ChecksumCache {
+    dir: PathBuf,
+    entries: Mutex<HashMap<String, CachedChecksum>>,
+}
+
+impl ChecksumCache {
+    // a missing or unreadable cache is treated as empty, and malformed lines are ignored
+    fn load(dir: &Path) -> Self {
+        let contents = fs::read_to_string(dir.join(CHECKSUM_CACHE_FILENAME)).unwrap_or_default();
+
+        let entries = contents
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                let (name, size, mtime, md5, download_md5) = match fields[..] {
+                    [name, size, mtime, md5] => (name, size, mtime, md5, None),
+                    [name, size, mtime, md5, download_md5] => {
+                        (name, size, mtime, md5, Some(download_md5.to_string()))
+                    }
+                    _ => return None,
+                };
+                Some((
+                    name.to_string(),
+                    CachedChecksum {
+                        size: size.parse().ok()?,
+                        mtime: mtime.parse().ok()?,
+                        md5: md5.to_string(),
+                        download_md5,
+                    },
+                ))
+            })
+            .collect();
+
+        ChecksumCache {
+            dir: dir.to_path_buf(),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn key(&self, path: &Path) -> String {
+        path.strip_prefix(&self.dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // only hashes the file if its size or mtime differ from the cached entry
+    fn md5(&self, path: &Path) -> io::Result<String> {
+        let (size, mtime) = file_stamp(path)?;
+        let key = self.key(path);
+
+        if let Some(cached) = self.entries.lock().unwrap().get(&key) {
+            if cached.size == size && cached.mtime == mtime {
+                return Ok(cached.md5.clone());
+            }
+        }
+
+        let md5 = file_md5(path)?;
+        self.entries.lock().unwrap().insert(
+            key,
+            CachedChecksum {
+                size,
+                mtime,
+                md5: md5.clone(),
+                download_md5: None,
+            },
+        );
+        Ok(md5)
+    }
+
+    // the md5 of the file as it was downloaded, which is only different from its md5 when it's
+    // been recorded with record_download_md5 and hasn't changed since
+    fn download_md5(&self, path: &Path) -> io::Result<String> {
+        let (size, mtime) = file_stamp(path)?;
+        if let Some(cached) = self.entries.lock().unwrap().get(&self.key(path)) {
+            if let (true, Some(download_md5)) = (
+                cached.size == size && cached.mtime == mtime,
+                &cached.download_md5,
+            ) {
+                return Ok(download_md5.clone());
+            }
+        }
+        self.md5(path)
+    }
+
+    // for a file that's been changed since it was downloaded with an md5 of `download_md5`
+    fn record_download_md5(&self, path: &Path, download_md5: &str) -> io::Result<()> {
+        self.md5(path)?;
+        if let Some(cached) = self.entries.lock().unwrap().get_mut(&self.key(path)) {
+            cached.download_md5 = Some(download_md5.to_string());
+        }
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) {
+        self.entries.lock().unwrap().remove(&self.key(path));
+    }
+
+    // entries for files that no longer exist (e.g. moved into --tar) are dropped
+    fn save(&self) -> io::Result<()> {
+        let entries = self.entries.lock().unwrap();
+        let mut names: Vec<&String> = entries
+            .keys()
+            .filter(|name| self.dir.join(name).exists())
+            .collect();
+        names.sort();
+
+        let mut contents = String::new();
+        for name in names {
+            let entry = &entries[name];
+            contents.push_str(&format!(
+                "{}\t{}\t{}\t{}",
+                name, entry.size, entry.mtime, entry.md5
+            ));
+            if let Some(download_md5) = &entry.download_md5 {
+                contents.push_str(&format!("\t{}", download_md5));
+            }
+            contents.push('\n');
+        }
+
+        let tmp_path = self.dir.join(format!("{}.tmp", CHECKSUM_CACHE_FILENAME));
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, self.dir.join(CHECKSUM_CACHE_FILENAME))
+    }
+}
+
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+// however many --retries there are, attempts are never further apart than this
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+// a server asking for longer than this is waited on for this long before trying again anyway
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+// anything that isn't a success is an error, along with the start of the response's body
+fn get(client: &Client, url: &str) -> Result<Response, GdlError> {
+    send(client.get(url), url)
+}
+
+fn send(request: RequestBuilder, url: &str) -> Result<Response, GdlError> {
+    let response = request.send().map_err(GdlError::request(url))?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(GdlError::RateLimited {
+            url: url.to_string(),
+            retry_after: response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, SystemTime::now())),
+        });
+    }
+
+    let mut body = Vec::new();
+    let _ = response
+        .take(ERROR_BODY_SNIPPET_LEN as u64 * 4)
+        .read_to_end(&mut body);
+    Err(GdlError::Http {
+        url: url.to_string(),
+        status,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+// connection errors and server errors (5xx) are retried with an exponential backoff. Anything
+// else that isn't a success (e.g. a 404 for a format the assembly doesn't have) fails straight
+// away.
+fn get_with_retries(
+    client: &Client,
+    url: &str,
+    options: &DownloadOptions,
+) -> Result<Response, GdlError> {
+    send_with_retries(&|| client.get(url), url, options)
+}
+
+// like get_with_retries, for requests that need more than a plain GET (e.g. headers). `request`
+// builds the request again for every attempt
+fn send_with_retries(
+    request: &dyn Fn() -> RequestBuilder,
+    url: &str,
+    options: &DownloadOptions,
+) -> Result<Response, GdlError> {
+    let mut attempt = 0;
+    loop {
+        let error = match send(request(), url) {
+            Ok(response) => {
+                options.slots.succeeded();
+                return Ok(response);
+            }
+            Err(e) if !e.is_retryable() => return Err(e),
+            Err(e) => e,
+        };
+
+        if attempt >= options.retries {
+            return Err(error);
+        }
+        back_off(&error, attempt, options);
+        attempt += 1;
+    }
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()
+            .map(|date| date.duration_since(now).unwrap_or_default()),
+    }
+}
+
+// waits before retrying after `error`: as long as a 429 asked for (up to MAX_RETRY_AFTER), and
+// exponentially longer with every attempt otherwise. 429s that keep coming also mean fewer
+// assemblies are downloaded at once from then on
+fn back_off(error: &GdlError, attempt: u32, options: &DownloadOptions) {
+    let delay = match error {
+        GdlError::RateLimited { retry_after, .. } => {
+            if let Some(allowed) = options.slots.rate_limited() {
+                eprintln!(
+                    "Warning: NCBI keeps rate limiting requests, downloading at most {} assemblies at once from now on",
+                    allowed
+                );
+            }
+            retry_after.map_or(retry_backoff(attempt), |retry_after| {
+                retry_after.min(MAX_RETRY_AFTER)
+            })
+        }
+        _ => retry_backoff(attempt),
+    };
+    thread::sleep(delay);
+}
+
+// RETRY_BACKOFF doubled with every attempt, up to MAX_RETRY_BACKOFF
+fn retry_backoff(attempt: u32) -> Duration {
+    RETRY_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_BACKOFF)
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// NCBI sometimes serves an HTML error page with a 200 during maintenance, which would otherwise be
+// saved as a .gz file that nothing can open
+fn check_download(path: &Path, format: &AssemblyFormat) -> Result<(), GdlError> {
+    let mut magic = [0u8; 2];
+    let n_read = File::open(path)
+        .and_then(|mut file| file.read(&mut magic))
+        .map_err(GdlError::io("read", path))?;
+
+    if n_read == 0 {
+        return Err(GdlError::InvalidDownload(format!(
+            "Downloaded an empty file to {}",
+            path.display()
+        )));
+    }
+    if format.file_suffix().ends_with(".gz") && (n_read < 2 || magic != GZIP_MAGIC) {
+        return Err(GdlError::InvalidDownload(format!(
+            "Downloaded {} is not gzipped (probably an error page)",
+            path.display()
+        )));
+    }
+
+    Ok(())
+}
+
+// for --verify-gzip-integrity. A file can start like gzip and still be cut off or corrupted
+// partway through, which only shows when it's decompressed. NCBI's files can have several gzip
+// members, so all of them are read
+fn check_gzip_integrity(path: &Path) -> Result<(), GdlError> {
+    let file = File::open(path).map_err(GdlError::io("read", path))?;
+    io::copy(
+        &mut MultiGzDecoder::new(BufReader::new(file)),
+        &mut io::sink(),
+    )
+    .map(|_| ())
+    .map_err(|e| {
+        GdlError::InvalidDownload(format!(
+            "Downloaded {} doesn't decompress ({})",
+            path.display(),
+            e
+        ))
+    })
+}
+
+// here we should re-use a single client to take advantage of keep-alive connection pooling
+fn download_assembly(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    out_path: &Path,
+    options: &DownloadOptions,
+) -> Result<PathBuf, GdlError> {
+    let url = assembly_url(assembly, format, options)?;
+
+    let assembly_path = out_path.join(local_filename(assembly, format, options)?);
+
+    // one HEAD request for both --chunked and --max-file-size
+    let head = (options.chunked.is_some() || options.max_file_size.is_some())
+        .then(|| client.head(&url).send().ok())
+        .flatten()
+        .filter(|response| response.status().is_success());
+    let head_size = head.as_ref().and_then(header_content_length);
+    check_file_size(&url, head_size, options)?;
+
+    create_parent_dir(&assembly_path)?;
+    // only moved to assembly_path once it's complete and checked, so that a download that's cut
+    // off is never mistaken for a complete one (e.g. by --only-missing)
+    let mut part_path = assembly_path.clone().into_os_string();
+    part_path.push(".part");
+    let part_path = PathBuf::from(part_path);
+
+    // only worth it for large files, and only if the server can serve parts of them
+    let chunked_size = options.chunked.as_ref().and_then(|chunked| {
+        let accepts_ranges = head
+            .as_ref()?
+            .headers()
+            .get(ACCEPT_RANGES)
+            .is_some_and(|value| value.as_bytes() == b"bytes");
+        let size = head_size?;
+        (accepts_ranges && size >= chunked.threshold).then_some((size, chunked.connections))
+    });
+
+    // bogus downloads and ones that are cut off are retried just like server errors
+    let mut attempt = 0;
+    loop {
+        let downloaded = if let Some((size, connections)) = chunked_size {
+            if attempt == 0 {
+                options.expected_bytes.add(Some(size));
+            }
+            // every range has already been retried on its own
+            if let Err(e) = download_chunked(client, &url, &part_path, size, connections, options) {
+                let _ = fs::remove_file(&part_path);
+                return Err(e);
+            }
+            Ok(())
+        } else {
+            let mut response = get_with_retries(client, &url, options)?;
+            // for a server that doesn't answer HEAD requests, before reading any of the body
+            if head_size.is_none() {
+                check_file_size(&url, response.content_length(), options)?;
+            }
+            if attempt == 0 {
+                options.expected_bytes.add(response.content_length());
+            }
+
+            let file = File::create(&part_path).map_err(GdlError::io("write to", &part_path))?;
+
+            response
+                .copy_to(&mut DownloadWriter::new(file, options))
+                .map(|_| ())
+                .map_err(GdlError::request(&url))
+        };
+
+        let mut checked = downloaded.and_then(|()| check_download(&part_path, format));
+        if checked.is_ok() && options.verify_gzip_integrity && format.file_suffix().ends_with(".gz")
+        {
+            checked = check_gzip_integrity(&part_path);
+        }
+        let error = match checked {
+            Ok(()) => {
+                fs::rename(&part_path, &assembly_path)
+                    .map_err(GdlError::io("write to", &assembly_path))?;
+                return Ok(assembly_path);
+            }
+            Err(e) => e,
+        };
+
+        let _ = fs::remove_file(&part_path);
+        if attempt >= options.retries {
+            return Err(error);
+        }
+        back_off(&error, attempt, options);
+        attempt += 1;
+    }
+}
+
+// --max-file-size, for a file whose size is known. Ones without a Content-Length are downloaded
+fn check_file_size(
+    url: &str,
+    size: Option<u64>,
+    options: &DownloadOptions,
+) -> Result<(), GdlError> {
+    match (size, options.max_file_size) {
+        (Some(size), Some(max_file_size)) if size > max_file_size => Err(GdlError::TooLarge {
+            url: url.to_string(),
+            size,
+            max_file_size,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// splits the file into one range per connection and has each one write its range straight into
+// the preallocated file. Every range is retried on its own, like a whole download would be.
+fn download_chunked(
+    client: &Client,
+    url: &str,
+    path: &Path,
+    size: u64,
+    connections: usize,
+    options: &DownloadOptions,
+) -> Result<(), GdlError> {
+    let retries = options.retries;
+    let file = File::create(path).map_err(GdlError::io("write to", path))?;
+    file.set_len(size).map_err(GdlError::io("write to", path))?;
+
+    let chunk_size = size.div_ceil(connections as u64).max(1);
+    let ranges: Vec<(u64, u64)> = (0..size)
+        .step_by(chunk_size as usize)
+        .map(|start| (start, (start + chunk_size).min(size) - 1))
+        .collect();
+
+    let download_range = |(start, end): (u64, u64)| -> Result<(), GdlError> {
+        let mut attempt = 0;
+        loop {
+            let error = match send(
+                client
+                    .get(url)
+                    .header(RANGE, format!("bytes={}-{}", start, end)),
+                url,
+            ) {
+                Ok(response) if response.status() != StatusCode::PARTIAL_CONTENT => {
+                    return Err(GdlError::InvalidDownload(format!(
+                        "{} returned {} instead of the requested range",
+                        url,
+                        response.status()
+                    )));
+                }
+                Ok(mut response) => {
+                    let mut file = fs::OpenOptions::new()
+                        .write(true)
+                        .open(path)
+                        .map_err(GdlError::io("write to", path))?;
+                    file.seek(SeekFrom::Start(start))
+                        .map_err(GdlError::io("write to", path))?;
+                    match response.copy_to(&mut DownloadWriter::new(file, options)) {
+                        Ok(n_written) if n_written == end - start + 1 => return Ok(()),
+                        Ok(n_written) => GdlError::InvalidDownload(format!(
+                            "{} returned {} bytes for a range of {} bytes",
+                            url,
+                            n_written,
+                            end - start + 1
+                        )),
+                        Err(e) => GdlError::request(url)(e),
+                    }
+                }
+                Err(e) if !e.is_retryable() => return Err(e),
+                Err(e) => e,
+            };
+
+            if attempt >= retries {
+                return Err(error);
+            }
+            back_off(&error, attempt, options);
+            attempt += 1;
+        }
+    };
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = ranges
+            .into_iter()
+            .map(|range| scope.spawn(move || download_range(range)))
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("Chunked download thread panicked"))
+    })
+}
+
+// NCBI serves plain gzip, which samtools and friends can't index. The file is replaced in place,
+// so it keeps its name.
+fn recompress_bgzip(path: &Path, level: Option<u32>) -> Result<(), GdlError> {
+    let tmp_path = path.with_extension("bgz.tmp");
+    let level = level
+        .and_then(|level| u8::try_from(level).ok())
+        .and_then(noodles_bgzf::io::writer::CompressionLevel::new)
+        .unwrap_or_default();
+
+    let recompress = || -> io::Result<()> {
+        let mut decoder = MultiGzDecoder::new(BufReader::new(File::open(path)?));
+        let mut writer = noodles_bgzf::io::writer::Builder::default()
+            .set_compression_level(level)
+            .build_from_writer(File::create(&tmp_path)?);
+        io::copy(&mut decoder, &mut writer)?;
+        writer.finish()?;
+        fs::rename(&tmp_path, path)
+    };
+
+    recompress().map_err(|source| {
+        let _ = fs::remove_file(&tmp_path);
+        GdlError::Decompress {
+            action: "bgzip",
+            path: path.to_path_buf(),
+            source,
+        }
+    })
+}
+
+// appends `suffix` to the header line of every record in a gzipped FASTA file
+fn annotate_fasta_headers(path: &Path, suffix: &str, level: Option<u32>) -> Result<(), GdlError> {
+    let tmp_path = path.with_extension("annotated.tmp");
+
+    let annotate = || -> io::Result<()> {
+        let mut reader = BufReader::new(MultiGzDecoder::new(BufReader::new(File::open(path)?)));
+        let mut encoder = GzEncoder::new(
+            io::BufWriter::new(File::create(&tmp_path)?),
+            level.map(Compression::new).unwrap_or_default(),
+        );
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            if line.starts_with(b">") {
+                let header_len = line.trim_ascii_end().len();
+                line.truncate(header_len);
+                line.extend_from_slice(suffix.as_bytes());
+                line.push(b'\n');
+            }
+            encoder.write_all(&line)?;
+            line.clear();
+        }
+        encoder.finish()?.flush()?;
+        fs::rename(&tmp_path, path)
+    };
+
+    annotate().map_err(|source| {
+        let _ = fs::remove_file(&tmp_path);
+        GdlError::Decompress {
+            action: "annotate the headers of",
+            path: path.to_path_buf(),
+            source,
+        }
+    })
+}
+
+// the ID (the header up to the first whitespace) of every record in a FASTA file, which can be
+// gzipped (or bgzipped), zstd-compressed or plain
+fn fasta_seqids(path: &Path) -> io::Result<Vec<String>> {
+    let file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn BufRead> = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("gz") => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        Some("zst") => Box::new(BufReader::new(zstd::Decoder::with_buffer(file)?)),
+        _ => Box::new(file),
+    };
+
+    let mut seqids = Vec::new();
+    for line in reader.lines() {
+        if let Some(header) = line?.strip_prefix('>') {
+            if let Some(seqid) = header.split_whitespace().next() {
+                seqids.push(seqid.to_string());
+            }
+        }
+    }
+    Ok(seqids)
+}
+
+// writes e.g. genome.fna next to genome.fna.gz, which is kept, and returns its path
+fn write_decompressed_copy(path: &Path) -> Result<PathBuf, GdlError> {
+    let Some(stem) = path.to_str().and_then(|path| path.strip_suffix(".gz")) else {
+        return Ok(path.to_path_buf());
+    };
+    let copy_path = PathBuf::from(stem);
+    let tmp_path = PathBuf::from(format!("{}.tmp", stem));
+
+    let decompress = || -> io::Result<()> {
+        let mut decoder = MultiGzDecoder::new(BufReader::new(File::open(path)?));
+        io::copy(&mut decoder, &mut File::create(&tmp_path)?)?;
+        fs::rename(&tmp_path, &copy_path)
+    };
+
+    decompress().map_err(|source| {
+        let _ = fs::remove_file(&tmp_path);
+        GdlError::Decompress {
+            action: "decompress",
+            path: path.to_path_buf(),
+            source,
+        }
+    })?;
+
+    Ok(copy_path)
+}
+
+// replaces e.g. genome.fna.gz with genome.fna.zst and returns the new path. Files that NCBI
+// doesn't gzip (e.g. the assembly report) are left alone.
+fn recompress_zstd(path: &Path, level: Option<u32>) -> Result<PathBuf, GdlError> {
+    let Some(stem) = path.to_str().and_then(|path| path.strip_suffix(".gz")) else {
+        return Ok(path.to_path_buf());
+    };
+    let zstd_path = PathBuf::from(format!("{}.zst", stem));
+    let tmp_path = PathBuf::from(format!("{}.zst.tmp", stem));
+
+    let recompress = || -> io::Result<()> {
+        let mut decoder = MultiGzDecoder::new(BufReader::new(File::open(path)?));
+        let level = level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |level| level as i32);
+        let mut encoder = zstd::Encoder::new(File::create(&tmp_path)?, level)?;
+        io::copy(&mut decoder, &mut encoder)?;
+        encoder.finish()?;
+        fs::rename(&tmp_path, &zstd_path)?;
+        fs::remove_file(path)
+    };
+
+    recompress().map_err(|source| {
+        let _ = fs::remove_file(&tmp_path);
+        GdlError::Decompress {
+            action: "recompress",
+            path: path.to_path_buf(),
+            source,
+        }
+    })?;
+
+    Ok(zstd_path)
+}
+
+fn published_md5<'a>(
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    checksums: &'a HashMap<String, String>,
+) -> Result<&'a String, GdlError> {
+    let remote_filename = remote_filename(assembly, format)?;
+    checksums
+        .get(&remote_filename)
+        .ok_or_else(|| GdlError::Parse(format!("No checksum listed for {}", remote_filename)))
+}
+
+// where a file ends up once it's been downloaded, e.g. genome.fna.zst for genome.fna.gz with
+// --recompress zstd
+fn final_filename(filename: &str, options: &DownloadOptions) -> String {
+    match (&options.recompress, filename.strip_suffix(".gz")) {
+        (Some(Recompression::Zstd), Some(stem)) => format!("{}.zst", stem),
+        _ => filename.to_string(),
+    }
+}
+
+// files that were already downloaded and matched their published checksum when they were
+// (before e.g. --recompress changed them, going by the checksum cache) are not downloaded again
+fn already_verified(
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    out_path: &Path,
+    options: &DownloadOptions,
+    expected: &str,
+    cache: &ChecksumCache,
+) -> Result<Option<PathBuf>, GdlError> {
+    let path = out_path.join(final_filename(
+        &local_filename(assembly, format, options)?,
+        options,
+    ));
+    let verified = path.exists() && cache.download_md5(&path).is_ok_and(|md5| md5 == expected);
+    Ok(verified.then_some(path))
+}
+
+// anything that doesn't match its published checksum after downloading is removed
+fn download_and_verify(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    out_path: &Path,
+    options: &DownloadOptions,
+    expected: &str,
+    cache: &ChecksumCache,
+) -> Result<PathBuf, GdlError> {
+    let remote_filename = remote_filename(assembly, format)?;
+    let assembly_path = download_assembly(client, assembly, format, out_path, options)?;
+    let actual = cache
+        .md5(&assembly_path)
+        .map_err(GdlError::io("read", &assembly_path))?;
+
+    if actual != expected {
+        let _ = fs::remove_file(&assembly_path);
+        cache.remove(&assembly_path);
+        return Err(GdlError::InvalidDownload(format!(
+            "Checksum mismatch for {} (expected {}, got {})",
+            remote_filename, expected, actual
+        )));
+    }
+
+    Ok(assembly_path)
+}
+
+// the `all all all all total-length <bases>` line of an _assembly_stats.txt
+fn parse_stats_total_length(contents: &str) -> Option<u64> {
+    contents.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        match fields.as_slice() {
+            ["all", "all", "all", "all", "total-length", length] => length.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+// the bases of every sequence in a gzipped fasta file, Ns included
+fn fasta_length(path: &Path) -> io::Result<u64> {
+    let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+    let mut length = 0;
+    for line in reader.split(b'\n') {
+        let line = line?;
+        if !line.starts_with(b">") {
+            length += line.iter().filter(|b| !b.is_ascii_whitespace()).count() as u64;
+        }
+    }
+    Ok(length)
+}
+
+// for --check-genome-length. A download that doesn't match is removed. When neither the stats nor
+// the summary have a length, there's only a warning
+fn check_genome_length(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    path: &Path,
+    options: &DownloadOptions,
+) -> Result<(), GdlError> {
+    let expected = extra_file_url(assembly, &ExtraFile::Stats, options)
+        .ok()
+        .and_then(|url| get_with_retries(client, &url, options).ok())
+        .and_then(|response| response.text().ok())
+        .and_then(|contents| parse_stats_total_length(&contents))
+        .or_else(|| assembly.genome_size.parse().ok());
+    let Some(expected) = expected else {
+        eprintln!(
+            "Warning: no genome length to check {} against",
+            assembly.assembly_accession
+        );
+        return Ok(());
+    };
+
+    let actual = fasta_length(path).map_err(|source| GdlError::Decompress {
+        action: "decompress",
+        path: path.to_path_buf(),
+        source,
+    })?;
+    if actual != expected {
+        let _ = fs::remove_file(path);
+        return Err(GdlError::InvalidDownload(format!(
+            "Genome length mismatch for {} (expected {} bases, {} has {})",
+            assembly.assembly_accession,
+            expected,
+            path.display(),
+            actual
+        )));
+    }
+    Ok(())
+}
+
+const EXEC_PLACEHOLDER: &str = "{}";
+
+// the path is passed to sh as $1 rather than pasted into the command, so it never needs quoting
+fn exec_command(command: &str, path: &Path) -> std::process::Command {
+    let mut exec = std::process::Command::new("sh");
+    exec.arg("-c")
+        .arg(command.replace(EXEC_PLACEHOLDER, "\"$1\""))
+        .arg("gdl")
+        .arg(path);
+    exec
+}
+
+// for --exec. The command's output goes to stderr so that stdout stays clean for --json
+fn run_exec(command: &str, path: &Path) -> Result<(), GdlError> {
+    let status = exec_command(command, path)
+        .stdout(io::stderr())
+        .status()
+        .map_err(GdlError::io("run --exec for", path))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(GdlError::Exec {
+            path: path.to_path_buf(),
+            status,
+        }),
+    }
+}
+
+// --format-fallback: when `format` isn't in the assembly's directory (a 404, or not listed in its
+// checksums when they've been fetched), each of `fallbacks` is tried in order instead. Returns the
+// format that was downloaded along with where it ended up. Any other error is returned right away
+#[allow(clippy::too_many_arguments)]
+fn fetch_assembly_file_or_fallback(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    fallbacks: &[AssemblyFormat],
+    out_path: &Path,
+    options: &DownloadOptions,
+    checksums: Option<&HashMap<String, String>>,
+    cache: Option<&ChecksumCache>,
+    header_suffix: Option<&str>,
+) -> Result<(AssemblyFormat, PathBuf), GdlError> {
+    let mut first_error = None;
+    for candidate in iter::once(format).chain(fallbacks) {
+        if checksums.is_some_and(|checksums| !is_format_available(assembly, candidate, checksums)) {
+            first_error.get_or_insert_with(|| {
+                GdlError::InvalidDownload(format!(
+                    "{} has no {} file",
+                    assembly.assembly_accession,
+                    candidate.as_str()
+                ))
+            });
+            continue;
+        }
+        match fetch_assembly_file(
+            client,
+            assembly,
+            candidate,
+            out_path,
+            options,
+            checksums.zip(cache),
+            header_suffix,
+        ) {
+            Ok(path) => return Ok((candidate.clone(), path)),
+            Err(e) if e.http_status() == Some(StatusCode::NOT_FOUND.as_u16()) => {
+                first_error.get_or_insert(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(first_error.expect("there's always a format to try"))
+}
+
+// downloads one format of an assembly (checking it against `verification`, the assembly's
+// checksums and the checksum cache, with --verify) and then recompresses it if asked to.
+// Returns where the file ended up.
+fn fetch_assembly_file(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    out_path: &Path,
+    options: &DownloadOptions,
+    verification: Option<(&HashMap<String, String>, &ChecksumCache)>,
+    header_suffix: Option<&str>,
+) -> Result<PathBuf, GdlError> {
+    let expected = verification
+        .map(|(checksums, _)| published_md5(assembly, format, checksums))
+        .transpose()?;
+    let path = match (verification, expected) {
+        (Some((_, cache)), Some(expected)) => {
+            if let Some(path) =
+                already_verified(assembly, format, out_path, options, expected, cache)?
+            {
+                return Ok(path);
+            }
+            download_and_verify(client, assembly, format, out_path, options, expected, cache)?
+        }
+        _ => download_assembly(client, assembly, format, out_path, options)?,
+    };
+
+    if options.check_genome_length && *format == AssemblyFormat::Fna {
+        check_genome_length(client, assembly, &path, options)?;
+    }
+
+    // whether the file has been rewritten in place since it was downloaded
+    let mut rewritten = false;
+
+    if let (Some(suffix), true) = (header_suffix, format.is_sequence()) {
+        // recompressed with zstd afterwards, so the level is only for gzip
+        let level = options
+            .compression_level
+            .filter(|_| options.recompress.is_none());
+        annotate_fasta_headers(&path, suffix, level)?;
+        rewritten = true;
+    }
+
+    if options.bgzip && format.is_sequence() {
+        recompress_bgzip(&path, options.compression_level)?;
+        rewritten = true;
+    }
+
+    if options.decompressed_copy && format.is_sequence() {
+        write_decompressed_copy(&path)?;
+    }
+
+    let final_path = match options.recompress {
+        Some(Recompression::Zstd) => recompress_zstd(&path, options.compression_level)?,
+        None => path.clone(),
+    };
+
+    // so that the next --verify run can tell the changed file is the one that was verified
+    if let (Some((_, cache)), Some(expected), true) =
+        (verification, expected, rewritten || final_path != path)
+    {
+        cache
+            .record_download_md5(&final_path, expected)
+            .map_err(GdlError::io("read", &final_path))?;
+    }
+    Ok(final_path)
+}
+
+// for --stdout. There's no file to check afterwards so, unlike download_assembly, a bogus
+// download can't be retried once it's been (partly) written. The progress goes to stderr
+fn stream_assembly_file(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    format: &AssemblyFormat,
+    writer: &mut dyn Write,
+    decompress: bool,
+    options: &DownloadOptions,
+) -> Result<u64, GdlError> {
+    let url = assembly_url(assembly, format, options)?;
+    let response = get_with_retries(client, &url, options)?;
+    options.expected_bytes.add(response.content_length());
+
+    let pb = ProgressBar::new(response.content_length().unwrap_or(0));
+    pb.set_style(
+        ProgressStyle::with_template(PB_PROGRESS_TEMPLATE)
+            .unwrap()
+            .progress_chars(PROGRESS_CHARS),
+    );
+    pb.set_message(format!("Downloading {}", assembly.assembly_accession));
+
+    let mut reader = pb.wrap_read(response);
+    let mut writer = DownloadWriter::new(writer, options);
+    let copied = if decompress {
+        io::copy(&mut MultiGzDecoder::new(reader), &mut writer)
+    } else {
+        io::copy(&mut reader, &mut writer)
+    };
+    pb.finish_and_clear();
+
+    match copied {
+        Ok(n_bytes) => Ok(n_bytes),
+        // the reading end (e.g. `head`) has seen all it wanted
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(0),
+        Err(source) if decompress => Err(GdlError::Decompress {
+            action: "decompress",
+            path: PathBuf::from(url),
+            source,
+        }),
+        Err(e) => Err(GdlError::io("write", Path::new("stdout"))(e)),
+    }
+}
+
+// the Datasets API bundles every requested format of an assembly into a single zip
+fn datasets_package_url(
+    datasets_url: &str,
+    assembly: &NCBIAssembly,
+    formats: &[AssemblyFormat],
+) -> Result<String, String> {
+    let mut url = Url::parse(&format!(
+        "{}/genome/accession/{}/download",
+        datasets_url.trim_end_matches('/'),
+        assembly.assembly_accession
+    ))
+    .map_err(|e| format!("Invalid Datasets URL {} ({})", datasets_url, e))?;
+
+    for annotation_type in formats
+        .iter()
+        .filter_map(AssemblyFormat::datasets_annotation_type)
+    {
+        url.query_pairs_mut()
+            .append_pair("include_annotation_type", annotation_type);
+    }
+
+    Ok(url.to_string())
+}
+
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4b, 0x03, 0x04];
+
+fn extra_file_url(
+    assembly: &NCBIAssembly,
+    extra_file: &ExtraFile,
+    options: &DownloadOptions,
+) -> Result<String, String> {
+    let assembly_name = assembly_name(&assembly.ftp_path)?;
+    assembly_dir_url(
+        assembly,
+        &extra_file.remote_filename(assembly_name),
+        options,
+    )
+}
+
+// named the same way as the formats (e.g. GCF_000005845.2_ASM584v2.assembly_stats.txt)
+fn extra_file_filename(
+    assembly: &NCBIAssembly,
+    extra_file: &ExtraFile,
+    options: &DownloadOptions,
+) -> Result<String, String> {
+    if options.preserve_directory_structure {
+        let remote_filename = extra_file.remote_filename(assembly_name(&assembly.ftp_path)?);
+        return mirrored_path(&assembly.ftp_path, &remote_filename);
+    }
+    let prefix = if let Some(template) = &options.filename_template {
+        templated_name(template, assembly, &options.name_delimiter)?
+    } else if options.flat_filenames {
+        assembly.assembly_accession.clone()
+    } else {
+        assembly_name(&assembly.ftp_path)?.to_string()
+    };
+    let filename = format!("{}.{}", prefix, extra_file.extension());
+    Ok(in_source_subdir(assembly, filename, options))
+}
+
+// for --preserve-directory-structure, where every assembly has its own directory
+fn create_parent_dir(path: &Path) -> Result<(), GdlError> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).map_err(GdlError::io("create", parent)),
+        None => Ok(()),
+    }
+}
+
+// saved next to the formats
+fn download_extra_file(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    extra_file: &ExtraFile,
+    out_path: &Path,
+    options: &DownloadOptions,
+) -> Result<PathBuf, GdlError> {
+    let url = extra_file_url(assembly, extra_file, options)?;
+    let path = out_path.join(extra_file_filename(assembly, extra_file, options)?);
+    create_parent_dir(&path)?;
+
+    let mut response = get_with_retries(client, &url, options)?;
+    options.expected_bytes.add(response.content_length());
+    let file = File::create(&path).map_err(GdlError::io("write to", &path))?;
+    response
+        .copy_to(&mut DownloadWriter::new(file, options))
+        .map_err(GdlError::request(&url))?;
+
+    Ok(path)
+}
+
+fn download_datasets_package(
+    client: &Client,
+    assembly: &NCBIAssembly,
+    formats: &[AssemblyFormat],
+    out_path: &Path,
+    options: &DownloadOptions,
+    datasets_url: &str,
+) -> Result<PathBuf, GdlError> {
+    let url = datasets_package_url(datasets_url, assembly, formats)?;
+    let package_path = out_path.join(datasets_package_filename(assembly, options));
+
+    // the Datasets API takes the key as a header
+    let request = || match &options.api_key {
+        Some(api_key) => client.get(&url).header("api-key", api_key),
+        None => client.get(&url),
+    };
+    let mut response = send_with_retries(&request, &url, options)?;
+    options.expected_bytes.add(response.content_length());
+
+    let file = File::create(&package_path).map_err(GdlError::io("write to", &package_path))?;
+    response
+        .copy_to(&mut DownloadWriter::new(file, options))
+        .map_err(GdlError::request(&url))?;
+
+    let mut magic = [0u8; 4];
+    let is_zip = File::open(&package_path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| magic == ZIP_MAGIC);
+    if !is_zip {
+        let _ = fs::remove_file(&package_path);
+        return Err(GdlError::InvalidDownload(format!(
+            "{} did not return a zip file",
+            url
+        )));
+    }
+
+    Ok(package_path)
+}
+
+// content_length() is the length of the (empty) body of a HEAD response, so the header is read
+// instead
+fn header_content_length(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+// how many assemblies are HEADed to estimate the size of a download
+const SIZE_ESTIMATE_SAMPLE: usize = 5;
+
+// extrapolates from the Content-Length of the files of a few assemblies spread across the
+// matches. Returns None if none of them reported a size.
+fn estimate_download_size(
+    client: &Client,
+    assemblies: &[NCBIAssembly],
+    formats: &[AssemblyFormat],
+    options: &DownloadOptions,
+) -> Option<u64> {
+    let step = assemblies.len().div_ceil(SIZE_ESTIMATE_SAMPLE).max(1);
+
+    let mut n_sampled = 0;
+    let mut total_bytes = 0;
+    for assembly in assemblies.iter().step_by(step) {
+        let sizes: Vec<u64> = formats
+            .iter()
+            .filter_map(|format| assembly_url(assembly, format, options).ok())
+            .filter_map(|url| client.head(&url).send().ok())
+            .filter(|response| response.status().is_success())
+            .filter_map(|response| header_content_length(&response))
+            .collect();
+        if !sizes.is_empty() {
+            n_sampled += 1;
+            total_bytes += sizes.iter().sum::<u64>();
+        }
+    }
+
+    (n_sampled > 0).then(|| total_bytes / n_sampled * assemblies.len() as u64)
+}
+
+// matching more than this many assemblies is probably a mistake, so gdl asks first
+const CONFIRM_THRESHOLD: usize = 1000;
+
+// asks on stderr and reads the answer from stdin. Anything but y/yes is a no
+fn confirm(prompt: &str) -> bool {
+    eprint!("{} [y/N] ", prompt);
+    let _ = io::stderr().flush();
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .expect("Unable to read answer");
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+// --state records the accession of every assembly once all of its files have been downloaded,
+// one per line. It's appended to as downloads finish so a crash only loses what was in flight.
+struct RunState {
+    file: Mutex<File>,
+}
+
+impl RunState {
+    // returns the state along with the accessions that were already completed
+    fn open(path: &str) -> (Self, HashSet<String>) {
+        let completed = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => panic!("Unable to read state file {} ({})", path, e),
+        };
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|e| panic!("Unable to open state file {} ({})", path, e));
+
+        (
+            RunState {
+                file: Mutex::new(file),
+            },
+            completed,
+        )
+    }
+
+    fn record(&self, accession: &str) {
+        let mut file = self.file.lock().expect("Unable to lock state file");
+        writeln!(file, "{}", accession).expect("Unable to write to state file");
+    }
+
+    // records are written straight to the file as they come in, but only the OS knows about
+    // them until they're synced
+    fn sync(&self) -> io::Result<()> {
+        self.file
+            .lock()
+            .expect("Unable to lock state file")
+            .sync_data()
+    }
+}
+
+// --json-errors gets a line for every failed download as it fails, so a crash doesn't lose the
+// failures seen so far
+struct ErrorLog {
+    file: Mutex<File>,
+}
+
+// one line of --json-errors
+#[derive(Debug, serde::Serialize)]
+struct FailedDownload<'a> {
+    accession: &'a str,
+    url: &'a str,
+    // e.g. http or invalid_download
+    kind: &'static str,
+    http_status: Option<u16>,
+    // errors that can go away are only given up on after every retry
+    retries: u32,
+    error: String,
+}
+
+impl ErrorLog {
+    fn create(path: &str) -> Self {
+        let file = File::create(path)
+            .unwrap_or_else(|e| panic!("Unable to create --json-errors file {} ({})", path, e));
+        ErrorLog {
+            file: Mutex::new(file),
+        }
+    }
+
+    // `url` is what was being fetched, unless the error knows better
+    fn record(&self, accession: &str, url: &str, error: &GdlError, retries: u32) {
+        let failure = FailedDownload {
+            accession,
+            url: error.url().unwrap_or(url),
+            kind: error.kind(),
+            http_status: error.http_status(),
+            retries: if error.is_retryable() { retries } else { 0 },
+            error: error.to_string(),
+        };
+        let line = serde_json::to_string(&failure).expect("Unable to serialize failure");
+        let mut file = self.file.lock().expect("Unable to lock --json-errors file");
+        writeln!(file, "{}", line).expect("Unable to write to --json-errors file");
+    }
+}
+
+// the taxonomy only contains scientific names, so synonyms and other name classes are looked up
+// directly in names.dmp. The tax ID of a synonym is always the tax ID of the accepted name.
+fn find_tax_ids_in_names(taxdump_path: &str, name: &str, name_class: &NameClass) -> Vec<String> {
+    let names_path = Path::new(taxdump_path).join("names.dmp");
+    let names_file = File::open(&names_path)
+        .unwrap_or_else(|_| panic!("Unable to open {}", names_path.display()));
+
+    let mut tax_ids: Vec<String> = Vec::new();
+
+    for line in BufReader::new(names_file).lines() {
+        let line = line.unwrap_or_else(|_| panic!("Unable to read {}", names_path.display()));
+        let mut fields = line.split("\t|\t");
+        if let (Some(tax_id), Some(name_txt), Some(class)) =
+            (fields.next(), fields.next(), fields.nth(1))
+        {
+            if name_txt.trim() == name
+                && name_class.includes(class.trim_end_matches("\t|").trim())
+                && !tax_ids.iter().any(|t| t == tax_id.trim())
+            {
+                tax_ids.push(tax_id.trim().to_string());
+            }
+        }
+    }
+
+    tax_ids
+}
+
+// the tax ID itself and, unless `exact`, everything below it
+fn taxon_and_descendants<'a>(
+    tax: &'a GeneralTaxonomy,
+    tax_id: &'a str,
+    exact: bool,
+) -> Vec<&'a str> {
+    if exact {
+        return vec![tax_id];
+    }
+    let mut tax_ids = tax
+        .descendants(tax_id)
+        .unwrap_or_else(|_| panic!("Unable to find taxonomic descendants for tax ID {}", tax_id));
+    tax_ids.push(tax_id);
+    tax_ids
+}
+
+// * matches any run of characters and ? any single one. Everything else is literal
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn find_tax_ids_by_pattern<'t>(tax: &'t GeneralTaxonomy, pattern: &Regex) -> Vec<&'t str> {
+    tax.tax_ids
+        .iter()
+        .zip(&tax.names)
+        .filter(|(_, name)| pattern.is_match(name))
+        .map(|(tax_id, _)| tax_id.as_str())
+        .collect()
+}
+
+fn get_tax_id<'a>(
+    tax_id: Option<&'a str>,
+    tax_name: Option<&'a str>,
+    tax: &'a GeneralTaxonomy,
+    taxdump_path: Option<&str>,
+    name_class: &NameClass,
+) -> Result<&'a str, GdlError> {
+    match (tax_id, tax_name) {
+        (Some(tax_id), None) if tax.to_internal_index(tax_id).is_err() => Err(
+            GdlError::TaxonNotFound(format!("No taxon with tax ID {}", tax_id)),
+        ),
+        (Some(tax_id), None) => Ok(tax_id),
+        (None, Some(tax_name)) => {
+            let mut matches = if name_class.includes("scientific name") {
+                tax.find_all_by_name(tax_name)
+            } else {
+                Vec::new()
+            };
+            if let (true, Some(taxdump_path)) = (matches.is_empty(), taxdump_path) {
+                matches = find_tax_ids_in_names(taxdump_path, tax_name, name_class)
+                    .iter()
+                    .filter_map(|tax_id| tax.to_internal_index(tax_id).ok())
+                    .filter_map(|index| tax.from_internal_index(index).ok())
+                    .collect();
+            }
+            match matches.len() {
+                0 => Err(GdlError::TaxonNotFound(format!(
+                    "No matches found for {}",
+                    tax_name
+                ))),
+                1 => Ok(matches
+                    .first()
+                    .unwrap_or_else(|| panic!("No tax ID found for name {}", tax_name))),
+                // the caller shows the matched lineages to help the user disambiguate
+                _ => Err(GdlError::TaxonNotFound(format!(
+                    "{} is ambiguous",
+                    tax_name
+                ))),
+            }
+        }
+        _ => Err(GdlError::TaxonNotFound(
+            "Either --tax-id or --tax-name must be provided, but not both".to_string(),
+        )),
+    }
+}
+
+// how a taxon was asked for, e.g. `--tax-id 562`
+fn given_taxon(tax_id: Option<&str>, tax_name: Option<&str>) -> String {
+    match (tax_id, tax_name) {
+        (Some(tax_id), _) => format!("--tax-id {}", tax_id),
+        (None, tax_name) => format!("--tax-name {}", tax_name.unwrap_or_default()),
+    }
+}
+
+fn download_and_extract_taxdump(client: &Client, url: &str, path: &str, progress: &MultiProgress) {
+    let mut response =
+        get(client, url).unwrap_or_else(|e| panic!("Unable to fetch NCBI taxonomy dump: {}", e));
+
+    // download next to the extracted taxdump rather than into the current directory
+    let archive_path = taxdump_archive_path(path);
+
+    let content_length = response.content_length().unwrap_or(0);
+
+    let pb = progress.add(ProgressBar::new(content_length));
+    pb.set_style(
+        ProgressStyle::with_template(PB_DOWNLOAD_TEMPLATE)
+            .unwrap()
+            .progress_chars(PROGRESS_CHARS),
+    );
+    pb.set_message("taxdump.tar.gz");
+
+    let file = File::create(&archive_path)
+        .unwrap_or_else(|_| panic!("Unable to write to {}", archive_path));
+    let mut wrapped_file = pb.wrap_write(file);
+
+    let _ = response.copy_to(&mut wrapped_file);
+
+    pb.finish();
+
+    // a truncated archive (e.g. the disk filled up while downloading it) is no use to the next run
+    extract_taxdump(&archive_path, path, progress).unwrap_or_else(|e| {
+        let _ = fs::remove_file(&archive_path);
+        panic!("{}", e)
+    });
+
+    fs::remove_file(&archive_path).unwrap_or_else(|_| panic!("Unable to remove {}", archive_path));
+}
+
+// where a downloaded taxdump.tar.gz is kept until it has been extracted to `path`
+fn taxdump_archive_path(path: &str) -> String {
+    format!("{}.tar.gz", path.trim_end_matches('/'))
+}
+
+// the other way around, for a --taxdump-path that's an archive: where it's extracted to
+fn taxdump_extracted_path(archive_path: &str) -> String {
+    match [".tar.gz", ".tgz"]
+        .iter()
+        .find_map(|suffix| archive_path.strip_suffix(suffix))
+    {
+        Some(path) if !path.is_empty() => path.to_string(),
+        _ => format!("{}.d", archive_path),
+    }
+}
+
+fn extract_taxdump(archive_path: &str, path: &str, progress: &MultiProgress) -> Result<(), String> {
+    let tar_gz =
+        File::open(archive_path).map_err(|e| format!("Unable to open {} ({})", archive_path, e))?;
+    let decompressed = GzDecoder::new(tar_gz);
+    let mut archive = Archive::new(decompressed);
+
+    std::fs::create_dir_all(path)
+        .map_err(|e| format!("Unable to create taxdump output dir {} ({})", path, e))?;
+
+    // the files are about to be overwritten, so the taxdump isn't extracted until they all are
+    let stamp_path = Path::new(path).join(TAXDUMP_EXTRACTED_STAMP);
+    let marker_path = Path::new(path).join(TAXDUMP_EXTRACTING_MARKER);
+    let _ = fs::remove_file(&stamp_path);
+    File::create(&marker_path)
+        .map_err(|e| format!("Unable to write {} ({})", marker_path.display(), e))?;
+
+    // the number of entries isn't known until the whole (gzipped) archive has been read, so the
+    // bar starts at the usual size of the taxdump and grows if there turn out to be more
+    let pb = progress.add(ProgressBar::new(TAXDUMP_N_ENTRIES));
+    pb.set_style(
+        ProgressStyle::with_template(PB_EXTRACT_TEMPLATE)
+            .unwrap()
+            .progress_chars(PROGRESS_CHARS),
+    );
+
+    // the entries extracted so far, removed again if the extraction fails to free up the space
+    let mut extracted = Vec::new();
+    let mut unpack_entries = || -> Result<(), String> {
+        let entries = archive
+            .entries()
+            .map_err(|e| format!("Unable to extract {} ({})", archive_path, e))?;
+        for entry in entries {
+            let mut entry =
+                entry.map_err(|e| format!("Unable to extract {} ({})", archive_path, e))?;
+            let entry_path = entry
+                .path()
+                .map(|entry_path| entry_path.display().to_string())
+                .unwrap_or_default();
+            pb.set_message(format!("Extracting {}", entry_path));
+
+            extracted.push(Path::new(path).join(&entry_path));
+            entry.unpack_in(path).map_err(|e| {
+                format!(
+                    "Unable to extract {} from {} ({})",
+                    entry_path, archive_path, e
+                )
+            })?;
+
+            pb.inc(1);
+            if pb.position() > pb.length().unwrap_or(0) {
+                pb.set_length(pb.position());
+            }
+        }
+        Ok(())
+    };
+    if let Err(e) = unpack_entries() {
+        for extracted_path in &extracted {
+            let _ = fs::remove_file(extracted_path);
+        }
+        return Err(e);
+    }
+    pb.set_length(pb.position());
+
+    File::create(&stamp_path)
+        .map_err(|e| format!("Unable to write {} ({})", stamp_path.display(), e))?;
+    fs::remove_file(&marker_path)
+        .map_err(|e| format!("Unable to remove {} ({})", marker_path.display(), e))?;
+
+    pb.finish_with_message("Extracted taxonomy");
+    Ok(())
+}
+
+// whether NCBI has published a newer taxdump (going by the Last-Modified of the archive) since
+// the one at `path` was extracted. Taxdumps extracted by an older gdl go by the directory's
+// mtime. Not knowing, e.g. when NCBI can't be reached, counts as up to date
+fn taxdump_is_stale(client: &Client, url: &str, path: &str) -> bool {
+    let Some(published) =
+        remote_last_modified(client, url).and_then(|value| httpdate::parse_http_date(&value).ok())
+    else {
+        return false;
+    };
+    fs::metadata(taxdump_extracted_stamp(path))
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|extracted| published > extracted)
+}
+
+// what the extraction time of the taxdump at `path` is read from
+fn taxdump_extracted_stamp(path: &str) -> PathBuf {
+    let stamp_path = Path::new(path).join(TAXDUMP_EXTRACTED_STAMP);
+    match stamp_path.exists() {
+        true => stamp_path,
+        false => PathBuf::from(path),
+    }
+}
+
+// the Last-Modified NCBI reports for `url`, without downloading it
+fn remote_last_modified(client: &Client, url: &str) -> Option<String> {
+    client
+        .head(url)
+        .send()
+        .ok()
+        .filter(|response| response.status().is_success())
+        .and_then(|response| last_modified(&response))
+}
+
+const TAXDUMP_DOWNLOAD_ATTEMPTS: u32 = 2;
+
+// catches a corrupt or truncated taxdump before it turns into a confusing panic while loading
+fn check_taxdump(path: &str, format: &TaxdumpFormat) -> Result<(), String> {
+    if Path::new(path).join(TAXDUMP_EXTRACTING_MARKER).exists() {
+        return Err(format!("{} was only partly extracted", path));
+    }
+    for filename in format.required_files() {
+        let file_path = Path::new(path).join(filename);
+        match fs::metadata(&file_path) {
+            Ok(metadata) if metadata.len() > 0 => {}
+            Ok(_) => return Err(format!("{} is empty", file_path.display())),
+            Err(_) => return Err(format!("{} is missing", file_path.display())),
+        }
+    }
+    Ok(())
+}
+
+// downloads and extracts the taxdump, downloading it again if the extracted files are incomplete
+fn download_taxdump(
+    client: &Client,
+    url: &str,
+    path: &str,
+    format: &TaxdumpFormat,
+    progress: &MultiProgress,
+) {
+    for attempt in 1..=TAXDUMP_DOWNLOAD_ATTEMPTS {
+        download_and_extract_taxdump(client, url, path, progress);
+        match check_taxdump(path, format) {
+            Ok(()) => return,
+            Err(e) if attempt < TAXDUMP_DOWNLOAD_ATTEMPTS => {
+                eprintln!("Warning: {}, downloading it again", e)
+            }
+            Err(e) => panic!("Taxdump downloaded from {} is incomplete: {}", url, e),
+        }
+    }
+}
+
+// the summary is downloaded to {path}.part and only moved into place once it's complete. An
+// interrupted download is resumed from where it stopped, but only if the summary is still the
+// same version (If-Range with the ETag or Last-Modified seen when it started). NCBI publishes a
+// new one daily, and a newer summary is downloaded from the start instead. When `out_path` ends
+// in .gz, the complete summary is gzipped into place. Returns the Last-Modified header of the
+// summary, if NCBI sent one.
+fn download_assembly_summary(
+    client: &Client,
+    assembly_summary_url: &str,
+    out_path: &str,
+    progress: &MultiProgress,
+) -> Option<String> {
+    // the part is plain text either way, so it can be resumed with or without
+    // --assembly-summary-gz
+    let part_path = format!("{}.part", out_path.strip_suffix(".gz").unwrap_or(out_path));
+    let validator_path = format!("{}{}", part_path, SUMMARY_VALIDATOR_SUFFIX);
+
+    let n_downloaded = fs::metadata(&part_path).map_or(0, |metadata| metadata.len());
+    let validator = fs::read_to_string(&validator_path)
+        .ok()
+        .filter(|validator| !validator.trim().is_empty());
+    let mut request = client.get(assembly_summary_url);
+    if let (true, Some(validator)) = (n_downloaded > 0, &validator) {
+        request = request
+            .header(RANGE, format!("bytes={}-", n_downloaded))
+            .header(IF_RANGE, validator.trim());
+    }
+
+    let mut response = match send(request, assembly_summary_url) {
+        // the whole summary was already downloaded
+        Err(GdlError::Http { status, .. }) if status == StatusCode::RANGE_NOT_SATISFIABLE => {
+            let _ = fs::remove_file(&part_path);
+            return download_assembly_summary(client, assembly_summary_url, out_path, progress);
+        }
+        result => result.unwrap_or_else(|e| panic!("Unable to fetch assembly summary: {}", e)),
+    };
+
+    // anything but the missing range (e.g. a 200 for a newer summary) starts over
+    let resumed = response.status() == StatusCode::PARTIAL_CONTENT
+        && content_range(&response).is_some_and(|(start, _)| start == n_downloaded);
+    let n_skipped = if resumed { n_downloaded } else { 0 };
+    let expected_size = match resumed {
+        true => content_range(&response).and_then(|(_, total)| total),
+        false => response.content_length(),
+    };
+    let last_modified = last_modified(&response);
+
+    if !resumed {
+        let validator = response
+            .headers()
+            .get(ETAG)
+            .or_else(|| response.headers().get(LAST_MODIFIED))
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        fs::write(&validator_path, validator)
+            .unwrap_or_else(|e| panic!("Unable to write {} ({})", validator_path, e));
+    }
+
+    let pb = progress.add(ProgressBar::new(expected_size.unwrap_or(0)));
+    pb.set_style(
+        ProgressStyle::with_template(PB_DOWNLOAD_TEMPLATE)
+            .unwrap()
+            .progress_chars(PROGRESS_CHARS),
+    );
+    pb.set_position(n_skipped);
+    if resumed {
+        pb.println(format!(
+            "Resuming the download of {} after {}",
+            out_path,
+            HumanBytes(n_skipped)
+        ));
+    }
+
+    pb.set_message(out_path.to_string());
+
+    let file = match resumed {
+        true => fs::OpenOptions::new().append(true).open(&part_path),
+        false => File::create(&part_path),
+    }
+    .unwrap_or_else(|_| panic!("Unable to open assembly summary {}", part_path));
+    let mut wrapped_file = pb.wrap_write(file);
+
+    response.copy_to(&mut wrapped_file).unwrap_or_else(|e| {
+        panic!(
+            "Unable to download assembly summary {} ({}). Run gdl again to resume",
+            assembly_summary_url, e
+        )
+    });
+
+    let size = fs::metadata(&part_path).map_or(0, |metadata| metadata.len());
+    if let Some(expected_size) = expected_size.filter(|&expected| expected != size) {
+        let _ = fs::remove_file(&part_path);
+        panic!(
+            "Downloaded assembly summary {} is {} bytes instead of {}",
+            assembly_summary_url, size, expected_size
+        );
+    }
+    if out_path.ends_with(".gz") {
+        gzip_file(&part_path, out_path)
+            .unwrap_or_else(|e| panic!("Unable to gzip {} to {} ({})", part_path, out_path, e));
+    } else {
+        fs::rename(&part_path, out_path)
+            .unwrap_or_else(|e| panic!("Unable to move {} to {} ({})", part_path, out_path, e));
+    }
+    let _ = fs::remove_file(&validator_path);
+
+    pb.finish();
+
+    last_modified
+}
+
+// replaces `from` with a gzipped copy at `to`
+fn gzip_file(from: &str, to: &str) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", to);
+    let gzip = || -> io::Result<()> {
+        let mut encoder = GzEncoder::new(
+            io::BufWriter::new(File::create(&tmp_path)?),
+            Compression::default(),
+        );
+        io::copy(&mut File::open(from)?, &mut encoder)?;
+        encoder.finish()?.flush()?;
+        fs::rename(&tmp_path, to)?;
+        fs::remove_file(from)
+    };
+    gzip().inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_path);
+    })
+}
+
+// kept next to a partially downloaded summary, holding what If-Range is sent with on resume
+const SUMMARY_VALIDATOR_SUFFIX: &str = ".validator";
+
+// (first byte, total size) of a 206, from e.g. "bytes 100-999/1000"
+fn content_range(response: &Response) -> Option<(u64, Option<u64>)> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()))
+}
+
+fn last_modified(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get(LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+// mtime of a file (or directory) as an RFC 3339 timestamp
+fn modified_at(path: &str) -> Option<String> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .map(|modified| humantime::format_rfc3339_seconds(modified).to_string())
+}
+
+fn create_tar(path: &str) -> Builder<Box<dyn Write + Send>> {
+    let file = File::create(path).unwrap_or_else(|_| panic!("Unable to create {}", path));
+    let writer: Box<dyn Write + Send> = if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+        Box::new(GzEncoder::new(file, Compression::default()))
+    } else {
+        Box::new(file)
+    };
+    Builder::new(writer)
+}
+
+// adds a downloaded file to the archive and removes it from disk. Files are downloaded in
+// parallel but have to be written to the archive one at a time. Entries are named by their path
+// within `out_path`, like the manifest's path column, so subdirectories are kept.
+fn append_to_tar(archive: &Mutex<Builder<Box<dyn Write + Send>>>, path: &Path, out_path: &Path) {
+    let name = path.strip_prefix(out_path).unwrap_or_else(|_| {
+        panic!(
+            "Unable to add {} to tar archive, it isn't in {}",
+            path.display(),
+            out_path.display()
+        )
+    });
+
+    archive
+        .lock()
+        .expect("Unable to lock tar archive")
+        .append_path_with_name(path, name)
+        .unwrap_or_else(|_| panic!("Unable to add {} to tar archive", path.display()));
+
+    fs::remove_file(path).unwrap_or_else(|_| panic!("Unable to remove {}", path.display()));
+}
+
+// everything needed to re-create (or cite) a set of downloaded assemblies
+#[derive(Debug, serde::Serialize)]
+struct Provenance {
+    gdl_version: &'static str,
+    command: Vec<String>,
+    created_at: String,
+    source: String,
+    assembly_summary: String,
+    // only known when the summary was fetched during this run
+    assembly_summary_last_modified: Option<String>,
+    assembly_summary_modified_at: Option<String>,
+    taxonomy: String,
+    taxonomy_path: String,
+    taxonomy_modified_at: Option<String>,
+    tax_id: Option<String>,
+    tax_name: Option<String>,
+    tax_ids: Vec<String>,
+    accessions: Vec<String>,
+    formats: Vec<String>,
+    n_assemblies: usize,
+}
+
+// a downloaded file as it's listed in the manifest, with the values of the requested columns
+struct ManifestRow {
+    accession: String,
+    format: String,
+    values: Vec<String>,
+}
+
+// the md5 is only computed when it's asked for, using the checksum cache with --verify
+fn manifest_row(
+    columns: &[ManifestColumn],
+    assembly: &NCBIAssembly,
+    format: &str,
+    url: &str,
+    path: &Path,
+    out_path: &Path,
+    checksum_cache: Option<&ChecksumCache>,
+) -> ManifestRow {
+    let values = columns
+        .iter()
+        .map(|column| match column {
+            ManifestColumn::Accession => assembly.assembly_accession.clone(),
+            ManifestColumn::Taxid => assembly.taxid.clone(),
+            ManifestColumn::OrganismName => assembly.organism_name.clone(),
+            ManifestColumn::FtpPath => assembly.ftp_path.clone(),
+            ManifestColumn::AssemblyLevel => assembly.assembly_level.clone(),
+            ManifestColumn::SeqRelDate => assembly.seq_rel_date.clone(),
+            ManifestColumn::ExcludedFromRefseq => assembly.excluded_from_refseq.clone(),
+            ManifestColumn::VersionStatus => assembly.version_status.clone(),
+            ManifestColumn::GenomeSize => assembly.genome_size.clone(),
+            ManifestColumn::RelationToTypeMaterial => assembly.relation_to_type_material.clone(),
+            ManifestColumn::Format => format.to_string(),
+            ManifestColumn::Path => path
+                .strip_prefix(out_path)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned(),
+            ManifestColumn::Url => url.to_string(),
+            ManifestColumn::Source => assembly.source.to_string(),
+            ManifestColumn::Md5 => match checksum_cache {
+                Some(cache) => cache.md5(path),
+                None => file_md5(path),
+            }
+            .unwrap_or_else(|e| panic!("Unable to hash {} ({})", path.display(), e)),
+            ManifestColumn::Sha256 => file_sha256(path)
+                .unwrap_or_else(|e| panic!("Unable to hash {} ({})", path.display(), e)),
+        })
+        .chain(assembly.passthrough.iter().cloned())
+        .collect();
+
+    ManifestRow {
+        accession: assembly.assembly_accession.clone(),
+        format: format.to_string(),
+        values,
+    }
+}
+
+// re-hashes the files listed in a manifest (relative to `out_path`) in parallel and returns how
+// many were checked along with a description of each one that is missing or doesn't match
+fn verify_manifest(manifest_path: &str, out_path: &Path, pb: &ProgressBar) -> (usize, Vec<String>) {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(manifest_path)
+        .unwrap_or_else(|e| panic!("Unable to read manifest {} ({})", manifest_path, e));
+    let headers = reader
+        .headers()
+        .unwrap_or_else(|e| panic!("Unable to read manifest {} ({})", manifest_path, e))
+        .clone();
+    let column = |column: ManifestColumn| {
+        headers
+            .iter()
+            .position(|header| header == column.name())
+            .unwrap_or_else(|| {
+                panic!(
+                    "Manifest {} has no {} column (write it with --manifest-columns)",
+                    manifest_path,
+                    column.name()
+                )
+            })
+    };
+    let (path_column, md5_column) = (column(ManifestColumn::Path), column(ManifestColumn::Md5));
+
+    let files: Vec<(String, String)> = reader
+        .records()
+        .filter_map(|record| {
+            let record = record
+                .unwrap_or_else(|e| panic!("Unable to read manifest {} ({})", manifest_path, e));
+            Some((
+                record.get(path_column)?.to_string(),
+                record.get(md5_column)?.to_string(),
+            ))
+        })
+        .collect();
+
+    pb.set_length(files.len() as u64);
+    let failures = files
+        .par_iter()
+        .filter_map(|(path, expected)| {
+            let failure = match file_md5(&out_path.join(path)) {
+                Ok(actual) if actual == *expected => None,
+                Ok(actual) => Some(format!(
+                    "{}: md5 mismatch (expected {}, got {})",
+                    path, expected, actual
+                )),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Some(format!("{}: missing", path)),
+                Err(e) => Some(format!("{}: unable to hash ({})", path, e)),
+            };
+            pb.inc(1);
+            failure
+        })
+        .collect();
+
+    (files.len(), failures)
+}
+
+// the manifest --dry-run writes: every file that would be downloaded, with the URL it would be
+// downloaded from and where it would be saved. Assemblies whose URL can't be worked out are
+// skipped, as they would be when downloading
+fn planned_manifest_rows(
+    columns: &[ManifestColumn],
+    assemblies: &[NCBIAssembly],
+    formats: &[AssemblyFormat],
+    extra_files: &[ExtraFile],
+    datasets_url: Option<&str>,
+    out_path: &Path,
+    options: &DownloadOptions,
+) -> Vec<ManifestRow> {
+    let mut rows = Vec::new();
+    for assembly in assemblies {
+        // (url, filename) of each file
+        let mut plan = |format: &str, file: Result<(String, String), String>| match file {
+            Ok((url, filename)) => rows.push(manifest_row(
+                columns,
+                assembly,
+                format,
+                &url,
+                &out_path.join(filename),
+                out_path,
+                None,
+            )),
+            Err(e) => eprintln!("Warning: skipping {}: {}", assembly.assembly_accession, e),
+        };
+
+        if let Some(datasets_url) = datasets_url {
+            plan(
+                "datasets",
+                datasets_package_url(datasets_url, assembly, formats)
+                    .map(|url| (url, datasets_package_filename(assembly, options))),
+            );
+            continue;
+        }
+        for format in formats {
+            plan(
+                format.as_str(),
+                assembly_url(assembly, format, options)
+                    .and_then(|url| Ok((url, local_filename(assembly, format, options)?))),
+            );
+        }
+        for extra_file in extra_files {
+            plan(
+                extra_file.extension(),
+                extra_file_url(assembly, extra_file, options)
+                    .and_then(|url| Ok((url, extra_file_filename(assembly, extra_file, options)?))),
+            );
+        }
+    }
+    rows
+}
+
+// rows are sorted so that the manifest doesn't depend on the order downloads finished in. It's
+// written next to `path` and moved into place, so that a crash while writing a --checkpoint-every
+// checkpoint leaves the previous one intact. The --summary-columns-passthrough columns come last,
+// under their names in the summary
+fn write_manifest(
+    path: &str,
+    columns: &[ManifestColumn],
+    passthrough_columns: &[String],
+    rows: &mut [ManifestRow],
+) {
+    rows.sort_by(|a, b| (&a.accession, &a.format).cmp(&(&b.accession, &b.format)));
+
+    let tmp_path = format!("{}.tmp", path);
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&tmp_path)
+        .unwrap_or_else(|e| panic!("Unable to write manifest to {} ({})", path, e));
+
+    writer
+        .write_record(
+            columns
+                .iter()
+                .map(|column| column.name().to_string())
+                .chain(passthrough_columns.iter().cloned()),
+        )
+        .and_then(|_| {
+            rows.iter()
+                .try_for_each(|row| writer.write_record(&row.values))
+        })
+        .and_then(|_| writer.flush().map_err(csv::Error::from))
+        .unwrap_or_else(|e| panic!("Unable to write manifest to {} ({})", path, e));
+    fs::rename(&tmp_path, path)
+        .unwrap_or_else(|e| panic!("Unable to write manifest to {} ({})", path, e));
+}
+
+fn write_provenance(provenance: &Provenance, path: &str) {
+    let file =
+        File::create(path).unwrap_or_else(|_| panic!("Unable to write provenance to {}", path));
+    serde_json::to_writer_pretty(file, provenance)
+        .unwrap_or_else(|_| panic!("Unable to write provenance to {}", path));
+}
+
+const RANKED_LINEAGE_FILENAME: &str = "rankedlineage.dmp";
+
+// rankedlineage.dmp in the new taxdump has one line per taxon with its name followed by the names
+// of its ancestors at each major rank, from species up (empty where there isn't one):
+// `tax_id | tax_name | species | genus | family | order | class | phylum | kingdom | ... |`.
+// Only the lineages of `tax_ids` are kept, highest rank first.
+fn load_ranked_lineages(taxdump_path: &str, tax_ids: &[&str]) -> HashMap<String, Vec<String>> {
+    let path = Path::new(taxdump_path).join(RANKED_LINEAGE_FILENAME);
+    let file = File::open(&path).unwrap_or_else(|_| panic!("Unable to open {}", path.display()));
+
+    let mut lineages = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.unwrap_or_else(|_| panic!("Unable to read {}", path.display()));
+        let mut fields = line.trim_end_matches("\t|").split("\t|\t").map(str::trim);
+        let Some(tax_id) = fields.next().filter(|tax_id| tax_ids.contains(tax_id)) else {
+            continue;
+        };
+        let mut lineage: Vec<String> = fields
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        lineage.reverse();
+        lineages.insert(tax_id.to_string(), lineage);
+    }
+
+    lineages
+}
+
+// e.g. "Bacteria; Pseudomonadota; Gammaproteobacteria; ...; Escherichia coli". Uses the ranked
+// lineages from the new taxdump when they're available, and walks up the taxonomy otherwise.
+fn lineage_string(
+    tax: &GeneralTaxonomy,
+    tax_id: &str,
+    ranked_lineages: Option<&HashMap<String, Vec<String>>>,
+) -> String {
+    if let Some(lineage) = ranked_lineages.and_then(|lineages| lineages.get(tax_id)) {
+        return lineage.join("; ");
+    }
+
+    let mut names: Vec<String> = lineage(tax, tax_id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(_, name)| name)
+        .collect();
+    // the root ("root") isn't part of anyone's lineage
+    names.pop();
+    names.reverse();
+    names.join("; ")
+}
+
+/// Returns the (rank, name) of `tax_id` and each of its ancestors, from `tax_id` up to the root
+pub fn lineage(tax: &GeneralTaxonomy, tax_id: &str) -> Result<Vec<(String, String)>, GdlError> {
+    let ancestors = tax
+        .lineage(tax_id)
+        .map_err(|_| GdlError::TaxonNotFound(format!("{} is not in the taxonomy", tax_id)))?;
+    Ok(ancestors
+        .into_iter()
+        .map(|ancestor| {
+            let rank = tax
+                .rank(ancestor)
+                .map(|rank| rank.to_ncbi_rank())
+                .unwrap_or("unknown");
+            let name = tax.name(ancestor).unwrap_or(ancestor);
+            (rank.to_string(), name.to_string())
+        })
+        .collect())
+}
+
+// the genome's leaf in a GTDB taxonomy, under either its GenBank or RefSeq accession
+fn gtdb_genome<'t>(tax: &'t GeneralTaxonomy, accession: &str) -> Option<&'t str> {
+    let paired_accession = match accession.split_at_checked(4) {
+        Some(("GCA_", rest)) => format!("GCF_{}", rest),
+        Some(("GCF_", rest)) => format!("GCA_{}", rest),
+        _ => accession.to_string(),
+    };
+    let index = tax
+        .to_internal_index(accession)
+        .or_else(|_| tax.to_internal_index(&paired_accession))
+        .ok()?;
+    tax.from_internal_index(index).ok()
+}
+
+// GTDB genomes are leaves below their species, and GTDB's own lineage strings are made up of the
+// prefixed names of the taxa above them (e.g. d__Bacteria;p__Pseudomonadota;...)
+fn gtdb_lineage(tax: &GeneralTaxonomy, accession: &str) -> String {
+    let lineage = gtdb_genome(tax, accession)
+        .and_then(|genome| tax.lineage(genome).ok())
+        .unwrap_or_default();
+
+    let root: &str = tax.root();
+    let mut lineage: Vec<&str> = lineage
+        .into_iter()
+        .skip(1)
+        .filter(|ancestor| *ancestor != root)
+        .collect();
+    lineage.reverse();
+    lineage.join(";")
+}
+
+// (tax ID, name, number of assemblies) for each taxon at `rank` that the assemblies belong to,
+// most assemblies first. Assemblies without an ancestor at that rank are counted as `unclassified`
+fn count_by_rank(
+    tax: &GeneralTaxonomy,
+    assemblies: &[NCBIAssembly],
+    rank: TaxRank,
+    taxonomy: &TaxonomyBackend,
+) -> Vec<(String, String, usize)> {
+    let mut counts: HashMap<Option<&str>, usize> = HashMap::new();
+    for assembly in assemblies {
+        let node = match taxonomy {
+            TaxonomyBackend::Ncbi => Some(assembly.taxid.as_str()),
+            TaxonomyBackend::Gtdb => gtdb_genome(tax, &assembly.assembly_accession),
+        };
+        let taxon = node
+            .and_then(|node| tax.parent_at_rank(node, rank).ok().flatten())
+            .map(|(taxon, _)| taxon);
+        *counts.entry(taxon).or_default() += 1;
+    }
+
+    let mut rows: Vec<(String, String, usize)> = counts
+        .into_iter()
+        .map(|(taxon, count)| match taxon {
+            Some(taxon) => (
+                taxon.to_string(),
+                tax.name(taxon).unwrap_or(taxon).to_string(),
+                count,
+            ),
+            None => ("NA".to_string(), "unclassified".to_string(), count),
+        })
+        .collect();
+    rows.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.1.cmp(&b.1)));
+    rows
+}
+
+// the lineage of an assembly's taxon, as --name-map and --annotate-headers write it
+fn assembly_lineage(
+    tax: &GeneralTaxonomy,
+    assembly: &NCBIAssembly,
+    taxonomy: &TaxonomyBackend,
+    ranked_lineages: Option<&HashMap<String, Vec<String>>>,
+) -> String {
+    match taxonomy {
+        TaxonomyBackend::Ncbi => lineage_string(tax, &assembly.taxid, ranked_lineages),
+        TaxonomyBackend::Gtdb => gtdb_lineage(tax, &assembly.assembly_accession),
+    }
+}
+
+// sorted by accession so the map doesn't depend on the order of the assembly summary
+fn write_name_map(
+    path: &str,
+    assemblies: &[NCBIAssembly],
+    format: &NameMapFormat,
+    tax: &GeneralTaxonomy,
+    taxonomy: &TaxonomyBackend,
+    ranked_lineages: Option<&HashMap<String, Vec<String>>>,
+) {
+    let mut rows: Vec<(&str, String)> = assemblies
+        .iter()
+        .map(|assembly| {
+            let value = match format {
+                NameMapFormat::Taxid => assembly.taxid.clone(),
+                NameMapFormat::Lineage => {
+                    assembly_lineage(tax, assembly, taxonomy, ranked_lineages)
+                }
+            };
+            (assembly.assembly_accession.as_str(), value)
+        })
+        .collect();
+    rows.sort();
+
+    let write = || -> io::Result<()> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        for (accession, value) in rows {
+            writeln!(writer, "{}\t{}", accession, value)?;
+        }
+        writer.flush()
+    };
+    write().unwrap_or_else(|e| panic!("Unable to write name map to {} ({})", path, e));
+}
+
+// sorted by sequence ID, without a header like kraken2-build expects
+fn write_seqid2taxid(path: &str, mut rows: Vec<(String, String)>) {
+    rows.sort();
+
+    let write = || -> io::Result<()> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        for (seqid, taxid) in rows {
+            writeln!(writer, "{}\t{}", seqid, taxid)?;
+        }
+        writer.flush()
+    };
+    write().unwrap_or_else(|e| panic!("Unable to write sequence ID map to {} ({})", path, e));
+}
+
+// whether `tax_id` is a species or sits below one (a strain, subspecies, serotype, ...), in which
+// case all of its descendants belong to the same species
+fn is_species_or_below(tax: &GeneralTaxonomy, tax_id: &str) -> bool {
+    tax.lineage(tax_id)
+        .unwrap_or_default()
+        .into_iter()
+        .any(|ancestor| matches!(tax.rank(ancestor), Ok(TaxRank::Species)))
+}
+
+// whether `tax_id` is within --include-rank-below/--include-rank-above. A taxon is at or below a
+// rank when it or one of its ancestors has that rank, and at or above it when none of its
+// ancestors do, so that taxa without a rank go with the ranked taxa above them
+fn in_rank_window(
+    tax: &GeneralTaxonomy,
+    tax_id: &str,
+    below: Option<TaxRank>,
+    above: Option<TaxRank>,
+) -> bool {
+    let lineage = tax.lineage(tax_id).unwrap_or_default();
+    let has_rank = |ancestors: &[&str], rank| {
+        ancestors
+            .iter()
+            .any(|&ancestor| tax.rank(ancestor).is_ok_and(|r| r == rank))
+    };
+    below.is_none_or(|rank| has_rank(&lineage, rank))
+        && above.is_none_or(|rank| !has_rank(lineage.get(1..).unwrap_or_default(), rank))
+}
+
+// `tax_id` and its descendants as (tax ID, name, rank, lineage), where the lineage starts at
+// `tax_id`. Sorting by lineage keeps every subtree together
+fn taxa_rows<'t>(
+    tax: &'t GeneralTaxonomy,
+    tax_id: &str,
+    descendant_tax_ids: &HashSet<&'t str>,
+) -> Vec<[String; 4]> {
+    let mut rows: Vec<[String; 4]> = descendant_tax_ids
+        .iter()
+        .map(|&descendant| {
+            let mut lineage: Vec<&str> = Vec::new();
+            for ancestor in tax.lineage(descendant).unwrap_or_default() {
+                lineage.push(tax.name(ancestor).unwrap_or(ancestor));
+                if ancestor == tax_id {
+                    break;
+                }
+            }
+            lineage.reverse();
+            let rank = tax
+                .rank(descendant)
+                .map(|rank| rank.to_ncbi_rank())
+                .unwrap_or("unknown");
+            [
+                descendant.to_string(),
+                tax.name(descendant).unwrap_or(descendant).to_string(),
+                rank.to_string(),
+                lineage.join("; "),
+            ]
+        })
+        .collect();
+    rows.sort_by(|a, b| a[3].cmp(&b[3]));
+    rows
+}
+
+fn print_taxa(tax: &GeneralTaxonomy, tax_id: &str, descendant_tax_ids: &HashSet<&str>) {
+    let mut stdout = io::stdout().lock();
+    for row in taxa_rows(tax, tax_id, descendant_tax_ids) {
+        writeln!(stdout, "{}", row.join("\t")).expect("Unable to write taxa");
+    }
+}
+
+// for --tax-id-output, in the same order as --print-taxa
+fn write_tax_id_output(
+    path: &str,
+    tax: &GeneralTaxonomy,
+    tax_id: &str,
+    descendant_tax_ids: &HashSet<&str>,
+    with_names: bool,
+) {
+    let rows = taxa_rows(tax, tax_id, descendant_tax_ids);
+    let write = || -> io::Result<()> {
+        let mut writer = io::BufWriter::new(File::create(path)?);
+        for row in &rows {
+            match with_names {
+                true => writeln!(writer, "{}", row.join("\t"))?,
+                false => writeln!(writer, "{}", row[0])?,
+            }
+        }
+        writer.flush()
+    };
+    write().unwrap_or_else(|e| panic!("Unable to write tax IDs to {} ({})", path, e));
+    eprintln!("Wrote {} tax IDs to {}", rows.len(), path);
+}
+
+const OUT_DIR_CHECK_FILENAME: &str = ".gdl-write-check";
+
+// creates the output directory if needed and makes sure files can be written to it
+fn check_out_dir(out_path: &Path) -> Result<(), GdlError> {
+    fs::create_dir_all(out_path).map_err(GdlError::io("create the output directory", out_path))?;
+    let check_path = out_path.join(OUT_DIR_CHECK_FILENAME);
+    File::create(&check_path).map_err(GdlError::io("write to the output directory", out_path))?;
+    fs::remove_file(&check_path).map_err(GdlError::io("remove", &check_path))
+}
+
+// the assembly summaries downloaded to --cache-dir and the --filter-cache of the last run
+fn cached_summaries(cache_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(cache_dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    (name.starts_with("assembly_summary_")
+                        && (name.ends_with(".txt")
+                            || name.ends_with(".txt.gz")
+                            || name.ends_with(".txt.part")
+                            || name.ends_with(SUMMARY_VALIDATOR_SUFFIX)))
+                        || name == FILTER_CACHE_FILENAME
+                        || (name.starts_with(".gdl-filter-cache-") && name.ends_with(".tsv"))
+                })
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+// .part and .tmp files anywhere under `dir`, e.g. left behind when gdl was killed mid-download
+fn leftover_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths = Vec::new();
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.is_dir() {
+            paths.extend(leftover_files(&path));
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "part" || extension == "tmp")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths
+}
+
+fn clean(paths: &[PathBuf]) {
+    let mut n_bytes = 0;
+    for path in paths {
+        let (size, removed) = if path.is_dir() {
+            (dir_size(path), fs::remove_dir_all(path))
+        } else {
+            (
+                fs::metadata(path).map_or(0, |metadata| metadata.len()),
+                fs::remove_file(path),
+            )
+        };
+        match removed {
+            Ok(()) => {
+                eprintln!("Removed {}", path.display());
+                n_bytes += size;
+            }
+            Err(e) => eprintln!("Warning: unable to remove {} ({})", path.display(), e),
+        }
+    }
+    eprintln!("Freed {}", HumanBytes(n_bytes));
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn load_taxonomy(taxdump_path: &str) -> Result<GeneralTaxonomy, GdlError> {
+    load(taxdump_path).map_err(|e| {
+        // the taxonomy crate keeps what actually went wrong (e.g. a missing file) as the source
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(inner) = source {
+            message.push_str(&format!(": {}", inner));
+            source = inner.source();
+        }
+        GdlError::TaxonomyLoad {
+            path: PathBuf::from(taxdump_path),
+            message,
+        }
+    })
+}
+
+fn download_gtdb_taxonomy(client: &Client, out_path: &str, progress: &MultiProgress) {
+    fs::create_dir_all(out_path)
+        .unwrap_or_else(|_| panic!("Unable to create GTDB output dir: {}", out_path));
+
+    for url in GTDB_TAXONOMY_URLS {
+        let mut response =
+            get(client, url).unwrap_or_else(|e| panic!("Unable to fetch GTDB taxonomy: {}", e));
+
+        let filename = url
+            .split('/')
+            .next_back()
+            .and_then(|filename| filename.strip_suffix(".gz"))
+            .unwrap_or_else(|| panic!("Failed to get the filename from GTDB URL {}", url));
+
+        let pb = progress.add(ProgressBar::new(response.content_length().unwrap_or(0)));
+        pb.set_style(
+            ProgressStyle::with_template(PB_DOWNLOAD_TEMPLATE)
+                .unwrap()
+                .progress_chars(PROGRESS_CHARS),
+        );
+        pb.set_message(filename.to_string());
+
+        let path = Path::new(out_path).join(filename);
+        let mut file =
+            File::create(&path).unwrap_or_else(|_| panic!("Unable to write to {}", path.display()));
+
+        let mut decompressed = GzDecoder::new(pb.wrap_read(&mut response));
+        io::copy(&mut decompressed, &mut file)
+            .unwrap_or_else(|_| panic!("Unable to extract {}", url));
+
+        pb.finish();
+    }
+}
+
+// GTDB taxonomy files map genome accessions (prefixed with RS_ or GB_) to a lineage of
+// rank-prefixed names, e.g. `d__Bacteria;p__Pseudomonadota;...;s__Escherichia coli`. The prefixed
+// names are used as tax IDs and the bare names as names so that --tax-name works without the rank
+// prefix. Genome accessions are added as leaves below their species.
+fn gtdb_taxonomy_from_reader<R: BufRead>(reader: R, tax: &mut GtdbTaxonomyBuilder) {
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.expect("Unable to read GTDB taxonomy");
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (accession, lineage) = line.split_once('\t').unwrap_or_else(|| {
+            panic!(
+                "Expected an accession and a lineage on line {} of GTDB taxonomy",
+                i + 1
+            )
+        });
+
+        let mut parent = 0;
+        for tax_id in lineage.split(';').map(str::trim) {
+            let (rank, name) = match tax_id.split_at_checked(3) {
+                Some(("d__", name)) => (TaxRank::Domain, name),
+                Some(("p__", name)) => (TaxRank::Phylum, name),
+                Some(("c__", name)) => (TaxRank::Class, name),
+                Some(("o__", name)) => (TaxRank::Order, name),
+                Some(("f__", name)) => (TaxRank::Family, name),
+                Some(("g__", name)) => (TaxRank::Genus, name),
+                Some(("s__", name)) => (TaxRank::Species, name),
+                _ => (TaxRank::Unspecified, tax_id),
+            };
+            parent = tax.add(tax_id, name, rank, parent);
+        }
+
+        let accession = accession
+            .strip_prefix("RS_")
+            .or_else(|| accession.strip_prefix("GB_"))
+            .unwrap_or(accession);
+        tax.add(accession, accession, TaxRank::Strain, parent);
+    }
+}
+
+#[derive(Default)]
+struct GtdbTaxonomyBuilder {
+    tax_ids: Vec<String>,
+    parent_ids: Vec<usize>,
+    names: Vec<String>,
+    ranks: Vec<TaxRank>,
+    lookup: HashMap<String, usize>,
+}
+
+impl GtdbTaxonomyBuilder {
+    fn new() -> Self {
+        // GTDB has two domains, so everything hangs off of a synthetic root
+        let mut tax = GtdbTaxonomyBuilder::default();
+        tax.add("root", "root", TaxRank::Unspecified, 0);
+        tax
+    }
+
+    fn add(&mut self, tax_id: &str, name: &str, rank: TaxRank, parent: usize) -> usize {
+        if let Some(&index) = self.lookup.get(tax_id) {
+            return index;
+        }
+        let index = self.tax_ids.len();
+        self.tax_ids.push(tax_id.to_string());
+        self.parent_ids.push(parent);
+        self.names.push(name.to_string());
+        self.ranks.push(rank);
+        self.lookup.insert(tax_id.to_string(), index);
+        index
+    }
+
+    fn build(self) -> GeneralTaxonomy {
+        GeneralTaxonomy::from_arrays(
+            self.tax_ids,
+            self.parent_ids,
+            Some(self.names),
+            Some(self.ranks),
+            None,
+            None,
+        )
+        .expect("Unable to build GTDB taxonomy")
+    }
+}
+
+fn load_gtdb_taxonomy(gtdb_path: &str) -> GeneralTaxonomy {
+    let path = Path::new(gtdb_path);
+
+    let paths: Vec<PathBuf> = if path.is_dir() {
+        let mut paths: Vec<PathBuf> = fs::read_dir(path)
+            .unwrap_or_else(|_| panic!("Unable to read GTDB taxonomy dir {}", gtdb_path))
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.ends_with("_taxonomy.tsv"))
+            })
+            .collect();
+        paths.sort();
+        paths
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    if paths.is_empty() {
+        panic!("No *_taxonomy.tsv files found in {}", gtdb_path);
+    }
+
+    let mut tax = GtdbTaxonomyBuilder::new();
+    for path in paths {
+        let file = File::open(&path)
+            .unwrap_or_else(|_| panic!("Unable to open GTDB taxonomy {}", path.display()));
+        gtdb_taxonomy_from_reader(BufReader::new(file), &mut tax);
+    }
+    tax.build()
+}
+
+// GenBank (GCA_) and RefSeq (GCF_) copies of the same assembly share the same numeric part, so
+// GTDB genomes are matched against the summary on that alone
+fn gtdb_accession_key(accession: &str) -> &str {
+    let accession = accession
+        .strip_prefix("GCA_")
+        .or_else(|| accession.strip_prefix("GCF_"))
+        .unwrap_or(accession);
+    accession.split('.').next().unwrap_or(accession)
+}
+
+// the columns a --filter expression can compare
+const FILTER_COLUMNS: [&str; 15] = [
+    "assembly_accession",
+    "taxid",
+    "organism_name",
+    "ftp_path",
+    "assembly_level",
+    "refseq_category",
+    "seq_rel_date",
+    "excluded_from_refseq",
+    "version_status",
+    "genome_size",
+    "relation_to_type_material",
+    "submitter",
+    "infraspecific_name",
+    "gbrs_paired_asm",
+    "paired_asm_comp",
+];
+
+// a parsed --filter, e.g. `assembly_level == 'Complete Genome' && refseq_category != 'na'`. As in
+// Rust, && binds tighter than || and parentheses group
+#[derive(Debug, Clone, PartialEq)]
+enum FilterExpr {
+    Eq(String, String),
+    Ne(String, String),
+    And(Box<FilterExpr>, Box<FilterExpr>),
+    Or(Box<FilterExpr>, Box<FilterExpr>),
+}
+
+impl FilterExpr {
+    // columns are checked when parsing so every column here exists
+    fn matches(&self, assembly: &NCBIAssembly) -> bool {
+        match self {
+            FilterExpr::Eq(column, value) => assembly.column(column) == Some(value.as_str()),
+            FilterExpr::Ne(column, value) => assembly.column(column) != Some(value.as_str()),
+            FilterExpr::And(a, b) => a.matches(assembly) && b.matches(assembly),
+            FilterExpr::Or(a, b) => a.matches(assembly) || b.matches(assembly),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum FilterToken {
+    // a column name or an unquoted value
+    Word(String),
+    Quoted(String),
+    Eq,
+    Ne,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize_filter(s: &str) -> Result<Vec<FilterToken>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => FilterToken::Open,
+            ')' => FilterToken::Close,
+            '\'' | '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(quote) if quote == c => break,
+                        Some(other) => value.push(other),
+                        None => return Err(format!("Unterminated string in filter `{}`", s)),
+                    }
+                }
+                FilterToken::Quoted(value)
+            }
+            '=' | '!' | '&' | '|' => match (c, chars.next()) {
+                ('=', Some('=')) => FilterToken::Eq,
+                ('!', Some('=')) => FilterToken::Ne,
+                ('&', Some('&')) => FilterToken::And,
+                ('|', Some('|')) => FilterToken::Or,
+                _ => {
+                    return Err(format!(
+                        "Unexpected `{}` in filter `{}` (expected ==, !=, && or ||)",
+                        c, s
+                    ))
+                }
+            },
+            _ => {
+                let mut word = c.to_string();
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !"'\"()=!&|".contains(*c))
+                {
+                    word.push(c);
+                }
+                FilterToken::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+type FilterTokens = std::iter::Peekable<std::vec::IntoIter<FilterToken>>;
+
+fn parse_filter_expr(s: &str) -> Result<FilterExpr, String> {
+    let mut tokens = tokenize_filter(s)?.into_iter().peekable();
+    let expr = parse_filter_or(&mut tokens, s)?;
+    match tokens.next() {
+        None => Ok(expr),
+        Some(FilterToken::Close) => Err(format!("Unbalanced `)` in filter `{}`", s)),
+        Some(_) => Err(format!("Expected && or || in filter `{}`", s)),
+    }
+}
+
+fn parse_filter_or(tokens: &mut FilterTokens, s: &str) -> Result<FilterExpr, String> {
+    let mut expr = parse_filter_and(tokens, s)?;
+    while tokens.next_if_eq(&FilterToken::Or).is_some() {
+        expr = FilterExpr::Or(Box::new(expr), Box::new(parse_filter_and(tokens, s)?));
+    }
+    Ok(expr)
+}
+
+fn parse_filter_and(tokens: &mut FilterTokens, s: &str) -> Result<FilterExpr, String> {
+    let mut expr = parse_filter_comparison(tokens, s)?;
+    while tokens.next_if_eq(&FilterToken::And).is_some() {
+        expr = FilterExpr::And(
+            Box::new(expr),
+            Box::new(parse_filter_comparison(tokens, s)?),
+        );
+    }
+    Ok(expr)
+}
+
+fn parse_filter_comparison(tokens: &mut FilterTokens, s: &str) -> Result<FilterExpr, String> {
+    let column = match tokens.next() {
+        Some(FilterToken::Open) => {
+            let expr = parse_filter_or(tokens, s)?;
+            return match tokens.next() {
+                Some(FilterToken::Close) => Ok(expr),
+                _ => Err(format!("Missing `)` in filter `{}`", s)),
+            };
+        }
+        Some(FilterToken::Word(column)) => column,
+        _ => return Err(format!("Expected a column name or `(` in filter `{}`", s)),
+    };
+    if !FILTER_COLUMNS.contains(&column.as_str()) {
+        return Err(format!(
+            "Unknown column `{}` in filter `{}` (expected one of {})",
+            column,
+            s,
+            FILTER_COLUMNS.join(", ")
+        ));
+    }
+    let operator = tokens.next();
+    let value = match tokens.next() {
+        Some(FilterToken::Word(value) | FilterToken::Quoted(value)) => value,
+        _ => {
+            return Err(format!(
+                "Expected a value after `{}` in filter `{}`",
+                column, s
+            ))
+        }
+    };
+    match operator {
+        Some(FilterToken::Eq) => Ok(FilterExpr::Eq(column, value)),
+        Some(FilterToken::Ne) => Ok(FilterExpr::Ne(column, value)),
+        _ => Err(format!(
+            "Expected == or != after `{}` in filter `{}`",
+            column, s
+        )),
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+struct AssemblyFilter<'a> {
+    // None when there's no taxon to filter by (i.e. only --accession)
+    tax_ids: Option<HashSet<&'a str>>,
+    accessions: Option<HashSet<String>>,
+    taxonomy: TaxonomyBackend,
+    // any one of the levels matches. Other combinations can be written with --filter
+    assembly_levels: Option<Vec<String>>,
+    after_date: Option<ReleaseDate>,
+    before_date: Option<ReleaseDate>,
+    exclude_flags: Vec<String>,
+    clean_only: bool,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    exclude_mags: bool,
+    mags_only: bool,
+    type_material_only: bool,
+    paired_only: bool,
+    unpaired_only: bool,
+    // lowercased --submitter
+    submitter: Option<String>,
+    // lowercased --organism-name-contains
+    organism_names: Vec<String>,
+    include_suppressed: bool,
+    // stop at the first malformed line instead of skipping it
+    strict_parse: bool,
+    expr: Option<FilterExpr>,
+    accession_version_policy: AccessionVersionPolicy,
+    // --stream-downloads: the matches are only passed to on_match, so that they're never all held
+    // in memory
+    discard_matches: bool,
+    // --summary-columns-passthrough, kept in each match's passthrough
+    passthrough_columns: Vec<String>,
+}
+
+const FILTER_CACHE_FILENAME: &str = ".gdl-filter-cache.tsv";
+
+// identifies a filter applied to a particular version of the assembly summary. Sets are sorted
+// since their iteration order changes from run to run
+fn filter_cache_key(filter: &AssemblyFilter, summary_path: &str) -> Option<String> {
+    let (size, mtime) = file_stamp(Path::new(summary_path)).ok()?;
+    let mut tax_ids: Option<Vec<&str>> = filter
+        .tax_ids
+        .as_ref()
+        .map(|tax_ids| tax_ids.iter().copied().collect());
+    let mut accessions: Option<Vec<&String>> = filter
+        .accessions
+        .as_ref()
+        .map(|accessions| accessions.iter().collect());
+    tax_ids.iter_mut().for_each(|tax_ids| tax_ids.sort());
+    accessions
+        .iter_mut()
+        .for_each(|accessions| accessions.sort());
+    let rest = AssemblyFilter {
+        tax_ids: None,
+        accessions: None,
+        ..filter.clone()
+    };
+
+    let key = format!(
+        "{}\t{}\t{}\t{}\t{:?}\t{:?}\t{:?}",
+        env!("CARGO_PKG_VERSION"),
+        summary_path,
+        size,
+        mtime,
+        tax_ids,
+        accessions,
+        rest
+    );
+    Some(format!("{:x}", md5::compute(key)))
+}
+
+// the cache is a summary of just the matched assemblies, with the key on the first line
+fn read_filter_cache(path: &Path, key: &str) -> Option<Vec<NCBIAssembly>> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut first_line = String::new();
+    reader.read_line(&mut first_line).ok()?;
+    if first_line.trim_end().strip_prefix("# ") != Some(key) {
+        return None;
+    }
+
+    ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(reader)
+        .deserialize()
+        .collect::<Result<_, _>>()
+        .ok()
+}
+
+fn write_filter_cache(path: &Path, key: &str, assemblies: &[NCBIAssembly]) -> io::Result<()> {
+    let mut file = io::BufWriter::new(File::create(path)?);
+    writeln!(file, "# {}", key)?;
+    let mut writer = csv::WriterBuilder::new().delimiter(b'\t').from_writer(file);
+    for assembly in assemblies {
+        writer.serialize(assembly)?;
+    }
+    writer.flush()
+}
+
+// accessions without a version (e.g. GCF_000005845) match every version unless the policy is
+// exact. With latest, keep_latest_versions then drops all but the highest one
+fn matches_accession(
+    accessions: &HashSet<String>,
+    assembly: &NCBIAssembly,
+    policy: &AccessionVersionPolicy,
+) -> bool {
+    let accession = assembly.assembly_accession.as_str();
+    accessions.contains(accession)
+        || (*policy != AccessionVersionPolicy::Exact
+            && accession
+                .rsplit_once('.')
+                .is_some_and(|(unversioned, _)| accessions.contains(unversioned)))
+}
+
+// the requested accessions that none of `assemblies` matches, sorted
+fn missing_accessions<'a>(
+    requested: &'a HashSet<String>,
+    assemblies: &[NCBIAssembly],
+    policy: &AccessionVersionPolicy,
+) -> Vec<&'a str> {
+    let mut found: HashSet<&str> = HashSet::new();
+    for assembly in assemblies {
+        let accession = assembly.assembly_accession.as_str();
+        found.insert(accession);
+        if *policy != AccessionVersionPolicy::Exact {
+            found.extend(
+                accession
+                    .rsplit_once('.')
+                    .map(|(unversioned, _)| unversioned),
+            );
+        }
+    }
+    let mut missing: Vec<&str> = requested
+        .iter()
+        .map(String::as_str)
+        .filter(|accession| !found.contains(accession))
+        .collect();
+    missing.sort();
+    missing
+}
+
+// how many of the missing accessions --validate-accessions lists
+const MISSING_ACCESSIONS_SHOWN: usize = 20;
+
+// for --accession-version-policy latest: of the assemblies that only matched `accessions` by an
+// accession without a version, keep the highest version of each
+fn keep_latest_versions(assemblies: &mut Vec<NCBIAssembly>, accessions: &HashSet<String>) {
+    let mut latest: HashMap<String, u32> = HashMap::new();
+    for assembly in assemblies.iter() {
+        if accessions.contains(&assembly.assembly_accession) {
+            continue;
+        }
+        let (unversioned, version) = split_accession_version(&assembly.assembly_accession);
+        let highest = latest.entry(unversioned.to_string()).or_default();
+        *highest = (*highest).max(version);
+    }
+    assemblies.retain(|assembly| {
+        let (unversioned, version) = split_accession_version(&assembly.assembly_accession);
+        accessions.contains(&assembly.assembly_accession)
+            || latest
+                .get(unversioned)
+                .is_none_or(|&highest| version == highest)
+    });
+}
+
+// the highest version of each accession in a --manifest, keyed by the accession without its
+// version
+fn read_manifest_versions(path: &str) -> HashMap<String, u32> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(path)
+        .unwrap_or_else(|e| panic!("Unable to read manifest {} ({})", path, e));
+    let accession_column = reader
+        .headers()
+        .unwrap_or_else(|e| panic!("Unable to read manifest {} ({})", path, e))
+        .iter()
+        .position(|header| header == ManifestColumn::Accession.name())
+        .unwrap_or_else(|| panic!("Manifest {} has no accession column", path));
+
+    let mut versions = HashMap::new();
+    for record in reader.records() {
+        let record = record.unwrap_or_else(|e| panic!("Unable to read manifest {} ({})", path, e));
+        let Some(accession) = record.get(accession_column) else {
+            continue;
+        };
+        let (unversioned, version) = split_accession_version(accession);
+        let highest = versions.entry(unversioned.to_string()).or_insert(version);
+        *highest = version.max(*highest);
+    }
+    versions
+}
+
+// GCF_000005845.2 -> (GCF_000005845, 2). Accessions without a version are version 0
+fn split_accession_version(accession: &str) -> (&str, u32) {
+    accession
+        .rsplit_once('.')
+        .and_then(|(unversioned, version)| Some((unversioned, version.parse().ok()?)))
+        .unwrap_or((accession, 0))
+}
+
+// new accessions, and ones that have been updated since `versions` were downloaded
+fn is_newer_than(assembly: &NCBIAssembly, versions: &HashMap<String, u32>) -> bool {
+    let (unversioned, version) = split_accession_version(&assembly.assembly_accession);
+    versions
+        .get(unversioned)
+        .is_none_or(|&downloaded| version > downloaded)
+}
+
+// one accession per line. Blank lines and lines starting with # are skipped, and only the first
+// column is used so that e.g. a TSV of accessions can be passed as is
+fn read_accession_file(path: &str) -> Vec<String> {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Unable to read accession file {} ({})", path, e));
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_string)
+        .collect()
+}
+
+fn write_jsonl<W: Write>(writer: &mut W, assembly: &NCBIAssembly) {
+    serde_json::to_writer(&mut *writer, &AssemblyRecord::from(assembly))
+        .and_then(|_| writeln!(writer).map_err(serde_json::Error::io))
+        .expect("Unable to write assembly as JSON");
+}
+
+// the columns that NCBIAssembly needs (the first one is `assembly_accession` in some summaries)
+const REQUIRED_SUMMARY_COLUMNS: [&str; 8] = [
+    "#assembly_accession",
+    "taxid",
+    "organism_name",
+    "ftp_path",
+    "assembly_level",
+    "seq_rel_date",
+    "excluded_from_refseq",
+    "version_status",
+];
+// used when they're there
+const OPTIONAL_SUMMARY_COLUMNS: [&str; 5] = [
+    "genome_size",
+    "relation_to_type_material",
+    "infraspecific_name",
+    "gbrs_paired_asm",
+    "paired_asm_comp",
+];
+
+fn is_summary_column(header: &str, column: &str) -> bool {
+    header == column || (column == "#assembly_accession" && header == "assembly_accession")
+}
+
+// columns are matched by name, so a summary that's missing one would otherwise only fail with a
+// deserialization error on its first line
+fn check_summary_columns(headers: &csv::StringRecord, name: &str) {
+    let missing: Vec<&str> = REQUIRED_SUMMARY_COLUMNS
+        .into_iter()
+        .filter(|column| {
+            !headers
+                .iter()
+                .any(|header| is_summary_column(header, column))
+        })
+        .collect();
+    if !missing.is_empty() {
+        panic!(
+            "Assembly summary {} is missing the column(s) {} (found: {}). Run with --assembly-summary-columns for details",
+            name,
+            missing.join(", "),
+            headers.iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+}
+
+// NCBI serves the summaries as plain text, but ones cached with --assembly-summary-gz (or given
+// with --assembly-summary-path) can be gzipped. Anything that starts like gzip is decompressed
+fn decompress_summary<'a, R: Read + 'a>(reader: R) -> io::Result<BufReader<Box<dyn Read + 'a>>> {
+    let mut reader = BufReader::new(reader);
+    let summary: Box<dyn Read + 'a> = if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    Ok(BufReader::new(summary))
+}
+
+// the summary starts with comments like `##  See ftp://...README_assembly_summary.txt`, followed
+// by the header, which itself starts with a # (`#assembly_accession\ttaxid...`). Comments are told
+// apart from the header by their ## or, for a single #, by not having any tabs. Returns the
+// header and everything after it
+fn skip_summary_comments<R: BufRead>(
+    mut reader: R,
+) -> io::Result<io::Chain<io::Cursor<String>, R>> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let is_comment = line.starts_with("##") || (line.starts_with('#') && !line.contains('\t'));
+        if !is_comment && !line.trim().is_empty() {
+            break;
+        }
+    }
+    Ok(io::Cursor::new(line).chain(reader))
+}
+
+// summaries from a mirror or edited on Windows can have CRLF line endings. The comments are
+// skipped by line, whatever the ending, and the csv reader ends records at \r\n as well as \n, so
+// no \r is left at the end of the last column
+fn summary_reader<R: Read>(summary: R) -> csv::Reader<R> {
+    ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(true)
+        .terminator(csv::Terminator::CRLF)
+        .from_reader(summary)
+}
+
+// --assembly-summary-path - reads the summary from stdin
+const STDIN_PATH: &str = "-";
+
+// the summary at `path` and its size, which stdin doesn't have
+fn open_summary(path: &str) -> (Box<dyn Read>, u64) {
+    if path == STDIN_PATH {
+        return (Box::new(io::stdin().lock()), 0);
+    }
+    let file =
+        File::open(path).unwrap_or_else(|_| panic!("Unable to open assembly summary {}", path));
+    let size = file.metadata().expect("Unable to get file size").len();
+    (Box::new(file), size)
+}
+
+// --stream-downloads. The summaries are filtered on this thread and every match is sent straight
+// to `parallel` download threads, with only a few matches waiting at a time. Returns how many
+// assemblies were matched and how many files failed to download
+#[allow(clippy::too_many_arguments)]
+fn stream_downloads(
+    client: &Client,
+    summaries: &mut [Summary],
+    filter: &AssemblyFilter,
+    formats: &[AssemblyFormat],
+    out_path: &Path,
+    options: &DownloadOptions,
+    parallel: usize,
+    stream_summary: bool,
+) -> (usize, usize) {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(parallel)
+        .build()
+        .expect("Unable to build thread pool");
+    let n_failed = AtomicUsize::new(0);
+    let mut n_assemblies = 0;
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel::<NCBIAssembly>(parallel * 2);
+        let downloads = scope.spawn(|| {
+            pool.install(|| {
+                receiver.into_iter().par_bridge().for_each(|assembly| {
+                    for format in formats {
+                        if let Err(e) = fetch_assembly_file(
+                            client, &assembly, format, out_path, options, None, None,
+                        ) {
+                            eprintln!("Failed to download {}: {}", assembly.assembly_accession, e);
+                            n_failed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+        });
+
+        for summary in summaries.iter_mut() {
+            let source = summary.source;
+            let mut on_match = |assembly: &NCBIAssembly| {
+                n_assemblies += 1;
+                let assembly = NCBIAssembly {
+                    source,
+                    ..assembly.clone()
+                };
+                // only fails once the download threads are gone, which they aren't until the
+                // sender is dropped
+                let _ = sender.send(assembly);
+            };
+            if stream_summary {
+                let response = get(client, &summary.url)
+                    .unwrap_or_else(|e| panic!("Unable to fetch assembly summary: {}", e));
+                let size = response.content_length().unwrap_or(0);
+                filter_assemblies(response, size, &summary.url, filter.clone(), &mut on_match);
+            } else {
+                let (file, size) = open_summary(&summary.path);
+                let name = match summary.path.as_str() {
+                    STDIN_PATH => "stdin",
+                    path => path,
+                };
+                filter_assemblies(file, size, name, filter.clone(), &mut on_match);
+            }
+        }
+        drop(sender);
+        downloads.join().expect("Unable to download assemblies");
+    });
+
+    (n_assemblies, n_failed.into_inner())
+}
+
+// an assembly summary to filter
+struct Summary {
+    // "refseq" or "genbank", or empty for --assembly-summary-path
+    source: &'static str,
+    // e.g. refseq_historical
+    name: &'static str,
+    url: String,
+    path: String,
+    // downloading it to `path` in the background, when it isn't cached
+    download: Option<thread::JoinHandle<Option<String>>>,
+    // only known when it was fetched during this run
+    last_modified: Option<String>,
+}
+
+impl Summary {
+    fn wait_for_download(&mut self) {
+        if let Some(download) = self.download.take() {
+            self.last_modified = download
+                .join()
+                .expect("Unable to download assembly summary");
+        }
+    }
+}
+
+// where the assembly summary of `source` is kept in --cache-dir, gzipped with
+// --assembly-summary-gz
+fn cached_summary_path(cache_dir: &str, source: &SummarySource, gzipped: bool) -> String {
+    let extension = if gzipped { "txt.gz" } else { "txt" };
+    Path::new(cache_dir)
+        .join(format!("assembly_summary_{}.{}", source.name, extension))
+        .to_string_lossy()
+        .into_owned()
+}
+
+// how long ago the file was last written (i.e. downloaded), if that's longer than `max_age`
+fn age_over(path: &str, max_age: Duration) -> Option<Duration> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    SystemTime::now()
+        .duration_since(modified)
+        .ok()
+        .filter(|&age| age > max_age)
+}
+
+// for --summary-max-age-warn
+fn warn_if_summary_is_old(path: &str, max_age: Duration) {
+    if let Some(age) = age_over(path, max_age) {
+        eprintln!(
+            "Warning: the cached assembly summary {} was downloaded {} ago, more than the {} of --summary-max-age-warn. Use --no-cache or gdl update to download it again",
+            path,
+            HumanDuration(age),
+            humantime::format_duration(max_age)
+        );
+    }
+}
+
+// `gdl update`. Both are downloaded even if they're cached, and must load (or, for the summary,
+// have the columns gdl needs) afterwards
+fn update(args: &Args) {
+    if args.offline {
+        panic!("--offline can't be combined with `gdl update`, which downloads everything again");
+    }
+    let client = build_client(args.email.as_deref());
+    let progress = MultiProgress::new();
+
+    let tax = match args.taxonomy {
+        TaxonomyBackend::Ncbi => {
+            let taxdump_url = args
+                .taxdump_url
+                .as_deref()
+                .unwrap_or(args.taxdump_format.url());
+            download_taxdump(
+                &client,
+                taxdump_url,
+                &args.taxdump_path,
+                &args.taxdump_format,
+                &progress,
+            );
+            load_taxonomy(&args.taxdump_path).unwrap_or_else(|e| panic!("{}", e))
+        }
+        TaxonomyBackend::Gtdb => {
+            download_gtdb_taxonomy(&client, &args.gtdb_path, &progress);
+            load_gtdb_taxonomy(&args.gtdb_path)
+        }
+    };
+    eprintln!("Updated the taxonomy ({} taxa)", tax.names.len());
+
+    // a summary given with --assembly-summary-path isn't gdl's to replace
+    if args.assembly_summary_path.is_some() || matches!(args.source, AssemblySource::None) {
+        return;
+    }
+    for source in args.source.summaries(args.include_historical) {
+        let path = cached_summary_path(&args.cache_dir, &source, args.assembly_summary_gz);
+        let url = args.assembly_summary_url.as_deref().unwrap_or(source.url);
+        download_assembly_summary(&client, url, &path, &progress);
+
+        let file = File::open(&path)
+            .unwrap_or_else(|_| panic!("Unable to open assembly summary {}", path));
+        let summary = decompress_summary(file)
+            .and_then(skip_summary_comments)
+            .unwrap_or_else(|e| panic!("Unable to read assembly summary {} ({})", path, e));
+        let mut reader = summary_reader(summary);
+        let headers = reader
+            .headers()
+            .unwrap_or_else(|e| panic!("Unable to read the header of {} ({})", path, e));
+        check_summary_columns(headers, &path);
+        eprintln!("Updated {}", path);
+    }
+}
+
+// the columns that `gdl stats` counts the values of, in the order they're printed
+const STATS_COLUMNS: [&str; 3] = ["assembly_level", "refseq_category", "group"];
+
+// (column, value, number of assemblies) for each of STATS_COLUMNS, most assemblies first within
+// each column. Values that are missing (older summaries don't have a group) are counted as na
+fn summary_stats(
+    assemblies: impl IntoIterator<Item = NCBIAssembly>,
+) -> Vec<(&'static str, String, usize)> {
+    let mut counts: Vec<HashMap<String, usize>> = vec![HashMap::new(); STATS_COLUMNS.len()];
+    for assembly in assemblies {
+        let values = [
+            &assembly.assembly_level,
+            &assembly.refseq_category,
+            &assembly.group,
+        ];
+        for (counts, value) in counts.iter_mut().zip(values) {
+            let value = if value.is_empty() { "na" } else { value };
+            *counts.entry(value.to_string()).or_default() += 1;
+        }
+    }
+
+    let mut rows = Vec::new();
+    for (column, counts) in STATS_COLUMNS.into_iter().zip(counts) {
+        let mut column_rows: Vec<(&str, String, usize)> = counts
+            .into_iter()
+            .map(|(value, count)| (column, value, count))
+            .collect();
+        column_rows.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.1.cmp(&b.1)));
+        rows.extend(column_rows);
+    }
+    rows
+}
+
+// `gdl stats`. Uses the cached summaries (or --assembly-summary-path) and only downloads the ones
+// that aren't cached yet. Counts are over every summary of --source together
+fn stats(args: &Args) {
+    let paths: Vec<String> = match (&args.assembly_summary_path, &args.source) {
+        (Some(path), AssemblySource::None) => vec![path.clone()],
+        (None, _) => {
+            let client = build_client(args.email.as_deref());
+            let progress = MultiProgress::new();
+            args.source
+                .summaries(args.include_historical)
+                .into_iter()
+                .map(|source| {
+                    let path =
+                        cached_summary_path(&args.cache_dir, &source, args.assembly_summary_gz);
+                    if args.no_cache || !Path::new(&path).exists() {
+                        if args.offline {
+                            offline_missing("the assembly summary", &path);
+                        }
+                        let url = args.assembly_summary_url.as_deref().unwrap_or(source.url);
+                        download_assembly_summary(&client, url, &path, &progress);
+                    }
+                    path
+                })
+                .collect()
+        }
+        _ => panic!("--source and --assembly-summary-path are mutually exclusive"),
+    };
+
+    let mut n_malformed = 0;
+    let mut readers = Vec::new();
+    for path in &paths {
+        let (file, _) = open_summary(path);
+        let summary = decompress_summary(file)
+            .and_then(skip_summary_comments)
+            .unwrap_or_else(|e| panic!("Unable to read assembly summary {} ({})", path, e));
+        let mut reader = summary_reader(summary);
+        let headers = reader
+            .headers()
+            .unwrap_or_else(|e| panic!("Unable to read the header of {} ({})", path, e));
+        check_summary_columns(headers, path);
+        readers.push(reader);
+    }
+    let assemblies = readers
+        .iter_mut()
+        .flat_map(|reader| reader.deserialize::<NCBIAssembly>())
+        .filter_map(|result| result.map_err(|_| n_malformed += 1).ok());
+    let rows = summary_stats(assemblies);
+
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "column\tvalue\tassemblies")
+        .and_then(|_| {
+            rows.into_iter().try_for_each(|(column, value, count)| {
+                writeln!(stdout, "{}\t{}\t{}", column, value, count)
+            })
+        })
+        .expect("Unable to write stats");
+    if n_malformed > 0 {
+        eprintln!("Warning: skipped {} malformed lines", n_malformed);
+    }
+}
+
+// the options of Args that the taxonomy is downloaded and loaded with. They're copied so that the
+// taxonomy can be loaded lazily while run() goes on using (and taking apart) Args
+struct TaxonomyOptions {
+    backend: TaxonomyBackend,
+    // --taxdump-path or --gtdb-path
+    path: String,
+    taxdump_url: String,
+    taxdump_format: TaxdumpFormat,
+    taxdump_archive: Option<String>,
+    no_cache: bool,
+    refresh_taxdump_if_stale: bool,
+    offline: bool,
+}
+
+impl From<&Args> for TaxonomyOptions {
+    fn from(args: &Args) -> Self {
+        TaxonomyOptions {
+            backend: args.taxonomy.clone(),
+            path: match args.taxonomy {
+                TaxonomyBackend::Ncbi => args.taxdump_path.clone(),
+                TaxonomyBackend::Gtdb => args.gtdb_path.clone(),
+            },
+            taxdump_url: args
+                .taxdump_url
+                .clone()
+                .unwrap_or_else(|| args.taxdump_format.url().to_string()),
+            taxdump_format: args.taxdump_format.clone(),
+            taxdump_archive: args.taxdump_archive.clone(),
+            no_cache: args.no_cache,
+            refresh_taxdump_if_stale: args.refresh_taxdump_if_stale,
+            offline: args.offline,
+        }
+    }
+}
+
+// --offline can't download what isn't there, e.g. `what` is "the taxdump"
+fn offline_missing(what: &str, path: &str) -> ! {
+    panic!(
+        "--offline: {} isn't at {} (run gdl once without --offline to download it)",
+        what, path
+    )
+}
+
+// downloads the taxonomy of --taxonomy unless it's cached (extracting a taxdump archive that's
+// already there instead, when there is one), then loads it
+fn load_or_download_taxonomy(
+    options: &TaxonomyOptions,
+    client: &Client,
+    progress: &MultiProgress,
+) -> GeneralTaxonomy {
+    let taxonomy_path = &options.path;
+    let taxdump_url = &options.taxdump_url;
+    let cached = !options.no_cache && Path::new(taxonomy_path).exists();
+    // whether the taxdump was already there, in which case it's downloaded again if it can't be
+    // loaded
+    let mut reused_taxdump = false;
+    match options.backend {
+        TaxonomyBackend::Ncbi => {
+            // a taxdump left behind by an interrupted extraction is downloaded again
+            let mut valid = cached
+                && check_taxdump(taxonomy_path, &options.taxdump_format)
+                    .map_err(|e| eprintln!("Warning: {}, downloading it again", e))
+                    .is_ok();
+            let stale =
+                valid && !options.offline && taxdump_is_stale(client, taxdump_url, taxonomy_path);
+            if stale && options.refresh_taxdump_if_stale {
+                eprintln!(
+                    "NCBI has published a newer taxdump than the one in {}, downloading it again",
+                    taxonomy_path
+                );
+                valid = false;
+            } else if stale {
+                eprintln!(
+                    "Warning: NCBI has published a newer taxdump than the one in {} (use --refresh-taxdump-if-stale to download it again)",
+                    taxonomy_path
+                );
+            }
+            // an archive that's already on disk (e.g. staged ahead of time on a cluster) is
+            // extracted rather than downloading the taxdump again
+            let archive = options.taxdump_archive.clone().or_else(|| {
+                let archive_path = taxdump_archive_path(taxonomy_path);
+                (!options.no_cache && Path::new(&archive_path).exists()).then_some(archive_path)
+            });
+            // a leftover archive is no newer than the stale taxdump
+            let extracted = !valid
+                && !stale
+                && archive.is_some_and(|archive| {
+                    match extract_taxdump(&archive, taxonomy_path, progress)
+                        .and_then(|_| check_taxdump(taxonomy_path, &options.taxdump_format))
+                    {
+                        Ok(()) => true,
+                        Err(e) if options.taxdump_archive.is_some() => {
+                            panic!("Unable to use taxdump archive {}: {}", archive, e)
+                        }
+                        Err(e) => {
+                            eprintln!("Warning: {}, downloading the taxdump again", e);
+                            false
+                        }
+                    }
+                });
+            reused_taxdump = valid;
+            if !valid && !extracted && options.offline {
+                offline_missing("the taxdump", taxonomy_path);
+            }
+            if !valid && !extracted {
+                download_taxdump(
+                    client,
+                    taxdump_url,
+                    taxonomy_path,
+                    &options.taxdump_format,
+                    progress,
+                );
+            }
+        }
+        TaxonomyBackend::Gtdb if !cached && options.offline => {
+            offline_missing("the GTDB taxonomy", taxonomy_path)
+        }
+        TaxonomyBackend::Gtdb if !cached => download_gtdb_taxonomy(client, taxonomy_path, progress),
+        TaxonomyBackend::Gtdb => {}
+    }
+
+    let pb = progress.add(ProgressBar::new(0));
+    pb.set_style(ProgressStyle::with_template(PB_SPINNER_TEMPLATE).unwrap());
+    pb.set_message(format!("Loading taxonomy from {}", taxonomy_path));
+
+    // Spawn a separate thread to tick the spinner
+    let pb_clone = pb.clone();
+    thread::spawn(move || {
+        while !pb_clone.is_finished() {
+            pb_clone.tick();
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+
+    let tax = match options.backend {
+        TaxonomyBackend::Ncbi => load_taxonomy(taxonomy_path).unwrap_or_else(|e| {
+            if !reused_taxdump {
+                panic!("{}", e);
+            }
+            pb.println(format!(
+                "Warning: {}. The cached taxdump looks corrupted, downloading it again",
+                e
+            ));
+            download_taxdump(
+                client,
+                taxdump_url,
+                taxonomy_path,
+                &options.taxdump_format,
+                progress,
+            );
+            load_taxonomy(taxonomy_path).unwrap_or_else(|e| panic!("{}", e))
+        }),
+        TaxonomyBackend::Gtdb => load_gtdb_taxonomy(taxonomy_path),
+    };
+    pb.finish_with_message(format!("Loaded {} taxa", tax.names.len()));
+    tax
+}
+
+// --format and the formats of --bundle, or just fna
+fn requested_formats(format: &[AssemblyFormat], bundle: Option<&Bundle>) -> Vec<AssemblyFormat> {
+    let mut formats = format.to_vec();
+    for format in bundle.into_iter().flat_map(Bundle::formats) {
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    if formats.is_empty() {
+        formats.push(AssemblyFormat::Fna);
+    }
+    formats
+}
+
+// the filters of `args` that are in use, as they were given
+fn active_filters(args: &Args) -> Vec<String> {
+    let date = |date: &ReleaseDate| format!("{}/{:02}/{:02}", date.year, date.month, date.day);
+    let flags = [
+        (args.no_children, "--no-children"),
+        (args.species_exact, "--species-exact"),
+        (args.clean_only, "--clean-only"),
+        (args.exclude_mags, "--exclude-mags"),
+        (args.mags_only, "--mags-only"),
+        (args.include_suppressed, "--include-suppressed"),
+        (args.type_material_only, "--type-material-only"),
+        (args.paired_only, "--paired-only"),
+        (args.unpaired_only, "--unpaired-only"),
+        (args.unique_taxid, "--unique-taxid"),
+        (args.only_missing, "--only-missing"),
+        (args.assembly_level_priority, "--assembly-level-priority"),
+    ];
+    let options = [
+        ("--tax-name-glob", args.tax_name_glob.clone()),
+        ("--tax-name-regex", args.tax_name_regex.clone()),
+        (
+            "--include-rank-below",
+            args.include_rank_below
+                .map(|rank| rank.to_ncbi_rank().to_string()),
+        ),
+        (
+            "--include-rank-above",
+            args.include_rank_above
+                .map(|rank| rank.to_ncbi_rank().to_string()),
+        ),
+        (
+            "--accession",
+            (!args.accession.is_empty()).then(|| args.accession.join(",")),
+        ),
+        ("--accession-file", args.accession_file.clone()),
+        (
+            "--include-accession-file",
+            args.include_accession_file.clone(),
+        ),
+        (
+            "--exclude-accession-file",
+            args.exclude_accession_file.clone(),
+        ),
+        ("--since-accession", args.since_accession.clone()),
+        (
+            "--assembly-level",
+            args.assembly_level.as_ref().map(|levels| levels.join(",")),
+        ),
+        ("--after-date", args.after_date.as_ref().map(date)),
+        ("--before-date", args.before_date.as_ref().map(date)),
+        (
+            "--exclude-flag",
+            (!args.exclude_flag.is_empty()).then(|| args.exclude_flag.join(",")),
+        ),
+        (
+            "--filter",
+            args.filter.as_ref().map(|expr| format!("{:?}", expr)),
+        ),
+        ("--submitter", args.submitter.clone()),
+        (
+            "--organism-name-contains",
+            (!args.organism_name_contains.is_empty())
+                .then(|| args.organism_name_contains.join(",")),
+        ),
+        ("--min-size", args.min_size.map(|size| size.to_string())),
+        ("--max-size", args.max_size.map(|size| size.to_string())),
+        (
+            "--max-per-taxid",
+            args.max_per_taxid.map(|max| max.to_string()),
+        ),
+        ("--limit", args.limit.map(|limit| limit.to_string())),
+    ];
+
+    flags
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| flag.to_string())
+        .chain(
+            options
+                .into_iter()
+                .filter_map(|(option, value)| value.map(|value| format!("{} {}", option, value))),
+        )
+        .collect()
+}
+
+// --print-plan. Only looks at what's already on disk (and asks NCBI whether there's a newer
+// taxdump), so nothing is downloaded. The taxon is only resolved when the taxonomy is cached
+fn print_plan(args: &Args) {
+    let mut plan: Vec<(&str, String)> = Vec::new();
+    let cached = |path: &str| !args.no_cache && Path::new(path).exists();
+
+    plan.push(("source", format!("{:?}", args.source).to_lowercase()));
+    match &args.assembly_summary_path {
+        Some(path) => plan.push(("summary", format!("{} (--assembly-summary-path)", path))),
+        None => {
+            for source in args.source.summaries(args.include_historical) {
+                let url = args.assembly_summary_url.as_deref().unwrap_or(source.url);
+                let path = cached_summary_path(&args.cache_dir, &source, args.assembly_summary_gz);
+                let summary = if args.stream_summary {
+                    format!("streamed from {}, not cached", url)
+                } else if cached(&path) {
+                    format!(
+                        "{} (cached, modified {})",
+                        path,
+                        modified_at(&path).unwrap_or_default()
+                    )
+                } else {
+                    format!("{} (will be downloaded from {})", path, url)
+                };
+                plan.push(("summary", summary));
+            }
+        }
+    }
+
+    let taxonomy_options = TaxonomyOptions::from(args);
+    let taxonomy_path = taxonomy_options.path.as_str();
+    let taxonomy_cached = cached(taxonomy_path);
+    let taxonomy = if !taxonomy_cached {
+        let url = match args.taxonomy {
+            TaxonomyBackend::Ncbi => taxonomy_options.taxdump_url.as_str(),
+            TaxonomyBackend::Gtdb => "GTDB",
+        };
+        format!(
+            "{} (will be downloaded from {} when needed)",
+            taxonomy_path, url
+        )
+    } else {
+        let stale = args.taxonomy == TaxonomyBackend::Ncbi
+            && !args.offline
+            && taxdump_is_stale(
+                &build_client(args.email.as_deref()),
+                &taxonomy_options.taxdump_url,
+                taxonomy_path,
+            );
+        format!(
+            "{} (cached, modified {}{})",
+            taxonomy_path,
+            modified_at(taxonomy_path).unwrap_or_default(),
+            if stale {
+                ", NCBI has published a newer one"
+            } else {
+                ""
+            }
+        )
+    };
+    plan.push((
+        "taxonomy",
+        format!(
+            "{}, {}",
+            format!("{:?}", args.taxonomy).to_lowercase(),
+            taxonomy
+        ),
+    ));
+
+    let requested_taxa = args
+        .tax_id
+        .iter()
+        .map(|tax_id| (Some(tax_id.as_str()), None))
+        .chain(
+            args.tax_name
+                .iter()
+                .map(|tax_name| (None, Some(tax_name.as_str()))),
+        );
+    for (requested_tax_id, requested_tax_name) in requested_taxa {
+        let given = given_taxon(requested_tax_id, requested_tax_name);
+        let taxon = if taxonomy_cached {
+            let tax = match args.taxonomy {
+                TaxonomyBackend::Ncbi => {
+                    load_taxonomy(taxonomy_path).unwrap_or_else(|e| panic!("{}", e))
+                }
+                TaxonomyBackend::Gtdb => load_gtdb_taxonomy(taxonomy_path),
+            };
+            let taxdump_path = (args.taxonomy == TaxonomyBackend::Ncbi).then_some(taxonomy_path);
+            match get_tax_id(
+                requested_tax_id,
+                requested_tax_name,
+                &tax,
+                taxdump_path,
+                &args.name_class,
+            ) {
+                Ok(tax_id) => {
+                    let exact = args.species_exact
+                        || (args.no_children && !is_species_or_below(&tax, tax_id));
+                    let n_taxa = taxon_and_descendants(&tax, tax_id, exact)
+                        .into_iter()
+                        .filter(|tax_id| {
+                            in_rank_window(
+                                &tax,
+                                tax_id,
+                                args.include_rank_below,
+                                args.include_rank_above,
+                            )
+                        })
+                        .count();
+                    format!(
+                        "{} ({}, {}), {} taxa including descendants",
+                        tax_id,
+                        tax.name(tax_id).unwrap_or(tax_id),
+                        tax.rank(tax_id)
+                            .map(|rank| rank.to_ncbi_rank())
+                            .unwrap_or("unknown"),
+                        n_taxa
+                    )
+                }
+                Err(e) if args.continue_on_taxon_error => {
+                    format!("{} ({}, skipped with --continue-on-taxon-error)", given, e)
+                }
+                Err(e) => format!("{} ({})", given, e),
+            }
+        } else {
+            format!("{} (resolved once the taxonomy is downloaded)", given)
+        };
+        plan.push(("taxon", taxon));
+    }
+
+    let filters = active_filters(args);
+    plan.push((
+        "filters",
+        match filters.is_empty() {
+            true => "none".to_string(),
+            false => filters.join("; "),
+        },
+    ));
+
+    let formats = requested_formats(&args.format, args.bundle.as_ref());
+    let names = |formats: &[AssemblyFormat]| {
+        formats
+            .iter()
+            .map(AssemblyFormat::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    plan.push((
+        "formats",
+        match args.format_fallback.is_empty() {
+            true => names(&formats),
+            false => format!(
+                "{} (falling back to {})",
+                names(&formats),
+                names(&args.format_fallback)
+            ),
+        },
+    ));
+    let naming = if args.preserve_directory_structure {
+        "NCBI's directory structure"
+    } else if args.flat_filenames {
+        "files named after the accession"
+    } else {
+        "NCBI's filenames"
+    };
+    let mut layout = vec![match &args.filename_template {
+        Some(template) => format!("files named after {}", template),
+        None => naming.to_string(),
+    }];
+    if formats.len() > 1
+        && !args.no_format_subdirs
+        && !args.preserve_directory_structure
+        && args.backend != Backend::Datasets
+    {
+        layout.push("a directory per format".to_string());
+    }
+    if args.source_subdirs {
+        layout.push("a directory per source".to_string());
+    }
+    if let Some(shard_size) = args.shard_size {
+        layout.push(format!(
+            "at most {} assemblies per numbered directory",
+            shard_size
+        ));
+    }
+    if args.bgzip {
+        layout.push("bgzipped".to_string());
+    }
+    if let Some(recompression) = &args.recompress {
+        layout.push(format!("recompressed with {:?}", recompression).to_lowercase());
+    }
+    if args.both {
+        layout.push("with decompressed copies".to_string());
+    }
+    plan.push((
+        "output",
+        format!(
+            "{} ({})",
+            args.out_dir.as_deref().unwrap_or("."),
+            layout.join(", ")
+        ),
+    ));
+    for (name, path) in [
+        ("tar", &args.tar),
+        ("manifest", &args.manifest),
+        ("state", &args.state),
+        ("name map", &args.name_map),
+        ("seqid2taxid", &args.seqid2taxid),
+        ("provenance", &args.provenance),
+    ] {
+        if let Some(path) = path {
+            plan.push((name, path.clone()));
+        }
+    }
+
+    plan.push((
+        "backend",
+        match args.backend {
+            Backend::Datasets => format!("datasets ({})", args.datasets_url),
+            _ => "ftp".to_string(),
+        },
+    ));
+    plan.push((
+        "concurrency",
+        format!(
+            "{} at once, {} retries{}{}",
+            match args.parallel {
+                1 => "1 download".to_string(),
+                parallel => format!("{} downloads", parallel),
+            },
+            args.retries,
+            match args.chunked {
+                true => format!(
+                    ", files over {} in {} chunks",
+                    HumanBytes(args.chunked_threshold),
+                    args.chunked_connections
+                ),
+                false => String::new(),
+            },
+            match args.max_bandwidth {
+                Some(bandwidth) => format!(", at most {}/s", HumanBytes(bandwidth)),
+                None => String::new(),
+            }
+        ),
+    ));
+
+    let mut stdout = io::stdout().lock();
+    plan.into_iter()
+        .try_for_each(|(name, value)| writeln!(stdout, "{}: {}", name, value))
+        .expect("Unable to write the plan");
+}
+
+// --version-info: everything about where the data comes from that a bug report needs, as
+// `name: value` lines like --print-plan. Only the HEAD requests for the Last-Modified go to NCBI
+fn print_version_info(args: &Args) {
+    let mut info: Vec<(String, String)> = Vec::new();
+    let mut push = |name: &str, value: String| info.push((name.to_string(), value));
+    let cached = |path: &str| !args.no_cache && Path::new(path).exists();
+    let client = build_client(args.email.as_deref());
+    let published = |url: &str| match args.offline {
+        true => "not checked (--offline)".to_string(),
+        false => remote_last_modified(&client, url).unwrap_or_else(|| "unknown".to_string()),
+    };
+
+    push("version", env!("CARGO_PKG_VERSION").to_string());
+
+    let taxonomy_options = TaxonomyOptions::from(args);
+    let taxonomy_path = taxonomy_options.path.as_str();
+    push("taxonomy", format!("{:?}", args.taxonomy).to_lowercase());
+    push("taxonomy_path", taxonomy_path.to_string());
+    match args.taxonomy {
+        TaxonomyBackend::Ncbi => {
+            let url = taxonomy_options.taxdump_url.as_str();
+            push("taxdump_url", url.to_string());
+            push(
+                "taxdump_extracted",
+                match cached(taxonomy_path) {
+                    true => taxdump_extracted_stamp(taxonomy_path)
+                        .to_str()
+                        .and_then(modified_at)
+                        .unwrap_or_default(),
+                    false => "not extracted".to_string(),
+                },
+            );
+            push("taxdump_last_modified", published(url));
+        }
+        TaxonomyBackend::Gtdb => {
+            push("gtdb_urls", GTDB_TAXONOMY_URLS.join(", "));
+            push(
+                "gtdb_modified",
+                match cached(taxonomy_path) {
+                    true => modified_at(taxonomy_path).unwrap_or_default(),
+                    false => "not downloaded".to_string(),
+                },
+            );
+        }
+    }
+
+    // (name, url, path) of each summary
+    let summaries: Vec<(&str, Option<&str>, String)> = match &args.assembly_summary_path {
+        Some(path) => vec![("summary", None, path.clone())],
+        None => args
+            .source
+            .summaries(args.include_historical)
+            .into_iter()
+            .map(|source| {
+                (
+                    source.name,
+                    Some(args.assembly_summary_url.as_deref().unwrap_or(source.url)),
+                    cached_summary_path(&args.cache_dir, &source, args.assembly_summary_gz),
+                )
+            })
+            .collect(),
+    };
+    for (name, url, path) in summaries {
+        if let Some(url) = url {
+            push(&format!("{}_url", name), url.to_string());
+        }
+        push(&format!("{}_path", name), path.clone());
+        // stdin isn't read, since it can only be read once
+        let on_disk = path != STDIN_PATH
+            && Path::new(&path).exists()
+            && (args.assembly_summary_path.is_some() || !args.no_cache);
+        if on_disk {
+            push(
+                &format!("{}_modified", name),
+                modified_at(&path).unwrap_or_default(),
+            );
+            push(
+                &format!("{}_rows", name),
+                count_summary_rows(&path).to_string(),
+            );
+        } else {
+            push(&format!("{}_modified", name), "not cached".to_string());
+        }
+        if let Some(url) = url {
+            push(&format!("{}_last_modified", name), published(url));
+        }
+    }
+
+    if args.backend == Backend::Datasets {
+        push("datasets_url", args.datasets_url.clone());
+    }
+
+    let mut stdout = io::stdout().lock();
+    info.into_iter()
+        .try_for_each(|(name, value)| writeln!(stdout, "{}: {}", name, value))
+        .expect("Unable to write the version info");
+}
+
+// the assemblies in a summary, malformed lines included
+fn count_summary_rows(path: &str) -> usize {
+    let (file, _) = open_summary(path);
+    let summary = decompress_summary(file)
+        .and_then(skip_summary_comments)
+        .unwrap_or_else(|e| panic!("Unable to read assembly summary {} ({})", path, e));
+    summary_reader(summary).records().count()
+}
+
+fn print_summary_columns(path: &str) {
+    let (file, _) = open_summary(path);
+    let summary = decompress_summary(file)
+        .and_then(skip_summary_comments)
+        .unwrap_or_else(|e| panic!("Unable to read assembly summary {} ({})", path, e));
+    let headers = summary_reader(summary)
+        .headers()
+        .unwrap_or_else(|e| panic!("Unable to read the header of {} ({})", path, e))
+        .clone();
+
+    let mut stdout = io::stdout().lock();
+    for (index, header) in headers.iter().enumerate() {
+        let used = if REQUIRED_SUMMARY_COLUMNS
+            .iter()
+            .any(|column| is_summary_column(header, column))
+        {
+            "required"
+        } else if OPTIONAL_SUMMARY_COLUMNS.contains(&header) {
+            "optional"
+        } else {
+            "unused"
+        };
+        writeln!(stdout, "{}\t{}\t{}", index, header, used).expect("Unable to write columns");
+    }
+    for column in REQUIRED_SUMMARY_COLUMNS {
+        if !headers
+            .iter()
+            .any(|header| is_summary_column(header, column))
+        {
+            writeln!(stdout, "-\t{}\tmissing", column).expect("Unable to write columns");
+        }
+    }
+}
+
+// only the first few malformed lines are shown, the rest are just counted
+const MALFORMED_LINES_SHOWN: usize = 5;
+
+// how many summary lines go by between updates of the counts shown while filtering
+const FILTER_PROGRESS_EVERY: u64 = 1000;
+
+// the filters of filter_assemblies, in the order they're applied, for the funnel it reports
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilterStage {
+    Taxon,
+    Accession,
+    AssemblyLevel,
+    Expr,
+    Submitter,
+    OrganismName,
+    Suppressed,
+    ReleaseDate,
+    GenomeSize,
+    ExclusionReasons,
+    TypeMaterial,
+    Metagenome,
+    Pairing,
+}
+
+const FILTER_STAGES: [FilterStage; 13] = [
+    FilterStage::Taxon,
+    FilterStage::Accession,
+    FilterStage::AssemblyLevel,
+    FilterStage::Expr,
+    FilterStage::Submitter,
+    FilterStage::OrganismName,
+    FilterStage::Suppressed,
+    FilterStage::ReleaseDate,
+    FilterStage::GenomeSize,
+    FilterStage::ExclusionReasons,
+    FilterStage::TypeMaterial,
+    FilterStage::Metagenome,
+    FilterStage::Pairing,
+];
+
+impl FilterStage {
+    // what the assemblies that made it through the stage are, e.g. "8000 at the assembly level"
+    fn description(&self) -> &'static str {
+        match self {
+            FilterStage::Taxon => "in the taxon",
+            FilterStage::Accession => "with a requested accession",
+            FilterStage::AssemblyLevel => "at the assembly level",
+            FilterStage::Expr => "matching --filter",
+            FilterStage::Submitter => "from the submitter",
+            FilterStage::OrganismName => "with a matching organism name",
+            FilterStage::Suppressed => "not suppressed",
+            FilterStage::ReleaseDate => "released in the date range",
+            FilterStage::GenomeSize => "in the size range",
+            FilterStage::ExclusionReasons => "without excluded reasons",
+            FilterStage::TypeMaterial => "from type material",
+            FilterStage::Metagenome => "through the MAG filter",
+            FilterStage::Pairing => "through the pairing filter",
+        }
+    }
+
+    fn is_active(&self, filter: &AssemblyFilter) -> bool {
+        match self {
+            FilterStage::Taxon => filter.tax_ids.is_some(),
+            FilterStage::Accession => filter.accessions.is_some(),
+            FilterStage::AssemblyLevel => filter.assembly_levels.is_some(),
+            FilterStage::Expr => filter.expr.is_some(),
+            FilterStage::Submitter => filter.submitter.is_some(),
+            FilterStage::OrganismName => !filter.organism_names.is_empty(),
+            FilterStage::Suppressed => !filter.include_suppressed,
+            FilterStage::ReleaseDate => filter.after_date.is_some() || filter.before_date.is_some(),
+            FilterStage::GenomeSize => filter.min_size.is_some() || filter.max_size.is_some(),
+            FilterStage::ExclusionReasons => filter.clean_only || !filter.exclude_flags.is_empty(),
+            FilterStage::TypeMaterial => filter.type_material_only,
+            FilterStage::Metagenome => filter.exclude_mags || filter.mags_only,
+            FilterStage::Pairing => filter.paired_only || filter.unpaired_only,
+        }
+    }
+}
+
+// how many of `n_assemblies` were left after each of the filters in use, given how many each
+// stage removed (indexed like FILTER_STAGES)
+fn filter_funnel(
+    n_assemblies: usize,
+    n_removed: &[usize; FILTER_STAGES.len()],
+    filter: &AssemblyFilter,
+) -> Vec<(FilterStage, usize)> {
+    let mut remaining = n_assemblies;
+    FILTER_STAGES
+        .iter()
+        .zip(n_removed)
+        .filter_map(|(stage, n_removed)| {
+            remaining -= n_removed;
+            stage.is_active(filter).then_some((*stage, remaining))
+        })
+        .collect()
+}
+
+// `assembly_summary` is either a local file or an HTTP response body when --stream-summary is
+// used, `size` is its length in bytes (0 if unknown) and `name` is only used for display.
+// `on_match` is called with each assembly as soon as it passes the filter.
+fn filter_assemblies<R: Read>(
+    assembly_summary: R,
+    size: u64,
+    name: &str,
+    filter: AssemblyFilter,
+    on_match: &mut dyn FnMut(&NCBIAssembly),
+) -> Vec<NCBIAssembly> {
+    let pb = ProgressBar::new(size);
+    // without a size (stdin, or a streamed summary without a Content-Length) there's nothing to
+    // show the progress against
+    pb.set_style(match size {
+        0 => ProgressStyle::with_template(PB_SPINNER_TEMPLATE).unwrap(),
+        _ => ProgressStyle::with_template(PB_PROGRESS_TEMPLATE)
+            .unwrap()
+            .progress_chars(PROGRESS_CHARS),
+    });
+    pb.set_message(format!("Filtering {}", name));
+
+    // the progress is in bytes of the summary as it's stored, before decompressing
+    let summary = decompress_summary(pb.wrap_read(assembly_summary))
+        .and_then(skip_summary_comments)
+        .unwrap_or_else(|e| panic!("Unable to read assembly summary {} ({})", name, e));
+
+    let mut reader = summary_reader(summary);
+    let headers = reader
+        .headers()
+        .unwrap_or_else(|e| panic!("Unable to read the header of {} ({})", name, e))
+        .clone();
+    check_summary_columns(&headers, name);
+
+    // a column the summary doesn't have is left empty, since older summaries lack a few. The
+    // first column can be named with or without its #
+    let passthrough_columns: Vec<Option<usize>> = filter
+        .passthrough_columns
+        .iter()
+        .map(|column| {
+            let index = headers.iter().position(|header| {
+                header.trim_start_matches('#') == column.trim_start_matches('#')
+            });
+            if index.is_none() {
+                pb.println(format!("Warning: {} has no {} column", name, column));
+            }
+            index
+        })
+        .collect();
+
+    let mut assemblies: Vec<NCBIAssembly> = Vec::new();
+    let mut n_bad_dates = 0;
+
+    let mut n_missing_sizes = 0;
+    let mut n_suppressed = 0;
+    let mut n_malformed = 0;
+    let mut n_scanned: u64 = 0;
+    // by FILTER_STAGES
+    let mut n_removed = [0; FILTER_STAGES.len()];
+    let mut remove = |stage: FilterStage| n_removed[stage as usize] += 1;
+
+    let filter_dates = filter.after_date.is_some() || filter.before_date.is_some();
+    let filter_sizes = filter.min_size.is_some() || filter.max_size.is_some();
+    let mut n_matched = 0;
+
+    for result in reader.records() {
+        n_scanned += 1;
+        if n_scanned.is_multiple_of(FILTER_PROGRESS_EVERY) {
+            pb.set_message(format!(
+                "Filtering {}: scanned {} / matched {}",
+                name, n_scanned, n_matched
+            ));
+        }
+
+        let parsed = result
+            .and_then(|record| Ok((record.deserialize::<NCBIAssembly>(Some(&headers))?, record)));
+        let (mut assembly, record) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) if filter.strict_parse => {
+                panic!("Unable to parse assembly summary {} ({})", name, e)
+            }
+            Err(e) => {
+                n_malformed += 1;
+                if n_malformed <= MALFORMED_LINES_SHOWN {
+                    pb.println(format!(
+                        "Warning: skipping malformed line in {} ({})",
+                        name, e
+                    ));
+                }
+                continue;
+            }
+        };
+
+        let taxon_key = match filter.taxonomy {
+            TaxonomyBackend::Ncbi => assembly.taxid.as_str(),
+            TaxonomyBackend::Gtdb => gtdb_accession_key(&assembly.assembly_accession),
+        };
+
+        if !filter
+            .tax_ids
+            .as_ref()
+            .is_none_or(|tax_ids| tax_ids.contains(taxon_key))
+        {
+            remove(FilterStage::Taxon);
+            continue;
+        }
+
+        if !filter.accessions.as_ref().is_none_or(|accessions| {
+            matches_accession(accessions, &assembly, &filter.accession_version_policy)
+        }) {
+            remove(FilterStage::Accession);
+            continue;
+        }
+
+        if !filter
+            .assembly_levels
+            .as_ref()
+            .is_none_or(|levels| levels.contains(&assembly.assembly_level))
+        {
+            remove(FilterStage::AssemblyLevel);
+            continue;
+        }
+
+        if !filter
+            .expr
+            .as_ref()
+            .is_none_or(|expr| expr.matches(&assembly))
+        {
+            remove(FilterStage::Expr);
+            continue;
+        }
+
+        if filter
+            .submitter
+            .as_ref()
+            .is_some_and(|submitter| !assembly.submitter.to_lowercase().contains(submitter))
+        {
+            remove(FilterStage::Submitter);
+            continue;
+        }
+
+        if !filter.organism_names.is_empty() && !matches_organism_name(&assembly, &filter) {
+            remove(FilterStage::OrganismName);
+            continue;
+        }
+
+        if !filter.include_suppressed && assembly.is_suppressed() {
+            n_suppressed += 1;
+            remove(FilterStage::Suppressed);
+            continue;
+        }
+
+        if filter_dates {
+            let Ok(release_date) = parse_release_date(&assembly.seq_rel_date) else {
+                n_bad_dates += 1;
+                remove(FilterStage::ReleaseDate);
+                continue;
+            };
+            if filter.after_date.is_some_and(|after| release_date < after)
+                || filter
+                    .before_date
+                    .is_some_and(|before| release_date > before)
+            {
+                remove(FilterStage::ReleaseDate);
+                continue;
+            }
+        }
+
+        if filter_sizes {
+            let Ok(genome_size) = assembly.genome_size.parse::<u64>() else {
+                n_missing_sizes += 1;
+                remove(FilterStage::GenomeSize);
+                continue;
+            };
+            if filter.min_size.is_some_and(|min| genome_size < min)
+                || filter.max_size.is_some_and(|max| genome_size > max)
+            {
+                remove(FilterStage::GenomeSize);
+                continue;
+            }
+        }
+
+        if filter.clean_only || !filter.exclude_flags.is_empty() {
+            let reasons = assembly.exclusion_reasons();
+            if (filter.clean_only && !reasons.is_empty())
+                || reasons.iter().any(|reason| {
+                    filter
+                        .exclude_flags
+                        .iter()
+                        .any(|flag| flag.eq_ignore_ascii_case(reason))
+                })
+            {
+                remove(FilterStage::ExclusionReasons);
+                continue;
+            }
+        }
+
+        if filter.type_material_only && !assembly.is_type_material() {
+            remove(FilterStage::TypeMaterial);
+            continue;
+        }
+
+        if (filter.exclude_mags || filter.mags_only)
+            && assembly.is_metagenome_derived() != filter.mags_only
+        {
+            remove(FilterStage::Metagenome);
+            continue;
+        }
+
+        if (filter.paired_only || filter.unpaired_only)
+            && assembly.is_paired() != filter.paired_only
+        {
+            remove(FilterStage::Pairing);
+            continue;
+        }
+
+        assembly.passthrough = passthrough_columns
+            .iter()
+            .map(|index| index.and_then(|i| record.get(i)).unwrap_or("").to_string())
+            .collect();
+        on_match(&assembly);
+        n_matched += 1;
+        if !filter.discard_matches {
+            assemblies.push(assembly);
+        }
+    }
+
+    pb.finish_with_message(format!("Kept {} of {} assemblies", n_matched, n_scanned));
+
+    // which filters removed how many assemblies, e.g. `500000 assemblies, 42000 in the taxon,
+    // 8000 at the assembly level`. Malformed lines aren't assemblies
+    let n_assemblies = n_scanned as usize - n_malformed;
+    let funnel = filter_funnel(n_assemblies, &n_removed, &filter);
+    if !funnel.is_empty() {
+        eprintln!(
+            "{}: {} assemblies, {}",
+            name,
+            n_assemblies,
+            funnel
+                .iter()
+                .map(|(stage, n)| format!("{} {}", n, stage.description()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    if n_malformed > 0 {
+        eprintln!(
+            "Warning: skipped {} malformed lines in {} (use --strict-parse to stop at the first one)",
+            n_malformed, name
+        );
+    }
+
+    if n_bad_dates > 0 {
+        eprintln!(
+            "Warning: skipped {} assemblies with an unparseable seq_rel_date",
+            n_bad_dates
+        );
+    }
+
+    if n_suppressed > 0 {
+        eprintln!(
+            "Excluded {} suppressed assemblies (use --include-suppressed to keep them)",
+            n_suppressed
+        );
+    }
+
+    if n_missing_sizes > 0 {
+        eprintln!(
+            "Warning: skipped {} assemblies without a genome_size",
+            n_missing_sizes
+        );
+    }
+
+    assemblies
+}
+
+// narrows the assemblies that matched the summaries down to the ones to download, in the order
+// they're downloaded in: the accession lists, --accession-version-policy latest,
+// --validate-accessions, --since-accession, --unique-taxid, --max-per-taxid, --cluster-file,
+// --sort and --limit
+fn select_assemblies(
+    args: &Args,
+    mut assemblies: Vec<NCBIAssembly>,
+    accessions: &[String],
+    include_accessions: Option<&HashSet<String>>,
+    exclude_accessions: Option<&HashSet<String>>,
+    downloaded_versions: Option<&HashMap<String, u32>>,
+) -> Vec<NCBIAssembly> {
+    if include_accessions.is_some() || exclude_accessions.is_some() {
+        assemblies.retain(|assembly| {
+            include_accessions.is_none_or(|accessions| {
+                matches_accession(accessions, assembly, &args.accession_version_policy)
+            }) && !exclude_accessions.is_some_and(|accessions| {
+                matches_accession(accessions, assembly, &AccessionVersionPolicy::Any)
+            })
+        });
+        eprintln!(
+            "Kept {} assemblies after applying the accession lists",
+            assemblies.len()
+        );
+    }
+
+    if args.accession_version_policy == AccessionVersionPolicy::Latest {
+        let n_before = assemblies.len();
+        if !accessions.is_empty() {
+            keep_latest_versions(&mut assemblies, &accessions.iter().cloned().collect());
+        }
+        if let Some(include_accessions) = include_accessions {
+            keep_latest_versions(&mut assemblies, include_accessions);
+        }
+        if assemblies.len() < n_before {
+            eprintln!(
+                "Kept {} assemblies after keeping the latest version of each accession",
+                assemblies.len()
+            );
+        }
+    }
+
+    if args.validate_accessions && (!accessions.is_empty() || include_accessions.is_some()) {
+        let requested: HashSet<String> = accessions
+            .iter()
+            .cloned()
+            .chain(include_accessions.into_iter().flatten().cloned())
+            .collect();
+        let missing = missing_accessions(&requested, &assemblies, &args.accession_version_policy);
+        if !missing.is_empty() {
+            let more = missing.len().saturating_sub(MISSING_ACCESSIONS_SHOWN);
+            panic!(
+                "{} of {} accessions not found: {}{}",
+                missing.len(),
+                requested.len(),
+                missing[..missing.len() - more].join(", "),
+                match more {
+                    0 => String::new(),
+                    more => format!(" and {} more", more),
+                }
+            );
+        }
+        eprintln!("Found all {} accessions", requested.len());
+    }
+
+    if let (Some(versions), Some(manifest_path)) = (downloaded_versions, &args.since_accession) {
+        assemblies.retain(|assembly| is_newer_than(assembly, versions));
+        eprintln!(
+            "Kept {} assemblies that are new or updated since {}",
+            assemblies.len(),
+            manifest_path
+        );
+    }
+
+    if args.unique_taxid {
+        assemblies = unique_by_taxid(assemblies, &args.prefer_assembly_level);
+        eprintln!(
+            "Kept {} assemblies after keeping one per tax ID",
+            assemblies.len()
+        );
+    }
+
+    if let Some(max) = args.max_per_taxid {
+        assemblies = max_per_taxid(assemblies, max, &args.prefer_assembly_level);
+        eprintln!(
+            "Kept {} assemblies after keeping at most {} per tax ID",
+            assemblies.len(),
+            max
+        );
+    }
+
+    if let Some(cluster_file) = &args.cluster_file {
+        let clusters = read_cluster_file(cluster_file);
+        let n_unclustered;
+        (assemblies, n_unclustered) = unique_by_cluster(
+            assemblies,
+            &clusters,
+            args.keep_unclustered,
+            &args.prefer_assembly_level,
+        );
+        if n_unclustered > 0 {
+            eprintln!(
+                "{} {} assemblies that aren't in {}",
+                match args.keep_unclustered {
+                    true => "Kept",
+                    false => "Left out",
+                },
+                n_unclustered,
+                cluster_file
+            );
+        }
+        eprintln!(
+            "Kept {} assemblies after keeping one per cluster",
+            assemblies.len()
+        );
+    }
+
+    // accessions are unique within a summary, but make sure two assemblies can never be written
+    // to the same file with --flat-filenames
+    if args.flat_filenames {
+        let mut seen = HashSet::new();
+        assemblies.retain(|assembly| {
+            let is_new = seen.insert(assembly.assembly_accession.clone());
+            if !is_new {
+                eprintln!(
+                    "Warning: skipping duplicate accession {}",
+                    assembly.assembly_accession
+                );
+            }
+            is_new
+        });
+    }
+
+    sort_assemblies(&mut assemblies, &args.sort, &args.prefer_assembly_level);
+
+    if let Some(limit) = args.limit {
+        let n_before = assemblies.len();
+        let priority = args
+            .assembly_level_priority
+            .then_some(args.prefer_assembly_level.as_slice());
+        assemblies = limit_assemblies(assemblies, limit, priority);
+        if assemblies.len() < n_before {
+            eprintln!(
+                "Kept the {} {} of {} assemblies (--limit)",
+                match priority {
+                    Some(_) => "best",
+                    None => "first",
+                },
+                assemblies.len(),
+                n_before
+            );
+        }
+    }
+
+    assemblies
+}
+
+// what the download loop ended with, which is only reported once everything else (e.g. the
+// provenance) has been written
+#[derive(Debug, Default)]
+struct DownloadOutcome {
+    n_failed: usize,
+    // --strict
+    stopped_early: bool,
+    // --min-free-space
+    out_of_space: bool,
+    // --max-runtime
+    out_of_time: bool,
+}
+
+// what download_assemblies needs from run() besides the Args
+struct DownloadContext<'a> {
+    client: &'a Client,
+    pool: &'a rayon::ThreadPool,
+    formats: &'a [AssemblyFormat],
+    options: &'a DownloadOptions,
+    datasets_url: Option<&'a str>,
+    out_dir: &'a str,
+    estimated_file_size: Option<u64>,
+    // the taxa in the progress message, e.g. " within the genus `Escherichia` (tax_id=561)"
+    within: String,
+    // for --annotate-headers, so that the taxonomy is only loaded when it's needed
+    lineage: &'a (dyn Fn(&NCBIAssembly) -> String + Sync),
+    // for --max-runtime
+    started: Instant,
+}
+
+// downloads every assembly (minus the ones --state says are done) on the --parallel pool, along
+// with everything that's written as they finish: --manifest, --tar, --seqid2taxid, --exec and
+// the checksum cache
+fn download_assemblies(
+    args: &Args,
+    assemblies: &mut Vec<NCBIAssembly>,
+    ctx: &DownloadContext,
+) -> DownloadOutcome {
+    let DownloadContext {
+        client,
+        pool,
+        formats,
+        options: download_options,
+        datasets_url,
+        out_dir,
+        estimated_file_size,
+        started,
+        ..
+    } = *ctx;
+    let out_path = Path::new(out_dir);
+
+    // the matched assemblies are re-derived from scratch, minus the ones that were already
+    // completed by a previous run with the same --state
+    let state = args.state.as_deref().map(|path| {
+        let (state, completed) = RunState::open(path);
+        let n_before = assemblies.len();
+        assemblies.retain(|assembly| !completed.contains(&assembly.assembly_accession));
+        if assemblies.len() < n_before {
+            eprintln!(
+                "Skipping {} assemblies already completed according to {}",
+                n_before - assemblies.len(),
+                path
+            );
+        }
+        state
+    });
+
+    // rayon hands each thread a contiguous run of assemblies, so neighbours stay together
+    if args.group_by_taxid {
+        assemblies.sort_by(|a, b| (&a.taxid, &a.ftp_path).cmp(&(&b.taxid, &b.ftp_path)));
+    }
+
+    // Download assemblies in parallel
+    let pb = ProgressBar::new(assemblies.len() as u64);
+    let bytes_received = Arc::clone(&download_options.bytes_received);
+    let byte_eta = Arc::new(ByteEta {
+        bytes_received: Arc::clone(&download_options.bytes_received),
+        expected_bytes: Arc::clone(&download_options.expected_bytes),
+        // the datasets backend downloads one package per assembly
+        n_files: match datasets_url {
+            Some(_) => assemblies.len() as u64,
+            None => (assemblies.len() * formats.len()) as u64,
+        },
+        estimated_file_size,
+    });
+    let eta = Arc::clone(&byte_eta);
+    pb.set_style(
+        ProgressStyle::with_template(PB_ASSEMBLIES_TEMPLATE)
+            .unwrap()
+            .with_key(
+                "eta",
+                move |state: &ProgressState, writer: &mut dyn std::fmt::Write| {
+                    let eta = eta.eta(state.elapsed()).unwrap_or_else(|| state.eta());
+                    let _ = write!(writer, "{:#}", HumanDuration(eta));
+                },
+            )
+            .with_key(
+                "throughput",
+                move |state: &ProgressState, writer: &mut dyn std::fmt::Write| {
+                    let bytes = bytes_received.load(Ordering::Relaxed);
+                    let _ = write!(
+                        writer,
+                        "{}/s",
+                        HumanBytes(bytes_per_second(bytes, state.elapsed()))
+                    );
+                },
+            )
+            .progress_chars(PROGRESS_CHARS),
+    );
+    pb.set_message(format!(
+        "Downloading {} assemblies{} in {} format\n",
+        assemblies.len(),
+        ctx.within,
+        formats
+            .iter()
+            .map(AssemblyFormat::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    ));
+
+    let bytes_downloaded = Arc::new(AtomicU64::new(0));
+
+    // the fastest second of the run, for the summary at the end
+    let peak_throughput = Arc::new(AtomicU64::new(0));
+    {
+        let pb = pb.clone();
+        let bytes_received = Arc::clone(&download_options.bytes_received);
+        let peak_throughput = Arc::clone(&peak_throughput);
+        thread::spawn(move || {
+            let mut last = (Instant::now(), bytes_received.load(Ordering::Relaxed));
+            while !pb.is_finished() {
+                thread::sleep(Duration::from_secs(1));
+                let now = (Instant::now(), bytes_received.load(Ordering::Relaxed));
+                let rate = bytes_per_second(now.1 - last.1, now.0 - last.0);
+                peak_throughput.fetch_max(rate, Ordering::Relaxed);
+                last = now;
+            }
+        });
+    }
+
+    // indicatif doesn't draw anything when stderr isn't a terminal, so print periodic
+    // heartbeat lines instead for anyone tailing a log
+    if let (Some(interval), true) = (args.progress_interval, pb.is_hidden()) {
+        let pb_clone = pb.clone();
+        let bytes_downloaded = Arc::clone(&bytes_downloaded);
+        let bytes_received = Arc::clone(&download_options.bytes_received);
+        let byte_eta = Arc::clone(&byte_eta);
+        thread::spawn(move || {
+            while !pb_clone.is_finished() {
+                thread::sleep(Duration::from_secs(interval));
+                eprintln!(
+                    "[{}] downloaded {}/{}, {} ({}/s), ETA {}",
+                    HumanDuration(pb_clone.elapsed()),
+                    pb_clone.position(),
+                    pb_clone.length().unwrap_or(0),
+                    HumanBytes(bytes_downloaded.load(Ordering::Relaxed)),
+                    HumanBytes(bytes_per_second(
+                        bytes_received.load(Ordering::Relaxed),
+                        pb_clone.elapsed()
+                    )),
+                    HumanDuration(
+                        byte_eta
+                            .eta(pb_clone.elapsed())
+                            .unwrap_or_else(|| pb_clone.eta())
+                    )
+                );
+            }
+        });
+    }
+
+    // with --tar, files are still downloaded to --out-dir first and then moved into the
+    // archive so that large genomes never have to be held in memory
+    let archive = args.tar.as_deref().map(|path| Mutex::new(create_tar(path)));
+
+    let n_failed = AtomicUsize::new(0);
+    // formats that --list-formats-available found missing from an assembly's directory
+    let n_unavailable = AtomicUsize::new(0);
+    // files over --max-file-size
+    let n_too_large = AtomicUsize::new(0);
+    // with --strict, the assemblies that haven't been started yet are skipped once anything
+    // has failed
+    let strict = args.strict && !args.keep_going;
+    let aborted = AtomicBool::new(false);
+    // set once --min-free-space has been reached, which also stops new downloads
+    let low_on_space = AtomicBool::new(false);
+    // and --max-runtime
+    let ran_out_of_time = AtomicBool::new(false);
+    let error_log = args.json_errors.as_deref().map(ErrorLog::create);
+    let record_failure = |assembly: &NCBIAssembly, url: &str, error: &GdlError, n: usize| {
+        if let Some(error_log) = &error_log {
+            error_log.record(&assembly.assembly_accession, url, error, args.retries);
+        }
+        n_failed.fetch_add(n, Ordering::Relaxed);
+        if strict {
+            aborted.store(true, Ordering::Relaxed);
+        }
+    };
+    // --exec commands that failed, which only fail the run with --strict. Returns whether the
+    // file still counts as done
+    let n_exec_failed = AtomicUsize::new(0);
+    let exec_hook = |assembly: &NCBIAssembly, url: &str, path: &Path| {
+        let Some(command) = &args.exec else {
+            return true;
+        };
+        match run_exec(command, path) {
+            Ok(()) => true,
+            Err(e) if strict => {
+                pb.println(e.to_string());
+                record_failure(assembly, url, &e, 1);
+                false
+            }
+            Err(e) => {
+                pb.println(format!("Warning: {}", e));
+                n_exec_failed.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+        }
+    };
+
+    let checksum_cache = args.verify.then(|| ChecksumCache::load(out_path));
+
+    // (sequence ID, tax ID) for --seqid2taxid
+    let seqid_rows = Mutex::new(Vec::new());
+
+    let manifest_rows = Mutex::new(Vec::new());
+    let add_to_manifest = |assembly: &NCBIAssembly, format: &str, url: &str, path: &Path| {
+        if args.manifest.is_some() {
+            let row = manifest_row(
+                &args.manifest_columns,
+                assembly,
+                format,
+                url,
+                path,
+                out_path,
+                checksum_cache.as_ref(),
+            );
+            manifest_rows.lock().unwrap().push(row);
+        }
+    };
+
+    // called once an assembly has been recorded in --manifest and --state. The manifest stays
+    // locked while it's written, so that downloads finishing meanwhile wait for it
+    let n_completed = AtomicU64::new(0);
+    let completed_assembly = || {
+        let Some(every) = args.checkpoint_every else {
+            return;
+        };
+        if !(n_completed.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(every) {
+            return;
+        }
+        if let Some(manifest_path) = &args.manifest {
+            let mut rows = manifest_rows.lock().unwrap();
+            write_manifest(
+                manifest_path,
+                &args.manifest_columns,
+                &args.summary_columns_passthrough,
+                &mut rows,
+            );
+        }
+        if let Some(Err(e)) = state.as_ref().map(RunState::sync) {
+            pb.println(format!("Warning: unable to sync the state file: {}", e));
+        }
+    };
+
+    let _tasks: Vec<_> = pool.install(|| {
+        assemblies
+            .par_iter()
+            .map(|assembly| {
+                if aborted.load(Ordering::Relaxed)
+                    || low_on_space.load(Ordering::Relaxed)
+                    || ran_out_of_time.load(Ordering::Relaxed)
+                {
+                    return;
+                }
+                if let Some(max_runtime) = args.max_runtime {
+                    if started.elapsed() >= max_runtime {
+                        if !ran_out_of_time.swap(true, Ordering::Relaxed) {
+                            pb.println(format!(
+                                "Reached --max-runtime {}. Not starting any more downloads",
+                                humantime::format_duration(max_runtime)
+                            ));
+                        }
+                        return;
+                    }
+                }
+                if let Some(min_free_space) = args.min_free_space {
+                    match fs4::available_space(out_path) {
+                        Ok(free_space) if free_space < min_free_space => {
+                            if !low_on_space.swap(true, Ordering::Relaxed) {
+                                pb.println(format!(
+                                    "Only {} left in {}, below --min-free-space {}. Not starting any more downloads",
+                                    HumanBytes(free_space),
+                                    out_dir,
+                                    HumanBytes(min_free_space)
+                                ));
+                            }
+                            return;
+                        }
+                        Ok(_) => {}
+                        Err(e) => eprintln!(
+                            "Warning: unable to check the free space in {}: {}",
+                            out_dir, e
+                        ),
+                    }
+                }
+                let _slot = download_options.slots.acquire();
+                let client = client.clone();
+                pb.inc(1);
+                if let Some(datasets_url) = datasets_url {
+                    match download_datasets_package(
+                        &client,
+                        assembly,
+                        formats,
+                        out_path,
+                        download_options,
+                        datasets_url,
+                    ) {
+                        Ok(path) => {
+                            if let Ok(metadata) = fs::metadata(&path) {
+                                bytes_downloaded.fetch_add(metadata.len(), Ordering::Relaxed);
+                            }
+                            let url = datasets_package_url(datasets_url, assembly, formats)
+                                .unwrap_or_default();
+                            add_to_manifest(assembly, "datasets", &url, &path);
+                            if !exec_hook(assembly, &url, &path) {
+                                return;
+                            }
+                            if let Some(archive) = &archive {
+                                append_to_tar(archive, &path, out_path);
+                            }
+                            if let Some(state) = &state {
+                                state.record(&assembly.assembly_accession);
+                            }
+                            completed_assembly();
+                        }
+                        Err(e) => {
+                            pb.println(format!(
+                                "Failed to download {}: {}",
+                                assembly.assembly_accession, e
+                            ));
+                            let url = datasets_package_url(datasets_url, assembly, formats)
+                                .unwrap_or_default();
+                            record_failure(assembly, &url, &e, 1);
+                        }
+                    }
+                    return;
+                }
+                let checksums = if checksum_cache.is_some() || args.list_formats_available {
+                    match fetch_md5_checksums(&client, assembly, download_options) {
+                        Ok(checksums) => Some(checksums),
+                        Err(e) if checksum_cache.is_some() => {
+                            pb.println(format!(
+                                "Failed to fetch checksums for {}: {}",
+                                assembly.assembly_accession, e
+                            ));
+                            let url = assembly_checksums_url(assembly, download_options)
+                                .unwrap_or_default();
+                            record_failure(assembly, &url, &e, formats.len());
+                            return;
+                        }
+                        // without the listing every format is tried, as without
+                        // --list-formats-available
+                        Err(e) => {
+                            pb.println(format!(
+                                "Warning: unable to list the files of {}: {}",
+                                assembly.assembly_accession, e
+                            ));
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                let header_suffix = args
+                    .annotate_headers
+                    .then(|| format!("|taxid={}|{}", assembly.taxid, (ctx.lineage)(assembly)));
+                let mut completed = true;
+                // formats downloaded as a fallback aren't downloaded again for another one
+                let mut fetched_formats: Vec<AssemblyFormat> = Vec::new();
+                for format in formats {
+                    let fallbacks: Vec<AssemblyFormat> = args
+                        .format_fallback
+                        .iter()
+                        .filter(|fallback| {
+                            !formats.contains(fallback) && !fetched_formats.contains(fallback)
+                        })
+                        .cloned()
+                        .collect();
+                    if let (true, Some(checksums)) = (args.list_formats_available, &checksums) {
+                        if iter::once(format)
+                            .chain(&fallbacks)
+                            .all(|format| !is_format_available(assembly, format, checksums))
+                        {
+                            pb.println(format!(
+                                "{} has no {} file, skipping it",
+                                assembly.assembly_accession,
+                                format.as_str()
+                            ));
+                            n_unavailable.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                    }
+                    let (format, path) = match fetch_assembly_file_or_fallback(
+                        &client,
+                        assembly,
+                        format,
+                        &fallbacks,
+                        out_path,
+                        download_options,
+                        checksums.as_ref(),
+                        checksum_cache.as_ref(),
+                        header_suffix.as_deref(),
+                    ) {
+                        Ok((fetched, path)) => {
+                            if &fetched != format {
+                                pb.println(format!(
+                                    "{} has no {} file, downloaded its {} instead",
+                                    assembly.assembly_accession,
+                                    format.as_str(),
+                                    fetched.as_str()
+                                ));
+                            }
+                            (fetched, path)
+                        }
+                        Err(e @ GdlError::TooLarge { .. }) => {
+                            pb.println(format!(
+                                "Skipped the {} of {} (too large): {}",
+                                format.as_str(),
+                                assembly.assembly_accession,
+                                e
+                            ));
+                            n_too_large.fetch_add(1, Ordering::Relaxed);
+                            // not recorded as done, for a run with a higher limit
+                            completed = false;
+                            continue;
+                        }
+                        Err(e) => {
+                            pb.println(format!(
+                                "Failed to download {}: {}",
+                                assembly.assembly_accession, e
+                            ));
+                            let url = assembly_url(assembly, format, download_options)
+                                .unwrap_or_default();
+                            record_failure(assembly, &url, &e, 1);
+                            completed = false;
+                            if strict {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    if let Ok(metadata) = fs::metadata(&path) {
+                        bytes_downloaded.fetch_add(metadata.len(), Ordering::Relaxed);
+                    }
+                    if args.seqid2taxid.is_some() && format.is_sequence() {
+                        match fasta_seqids(&path) {
+                            Ok(seqids) => seqid_rows.lock().unwrap().extend(
+                                seqids
+                                    .into_iter()
+                                    .map(|seqid| (seqid, assembly.taxid.clone())),
+                            ),
+                            Err(e) => pb.println(format!(
+                                "Warning: unable to read the sequence IDs of {}: {}",
+                                path.display(),
+                                e
+                            )),
+                        }
+                    }
+                    // before --tar moves the file away
+                    let url =
+                        assembly_url(assembly, &format, download_options).unwrap_or_default();
+                    add_to_manifest(assembly, format.as_str(), &url, &path);
+                    if !exec_hook(assembly, &url, &path) {
+                        completed = false;
+                        break;
+                    }
+                    if let Some(archive) = &archive {
+                        append_to_tar(archive, &path, out_path);
+                    }
+                    fetched_formats.push(format);
+                }
+                // only nice to have, so these never fail the run
+                for extra_file in &args.extra_files {
+                    match download_extra_file(
+                        &client,
+                        assembly,
+                        extra_file,
+                        out_path,
+                        download_options,
+                    ) {
+                        Ok(path) => {
+                            let url = extra_file_url(assembly, extra_file, download_options)
+                                .unwrap_or_default();
+                            add_to_manifest(assembly, extra_file.extension(), &url, &path);
+                            if !exec_hook(assembly, &url, &path) {
+                                completed = false;
+                            }
+                            if let Some(archive) = &archive {
+                                append_to_tar(archive, &path, out_path);
+                            }
+                        }
+                        Err(e) => pb.println(format!(
+                            "Warning: unable to download the {} of {}: {}",
+                            extra_file.extension(),
+                            assembly.assembly_accession,
+                            e
+                        )),
+                    }
+                }
+                if let (true, Some(state)) = (completed, &state) {
+                    state.record(&assembly.assembly_accession);
+                }
+                if completed {
+                    completed_assembly();
+                }
+            })
+            .collect()
+    });
+
+    if let Some(manifest_path) = &args.manifest {
+        write_manifest(
+            manifest_path,
+            &args.manifest_columns,
+            &args.summary_columns_passthrough,
+            &mut manifest_rows.into_inner().unwrap(),
+        );
+    }
+
+    if let Some(seqid2taxid_path) = &args.seqid2taxid {
+        write_seqid2taxid(seqid2taxid_path, seqid_rows.into_inner().unwrap());
+    }
+
+    if let Some(cache) = &checksum_cache {
+        if let Err(e) = cache.save() {
+            eprintln!("Warning: unable to save checksum cache: {}", e);
+        }
+    }
+
+    if let Some(archive) = archive {
+        archive
+            .into_inner()
+            .expect("Unable to lock tar archive")
+            .into_inner()
+            .and_then(|mut writer| writer.flush())
+            .expect("Unable to finish writing tar archive");
+    }
+
+    pb.finish_with_message(format!(
+        "Saved {} assemblies to {}",
+        assemblies.len(),
+        args.tar.as_deref().unwrap_or(out_dir)
+    ));
+
+    let n_bytes_received = download_options.bytes_received.load(Ordering::Relaxed);
+    if n_bytes_received > 0 {
+        let average = bytes_per_second(n_bytes_received, pb.elapsed());
+        eprintln!(
+            "Downloaded {} in {} (average {}/s, peak {}/s)",
+            HumanBytes(n_bytes_received),
+            HumanDuration(pb.elapsed()),
+            HumanBytes(average),
+            // runs shorter than a second don't get a sample
+            HumanBytes(peak_throughput.load(Ordering::Relaxed).max(average))
+        );
+    }
+
+    let n_unavailable = n_unavailable.into_inner();
+    if n_unavailable > 0 {
+        eprintln!(
+            "Skipped {} files that aren't available for their assembly",
+            n_unavailable
+        );
+    }
+
+    let n_exec_failed = n_exec_failed.into_inner();
+    if n_exec_failed > 0 {
+        eprintln!(
+            "Warning: --exec failed for {} files (use --strict to fail the run)",
+            n_exec_failed
+        );
+    }
+
+    let n_too_large = n_too_large.into_inner();
+    if n_too_large > 0 {
+        eprintln!(
+            "Skipped {} files larger than --max-file-size {}",
+            n_too_large,
+            HumanBytes(args.max_file_size.unwrap_or_default())
+        );
+    }
+
+    DownloadOutcome {
+        n_failed: n_failed.into_inner(),
+        stopped_early: aborted.into_inner(),
+        out_of_space: low_on_space.into_inner(),
+        out_of_time: ran_out_of_time.into_inner(),
+    }
+}
+
+/// Runs gdl with the given command-line arguments
+pub fn run(mut args: Args) {
+    // for --max-runtime, which includes the time spent on the summaries and taxonomy
+    let started = Instant::now();
+
+    // a --taxdump-path that's a file rather than a directory is the taxdump.tar.gz, likely the one
+    // gdl downloaded before. It's extracted (once) like a --taxdump-archive and loaded from there
+    if Path::new(&args.taxdump_path).is_file() {
+        if let Some(archive) = &args.taxdump_archive {
+            panic!(
+                "--taxdump-path {} is a file, which can't be combined with --taxdump-archive {}. Use --taxdump-path for the directory to extract it to",
+                args.taxdump_path, archive
+            );
+        }
+        let archive = std::mem::take(&mut args.taxdump_path);
+        args.taxdump_path = taxdump_extracted_path(&archive);
+        if !Path::new(&args.taxdump_path).exists() {
+            eprintln!(
+                "--taxdump-path {} is an archive, extracting it to {}",
+                archive, args.taxdump_path
+            );
+        }
+        args.taxdump_archive = Some(archive);
+    }
+    match args.command {
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Args::command(), "gdl", &mut io::stdout());
+            return;
+        }
+        Some(Command::Clean {
+            summaries,
+            taxdump,
+            partial,
+        }) => {
+            let everything = !(summaries || taxdump || partial);
+            let mut paths = Vec::new();
+            if summaries || everything {
+                paths.extend(cached_summaries(Path::new(&args.cache_dir)));
+            }
+            if (taxdump || everything) && Path::new(&args.taxdump_path).exists() {
+                paths.push(PathBuf::from(&args.taxdump_path));
+            }
+            if partial || everything {
+                let out_dir = args.out_dir.as_deref().unwrap_or(".");
+                paths.extend(leftover_files(Path::new(out_dir)));
+            }
+            clean(&paths);
+            return;
+        }
+        Some(Command::Update) => {
+            update(&args);
+            return;
+        }
+        Some(Command::Stats) => {
+            stats(&args);
+            return;
+        }
+        Some(Command::ListSources) => {
+            let mut stdout = io::stdout().lock();
+            for row in source_rows() {
+                writeln!(stdout, "{}", row.join("\t")).expect("Unable to write sources");
+            }
+            return;
+        }
+        None => {}
+    }
+
+    if let (true, Some(manifest_path)) = (args.verify_only, &args.manifest) {
+        let out_dir = args.out_dir.as_deref().unwrap_or(".");
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(args.verify_parallel.unwrap_or(0))
+            .build()
+            .expect("Unable to build thread pool");
+        let pb = ProgressBar::new(0);
+        pb.set_style(
+            ProgressStyle::with_template(PB_EXTRACT_TEMPLATE)
+                .unwrap()
+                .progress_chars(PROGRESS_CHARS),
+        );
+        pb.set_message("Verifying");
+        let (n_checked, failures) =
+            pool.install(|| verify_manifest(manifest_path, Path::new(out_dir), &pb));
+        pb.finish_and_clear();
+        for failure in &failures {
+            eprintln!("{}", failure);
+        }
+        if !failures.is_empty() {
+            panic!(
+                "{} of {} files in {} failed verification",
+                failures.len(),
+                n_checked,
+                manifest_path
+            );
+        }
+        eprintln!("Verified {} files in {}", n_checked, manifest_path);
+        return;
+    }
+
+    if let Some(level) = args.compression_level {
+        if !(args.bgzip || args.recompress.is_some() || args.annotate_headers) {
+            panic!("--compression-level needs --bgzip, --recompress or --annotate-headers");
+        }
+        let levels = compression_levels(args.recompress.as_ref());
+        if !levels.contains(&level) {
+            panic!(
+                "--compression-level {} is out of range, it has to be between {} and {}",
+                level,
+                levels.start(),
+                levels.end()
+            );
+        }
+    }
+
+    // GTDB taxa are names like g__Escherichia, so only NCBI tax IDs can be checked
+    if args.taxonomy == TaxonomyBackend::Ncbi {
+        args.tax_id = args
+            .tax_id
+            .iter()
+            .map(|tax_id| match parse_tax_id(tax_id) {
+                Ok(tax_id) => tax_id,
+                // reported with the other taxa that can't be resolved
+                Err(_) if args.continue_on_taxon_error => tax_id.clone(),
+                Err(e) => panic!("{}", e),
+            })
+            .collect();
+    }
+
+    if args.print_plan {
+        print_plan(&args);
+        return;
+    }
+
+    if args.version_info {
+        print_version_info(&args);
+        return;
+    }
+
+    if let Some(urls) = &args.rebase_url {
+        Url::parse(&urls[1])
+            .unwrap_or_else(|e| panic!("--rebase-url {} is not a valid URL: {}", urls[1], e));
+    }
+
+    // a missing or read-only --out-dir would otherwise only show up once every download fails
+    if !(args.dry_run
+        || args.stdout
+        || args.print_taxa
+        || args.count_by.is_some()
+        || args.only_formats_present
+        || args.assembly_summary_columns)
+    {
+        let out_dir = args.out_dir.as_deref().unwrap_or(".");
+        check_out_dir(Path::new(out_dir)).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    // a single client is used for everything so that --email is always sent and connections
+    // are reused
+    let client = build_client(args.email.as_deref());
+    let taxonomy_options = TaxonomyOptions::from(&args);
+
+    // either use the provided assembly summary file or fetch it from source. if fetching from
+    // source and it already exists; just use the existing file unless --no-cache is enabled.
+    // The summaries and the taxonomy are independent, so the summaries are downloaded in the
+    // background while the taxonomy is downloaded and loaded. They're only needed once filtering
+    // starts.
+    let progress = MultiProgress::new();
+
+    let sources = args.source.summaries(args.include_historical);
+    if args.assembly_summary_url.is_some() && sources.len() > 1 {
+        panic!("--assembly-summary-url can only be used with a single summary, not with --source all or --include-historical");
+    }
+    if args.offline && !args.print_taxa {
+        let paths = match &args.assembly_summary_path {
+            Some(path) if path == STDIN_PATH => Vec::new(),
+            Some(path) => vec![path.clone()],
+            None => sources
+                .iter()
+                .map(|source| {
+                    cached_summary_path(&args.cache_dir, source, args.assembly_summary_gz)
+                })
+                .collect(),
+        };
+        if let Some(path) = paths.iter().find(|path| !Path::new(path).exists()) {
+            offline_missing("the assembly summary", path);
+        }
+    }
+    let mut summaries: Vec<Summary> = match (args.assembly_summary_path.clone(), &args.source) {
+        (Some(path), AssemblySource::None) => vec![Summary {
+            source: "",
+            name: "",
+            url: args.assembly_summary_url.clone().unwrap_or_default(),
+            path,
+            download: None,
+            last_modified: None,
+        }],
+        (None, _) => sources
+            .into_iter()
+            .map(|source| {
+                let path = cached_summary_path(&args.cache_dir, &source, args.assembly_summary_gz);
+                let url = args
+                    .assembly_summary_url
+                    .clone()
+                    .unwrap_or_else(|| source.url.to_string());
+                let cached = Path::new(&path).exists() && !args.no_cache;
+                if let (true, Some(max_age)) = (cached, args.summary_max_age_warn) {
+                    warn_if_summary_is_old(&path, max_age);
+                }
+                let download = (!args.stream_summary && !args.print_taxa && !cached).then(|| {
+                    let (client, url, path, progress) =
+                        (client.clone(), url.clone(), path.clone(), progress.clone());
+                    thread::spawn(move || {
+                        download_assembly_summary(&client, &url, &path, &progress)
+                    })
+                });
+                Summary {
+                    source: source.origin,
+                    name: source.name,
+                    url,
+                    path,
+                    download,
+                    last_modified: None,
+                }
+            })
+            .collect(),
+        _ => {
+            panic!("--source and --assembly-summary-path are mutually exclusive")
+        }
+    };
+
+    if args.assembly_summary_columns {
+        for summary in &mut summaries {
+            summary.wait_for_download();
+            print_summary_columns(&summary.path);
+        }
+        return;
+    }
+
+    let taxonomy_path = match args.taxonomy {
+        TaxonomyBackend::Ncbi => &args.taxdump_path,
+        TaxonomyBackend::Gtdb => &args.gtdb_path,
+    };
+
+    // the taxonomy is only downloaded and loaded once something needs it: right away for the taxon
+    // filters, but only after filtering for e.g. --name-map, and never for runs that just use
+    // --accession
+    let tax = LazyLock::new(|| load_or_download_taxonomy(&taxonomy_options, &client, &progress));
+
+    let pb = progress.add(ProgressBar::new(0));
+    pb.set_style(ProgressStyle::with_template(PB_SPINNER_TEMPLATE).unwrap());
+
+    let taxdump_path = match args.taxonomy {
+        TaxonomyBackend::Ncbi => Some(taxonomy_path.as_str()),
+        TaxonomyBackend::Gtdb => None,
+    };
+
+    // with --accession, the taxon is optional. Each --tax-id and --tax-name is resolved on its
+    // own, so that --continue-on-taxon-error can skip the ones that can't be
+    let requested_taxa: Vec<(Option<&str>, Option<&str>)> = args
+        .tax_id
+        .iter()
+        .map(|tax_id| (Some(tax_id.as_str()), None))
+        .chain(
+            args.tax_name
+                .iter()
+                .map(|tax_name| (None, Some(tax_name.as_str()))),
+        )
+        .collect();
+    let mut requested_tax_ids: Vec<&str> = Vec::new();
+    let mut unresolved_taxa = Vec::new();
+    for &(requested_tax_id, requested_tax_name) in &requested_taxa {
+        match get_tax_id(
+            requested_tax_id,
+            requested_tax_name,
+            &tax,
+            taxdump_path,
+            &args.name_class,
+        ) {
+            Ok(tax_id) => {
+                // let the user know when --tax-name was a synonym (e.g. after a reclassification)
+                // so they aren't surprised by the name in the rest of the output
+                if let Some(tax_name) = requested_tax_name {
+                    let canonical_name = tax.name(tax_id).unwrap_or(tax_name);
+                    if canonical_name != tax_name {
+                        pb.println(format!(
+                            "'{}' → '{}' (tax ID {})",
+                            tax_name, canonical_name, tax_id
+                        ));
+                    }
+                }
+                if !requested_tax_ids.contains(&tax_id) {
+                    requested_tax_ids.push(tax_id);
+                }
+            }
+            Err(e) if args.continue_on_taxon_error => {
+                let given = given_taxon(requested_tax_id, requested_tax_name);
+                pb.println(format!("Warning: skipping {} ({})", given, e));
+                unresolved_taxa.push(given);
+            }
+            Err(e) => {
+                // show the lineage of every match to help pick between them
+                let matches = requested_tax_name
+                    .map(|tax_name| tax.find_all_by_name(tax_name))
+                    .unwrap_or_default();
+                if matches.len() > 1 {
+                    let ranked_lineages = match (&args.taxonomy, &args.taxdump_format) {
+                        (TaxonomyBackend::Ncbi, TaxdumpFormat::New) => {
+                            Some(load_ranked_lineages(taxonomy_path, &matches))
+                        }
+                        _ => None,
+                    };
+                    pb.println("Matching taxa:");
+                    for &tax_id in &matches {
+                        pb.println(format!(
+                            "  {} (tax ID {}): {}",
+                            tax.rank(tax_id)
+                                .map(|rank| rank.to_ncbi_rank())
+                                .unwrap_or("unknown"),
+                            tax_id,
+                            lineage_string(&tax, tax_id, ranked_lineages.as_ref())
+                        ));
+                    }
+                    pb.println("Use --tax-id to pick one");
+                }
+                panic!("{}", e)
+            }
+        }
+    }
+    if !unresolved_taxa.is_empty() {
+        if requested_tax_ids.is_empty() {
+            panic!(
+                "None of the {} taxa could be resolved: {}",
+                requested_taxa.len(),
+                unresolved_taxa.join(", ")
+            );
+        }
+        pb.println(format!(
+            "Skipped {} of {} taxa that couldn't be resolved: {}",
+            unresolved_taxa.len(),
+            requested_taxa.len(),
+            unresolved_taxa.join(", ")
+        ));
+    }
+    // the taxon named in the output when only one was asked for
+    let tax_id: Option<&str> = match requested_tax_ids[..] {
+        [tax_id] => Some(tax_id),
+        _ => None,
+    };
+
+    // --tax-name-glob and --tax-name-regex can match any number of taxa
+    let name_pattern = match (&args.tax_name_glob, &args.tax_name_regex) {
+        (Some(glob), _) => Some((glob, glob_to_regex(glob))),
+        (None, Some(regex)) => Some((regex, regex.clone())),
+        (None, None) => None,
+    };
+    let pattern_tax_ids: Option<Vec<&str>> = name_pattern.map(|(pattern, regex)| {
+        let regex = Regex::new(&regex)
+            .unwrap_or_else(|e| panic!("Invalid name pattern {} ({})", pattern, e));
+        let tax_ids = find_tax_ids_by_pattern(&tax, &regex);
+        if tax_ids.is_empty() {
+            panic!("No taxon names match {}", pattern);
+        }
+        pb.println(format!("'{}' matched {} taxa", pattern, tax_ids.len()));
+        tax_ids
+    });
+
+    pb.finish_and_clear();
+
+    let with_descendants = |tax_id| {
+        let exact = args.species_exact || (args.no_children && !is_species_or_below(&tax, tax_id));
+        taxon_and_descendants(&tax, tax_id, exact)
+    };
+    let mut descendant_tax_ids: Option<HashSet<&str>> =
+        match (requested_tax_ids.is_empty(), &pattern_tax_ids) {
+            (false, _) => Some(
+                requested_tax_ids
+                    .iter()
+                    .flat_map(|&tax_id| with_descendants(tax_id))
+                    .collect(),
+            ),
+            (true, Some(tax_ids)) => Some(
+                tax_ids
+                    .iter()
+                    .flat_map(|&tax_id| with_descendants(tax_id))
+                    .collect(),
+            ),
+            (true, None) => None,
+        };
+
+    if let (Some(tax_ids), true) = (
+        &mut descendant_tax_ids,
+        args.include_rank_below.is_some() || args.include_rank_above.is_some(),
+    ) {
+        let n_taxa = tax_ids.len();
+        tax_ids.retain(|tax_id| {
+            in_rank_window(
+                &tax,
+                tax_id,
+                args.include_rank_below,
+                args.include_rank_above,
+            )
+        });
+        eprintln!(
+            "Kept {} of {} taxa within the rank window",
+            tax_ids.len(),
+            n_taxa
+        );
+    }
+
+    if let (Some(path), Some(descendant_tax_ids)) = (&args.tax_id_output, &descendant_tax_ids) {
+        write_tax_id_output(
+            path,
+            &tax,
+            tax_id.unwrap_or(tax.root()),
+            descendant_tax_ids,
+            args.tax_id_output_names,
+        );
+    }
+
+    if let (true, Some(descendant_tax_ids)) = (args.print_taxa, &descendant_tax_ids) {
+        // lineages are shown from the requested taxon down, or in full for name patterns
+        print_taxa(&tax, tax_id.unwrap_or(tax.root()), descendant_tax_ids);
+        return;
+    }
+
+    let resolved_tax_ids: Vec<String> = match (&descendant_tax_ids, &args.provenance) {
+        (Some(descendant_tax_ids), Some(_)) => {
+            let mut tax_ids: Vec<String> =
+                descendant_tax_ids.iter().map(|t| t.to_string()).collect();
+            tax_ids.sort();
+            tax_ids
+        }
+        _ => Vec::new(),
+    };
+
+    // GTDB assigns genomes rather than tax IDs to taxa, so match on the genomes' accessions
+    let descendant_tax_ids = match args.taxonomy {
+        TaxonomyBackend::Ncbi => descendant_tax_ids,
+        TaxonomyBackend::Gtdb => descendant_tax_ids.map(|descendant_tax_ids| {
+            descendant_tax_ids
+                .into_iter()
+                .map(gtdb_accession_key)
+                .collect()
+        }),
+    };
+
+    let mut accessions = args.accession.clone();
+    if let Some(accession_file) = &args.accession_file {
+        accessions.extend(read_accession_file(accession_file));
+    }
+
+    let filter = AssemblyFilter {
+        tax_ids: descendant_tax_ids,
+        accessions: (!accessions.is_empty()).then(|| accessions.iter().cloned().collect()),
+        taxonomy: args.taxonomy.clone(),
+        assembly_levels: args.assembly_level.clone(),
+        after_date: args.after_date,
+        before_date: args.before_date,
+        exclude_flags: args.exclude_flag.clone(),
+        clean_only: args.clean_only,
+        min_size: args.min_size,
+        max_size: args.max_size,
+        exclude_mags: args.exclude_mags,
+        mags_only: args.mags_only,
+        type_material_only: args.type_material_only,
+        paired_only: args.paired_only,
+        unpaired_only: args.unpaired_only,
+        submitter: args.submitter.as_deref().map(str::to_lowercase),
+        organism_names: args
+            .organism_name_contains
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect(),
+        include_suppressed: args.include_suppressed,
+        strict_parse: args.strict_parse,
+        expr: args.filter.clone(),
+        accession_version_policy: args.accession_version_policy.clone(),
+        discard_matches: args.stream_downloads,
+        passthrough_columns: args.summary_columns_passthrough.clone(),
+    };
+
+    summaries.iter_mut().for_each(Summary::wait_for_download);
+
+    let include_accessions: Option<HashSet<String>> = args
+        .include_accession_file
+        .as_deref()
+        .map(|path| read_accession_file(path).into_iter().collect());
+    let exclude_accessions: Option<HashSet<String>> = args
+        .exclude_accession_file
+        .as_deref()
+        .map(|path| read_accession_file(path).into_iter().collect());
+    let downloaded_versions = args.since_accession.as_deref().map(read_manifest_versions);
+    let keep_latest = args.accession_version_policy == AccessionVersionPolicy::Latest;
+
+    // --unique-taxid can only pick an assembly once everything has been filtered, so only
+    // stream --jsonl output when nothing else has to happen to the matched assemblies
+    let stream_jsonl = args.jsonl
+        && !args.unique_taxid
+        && args.max_per_taxid.is_none()
+        && args.sort == SortOrder::None
+        && !keep_latest
+        && include_accessions.is_none()
+        && exclude_accessions.is_none()
+        && downloaded_versions.is_none()
+        && args.limit.is_none()
+        && args.cluster_file.is_none()
+        && !args.flat_filenames;
+    let mut on_match = |assembly: &NCBIAssembly| {
+        if stream_jsonl {
+            write_jsonl(&mut io::stdout(), assembly);
+        }
+    };
+
+    let formats = requested_formats(&args.format, args.bundle.as_ref());
+    let mut download_options = DownloadOptions {
+        retries: args.retries,
+        scheme: args.scheme.clone(),
+        rebase_url: args
+            .rebase_url
+            .as_ref()
+            .map(|urls| (urls[0].clone(), urls[1].clone())),
+        bgzip: args.bgzip,
+        recompress: args.recompress.clone(),
+        compression_level: args.compression_level,
+        decompressed_copy: args.both,
+        check_genome_length: args.check_genome_length,
+        verify_gzip_integrity: args.verify_gzip_integrity,
+        flat_filenames: args.flat_filenames,
+        filename_template: args.filename_template.clone(),
+        name_delimiter: args.name_delimiter.clone(),
+        // the datasets backend puts every format in a single package
+        format_subdirs: formats.len() > 1
+            && !args.no_format_subdirs
+            && !args.preserve_directory_structure
+            && args.backend != Backend::Datasets,
+        source_subdirs: args.source_subdirs,
+        // once the assemblies are known
+        shards: HashMap::new(),
+        api_key: args.api_key.clone(),
+        preserve_directory_structure: args.preserve_directory_structure,
+        chunked: args.chunked.then(|| ChunkedDownload {
+            threshold: args.chunked_threshold,
+            connections: args.chunked_connections.max(1),
+        }),
+        max_file_size: args.max_file_size,
+        throttle: args.max_bandwidth.map(Throttle::new),
+        bytes_received: Arc::new(AtomicU64::new(0)),
+        expected_bytes: Arc::new(ExpectedBytes::default()),
+        slots: DownloadSlots::new(args.parallel),
+    };
+
+    if args.stream_downloads {
+        if args.backend == Backend::Datasets {
+            panic!("--stream-downloads can't be combined with --backend datasets");
+        }
+        if keep_latest {
+            panic!("--stream-downloads can't be combined with --accession-version-policy latest");
+        }
+        let out_path = Path::new(args.out_dir.as_deref().unwrap_or("."));
+        let (n_assemblies, n_failed) = stream_downloads(
+            &client,
+            &mut summaries,
+            &filter,
+            &formats,
+            out_path,
+            &download_options,
+            args.parallel,
+            args.stream_summary,
+        );
+        eprintln!(
+            "Downloaded {} assemblies to {}",
+            n_assemblies,
+            out_path.display()
+        );
+        if n_failed > 0 {
+            panic!("{} files failed to download", n_failed);
+        }
+        return;
+    }
+
+    let mut assemblies: Vec<NCBIAssembly> = Vec::new();
+    let n_summaries = summaries.len();
+    for summary in &mut summaries {
+        let filter = filter.clone();
+        let mut matched = if args.stream_summary {
+            let url = &summary.url;
+            let response = get(&client, url)
+                .unwrap_or_else(|e| panic!("Unable to fetch assembly summary: {}", e));
+            let size = response.content_length().unwrap_or(0);
+            summary.last_modified = last_modified(&response);
+            filter_assemblies(response, size, url, filter, &mut on_match)
+        } else {
+            let (file, size) = open_summary(&summary.path);
+            let is_stdin = summary.path == STDIN_PATH;
+
+            // a cache only holds the matches from one summary
+            let cache_filename = match n_summaries {
+                1 => FILTER_CACHE_FILENAME.to_string(),
+                _ => format!(".gdl-filter-cache-{}.tsv", summary.name),
+            };
+            // stdin can't be told apart from one run to the next
+            let filter_cache = (args.filter_cache && !is_stdin)
+                .then(|| filter_cache_key(&filter, &summary.path))
+                .flatten()
+                .map(|key| (Path::new(&args.cache_dir).join(cache_filename), key));
+            let cached = filter_cache
+                .as_ref()
+                .and_then(|(path, key)| read_filter_cache(path, key));
+
+            match cached {
+                Some(assemblies) => {
+                    eprintln!(
+                        "Using {} assemblies matched by the same filters last time",
+                        assemblies.len()
+                    );
+                    assemblies.iter().for_each(&mut on_match);
+                    assemblies
+                }
+                None => {
+                    let name = if is_stdin { "stdin" } else { &summary.path };
+                    let assemblies = filter_assemblies(file, size, name, filter, &mut on_match);
+                    if let Some((path, key)) = &filter_cache {
+                        if let Err(e) = write_filter_cache(path, key, &assemblies) {
+                            eprintln!("Warning: unable to save filter cache: {}", e);
+                        }
+                    }
+                    assemblies
+                }
+            }
+        };
+        for assembly in &mut matched {
+            assembly.source = summary.source;
+        }
+        assemblies.append(&mut matched);
+    }
+
+    // an explicitly versioned --accession that isn't the latest version (e.g. GCF_000005845.1
+    // once there's a .2) is only in the historical summary, so that's looked through for the
+    // versions that weren't found even without --include-historical
+    let missing_versions: HashSet<String> = {
+        let found: HashSet<&str> = assemblies
+            .iter()
+            .map(|assembly| assembly.assembly_accession.as_str())
+            .collect();
+        accessions
+            .iter()
+            .filter(|accession| accession.contains('.') && !found.contains(accession.as_str()))
+            .cloned()
+            .collect()
+    };
+    let historical_sources = match (&args.assembly_summary_url, args.include_historical) {
+        (None, false) if !missing_versions.is_empty() && !args.stream_summary => args
+            .source
+            .summaries(true)
+            .into_iter()
+            .filter(|source| source.historical)
+            .collect(),
+        _ => Vec::new(),
+    };
+    for source in historical_sources {
+        let path = cached_summary_path(&args.cache_dir, &source, args.assembly_summary_gz);
+        let exists = Path::new(&path).exists();
+        if args.offline && !exists {
+            eprintln!(
+                "Warning: not looking for {} in the {} summary, which isn't at {} (--offline)",
+                missing_versions.len(),
+                source.name,
+                path
+            );
+            continue;
+        }
+        if let (true, false, Some(max_age)) = (exists, args.no_cache, args.summary_max_age_warn) {
+            warn_if_summary_is_old(&path, max_age);
+        }
+        let last_modified = (args.no_cache || !exists)
+            .then(|| download_assembly_summary(&client, source.url, &path, &progress))
+            .flatten();
+
+        let file = File::open(&path)
+            .unwrap_or_else(|_| panic!("Unable to open assembly summary path {}", path));
+        let size = file.metadata().expect("Unable to get file size").len();
+        let filter = AssemblyFilter {
+            accessions: Some(missing_versions.clone()),
+            ..filter.clone()
+        };
+        let mut matched = filter_assemblies(file, size, &path, filter, &mut on_match);
+        eprintln!(
+            "Found {} of {} older accession versions in the {} summary",
+            matched.len(),
+            missing_versions.len(),
+            source.name
+        );
+        for assembly in &mut matched {
+            assembly.source = source.origin;
+        }
+        assemblies.append(&mut matched);
+        summaries.push(Summary {
+            source: source.origin,
+            name: source.name,
+            url: source.url.to_string(),
+            path,
+            download: None,
+            last_modified,
+        });
+    }
+
+    let mut assemblies = select_assemblies(
+        &args,
+        assemblies,
+        &accessions,
+        include_accessions.as_ref(),
+        exclude_accessions.as_ref(),
+        downloaded_versions.as_ref(),
+    );
+
+    if let Some(rank) = args.count_by {
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "tax_id\t{}\tassemblies", rank.to_ncbi_rank())
+            .and_then(|_| {
+                count_by_rank(&tax, &assemblies, rank, &args.taxonomy)
+                    .into_iter()
+                    .try_for_each(|(tax_id, name, count)| {
+                        writeln!(stdout, "{}\t{}\t{}", tax_id, name, count)
+                    })
+            })
+            .expect("Unable to write counts");
+        return;
+    }
+
+    let n_assemblies = assemblies.len();
+
+    // setup threadpool using --parallel
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(args.parallel)
+        .build()
+        .expect("Unable to build thread pool");
+
+    let out_dir = args.out_dir.clone().unwrap_or(".".to_string());
+    let out_path = Path::new(&out_dir);
+
+    if !out_path.exists() {
+        fs::create_dir_all(out_path).expect("Unable to create path");
+    }
+
+    if args.bgzip {
+        let skipped: Vec<&str> = formats
+            .iter()
+            .filter(|format| !format.is_sequence())
+            .map(AssemblyFormat::as_str)
+            .collect();
+        if !skipped.is_empty() {
+            eprintln!(
+                "Warning: --bgzip is only meaningful for sequence formats (fna, faa, cds, rna), leaving {} as downloaded",
+                skipped.join(", ")
+            );
+        }
+    }
+
+    let needs_lineages = args.name_map.is_some() || args.annotate_headers;
+    let ranked_lineages = match (&args.taxonomy, &args.taxdump_format) {
+        (TaxonomyBackend::Ncbi, TaxdumpFormat::New) if needs_lineages => {
+            let tax_ids: Vec<&str> = assemblies.iter().map(|a| a.taxid.as_str()).collect();
+            Some(load_ranked_lineages(taxonomy_path, &tax_ids))
+        }
+        _ => None,
+    };
+
+    if let Some(name_map_path) = &args.name_map {
+        write_name_map(
+            name_map_path,
+            &assemblies,
+            &args.name_map_format,
+            &tax,
+            &args.taxonomy,
+            ranked_lineages.as_ref(),
+        );
+    }
+
+    if args.json {
+        let records: Vec<AssemblyRecord> = assemblies.iter().map(AssemblyRecord::from).collect();
+        serde_json::to_writer_pretty(io::stdout(), &records)
+            .expect("Unable to write assemblies as JSON");
+        println!();
+    }
+
+    if args.jsonl && !stream_jsonl {
+        let mut stdout = io::stdout();
+        for assembly in &assemblies {
+            write_jsonl(&mut stdout, assembly);
+        }
+    }
+
+    download_options.shards = args
+        .shard_size
+        .map(|shard_size| assign_shards(&assemblies, shard_size as usize))
+        .unwrap_or_default();
+
+    if args.only_formats_present {
+        let present = pool.install(|| {
+            assemblies
+                .par_iter()
+                .map(|assembly| formats_present(&client, assembly, &download_options))
+                .collect()
+        });
+        print_formats_present(&assemblies, present);
+        return;
+    }
+
+    if args.source_subdirs && !matches!(args.source, AssemblySource::All) {
+        panic!("--source-subdirs needs --source all");
+    }
+    let source_paths: Vec<PathBuf> = match args.source_subdirs {
+        true => args
+            .source
+            .summaries(false)
+            .iter()
+            .map(|source| out_path.join(source.name))
+            .collect(),
+        false => vec![out_path.to_path_buf()],
+    };
+    if !args.dry_run {
+        for source_path in &source_paths {
+            let mut paths = vec![source_path.clone()];
+            if download_options.format_subdirs {
+                paths.extend(
+                    formats
+                        .iter()
+                        .map(|format| source_path.join(format.as_str())),
+                );
+            }
+            for path in paths {
+                fs::create_dir_all(&path)
+                    .unwrap_or_else(|_| panic!("Unable to create {}", path.display()));
+            }
+        }
+    }
+
+    let datasets_url = (args.backend == Backend::Datasets).then_some(args.datasets_url.as_str());
+
+    if datasets_url.is_some() {
+        if args.verify
+            || args.bgzip
+            || args.recompress.is_some()
+            || args.annotate_headers
+            || args.seqid2taxid.is_some()
+            || !args.format_fallback.is_empty()
+            || args.check_genome_length
+            || args.max_file_size.is_some()
+            || args.verify_gzip_integrity
+        {
+            panic!("--backend datasets can't be combined with --verify, --bgzip, --recompress, --annotate-headers, --seqid2taxid, --format-fallback, --check-genome-length, --max-file-size or --verify-gzip-integrity");
+        }
+        let unsupported: Vec<&str> = formats
+            .iter()
+            .filter(|format| format.datasets_annotation_type().is_none())
+            .map(AssemblyFormat::as_str)
+            .collect();
+        if !unsupported.is_empty() {
+            eprintln!(
+                "Warning: the Datasets API doesn't provide {}, skipping",
+                unsupported.join(", ")
+            );
+        }
+    }
+
+    if args.print_urls {
+        for assembly in &assemblies {
+            if let Some(datasets_url) = datasets_url {
+                match datasets_package_url(datasets_url, assembly, &formats) {
+                    Ok(url) => println!("{}", url),
+                    Err(e) => eprintln!("Warning: skipping {}: {}", assembly.assembly_accession, e),
+                }
+                continue;
+            }
+            for format in &formats {
+                match assembly_url(assembly, format, &download_options) {
+                    Ok(url) => println!("{}", url),
+                    Err(e) => eprintln!("Warning: skipping {}: {}", assembly.assembly_accession, e),
+                }
+            }
+        }
+    }
+
+    for algorithm in &args.checksum_algorithm {
+        if !args.manifest_columns.contains(&algorithm.manifest_column()) {
+            args.manifest_columns.push(algorithm.manifest_column());
+        }
+    }
+    if let (true, Some(column)) = (
+        args.dry_run,
+        args.manifest_columns
+            .iter()
+            .find(|column| column.is_checksum()),
+    ) {
+        panic!(
+            "--manifest-columns {} needs the files to be downloaded, which --dry-run doesn't do",
+            column.name()
+        );
+    }
+
+    // nothing ends up in --out-dir, so none of the bookkeeping of the download loop applies
+    if args.stdout {
+        let [assembly] = assemblies.as_slice() else {
+            panic!(
+                "--stdout needs exactly one matching assembly, but {} matched",
+                assemblies.len()
+            );
+        };
+        let [format] = formats.as_slice() else {
+            panic!("--stdout can only write a single --format");
+        };
+        if datasets_url.is_some() {
+            panic!("--stdout can't be combined with --backend datasets");
+        }
+        if !args.dry_run {
+            stream_assembly_file(
+                &client,
+                assembly,
+                format,
+                &mut io::stdout().lock(),
+                args.decompress,
+                &download_options,
+            )
+            .unwrap_or_else(|e| panic!("{}", e));
+        }
+        return;
+    }
+
+    if args.only_missing {
+        let n_before = assemblies.len();
+        assemblies.retain(|assembly| {
+            !is_already_downloaded(
+                assembly,
+                &formats,
+                out_path,
+                &download_options,
+                datasets_url.is_some(),
+            )
+        });
+        eprintln!(
+            "Skipping {} assemblies that are already in {}",
+            n_before - assemblies.len(),
+            out_dir
+        );
+    }
+
+    // nothing can be downloaded with --offline, so every file has to be in --out-dir already.
+    // All that's left to do then is what --dry-run does
+    if args.offline && !args.dry_run {
+        let missing: Vec<&str> = assemblies
+            .iter()
+            .filter(|assembly| {
+                !is_already_downloaded(
+                    assembly,
+                    &formats,
+                    out_path,
+                    &download_options,
+                    datasets_url.is_some(),
+                )
+            })
+            .map(|assembly| assembly.assembly_accession.as_str())
+            .collect();
+        if let Some(first) = missing.first() {
+            panic!(
+                "--offline: {} of {} assemblies aren't in {} yet (e.g. {}). Run without --offline to download them, or add --dry-run",
+                missing.len(),
+                assemblies.len(),
+                out_dir,
+                first
+            );
+        }
+        args.dry_run = true;
+    }
+
+    // assemblies that share a filename (e.g. from different --source summaries) would silently
+    // overwrite each other
+    let collisions = colliding_filenames(
+        &assemblies,
+        &formats,
+        &download_options,
+        datasets_url.is_some(),
+    );
+    if !collisions.is_empty() {
+        let shown: Vec<String> = collisions
+            .iter()
+            .take(COLLISIONS_SHOWN)
+            .map(|(filename, accessions)| format!("{} ({})", filename, accessions.join(", ")))
+            .collect();
+        let more = collisions.len().saturating_sub(COLLISIONS_SHOWN);
+        panic!(
+            "{} files would be written by more than one assembly: {}{}. Use --flat-filenames or --source-subdirs to tell them apart",
+            collisions.len(),
+            shown.join("; "),
+            match more {
+                0 => String::new(),
+                more => format!(" and {} more", more),
+            }
+        );
+    }
+
+    // the Datasets API doesn't say how big a package is until it's been built
+    let show_estimate = !assemblies.is_empty()
+        && datasets_url.is_none()
+        && !(args.json || args.jsonl || args.print_urls || args.offline);
+    let estimate = show_estimate
+        .then(|| estimate_download_size(&client, &assemblies, &formats, &download_options));
+    // per file, since e.g. --state can still leave some assemblies out
+    let estimated_file_size = estimate
+        .flatten()
+        .map(|bytes| bytes / (assemblies.len() * formats.len()).max(1) as u64);
+    if let Some(estimate) = estimate {
+        eprintln!(
+            "~{} assemblies, estimated ~{}",
+            assemblies.len(),
+            estimate.map_or("unknown size".to_string(), |bytes| HumanBytes(bytes)
+                .to_string())
+        );
+    }
+
+    // there's nobody to ask when gdl isn't run from a terminal, so --confirm needs a --yes there
+    // and large downloads only prompt in a terminal
+    let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+    let ask = !args.dry_run
+        && !args.yes
+        && !assemblies.is_empty()
+        && (args.confirm || (interactive && assemblies.len() > CONFIRM_THRESHOLD));
+    if ask {
+        if !interactive {
+            eprintln!("Not downloading: --confirm needs a terminal to ask in (use --yes instead)");
+            return;
+        }
+        if !confirm(&format!("Download {} assemblies?", assemblies.len())) {
+            eprintln!("Aborted");
+            return;
+        }
+    }
+
+    let outcome = if !args.dry_run {
+        let within = match tax_id {
+            Some(tax_id) => format!(
+                " within the {} `{}` (tax_id={})",
+                tax.rank(tax_id).unwrap(),
+                tax.name(tax_id).unwrap(),
+                tax_id
+            ),
+            None if requested_tax_ids.len() > 1 => {
+                format!(" within {} taxa", requested_tax_ids.len())
+            }
+            None => String::new(),
+        };
+        let lineage = |assembly: &NCBIAssembly| {
+            assembly_lineage(&tax, assembly, &args.taxonomy, ranked_lineages.as_ref())
+        };
+        let ctx = DownloadContext {
+            client: &client,
+            pool: &pool,
+            formats: &formats,
+            options: &download_options,
+            datasets_url,
+            out_dir: &out_dir,
+            estimated_file_size,
+            within,
+            lineage: &lineage,
+            started,
+        };
+        download_assemblies(&args, &mut assemblies, &ctx)
+    } else {
+        if let Some(manifest_path) = &args.manifest {
+            let mut rows = planned_manifest_rows(
+                &args.manifest_columns,
+                &assemblies,
+                &formats,
+                &args.extra_files,
+                datasets_url,
+                out_path,
+                &download_options,
+            );
+            eprintln!(
+                "Wrote the {} files that would be downloaded to {}",
+                rows.len(),
+                manifest_path
+            );
+            write_manifest(
+                manifest_path,
+                &args.manifest_columns,
+                &args.summary_columns_passthrough,
+                &mut rows,
+            );
+        }
+        DownloadOutcome::default()
+    };
+
+    if let Some(provenance_path) = &args.provenance {
+        let provenance = Provenance {
+            gdl_version: env!("CARGO_PKG_VERSION"),
+            command: std::env::args().collect(),
+            created_at: humantime::format_rfc3339_seconds(std::time::SystemTime::now()).to_string(),
+            source: format!("{:?}", args.source).to_lowercase(),
+            assembly_summary: summaries
+                .iter()
+                .map(|summary| match args.stream_summary {
+                    true => summary.url.as_str(),
+                    false => summary.path.as_str(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            assembly_summary_last_modified: summaries
+                .iter()
+                .map(|summary| summary.last_modified.clone())
+                .collect::<Option<Vec<_>>>()
+                .map(|last_modified| last_modified.join(", ")),
+            assembly_summary_modified_at: if args.stream_summary {
+                None
+            } else {
+                summaries
+                    .iter()
+                    .map(|summary| modified_at(&summary.path))
+                    .collect::<Option<Vec<_>>>()
+                    .map(|modified_at| modified_at.join(", "))
+            },
+            taxonomy: format!("{:?}", args.taxonomy).to_lowercase(),
+            taxonomy_path: taxonomy_path.clone(),
+            taxonomy_modified_at: modified_at(taxonomy_path),
+            tax_id: (!requested_tax_ids.is_empty()).then(|| requested_tax_ids.join(", ")),
+            tax_name: (!requested_tax_ids.is_empty()).then(|| {
+                requested_tax_ids
+                    .iter()
+                    .filter_map(|&tax_id| tax.name(tax_id).ok())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }),
+            accessions,
+            tax_ids: resolved_tax_ids,
+            formats: formats.iter().map(|f| f.as_str().to_string()).collect(),
+            n_assemblies,
+        };
+        write_provenance(&provenance, provenance_path);
+    }
+
+    if outcome.out_of_space {
+        panic!(
+            "Stopped because {} is low on space (--min-free-space). Free up some space and run again to download the rest",
+            out_dir
+        );
+    }
+    if outcome.out_of_time {
+        eprintln!(
+            "Stopped after --max-runtime {}. Run the same command again to download the rest",
+            humantime::format_duration(args.max_runtime.unwrap_or_default())
+        );
+    }
+    if outcome.stopped_early {
+        panic!(
+            "Stopped after {} files failed to download (--strict)",
+            outcome.n_failed
+        );
+    }
+    if outcome.n_failed > 0 {
+        panic!("{} files failed to download", outcome.n_failed);
+    }
+
+    // keep stdout clean for anyone piping --json into something else
+    if !args.json && !args.jsonl && !args.print_urls {
+        println!("Thank you for flying gdl!");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::Method::{GET, HEAD};
+    use httpmock::MockServer;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_download_assembly() {
+        // Start a mock HTTP server
+        let server = MockServer::start();
+        let file_content = b"\x1f\x8btest genome data";
+        let ftp_path = format!("{}/test_asm", server.url(""));
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/test_asm/test_asm_genomic.fna.gz");
+            then.status(200)
+                .header("Content-Type", "application/octet-stream")
+                .body(file_content);
+        });
+
+        let assembly = NCBIAssembly {
+            taxid: "123".to_string(),
+            ftp_path: ftp_path.clone(),
+            assembly_level: "Complete Genome".to_string(),
+            ..Default::default()
+        };
+        let format = AssemblyFormat::Fna;
+        let client = Client::new();
+        let tmp_dir = tempdir().unwrap();
+        let out_path = tmp_dir.path();
+
+        let result_path = download_assembly(
+            &client,
+            &assembly,
+            &format,
+            out_path,
+            &DownloadOptions::default(),
+        )
+        .unwrap();
+        let result_data = std::fs::read(&result_path).unwrap();
+        assert_eq!(result_data, file_content);
+        mock.assert();
+    }
+
+    #[test]
+    fn test_rate_limiting() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 09 Sep 2001 01:48:40 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        // dates in the past mean trying again straight away
+        assert_eq!(
+            parse_retry_after("Sat, 01 Jan 2000 00:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/busy");
+            then.status(429).header("Retry-After", "0");
+        });
+        let options = DownloadOptions {
+            retries: 1,
+            ..Default::default()
+        };
+        let result = get_with_retries(&Client::new(), &server.url("/busy"), &options);
+        assert!(matches!(
+            result,
+            Err(GdlError::RateLimited {
+                retry_after: Some(Duration::ZERO),
+                ..
+            })
+        ));
+        mock.assert_hits(2);
+
+        // only 429s that keep coming halve the number of downloads running at once
+        let slots = DownloadSlots::new(8);
+        let running: Vec<DownloadSlot> = (0..8).map(|_| slots.acquire()).collect();
+        assert_eq!(slots.rate_limited(), None);
+        slots.succeeded();
+        assert_eq!(slots.rate_limited(), None);
+        assert_eq!(slots.rate_limited(), None);
+        assert_eq!(slots.rate_limited(), Some(4));
+        drop(running);
+        for _ in 0..RATE_LIMITED_BEFORE_SLOWING * 4 {
+            slots.rate_limited();
+        }
+        assert_eq!(slots.slots.lock().unwrap().0, 1);
+    }
+
+    #[test]
+    fn test_stream_assembly_file() {
+        let server = MockServer::start();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b">seq1\nACGT\n").unwrap();
+        let file_content = encoder.finish().unwrap();
+        server.mock(|when, then| {
+            when.method(GET).path("/test_asm/test_asm_genomic.fna.gz");
+            then.status(200).body(&file_content);
+        });
+        let assembly = NCBIAssembly {
+            ftp_path: server.url("/test_asm"),
+            ..Default::default()
+        };
+        let client = Client::new();
+        let options = DownloadOptions::default();
+
+        let mut compressed = Vec::new();
+        stream_assembly_file(
+            &client,
+            &assembly,
+            &AssemblyFormat::Fna,
+            &mut compressed,
+            false,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(compressed, file_content);
+
+        let mut decompressed = Vec::new();
+        stream_assembly_file(
+            &client,
+            &assembly,
+            &AssemblyFormat::Fna,
+            &mut decompressed,
+            true,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(decompressed, b">seq1\nACGT\n");
+    }
+
+    #[test]
+    fn test_verify_gzip_integrity() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[b'A'; 10_000]).unwrap();
+        let genome = encoder.finish().unwrap();
+        let truncated = &genome[..genome.len() - 8];
+
+        let server = MockServer::start();
+        for (name, body) in [("complete", &genome[..]), ("truncated", truncated)] {
+            server.mock(|when, then| {
+                when.method(GET)
+                    .path(format!("/{}/{}_genomic.fna.gz", name, name));
+                then.status(200).body(body);
+            });
+        }
+
+        let tmp_dir = tempdir().unwrap();
+        let download = |name: &str, verify_gzip_integrity| {
+            download_assembly(
+                &Client::new(),
+                &NCBIAssembly {
+                    ftp_path: format!("{}/{}", server.url(""), name),
+                    ..Default::default()
+                },
+                &AssemblyFormat::Fna,
+                tmp_dir.path(),
+                &DownloadOptions {
+                    verify_gzip_integrity,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(download("complete", true).is_ok());
+        // starts like gzip, so it's only caught by decompressing it
+        assert!(download("truncated", false).is_ok());
+        let result = download("truncated", true);
+        assert!(matches!(result, Err(GdlError::InvalidDownload(_))));
+    }
+
+    #[test]
+    fn test_download_assembly_cut_off() {
+        // httpmock always sends the whole body, so the connection is cut off by hand: the first
+        // `n_cut_off` responses promise the whole genome but stop halfway through it
+        let genome = b"\x1f\x8btest genome data".repeat(100);
+        let serve = |n_cut_off: usize| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/test_asm", listener.local_addr().unwrap());
+            let genome = genome.clone();
+            thread::spawn(move || {
+                for (i, stream) in listener.incoming().enumerate() {
+                    let mut stream = stream.unwrap();
+                    let mut request = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    while request.read_line(&mut line).unwrap() > 2 {
+                        line.clear();
+                    }
+                    let body = match i < n_cut_off {
+                        true => &genome[..genome.len() / 2],
+                        false => &genome[..],
+                    };
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        genome.len()
+                    );
+                    let _ = stream.write_all(body);
+                }
+            });
+            url
+        };
+
+        let tmp_dir = tempdir().unwrap();
+        let download = |url: String, retries| {
+            download_assembly(
+                &Client::new(),
+                &NCBIAssembly {
+                    ftp_path: url,
+                    ..Default::default()
+                },
+                &AssemblyFormat::Fna,
+                tmp_dir.path(),
+                &DownloadOptions {
+                    retries,
+                    ..Default::default()
+                },
+            )
+        };
+        let path = tmp_dir.path().join("test_asm.fna.gz");
+
+        // nothing is left behind that --only-missing would take for a complete download
+        let result = download(serve(usize::MAX), 0);
+        assert!(matches!(result, Err(GdlError::Request { .. })));
+        assert!(!path.exists());
+        assert!(leftover_files(tmp_dir.path()).is_empty());
+
+        // and it's retried like any other connection error
+        assert_eq!(download(serve(1), 1).unwrap(), path);
+        assert_eq!(fs::read(&path).unwrap(), genome);
+        assert!(leftover_files(tmp_dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_max_file_size() {
+        let server = MockServer::start();
+        let file_content = b"\x1f\x8btest genome data";
+        // one that says how large it is when asked, and one that only does when downloaded
+        let large_get = server.mock(|when, then| {
+            when.method(GET).path("/large/large_genomic.fna.gz");
+            then.status(200).body(file_content);
+        });
+        server.mock(|when, then| {
+            when.method(HEAD).path("/large/large_genomic.fna.gz");
+            then.status(200).header("Content-Length", "5000000000");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/small/small_genomic.fna.gz");
+            then.status(200).body(file_content);
+        });
+
+        let assembly = |name: &str| NCBIAssembly {
+            ftp_path: format!("{}/{}", server.url(""), name),
+            ..Default::default()
+        };
+        let options = |max_file_size| DownloadOptions {
+            max_file_size: Some(max_file_size),
+            ..Default::default()
+        };
+        let tmp_dir = tempdir().unwrap();
+        let download = |name: &str, max_file_size| {
+            download_assembly(
+                &Client::new(),
+                &assembly(name),
+                &AssemblyFormat::Fna,
+                tmp_dir.path(),
+                &options(max_file_size),
+            )
+        };
+
+        let result = download("large", 1_000_000_000);
+        assert!(matches!(
+            result,
+            Err(GdlError::TooLarge {
+                size: 5_000_000_000,
+                ..
+            })
+        ));
+        large_get.assert_hits(0);
+
+        assert!(matches!(
+            download("small", 10),
+            Err(GdlError::TooLarge { size: 18, .. })
+        ));
+        assert!(fs::read_dir(tmp_dir.path()).unwrap().next().is_none());
+        assert!(download("small", 18).is_ok());
+    }
+
+    #[test]
+    fn test_chunked_download() {
+        let server = MockServer::start();
+        let file_content = b"\x1f\x8bchunked!";
+        let ftp_path = format!("{}/test_asm", server.url(""));
+        let path = "/test_asm/test_asm_genomic.fna.gz";
+        server.mock(|when, then| {
+            when.method(HEAD).path(path);
+            then.status(200)
+                .header("Content-Length", "10")
+                .header("Accept-Ranges", "bytes");
+        });
+        let ranges: Vec<_> = [(0, 4), (5, 9)]
+            .into_iter()
+            .map(|(start, end)| {
+                server.mock(|when, then| {
+                    when.method(GET)
+                        .path(path)
+                        .header("range", format!("bytes={}-{}", start, end));
+                    then.status(206).body(&file_content[start..=end]);
+                })
+            })
+            .collect();
+
+        let assembly = NCBIAssembly {
+            ftp_path,
+            ..Default::default()
+        };
+        let options = DownloadOptions {
+            chunked: Some(ChunkedDownload {
+                threshold: 10,
+                connections: 2,
+            }),
+            ..Default::default()
+        };
+        let tmp_dir = tempdir().unwrap();
+
+        let result_path = download_assembly(
+            &Client::new(),
+            &assembly,
+            &AssemblyFormat::Fna,
+            tmp_dir.path(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(result_path).unwrap(), file_content);
+        for range in ranges {
+            range.assert();
+        }
+    }
+
+    #[test]
+    fn test_http_errors_include_the_status_and_body() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/missing");
+            then.status(404)
+                .body("<html>\n  <body>Not here</body>\n</html>");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/down");
+            then.status(503)
+                .body("x".repeat(ERROR_BODY_SNIPPET_LEN * 2));
+        });
+        let client = Client::new();
+
+        let error = get(&client, &server.url("/missing")).unwrap_err();
+        assert!(matches!(
+            error,
+            GdlError::Http {
+                status: StatusCode::NOT_FOUND,
+                ..
+            }
+        ));
+        assert!(!error.is_retryable());
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} returned 404 Not Found: <html> <body>Not here</body> </html>",
+                server.url("/missing")
+            )
+        );
+
+        let error = get(&client, &server.url("/down")).unwrap_err();
+        assert!(error.is_retryable());
+        assert!(error
+            .to_string()
+            .ends_with(&format!("{}…", "x".repeat(ERROR_BODY_SNIPPET_LEN))));
+
+        let error = get(&client, "http://127.0.0.1:1/").unwrap_err();
+        assert!(matches!(error, GdlError::Request { .. }));
+    }
+
+    #[test]
+    fn test_assembly_file_url() {
+        let ftp_path =
+            "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845/GCF_000005845.2_ASM584v2";
+        let expected = "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845/GCF_000005845.2_ASM584v2/GCF_000005845.2_ASM584v2_genomic.fna.gz";
+
+        assert_eq!(
+            assembly_file_url(ftp_path, &AssemblyFormat::Fna, None),
+            Ok(expected.to_string())
+        );
+        assert_eq!(
+            assembly_file_url(&format!("{}/", ftp_path), &AssemblyFormat::Fna, None),
+            Ok(expected.to_string())
+        );
+        assert_eq!(
+            assembly_file_url(
+                &ftp_path.replace("https://", "ftp://"),
+                &AssemblyFormat::Fna,
+                None
+            ),
+            Ok(expected.to_string())
+        );
+        assert_eq!(
+            assembly_file_url(ftp_path, &AssemblyFormat::Cds, None),
+            Ok(expected.replace("_genomic.fna.gz", "_cds_from_genomic.fna.gz"))
+        );
+        assert_eq!(
+            assembly_file_url(ftp_path, &AssemblyFormat::Rna, None),
+            Ok(expected.replace("_genomic.fna.gz", "_rna_from_genomic.fna.gz"))
+        );
+        assert_eq!(
+            assembly_filename(ftp_path, &AssemblyFormat::Gbff),
+            Ok("GCF_000005845.2_ASM584v2.gbff.gz".to_string())
+        );
+
+        let assembly = NCBIAssembly {
+            assembly_accession: "GCF_000005845.2".to_string(),
+            ftp_path: ftp_path.to_string(),
+            ..Default::default()
+        };
+        let flat = DownloadOptions {
+            flat_filenames: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            local_filename(&assembly, &AssemblyFormat::Fna, &flat),
+            Ok("GCF_000005845.2.fna.gz".to_string())
+        );
+        assert_eq!(
+            local_filename(&assembly, &AssemblyFormat::Fna, &DownloadOptions::default()),
+            Ok("GCF_000005845.2_ASM584v2.fna.gz".to_string())
+        );
+        let format_subdirs = DownloadOptions {
+            format_subdirs: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            local_filename(&assembly, &AssemblyFormat::Gff, &format_subdirs),
+            Ok("gff/GCF_000005845.2_ASM584v2.gff.gz".to_string())
+        );
+        let mirror = DownloadOptions {
+            preserve_directory_structure: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            local_filename(&assembly, &AssemblyFormat::Fna, &mirror),
+            Ok(format!(
+                "{}/GCF_000005845.2_ASM584v2_genomic.fna.gz",
+                "genomes/all/GCF/000/005/845/GCF_000005845.2_ASM584v2"
+            ))
+        );
+
+        assert!(assembly_file_url("na", &AssemblyFormat::Fna, None).is_err());
+        assert!(assembly_file_url("", &AssemblyFormat::Fna, None).is_err());
+        assert!(assembly_file_url("GCF_000005845.2", &AssemblyFormat::Fna, None).is_err());
+        assert!(assembly_file_url("https://", &AssemblyFormat::Fna, None).is_err());
+    }
+
+    #[test]
+    fn test_assign_shards() {
+        let assemblies: Vec<NCBIAssembly> = ["GCF_3", "GCF_1", "GCF_2", "GCF_1"]
+            .iter()
+            .map(|accession| NCBIAssembly {
+                assembly_accession: accession.to_string(),
+                ftp_path: format!("https://ftp.ncbi.nlm.nih.gov/genomes/all/{}", accession),
+                ..Default::default()
+            })
+            .collect();
+        let shards = assign_shards(&assemblies, 2);
+        assert_eq!(shards.len(), 3);
+        assert_eq!(shards["GCF_1"], "000");
+        assert_eq!(shards["GCF_2"], "000");
+        assert_eq!(shards["GCF_3"], "001");
+
+        let options = DownloadOptions {
+            shards,
+            source_subdirs: true,
+            ..Default::default()
+        };
+        let assembly = NCBIAssembly {
+            source: "refseq",
+            ..assemblies[0].clone()
+        };
+        assert_eq!(
+            local_filename(&assembly, &AssemblyFormat::Fna, &options),
+            Ok("001/refseq/GCF_3.fna.gz".to_string())
+        );
+
+        let many: Vec<NCBIAssembly> = (0..2001)
+            .map(|i| NCBIAssembly {
+                assembly_accession: format!("GCF_{:06}", i),
+                ..Default::default()
+            })
+            .collect();
+        assert_eq!(assign_shards(&many, 2)["GCF_002000"], "1000");
+    }
+
+    #[test]
+    fn test_filename_template() {
+        assert_eq!(
+            sanitize_filename_part("Escherichia coli O157:H7 (strain Sakai)", "_"),
+            "Escherichia_coli_O157_H7_strain_Sakai"
+        );
+        assert_eq!(sanitize_filename_part("a/b\\c", "-"), "a-b-c");
+        assert_eq!(sanitize_filename_part(" Bacillus  sp. ", ""), "Bacillussp.");
+        assert_eq!(
+            sanitize_filename_part("Candidatus Café", "_"),
+            "Candidatus_Caf"
+        );
+
+        assert!(parse_filename_template("{organism}_{accession}").is_ok());
+        assert!(parse_filename_template("{species}").is_err());
+        assert!(parse_filename_template("{accession").is_err());
+        assert!(parse_filename_template("accession}").is_err());
+        assert!(parse_filename_template("{taxid}/{accession}").is_err());
+        assert!(parse_name_delimiter("").is_ok());
+        assert!(parse_name_delimiter(" ").is_err());
+
+        let assembly = NCBIAssembly {
+            assembly_accession: "GCF_000008865.2".to_string(),
+            taxid: "386585".to_string(),
+            organism_name: "Escherichia coli O157:H7 str. Sakai".to_string(),
+            ftp_path:
+                "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/008/865/GCF_000008865.2_ASM886v2"
+                    .to_string(),
+            ..Default::default()
+        };
+        let options = DownloadOptions {
+            filename_template: Some("{organism}_{taxid}_{asm_name}".to_string()),
+            name_delimiter: "_".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            local_filename(&assembly, &AssemblyFormat::Fna, &options),
+            Ok("Escherichia_coli_O157_H7_str._Sakai_386585_ASM886v2.fna.gz".to_string())
+        );
+    }
+
+    #[test]
+    fn test_is_already_downloaded() {
+        let tmp_dir = tempdir().unwrap();
+        let assembly = NCBIAssembly {
+            assembly_accession: "GCF_000005845.2".to_string(),
+            ftp_path:
+                "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845/GCF_000005845.2_ASM584v2"
+                    .to_string(),
+            ..Default::default()
+        };
+        let formats = [AssemblyFormat::Fna, AssemblyFormat::Gff];
+        let options = DownloadOptions::default();
+        let is_downloaded = |options: &DownloadOptions| {
+            is_already_downloaded(&assembly, &formats, tmp_dir.path(), options, false)
+        };
+
+        let fna = local_filename(&assembly, &AssemblyFormat::Fna, &options).unwrap();
+        let gff = local_filename(&assembly, &AssemblyFormat::Gff, &options).unwrap();
+        std::fs::write(tmp_dir.path().join(&fna), "").unwrap();
+        assert!(!is_downloaded(&options));
+        std::fs::write(tmp_dir.path().join(&gff), "").unwrap();
+        assert!(is_downloaded(&options));
+
+        let zstd = DownloadOptions {
+            recompress: Some(Recompression::Zstd),
+            ..Default::default()
+        };
+        assert!(!is_downloaded(&zstd));
+        for filename in [&fna, &gff] {
+            let stem = filename.strip_suffix(".gz").unwrap();
+            std::fs::write(tmp_dir.path().join(format!("{}.zst", stem)), "").unwrap();
+        }
+        assert!(is_downloaded(&zstd));
+    }
+
+    #[test]
+    fn test_colliding_filenames() {
+        // e.g. a mirror that renamed a directory, merged with NCBI's summary
+        let assemblies = [
+            (
+                "GCF_000005845.2",
+                "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845/GCF_000005845.2_ASM584v2",
+            ),
+            (
+                "GCF_000005845.3",
+                "https://mirror.example.org/genomes/GCF_000005845.2_ASM584v2",
+            ),
+            (
+                "GCF_000001405.40",
+                "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/001/405/GCF_000001405.40_GRCh38.p14",
+            ),
+        ]
+        .map(|(accession, ftp_path)| NCBIAssembly {
+            assembly_accession: accession.to_string(),
+            ftp_path: ftp_path.to_string(),
+            ..Default::default()
+        });
+        let formats = [AssemblyFormat::Fna, AssemblyFormat::Gff];
+
+        assert_eq!(
+            colliding_filenames(&assemblies, &formats, &DownloadOptions::default(), false),
+            vec![
+                (
+                    "GCF_000005845.2_ASM584v2.fna.gz".to_string(),
+                    vec!["GCF_000005845.2", "GCF_000005845.3"]
+                ),
+                (
+                    "GCF_000005845.2_ASM584v2.gff.gz".to_string(),
+                    vec!["GCF_000005845.2", "GCF_000005845.3"]
+                ),
+            ]
+        );
+        let flat = DownloadOptions {
+            flat_filenames: true,
+            ..Default::default()
+        };
+        assert!(colliding_filenames(&assemblies, &formats, &flat, false).is_empty());
+        assert!(
+            colliding_filenames(&assemblies, &formats, &DownloadOptions::default(), true)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_append_to_tar() {
+        let tmp_dir = tempdir().unwrap();
+        let tar_path = tmp_dir.path().join("assemblies.tar.gz");
+        let genome_path = tmp_dir.path().join("GCF_000005845.2_ASM584v2.fna.gz");
+        std::fs::write(&genome_path, b"test genome data").unwrap();
+
+        let archive = Mutex::new(create_tar(tar_path.to_str().unwrap()));
+        append_to_tar(&archive, &genome_path, tmp_dir.path());
+        archive
+            .into_inner()
+            .unwrap()
+            .into_inner()
+            .unwrap()
+            .flush()
+            .unwrap();
+
+        assert!(!genome_path.exists());
+
+        let mut archive = Archive::new(GzDecoder::new(File::open(&tar_path).unwrap()));
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        assert_eq!(
+            entry.path().unwrap().to_str(),
+            Some("GCF_000005845.2_ASM584v2.fna.gz")
+        );
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"test genome data");
+    }
+
+    #[test]
+    fn test_genome_length() {
+        let stats = "# Assembly name:  ASM584v2\n\
+            # unit-name\tmolecule-name\tmolecule-type/loc\tsequence-type\tstatistic\tvalue\n\
+            all\tall\tall\tall\ttotal-length\t4641652\n\
+            all\tall\tall\tall\tungapped-length\t4641652\n\
+            Primary Assembly\tall\tall\tall\ttotal-length\t4641652\n";
+        assert_eq!(parse_stats_total_length(stats), Some(4641652));
+        assert_eq!(parse_stats_total_length("# nothing to see here\n"), None);
+
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("genome.fna.gz");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder
+            .write_all(b">seq1 first\nACGTN\nAC\r\n>seq2\nGGG\n")
+            .unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(fasta_length(&path).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_exec() {
+        assert!(parse_exec("seqkit stats {}").is_ok());
+        assert!(parse_exec("seqkit stats").is_err());
+
+        let tmp_dir = tempdir().unwrap();
+        // a path that would break a command it was pasted into
+        let path = tmp_dir.path().join("it's a genome.fna.gz");
+        std::fs::write(&path, b"test genome data").unwrap();
+        run_exec("cp {} {}.copy", &path).unwrap();
+        assert!(tmp_dir.path().join("it's a genome.fna.gz.copy").exists());
+
+        let e = run_exec("test ! -e {}", &path).unwrap_err();
+        assert_eq!(e.kind(), "exec");
+        assert!(e.to_string().contains("exit status: 1"));
+    }
+
+    #[test]
+    fn test_parse_md5_checksums() {
+        let checksums = parse_md5_checksums(
+            "5A3D0C0E8E1A1C7A1B7F8F1D2E3C4B5A  ./GCF_000005845.2_ASM584v2_genomic.fna.gz\n\
+             0f1e2d3c4b5a69788796a5b4c3d2e1f0  ./GCF_000005845.2_ASM584v2_protein.faa.gz\n",
+        );
+
+        assert_eq!(checksums.len(), 2);
+        assert_eq!(
+            checksums["GCF_000005845.2_ASM584v2_genomic.fna.gz"],
+            "5a3d0c0e8e1a1c7a1b7f8f1d2e3c4b5a"
+        );
+    }
+
+    #[test]
+    fn test_formats_present() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET)
+                .path("/GCF_000005845.2_ASM584v2/md5checksums.txt");
+            then.status(200).body(
+                "5a3d0c0e8e1a1c7a1b7f8f1d2e3c4b5a  ./GCF_000005845.2_ASM584v2_genomic.fna.gz\n\
+                 0f1e2d3c4b5a69788796a5b4c3d2e1f0  ./GCF_000005845.2_ASM584v2_genomic.gff.gz\n",
+            );
+        });
+        let assembly = NCBIAssembly {
+            ftp_path: server.url("/GCF_000005845.2_ASM584v2"),
+            ..Default::default()
+        };
+
+        let present =
+            formats_present(&Client::new(), &assembly, &DownloadOptions::default()).unwrap();
+        let present: Vec<&str> = AssemblyFormat::value_variants()
+            .iter()
+            .zip(present)
+            .filter(|(_, present)| *present)
+            .map(|(format, _)| format.as_str())
+            .collect();
+        assert_eq!(present, ["fna", "gff"]);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        assert_eq!(retry_backoff(0), Duration::from_millis(500));
+        assert_eq!(retry_backoff(3), Duration::from_secs(4));
+        assert_eq!(retry_backoff(12), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_backoff(40), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn test_checksum_cache() {
+        let tmp_dir = tempdir().unwrap();
+        let genome_path = tmp_dir.path().join("genome.fna.gz");
+        std::fs::write(&genome_path, b"test genome data").unwrap();
+        let md5 = file_md5(&genome_path).unwrap();
+
+        let cache = ChecksumCache::load(tmp_dir.path());
+        assert_eq!(cache.md5(&genome_path).unwrap(), md5);
+        cache.save().unwrap();
+
+        // an unchanged file is trusted without being hashed again
+        let (size, mtime) = file_stamp(&genome_path).unwrap();
+        std::fs::write(
+            tmp_dir.path().join(CHECKSUM_CACHE_FILENAME),
+            format!("genome.fna.gz\t{}\t{}\tcached\n", size, mtime),
+        )
+        .unwrap();
+        let cache = ChecksumCache::load(tmp_dir.path());
+        assert_eq!(cache.md5(&genome_path).unwrap(), "cached");
+
+        // a file that's been changed since it was downloaded keeps the md5 it was downloaded with
+        assert_eq!(cache.download_md5(&genome_path).unwrap(), "cached");
+        cache
+            .record_download_md5(&genome_path, "downloaded")
+            .unwrap();
+        cache.save().unwrap();
+        let cache = ChecksumCache::load(tmp_dir.path());
+        assert_eq!(cache.md5(&genome_path).unwrap(), "cached");
+        assert_eq!(cache.download_md5(&genome_path).unwrap(), "downloaded");
+
+        // but a file whose size changed is hashed again
+        std::fs::write(&genome_path, b"other genome data").unwrap();
+        assert_eq!(
+            cache.md5(&genome_path).unwrap(),
+            file_md5(&genome_path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_file_sha256() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("genome.fna.gz");
+        std::fs::write(&path, b"hello").unwrap();
+        assert_eq!(
+            file_sha256(&path).unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+
+    #[test]
+    fn test_write_decompressed_copy() {
+        let tmp_dir = tempdir().unwrap();
+        let genome_path = tmp_dir.path().join("genome.fna.gz");
+        let mut encoder =
+            GzEncoder::new(File::create(&genome_path).unwrap(), Compression::default());
+        encoder.write_all(b">chr1\nACGT\n").unwrap();
+        encoder.finish().unwrap();
+
+        let copy_path = write_decompressed_copy(&genome_path).unwrap();
+
+        assert_eq!(copy_path, tmp_dir.path().join("genome.fna"));
+        assert_eq!(std::fs::read(&copy_path).unwrap(), b">chr1\nACGT\n");
+        assert!(genome_path.exists());
+    }
+
+    #[test]
+    fn test_recompress_bgzip() {
+        let tmp_dir = tempdir().unwrap();
+        let genome_path = tmp_dir.path().join("genome.fna.gz");
+        let mut encoder =
+            GzEncoder::new(File::create(&genome_path).unwrap(), Compression::default());
+        encoder.write_all(b">chr1\nACGT\n").unwrap();
+        encoder.finish().unwrap();
+
+        recompress_bgzip(&genome_path, Some(9)).unwrap();
+
+        let mut contents = String::new();
+        noodles_bgzf::io::Reader::new(File::open(&genome_path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, ">chr1\nACGT\n");
+    }
+
+    #[test]
+    fn test_fasta_seqids() {
+        let tmp_dir = tempdir().unwrap();
+        let fasta = b">NC_000913.3 Escherichia coli K-12\nACGT\nACGT\n>pUC19\nACGT\n";
+        let plain_path = tmp_dir.path().join("genome.fna");
+        std::fs::write(&plain_path, fasta).unwrap();
+        let gzip_path = tmp_dir.path().join("genome.fna.gz");
+        let mut encoder = GzEncoder::new(File::create(&gzip_path).unwrap(), Compression::default());
+        encoder.write_all(fasta).unwrap();
+        encoder.finish().unwrap();
+        let zstd_path = tmp_dir.path().join("genome.fna.zst");
+        std::fs::write(&zstd_path, zstd::encode_all(&fasta[..], 0).unwrap()).unwrap();
+
+        for path in [plain_path, gzip_path, zstd_path] {
+            assert_eq!(fasta_seqids(&path).unwrap(), ["NC_000913.3", "pUC19"]);
+        }
+    }
+
+    #[test]
+    fn test_recompress_zstd() {
+        let tmp_dir = tempdir().unwrap();
+        let genome_path = tmp_dir.path().join("genome.fna.gz");
+        let mut encoder =
+            GzEncoder::new(File::create(&genome_path).unwrap(), Compression::default());
+        encoder.write_all(b">chr1\nACGT\n").unwrap();
+        encoder.finish().unwrap();
+
+        let zstd_path = recompress_zstd(&genome_path, Some(19)).unwrap();
+
+        assert_eq!(zstd_path, tmp_dir.path().join("genome.fna.zst"));
+        assert!(!genome_path.exists());
+        let contents = zstd::decode_all(File::open(&zstd_path).unwrap()).unwrap();
+        assert_eq!(contents, b">chr1\nACGT\n");
+
+        // the assembly report isn't compressed to begin with
+        let report_path = tmp_dir.path().join("genome.assembly_report.txt");
+        std::fs::write(&report_path, b"# Assembly name:").unwrap();
+        assert_eq!(recompress_zstd(&report_path, None).unwrap(), report_path);
+
+        assert_eq!(compression_levels(Some(&Recompression::Zstd)), 1..=22);
+        assert_eq!(compression_levels(None), 0..=9);
+    }
+
+    #[test]
+    fn test_estimate_download_size() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .path("/asm_1/asm_1_genomic.fna.gz");
+            then.status(200).header("Content-Length", "1000");
+        });
+        server.mock(|when, then| {
+            when.method(httpmock::Method::HEAD)
+                .path("/asm_2/asm_2_genomic.fna.gz");
+            then.status(200).header("Content-Length", "3000");
+        });
+
+        let assemblies: Vec<NCBIAssembly> = (1..=10)
+            .map(|i| NCBIAssembly {
+                ftp_path: server.url(format!("/asm_{}", i)),
+                ..Default::default()
+            })
+            .collect();
+        let client = Client::new();
+
+        // assemblies 1, 3, 5, 7 and 9 are sampled but only the first has a size
+        assert_eq!(
+            estimate_download_size(
+                &client,
+                &assemblies,
+                &[AssemblyFormat::Fna],
+                &DownloadOptions::default()
+            ),
+            Some(10_000)
+        );
+        assert_eq!(
+            estimate_download_size(
+                &client,
+                &assemblies[2..],
+                &[AssemblyFormat::Faa],
+                &DownloadOptions::default()
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_datasets_package_url() {
+        let assembly = NCBIAssembly {
+            assembly_accession: "GCF_000005845.2".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            datasets_package_url(
+                DATASETS_API_URL,
+                &assembly,
+                &[AssemblyFormat::Fna, AssemblyFormat::FeatureTable, AssemblyFormat::Gff]
+            ),
+            Ok("https://api.ncbi.nlm.nih.gov/datasets/v2/genome/accession/GCF_000005845.2/download?include_annotation_type=GENOME_FASTA&include_annotation_type=GENOME_GFF".to_string())
+        );
+    }
+
+    #[test]
+    fn test_taxdump_extracted_path() {
+        assert_eq!(taxdump_extracted_path("taxdump.tar.gz"), "taxdump");
+        assert_eq!(
+            taxdump_extracted_path("/data/new_taxdump.tgz"),
+            "/data/new_taxdump"
+        );
+        assert_eq!(taxdump_extracted_path("taxdump.zip"), "taxdump.zip.d");
+        assert_eq!(
+            taxdump_extracted_path(&taxdump_archive_path("taxdump/")),
+            "taxdump"
+        );
+    }
+
+    #[test]
+    fn test_check_taxdump() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().to_str().unwrap();
+
+        assert!(check_taxdump(path, &TaxdumpFormat::Classic)
+            .unwrap_err()
+            .ends_with("nodes.dmp is missing"));
+
+        std::fs::write(tmp_dir.path().join("nodes.dmp"), "1\t|\t1\t|\n").unwrap();
+        std::fs::write(tmp_dir.path().join("names.dmp"), "").unwrap();
+        assert!(check_taxdump(path, &TaxdumpFormat::Classic)
+            .unwrap_err()
+            .ends_with("names.dmp is empty"));
+
+        std::fs::write(tmp_dir.path().join("names.dmp"), "1\t|\troot\t|\n").unwrap();
+        assert_eq!(check_taxdump(path, &TaxdumpFormat::Classic), Ok(()));
+        assert!(check_taxdump(path, &TaxdumpFormat::New)
+            .unwrap_err()
+            .ends_with("rankedlineage.dmp is missing"));
+    }
+
+    #[test]
+    fn test_extract_taxdump_cleans_up_after_a_failure() {
+        let tmp_dir = tempdir().unwrap();
+        let archive_path = tmp_dir.path().join("taxdump.tar.gz");
+        let path = tmp_dir.path().join("taxdump");
+        let path = path.to_str().unwrap();
+
+        // names.dmp is big enough that truncating the archive cuts into it, after nodes.dmp
+        let names: String = (0..20_000)
+            .map(|i| format!("{}\t|\t{:x}\t|\n", i, md5::compute(i.to_string())))
+            .collect();
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (filename, contents) in [("nodes.dmp", "1\t|\t1\t|\n"), ("names.dmp", &names)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, filename, contents.as_bytes())
+                .unwrap();
+        }
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+        let progress = MultiProgress::new();
+
+        std::fs::write(&archive_path, &archive[..archive.len() / 2]).unwrap();
+        assert!(extract_taxdump(archive_path.to_str().unwrap(), path, &progress).is_err());
+        assert!(!Path::new(path).join("nodes.dmp").exists());
+        assert!(check_taxdump(path, &TaxdumpFormat::Classic)
+            .unwrap_err()
+            .ends_with("was only partly extracted"));
+
+        std::fs::write(&archive_path, &archive).unwrap();
+        extract_taxdump(archive_path.to_str().unwrap(), path, &progress).unwrap();
+        assert_eq!(check_taxdump(path, &TaxdumpFormat::Classic), Ok(()));
+        assert!(Path::new(path).join(TAXDUMP_EXTRACTED_STAMP).exists());
+    }
+
+    #[test]
+    fn test_normalize_scheme() {
+        let path = "ftp://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845";
+        assert_eq!(
+            normalize_scheme(path, None),
+            "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845"
+        );
+        assert_eq!(
+            normalize_scheme(path, Some(&Scheme::Http)),
+            "http://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845"
+        );
+
+        let path = "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845";
+        assert_eq!(normalize_scheme(path, None), path);
+    }
+
+    #[test]
+    fn test_rebase_url() {
+        let url = "https://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845/x_genomic.fna.gz";
+        assert_eq!(
+            rebase_url(
+                url,
+                "https://ftp.ncbi.nlm.nih.gov/genomes",
+                "http://mirror.local/ncbi"
+            ),
+            Ok("http://mirror.local/ncbi/all/GCF/000/005/845/x_genomic.fna.gz".to_string())
+        );
+        assert_eq!(
+            rebase_url(url, "https://example.org", "http://mirror.local"),
+            Ok(url.to_string())
+        );
+        assert!(rebase_url(url, "https://ftp.ncbi.nlm.nih.gov", "not a url").is_err());
+    }
+
+    #[test]
+    fn test_parse_release_date() {
+        let expected = ReleaseDate {
+            year: 2011,
+            month: 2,
+            day: 8,
+        };
+        assert_eq!(parse_release_date("2011/02/08"), Ok(expected));
+        assert_eq!(parse_release_date("2011-02-08"), Ok(expected));
+        assert_eq!(parse_release_date("20110208"), Ok(expected));
+        assert_eq!(parse_release_date("2011-02-08T12:00:00Z"), Ok(expected));
+        assert_eq!(parse_release_date("2011/02/08 12:00"), Ok(expected));
+        assert!(parse_release_date("na").is_err());
+        assert!(parse_release_date("2011/13/08").is_err());
+        assert!(parse_release_date("").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("5Mb"), Ok(5_000_000));
+        assert_eq!(parse_size("200kb"), Ok(200_000));
+        assert_eq!(parse_size("1.5G"), Ok(1_500_000_000));
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert_eq!(parse_size("1000bp"), Ok(1000));
+        assert!(parse_size("big").is_err());
+        assert!(parse_size("-5Mb").is_err());
+    }
+
+    #[test]
+    fn test_annotate_fasta_headers() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("genome.fna.gz");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder
+            .write_all(
+                b">NC_000913.3 Escherichia coli K-12\nACGT\nACGT\n>NC_000914.1 plasmid\r\nGG\n",
+            )
+            .unwrap();
+        encoder.finish().unwrap();
+
+        annotate_fasta_headers(&path, "|taxid=562|Bacteria; Escherichia coli", None).unwrap();
+
+        let mut contents = String::new();
+        MultiGzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(
+            contents,
+            ">NC_000913.3 Escherichia coli K-12|taxid=562|Bacteria; Escherichia coli\nACGT\nACGT\n\
+            >NC_000914.1 plasmid|taxid=562|Bacteria; Escherichia coli\nGG\n"
+        );
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let regex = Regex::new(&glob_to_regex("Strepto*")).unwrap();
+        assert!(regex.is_match("Streptococcus"));
+        assert!(regex.is_match("Streptomyces coelicolor"));
+        assert!(!regex.is_match("Enterococcus"));
+        assert!(!regex.is_match("streptococcus"));
+
+        let regex = Regex::new(&glob_to_regex("Escherichia coli str. K-1?")).unwrap();
+        assert!(regex.is_match("Escherichia coli str. K-12"));
+        // . is literal
+        assert!(!regex.is_match("Escherichia coli strX K-12"));
+    }
+
+    #[test]
+    fn test_parse_bandwidth() {
+        assert_eq!(parse_bandwidth("10MB/s"), Ok(10_000_000));
+        assert_eq!(parse_bandwidth("500k"), Ok(500_000));
+        assert!(parse_bandwidth("0/s").is_err());
+        assert!(parse_bandwidth("fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(
+            parse_duration("2h 30m"),
+            Ok(Duration::from_secs(2 * 3600 + 30 * 60))
+        );
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("a while").is_err());
+    }
+
+    #[test]
+    fn test_age_over() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("assembly_summary_refseq.txt");
+        let file = File::create(&path).unwrap();
+        let day = Duration::from_secs(24 * 3600);
+        file.set_modified(SystemTime::now() - 10 * day).unwrap();
+        let path = path.to_str().unwrap();
+
+        assert!(age_over(path, day).is_some_and(|age| age >= 10 * day));
+        assert_eq!(age_over(path, 30 * day), None);
+        assert_eq!(age_over("missing.txt", day), None);
+    }
+
+    #[test]
+    fn test_bytes_per_second() {
+        assert_eq!(bytes_per_second(5_000, Duration::from_millis(500)), 10_000);
+        assert_eq!(bytes_per_second(5_000, Duration::ZERO), 0);
+    }
+
+    #[test]
+    fn test_byte_eta() {
+        let second = Duration::from_secs(1);
+        // nothing received yet
+        assert_eq!(byte_eta(0, 0, 0, 10, Some(1_000), second), None);
+        // before any file has started, the others are as big as the estimate
+        assert_eq!(
+            byte_eta(100, 0, 0, 10, Some(1_000), second),
+            Some(Duration::from_secs(99))
+        );
+        assert_eq!(byte_eta(100, 0, 0, 10, None, second), None);
+        // one 9,000 byte file out of 10 started, 1,000 bytes in: 90,000 - 1,000 bytes to go
+        assert_eq!(
+            byte_eta(1_000, 9_000, 1, 10, Some(10), second),
+            Some(Duration::from_secs(89))
+        );
+        // a single large file left doesn't look like one file out of many
+        assert_eq!(
+            byte_eta(1_000, 10_000, 2, 2, None, second),
+            Some(Duration::from_secs(9))
+        );
+    }
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(100_000);
+        let start = Instant::now();
+        // the first second's worth goes straight through
+        throttle.consume(100_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        throttle.consume(20_000);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_active_filters() {
+        let args = Args::parse_from([
+            "gdl",
+            "--tax-id",
+            "562",
+            "--exclude-mags",
+            "--assembly-level",
+            "Complete Genome",
+            "--after-date",
+            "2020-01-05",
+        ]);
+        assert_eq!(
+            active_filters(&args),
+            [
+                "--exclude-mags",
+                "--assembly-level Complete Genome",
+                "--after-date 2020/01/05"
+            ]
+        );
+        assert!(active_filters(&Args::parse_from(["gdl", "--tax-id", "562"])).is_empty());
+    }
+
+    #[test]
+    fn test_parse_tax_id() {
+        assert_eq!(parse_tax_id("562"), Ok("562".to_string()));
+        assert_eq!(parse_tax_id(" 0562 "), Ok("562".to_string()));
+        assert!(parse_tax_id("coli").is_err());
+        assert!(parse_tax_id("-562").is_err());
+        assert!(parse_tax_id("").is_err());
+    }
+
+    #[test]
+    fn test_parse_email() {
+        assert_eq!(
+            parse_email("someone@example.org"),
+            Ok("someone@example.org".to_string())
+        );
+        assert!(parse_email("someone").is_err());
+        assert!(parse_email("@example.org").is_err());
+        assert!(parse_email("someone@example").is_err());
+        assert!(parse_email("some one@example.org").is_err());
+    }
+
+    #[test]
+    fn test_exclusion_reasons() {
+        let assembly = NCBIAssembly {
+            excluded_from_refseq: "derived from metagenome; low quality sequence".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            assembly.exclusion_reasons(),
+            vec!["derived from metagenome", "low quality sequence"]
+        );
+
+        let assembly = NCBIAssembly {
+            excluded_from_refseq: "na".to_string(),
+            ..Default::default()
+        };
+        assert!(assembly.exclusion_reasons().is_empty());
+    }
+
+    #[test]
+    fn test_is_metagenome_derived() {
+        let mag = NCBIAssembly {
+            excluded_from_refseq: "derived from metagenome; fragmented assembly".to_string(),
+            ..Default::default()
+        };
+        assert!(mag.is_metagenome_derived());
+
+        let environmental = NCBIAssembly {
+            excluded_from_refseq: "Derived from environmental source".to_string(),
+            ..Default::default()
+        };
+        assert!(environmental.is_metagenome_derived());
+
+        let isolate = NCBIAssembly {
+            excluded_from_refseq: "fragmented assembly".to_string(),
+            ..Default::default()
+        };
+        assert!(!isolate.is_metagenome_derived());
+
+        let type_strain = NCBIAssembly {
+            excluded_from_refseq: "derived from metagenome".to_string(),
+            relation_to_type_material: "assembly from type material".to_string(),
+            ..Default::default()
+        };
+        assert!(!type_strain.is_metagenome_derived());
+    }
+
+    #[test]
+    fn test_gtdb_taxonomy() {
+        let tsv = "RS_GCF_000005845.2\td__Bacteria;p__Pseudomonadota;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Escherichia;s__Escherichia coli
+GB_GCA_000009605.1\td__Bacteria;p__Pseudomonadota;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Buchnera;s__Buchnera aphidicola
+RS_GCF_000007225.1\td__Archaea;p__Thermoproteota;c__Thermoprotei;o__Thermoproteales;f__Thermoproteaceae;g__Pyrobaculum;s__Pyrobaculum aerophilum
+";
+        let mut builder = GtdbTaxonomyBuilder::new();
+        gtdb_taxonomy_from_reader(tsv.as_bytes(), &mut builder);
+        let tax = builder.build();
+
+        assert_eq!(
+            tax.find_all_by_name("Enterobacteriaceae"),
+            vec!["f__Enterobacteriaceae"]
+        );
+        assert_eq!(tax.rank("g__Escherichia").unwrap(), TaxRank::Genus);
+        assert_eq!(tax.parent("d__Archaea").unwrap().unwrap().0, "root");
+
+        let mut descendants = tax.descendants("f__Enterobacteriaceae").unwrap();
+        descendants.retain(|tax_id| tax_id.starts_with("GC"));
+        descendants.sort();
+        assert_eq!(descendants, vec!["GCA_000009605.1", "GCF_000005845.2"]);
+
+        assert_eq!(gtdb_accession_key("GCA_000005845.1"), "000005845");
+        assert_eq!(gtdb_accession_key("GCF_000005845.2"), "000005845");
+
+        let lineage = "d__Bacteria;p__Pseudomonadota;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Buchnera;s__Buchnera aphidicola";
+        assert_eq!(gtdb_lineage(&tax, "GCA_000009605.1"), lineage);
+        assert_eq!(gtdb_lineage(&tax, "GCF_000009605.1"), lineage);
+        assert_eq!(gtdb_lineage(&tax, "GCF_000000001.1"), "");
+    }
+
+    #[test]
+    fn test_get_tax_id_synonym() {
+        let taxdump = tempdir().unwrap();
+        std::fs::write(
+            taxdump.path().join("names.dmp"),
+            "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+             1496\t|\tClostridioides difficile\t|\t\t|\tscientific name\t|\n\
+             1496\t|\tClostridium difficile\t|\t\t|\tsynonym\t|\n\
+             1496\t|\tHall and O'Toole\t|\t\t|\tauthority\t|\n",
+        )
+        .unwrap();
+
+        let tax = GeneralTaxonomy::from_arrays(
+            vec!["1".to_string(), "1496".to_string()],
+            vec![0, 0],
+            Some(vec![
+                "root".to_string(),
+                "Clostridioides difficile".to_string(),
+            ]),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let taxdump_path = taxdump.path().to_str();
+        assert_eq!(
+            get_tax_id(
+                None,
+                Some("Clostridium difficile"),
+                &tax,
+                taxdump_path,
+                &NameClass::Scientific
+            )
+            .ok(),
+            Some("1496")
+        );
+        assert_eq!(
+            get_tax_id(
+                None,
+                Some("Clostridioides difficile"),
+                &tax,
+                taxdump_path,
+                &NameClass::Scientific
+            )
+            .ok(),
+            Some("1496")
+        );
+        assert!(get_tax_id(
+            None,
+            Some("Clostridium difficile"),
+            &tax,
+            None,
+            &NameClass::Scientific
+        )
+        .is_err());
+        assert_eq!(
+            get_tax_id(Some("1496"), None, &tax, None, &NameClass::Scientific).ok(),
+            Some("1496")
+        );
+        assert!(get_tax_id(Some("999999"), None, &tax, None, &NameClass::Scientific).is_err());
+
+        // an authority string that happens to be the same as a name is only used when asked for
+        let find = |name: &str, name_class: NameClass| {
+            get_tax_id(None, Some(name), &tax, taxdump_path, &name_class)
+                .ok()
+                .map(str::to_string)
+        };
+        assert_eq!(find("Hall and O'Toole", NameClass::Scientific), None);
+        assert_eq!(
+            find("Hall and O'Toole", NameClass::Authority),
+            Some("1496".to_string())
+        );
+        assert_eq!(
+            find("Hall and O'Toole", NameClass::Any),
+            Some("1496".to_string())
+        );
+        assert_eq!(find("Clostridioides difficile", NameClass::Synonym), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "is missing the column(s) taxid, ftp_path")]
+    fn test_filter_assemblies_names_missing_columns() {
+        let summary = "##  See ftp://ftp.ncbi.nlm.nih.gov/genomes/README_assembly_summary.txt\n\
+            assembly_accession\ttax_id\torganism_name\tassembly_level\tseq_rel_date\texcluded_from_refseq\tversion_status\n\
+            GCF_000005845.2\t511145\tEscherichia coli\tComplete Genome\t2013/09/26\t\tlatest\n";
+
+        filter_assemblies(
+            summary.as_bytes(),
+            0,
+            "summary",
+            AssemblyFilter::default(),
+            &mut |_| {},
+        );
+    }
+
+    #[test]
+    fn test_skip_summary_comments() {
+        let header = "#assembly_accession\ttaxid\n";
+        for summary in [
+            format!("##  See ftp://ftp.ncbi.nlm.nih.gov/genomes/README_assembly_summary.txt\n{}GCF_000005845.2\t511145\n", header),
+            format!("## one\n## two\n# three\n\n{}GCF_000005845.2\t511145\n", header),
+            format!("{}GCF_000005845.2\t511145\n", header),
+        ] {
+            let mut contents = String::new();
+            skip_summary_comments(summary.as_bytes())
+                .unwrap()
+                .read_to_string(&mut contents)
+                .unwrap();
+            assert_eq!(contents, format!("{}GCF_000005845.2\t511145\n", header));
+        }
+    }
+
+    #[test]
+    fn test_filter_assemblies_skips_malformed_lines() {
+        let summary = "##  See ftp://ftp.ncbi.nlm.nih.gov/genomes/README_assembly_summary.txt\n\
+            #assembly_accession\ttaxid\torganism_name\tftp_path\tassembly_level\tseq_rel_date\texcluded_from_refseq\tversion_status\n\
+            GCF_000000001.1\t2\tBacteria\n\
+            GCF_000000002.1\t2\tBacteria\tna\tContig\t2013/09/26\t\tlatest\n";
+
+        let assemblies = filter_assemblies(
+            summary.as_bytes(),
+            0,
+            "summary",
+            AssemblyFilter::default(),
+            &mut |_| {},
+        );
+        assert_eq!(assemblies.len(), 1);
+        assert_eq!(assemblies[0].assembly_accession, "GCF_000000002.1");
+
+        let strict = AssemblyFilter {
+            strict_parse: true,
+            ..Default::default()
+        };
+        let result = std::panic::catch_unwind(|| {
+            filter_assemblies(summary.as_bytes(), 0, "summary", strict, &mut |_| {})
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_filter_assemblies_with_crlf_line_endings() {
+        let summary = "##  See ftp://ftp.ncbi.nlm.nih.gov/genomes/README_assembly_summary.txt\n\
+            #assembly_accession\ttaxid\torganism_name\tftp_path\tassembly_level\tseq_rel_date\texcluded_from_refseq\tversion_status\n\
+            GCF_000000001.1\t2\tBacteria\tna\tComplete Genome\t2013/09/26\t\tlatest\n\
+            GCF_000000002.1\t2\tBacteria\tna\tContig\t2013/09/26\t\treplaced\n";
+        let filter = || AssemblyFilter {
+            assembly_levels: Some(vec!["Complete Genome".to_string()]),
+            ..Default::default()
+        };
+
+        let unix = filter_assemblies(summary.as_bytes(), 0, "summary", filter(), &mut |_| {});
+        let windows = summary.replace('\n', "\r\n");
+        let windows = filter_assemblies(windows.as_bytes(), 0, "summary", filter(), &mut |_| {});
+        assert_eq!(windows.len(), 1);
+        assert_eq!(
+            serde_json::to_string(&windows).unwrap(),
+            serde_json::to_string(&unix).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_filter_expr() {
+        let complete = NCBIAssembly {
+            assembly_level: "Complete Genome".to_string(),
+            refseq_category: "reference genome".to_string(),
+            ..Default::default()
+        };
+        let contig = NCBIAssembly {
+            assembly_level: "Contig".to_string(),
+            refseq_category: "na".to_string(),
+            taxid: "562".to_string(),
+            ..Default::default()
+        };
+        let matches = |expr: &str, assembly: &NCBIAssembly| {
+            parse_filter_expr(expr).unwrap().matches(assembly)
+        };
+
+        let expr = "assembly_level == 'Complete Genome' && refseq_category != 'na'";
+        assert!(matches(expr, &complete));
+        assert!(!matches(expr, &contig));
+        assert!(matches(
+            "taxid == 562 || assembly_level == \"Complete Genome\"",
+            &contig
+        ));
+        assert!(matches(
+            "taxid == 562 || assembly_level == \"Complete Genome\"",
+            &complete
+        ));
+        // && binds tighter than ||
+        assert!(matches("taxid == 1 && taxid == 2 || taxid == 562", &contig));
+        assert!(!matches(
+            "taxid == 1 && (taxid == 2 || taxid == 562)",
+            &contig
+        ));
+
+        for column in FILTER_COLUMNS {
+            assert!(complete.column(column).is_some());
+        }
+
+        assert!(parse_filter_expr("species == 'coli'").is_err());
+        assert!(parse_filter_expr("taxid = 562").is_err());
+        assert!(parse_filter_expr("taxid == '562").is_err());
+        assert!(parse_filter_expr("(taxid == 562").is_err());
+        assert!(parse_filter_expr("taxid == 562)").is_err());
+        assert!(parse_filter_expr("taxid == 562 taxid == 2").is_err());
+        assert!(parse_filter_expr("taxid ==").is_err());
+        assert!(parse_filter_expr("").is_err());
+    }
+
+    #[test]
+    fn test_filter_assemblies_parses_accession() {
+        let summary = "##  See ftp://ftp.ncbi.nlm.nih.gov/genomes/README_assembly_summary.txt\n\
+            #assembly_accession\ttaxid\torganism_name\tftp_path\tassembly_level\tseq_rel_date\texcluded_from_refseq\tversion_status\n\
+            GCF_000005845.2\t511145\tEscherichia coli\thttps://ftp.ncbi.nlm.nih.gov/genomes/all/GCF/000/005/845/GCF_000005845.2_ASM584v2\tComplete Genome\t2013/09/26\t\tlatest\n\
+            GCF_000000001.1\t2\tBacteria\tna\tContig\t2013/09/26\t\tlatest\n";
+
+        let filter = AssemblyFilter {
+            tax_ids: Some(HashSet::from(["511145"])),
+            ..Default::default()
+        };
+        let assemblies = filter_assemblies(summary.as_bytes(), 0, "summary", filter, &mut |_| {});
+
+        assert_eq!(assemblies.len(), 1);
+        assert_eq!(assemblies[0].assembly_accession, "GCF_000005845.2");
+        assert!(!assemblies[0].is_suppressed());
+        assert_eq!(assemblies[0].accession_version(), 2);
+    }
+
+    #[test]
+    fn test_filter_by_submitter() {
+        let summary = "##  See ftp://ftp.ncbi.nlm.nih.gov/genomes/README_assembly_summary.txt\n\
+            #assembly_accession\ttaxid\torganism_name\tftp_path\tassembly_level\tseq_rel_date\texcluded_from_refseq\tversion_status\tasm_submitter\n\
+            GCF_000000001.1\t2\tBacteria\tna\tContig\t2013/09/26\t\tlatest\tWellcome Sanger Institute\n\
+            GCF_000000002.1\t2\tBacteria\tna\tContig\t2013/09/26\t\tlatest\tBroad Institute\n";
+
+        let filter = AssemblyFilter {
+            submitter: Some("sanger".to_string()),
+            ..Default::default()
+        };
+        let assemblies = filter_assemblies(summary.as_bytes(), 0, "summary", filter, &mut |_| {});
+
+        assert_eq!(assemblies.len(), 1);
+        assert_eq!(assemblies[0].assembly_accession, "GCF_000000001.1");
+        assert_eq!(
+            assemblies[0].column("submitter"),
+            Some("Wellcome Sanger Institute")
+        );
+    }
+
+    #[test]
+    fn test_filter_by_organism_name() {
+        let summary = "##  See ftp://ftp.ncbi.nlm.nih.gov/genomes/README_assembly_summary.txt\n\
+            #assembly_accession\ttaxid\torganism_name\tinfraspecific_name\tftp_path\tassembly_level\tseq_rel_date\texcluded_from_refseq\tversion_status\n\
+            GCF_000000001.1\t562\tEscherichia coli O157:H7 str. Sakai\tstrain=Sakai\tna\tContig\t2013/09/26\t\tlatest\n\
+            GCF_000000002.1\t562\tEscherichia coli\tstrain=EDL933 (O157)\tna\tContig\t2013/09/26\t\tlatest\n\
+            GCF_000000003.1\t562\tEscherichia coli\tstrain=K-12\tna\tContig\t2013/09/26\t\tlatest\n\
+            GCF_000000004.1\t562\tEscherichia coli\tstrain=O104:H4\tna\tContig\t2013/09/26\t\tlatest\n";
+
+        let matching = |names: &[&str]| {
+            let filter = AssemblyFilter {
+                organism_names: names.iter().map(|name| name.to_lowercase()).collect(),
+                ..Default::default()
+            };
+            filter_assemblies(summary.as_bytes(), 0, "summary", filter, &mut |_| {})
+                .into_iter()
+                .map(|assembly| assembly.assembly_accession)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching(&["o157"]), ["GCF_000000001.1", "GCF_000000002.1"]);
+        assert_eq!(
+            matching(&["O157", "k-12"]),
+            ["GCF_000000001.1", "GCF_000000002.1", "GCF_000000003.1"]
+        );
+        assert_eq!(matching(&[]).len(), 4);
+    }
+
+    #[test]
+    fn test_filter_funnel() {
+        let filter = AssemblyFilter {
+            tax_ids: Some(HashSet::from(["562"])),
+            assembly_levels: Some(vec!["Complete Genome".to_string()]),
+            ..Default::default()
+        };
+        let mut n_removed = [0; FILTER_STAGES.len()];
+        n_removed[FilterStage::Taxon as usize] = 458_000;
+        n_removed[FilterStage::AssemblyLevel as usize] = 34_000;
+        n_removed[FilterStage::Suppressed as usize] = 200;
+
+        assert_eq!(
+            filter_funnel(500_000, &n_removed, &filter),
+            [
+                (FilterStage::Taxon, 42_000),
+                (FilterStage::AssemblyLevel, 8_000),
+                (FilterStage::Suppressed, 7_800)
+            ]
+        );
+        let everything = AssemblyFilter {
+            include_suppressed: true,
+            ..Default::default()
+        };
+        assert!(filter_funnel(10, &[0; FILTER_STAGES.len()], &everything).is_empty());
+    }
+
+    #[test]
+    fn test_filter_by_pairing() {
+        let summary = "#assembly_accession\ttaxid\torganism_name\tftp_path\tassembly_level\tseq_rel_date\texcluded_from_refseq\tversion_status\tgbrs_paired_asm\tpaired_asm_comp\n\
+            GCA_000000001.1\t2\tBacteria\tna\tContig\t2013/09/26\t\tlatest\tGCF_000000001.1\tidentical\n\
+            GCA_000000002.1\t2\tBacteria\tna\tContig\t2013/09/26\t\tlatest\tna\tna\n";
+        let accessions = |filter| {
+            filter_assemblies(summary.as_bytes(), 0, "summary", filter, &mut |_| {})
+                .into_iter()
+                .map(|assembly| assembly.assembly_accession)
+                .collect::<Vec<_>>()
+        };
+
+        let paired = accessions(AssemblyFilter {
+            paired_only: true,
+            ..Default::default()
+        });
+        assert_eq!(paired, ["GCA_000000001.1"]);
+        let unpaired = accessions(AssemblyFilter {
+            unpaired_only: true,
+            ..Default::default()
+        });
+        assert_eq!(unpaired, ["GCA_000000002.1"]);
+        assert_eq!(accessions(AssemblyFilter::default()).len(), 2);
+    }
+
+    #[test]
+    fn test_since_accession() {
+        let tmp_dir = tempdir().unwrap();
+        let manifest_path = tmp_dir.path().join("manifest.tsv");
+        std::fs::write(
+            &manifest_path,
+            "accession\tformat\tpath\n\
+            GCF_000005845.2\tfna\tGCF_000005845.2_ASM584v2.fna.gz\n\
+            GCF_000005845.2\tgff\tGCF_000005845.2_ASM584v2.gff.gz\n\
+            GCF_000001405.40\tfna\tGCF_000001405.40_GRCh38.p14.fna.gz\n",
+        )
+        .unwrap();
+        let versions = read_manifest_versions(manifest_path.to_str().unwrap());
+
+        let is_newer = |accession: &str| {
+            let assembly = NCBIAssembly {
+                assembly_accession: accession.to_string(),
+                ..Default::default()
+            };
+            is_newer_than(&assembly, &versions)
+        };
+        assert!(!is_newer("GCF_000005845.2"));
+        assert!(is_newer("GCF_000005845.3"));
+        assert!(!is_newer("GCF_000001405.39"));
+        assert!(is_newer("GCF_000006945.2"));
+        // the GenBank accession is a different assembly as far as the manifest is concerned
+        assert!(is_newer("GCA_000005845.2"));
+    }
+
+    #[test]
+    fn test_accession_version_policy() {
+        let assemblies: Vec<NCBIAssembly> =
+            ["GCF_000005845.1", "GCF_000005845.2", "GCF_000001405.39"]
+                .iter()
+                .map(|accession| NCBIAssembly {
+                    assembly_accession: accession.to_string(),
+                    ..Default::default()
+                })
+                .collect();
+        let accessions =
+            HashSet::from(["GCF_000005845".to_string(), "GCF_000001405.39".to_string()]);
+        let matching = |policy: AccessionVersionPolicy| {
+            let mut matched: Vec<NCBIAssembly> = assemblies
+                .iter()
+                .filter(|assembly| matches_accession(&accessions, assembly, &policy))
+                .cloned()
+                .collect();
+            if policy == AccessionVersionPolicy::Latest {
+                keep_latest_versions(&mut matched, &accessions);
+            }
+            matched
+                .into_iter()
+                .map(|assembly| assembly.assembly_accession)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            matching(AccessionVersionPolicy::Any),
+            ["GCF_000005845.1", "GCF_000005845.2", "GCF_000001405.39"]
+        );
+        assert_eq!(
+            matching(AccessionVersionPolicy::Latest),
+            ["GCF_000005845.2", "GCF_000001405.39"]
+        );
+        assert_eq!(
+            matching(AccessionVersionPolicy::Exact),
+            ["GCF_000001405.39"]
+        );
+
+        let requested = HashSet::from([
+            "GCF_000005845".to_string(),
+            "GCF_000001405.40".to_string(),
+            "GCF_000001405.39".to_string(),
+        ]);
+        assert_eq!(
+            missing_accessions(&requested, &assemblies, &AccessionVersionPolicy::Any),
+            ["GCF_000001405.40"]
+        );
+        assert_eq!(
+            missing_accessions(&requested, &assemblies, &AccessionVersionPolicy::Exact),
+            ["GCF_000001405.40", "GCF_000005845"]
+        );
+    }
+
+    #[test]
+    fn test_filter_cache() {
+        let tmp_dir = tempdir().unwrap();
+        let summary_path = tmp_dir.path().join("assembly_summary_refseq.txt");
+        std::fs::write(&summary_path, "a summary").unwrap();
+        let summary_path = summary_path.to_str().unwrap();
+        let cache_path = tmp_dir.path().join(FILTER_CACHE_FILENAME);
+
+        let filter = AssemblyFilter {
+            tax_ids: Some(HashSet::from(["562", "2", "1496"])),
+            ..Default::default()
+        };
+        let key = filter_cache_key(&filter, summary_path).unwrap();
+        let same_filter = AssemblyFilter {
+            tax_ids: Some(HashSet::from(["1496", "562", "2"])),
+            ..Default::default()
+        };
+        assert_eq!(filter_cache_key(&same_filter, summary_path).unwrap(), key);
+        let other_filter = AssemblyFilter {
+            type_material_only: true,
+            ..filter.clone()
+        };
+        assert_ne!(filter_cache_key(&other_filter, summary_path).unwrap(), key);
+
+        let assemblies = vec![NCBIAssembly {
+            assembly_accession: "GCF_000005845.2".to_string(),
+            taxid: "562".to_string(),
+            organism_name: "Escherichia coli".to_string(),
+            ..Default::default()
+        }];
+        write_filter_cache(&cache_path, &key, &assemblies).unwrap();
+
+        let cached = read_filter_cache(&cache_path, &key).unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].assembly_accession, "GCF_000005845.2");
+        assert_eq!(cached[0].organism_name, "Escherichia coli");
+        assert!(read_filter_cache(&cache_path, "some other key").is_none());
+
+        std::fs::write(summary_path, "a newer summary").unwrap();
+        assert_ne!(filter_cache_key(&filter, summary_path).unwrap(), key);
+    }
+
+    #[test]
+    fn test_lineage_string() {
+        let tmp_dir = tempdir().unwrap();
+        std::fs::write(
+            tmp_dir.path().join("nodes.dmp"),
+            "1\t|\t1\t|\tno rank\t|\t\t|\t8\t|\t0\t|\t1\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|\n\
+             2\t|\t1\t|\tsuperkingdom\t|\t\t|\t0\t|\t0\t|\t11\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|\n\
+             562\t|\t2\t|\tspecies\t|\tEC\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp_dir.path().join("names.dmp"),
+            "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+             2\t|\tBacteria\t|\t\t|\tscientific name\t|\n\
+             562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp_dir.path().join(RANKED_LINEAGE_FILENAME),
+            "562\t|\tEscherichia coli\t|\t\t|\tEscherichia\t|\tEnterobacteriaceae\t|\t\t|\t\t|\t\t|\t\t|\tBacteria\t|\n",
+        )
+        .unwrap();
+        let path = tmp_dir.path().to_str().unwrap();
+        let tax = load_taxonomy(path).unwrap();
+
+        assert_eq!(
+            lineage_string(&tax, "562", None),
+            "Bacteria; Escherichia coli"
+        );
+        assert_eq!(lineage_string(&tax, "12345", None), "");
+
+        let pairs = |lineage: Vec<(String, String)>| {
+            lineage
+                .into_iter()
+                .map(|(rank, name)| format!("{}:{}", rank, name))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            pairs(lineage(&tax, "562").unwrap()),
+            [
+                "species:Escherichia coli",
+                "superkingdom:Bacteria",
+                "no rank:root"
+            ]
+        );
+        assert_eq!(pairs(lineage(&tax, "1").unwrap()), ["no rank:root"]);
+        assert!(matches!(
+            lineage(&tax, "12345"),
+            Err(GdlError::TaxonNotFound(_))
+        ));
+
+        let ranked_lineages = load_ranked_lineages(path, &["562"]);
+        assert_eq!(
+            lineage_string(&tax, "562", Some(&ranked_lineages)),
+            "Bacteria; Enterobacteriaceae; Escherichia; Escherichia coli"
+        );
+    }
+
+    #[test]
+    fn test_assembly_sources() {
+        assert_eq!(AssemblySource::None.summaries(true), Vec::new());
+        assert_eq!(AssemblySource::None.as_str(), None);
+        assert_eq!(AssemblySource::All.url(), None);
+
+        let names = |source: AssemblySource, include_historical| -> Vec<&str> {
+            source
+                .summaries(include_historical)
+                .iter()
+                .map(|summary| summary.name)
+                .collect()
+        };
+        assert_eq!(names(AssemblySource::All, false), ["refseq", "genbank"]);
+        assert_eq!(
+            names(AssemblySource::All, true),
+            [
+                "refseq",
+                "refseq_historical",
+                "genbank",
+                "genbank_historical"
+            ]
+        );
+        let historical = AssemblySource::Genbank.summaries(true)[1];
+        assert_eq!(historical.origin, "genbank");
+        assert!(historical.historical);
+
+        let rows = source_rows();
+        assert_eq!(rows[0], ["source", "summary", "historical", "url"]);
+        assert!(rows
+            .iter()
+            .any(|row| row[0] == "none" && row[1] == "--assembly-summary-path"));
+        assert_eq!(rows.iter().filter(|row| row[0] == "all").count(), 4);
+    }
+
+    #[test]
+    fn test_taxa_rows() {
+        let tmp_dir = tempdir().unwrap();
+        std::fs::write(
+            tmp_dir.path().join("nodes.dmp"),
+            "1\t|\t1\t|\tno rank\t|\t\t|\t8\t|\t0\t|\t1\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|\n\
+             2\t|\t1\t|\tsuperkingdom\t|\t\t|\t0\t|\t0\t|\t11\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|\n\
+             561\t|\t2\t|\tgenus\t|\t\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n\
+             562\t|\t561\t|\tspecies\t|\tEC\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n\
+             1496\t|\t2\t|\tspecies\t|\tCD\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp_dir.path().join("names.dmp"),
+            "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+             2\t|\tBacteria\t|\t\t|\tscientific name\t|\n\
+             561\t|\tEscherichia\t|\t\t|\tscientific name\t|\n\
+             562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|\n\
+             1496\t|\tClostridioides difficile\t|\t\t|\tscientific name\t|\n",
+        )
+        .unwrap();
+        let tax = load_taxonomy(tmp_dir.path().to_str().unwrap()).unwrap();
+        let descendant_tax_ids = HashSet::from(["2", "561", "562", "1496"]);
+
+        let rows = taxa_rows(&tax, "2", &descendant_tax_ids);
+        let lines: Vec<String> = rows.iter().map(|row| row.join("\t")).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "2\tBacteria\tsuperkingdom\tBacteria",
+                "1496\tClostridioides difficile\tspecies\tBacteria; Clostridioides difficile",
+                "561\tEscherichia\tgenus\tBacteria; Escherichia",
+                "562\tEscherichia coli\tspecies\tBacteria; Escherichia; Escherichia coli",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summary_stats() {
+        let assemblies = [
+            ("Complete Genome", "reference genome", "bacteria"),
+            ("Contig", "na", "bacteria"),
+            ("Complete Genome", "na", "viral"),
+            ("Complete Genome", "", ""),
+        ]
+        .map(|(assembly_level, refseq_category, group)| NCBIAssembly {
+            assembly_level: assembly_level.to_string(),
+            refseq_category: refseq_category.to_string(),
+            group: group.to_string(),
+            ..Default::default()
+        });
+
+        let row = |column, value: &str, count| (column, value.to_string(), count);
+        assert_eq!(
+            summary_stats(assemblies),
+            vec![
+                row("assembly_level", "Complete Genome", 3),
+                row("assembly_level", "Contig", 1),
+                row("refseq_category", "na", 3),
+                row("refseq_category", "reference genome", 1),
+                row("group", "bacteria", 2),
+                row("group", "na", 1),
+                row("group", "viral", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_in_rank_window() {
+        let tmp_dir = tempdir().unwrap();
+        // a clade without a rank between the genus and its species, and a strain
+        std::fs::write(
+            tmp_dir.path().join("nodes.dmp"),
+            "1\t|\t1\t|\tno rank\t|\t\t|\t8\t|\t0\t|\t1\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|\n\
+             561\t|\t1\t|\tgenus\t|\t\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n\
+             1000\t|\t561\t|\tno rank\t|\t\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n\
+             562\t|\t1000\t|\tspecies\t|\t\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n\
+             83333\t|\t562\t|\tstrain\t|\t\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp_dir.path().join("names.dmp"),
+            "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+             561\t|\tEscherichia\t|\t\t|\tscientific name\t|\n\
+             1000\t|\tsome clade\t|\t\t|\tscientific name\t|\n\
+             562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|\n\
+             83333\t|\tEscherichia coli K-12\t|\t\t|\tscientific name\t|\n",
+        )
+        .unwrap();
+        let tax = load_taxonomy(tmp_dir.path().to_str().unwrap()).unwrap();
+        let window = |below, above| {
+            ["561", "1000", "562", "83333"]
+                .into_iter()
+                .filter(|tax_id| in_rank_window(&tax, tax_id, below, above))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(window(Some(TaxRank::Species), None), ["562", "83333"]);
+        assert_eq!(window(None, Some(TaxRank::Species)), ["561", "1000", "562"]);
+        assert_eq!(
+            window(Some(TaxRank::Genus), Some(TaxRank::Species)),
+            ["561", "1000", "562"]
+        );
+        assert_eq!(window(None, None), ["561", "1000", "562", "83333"]);
+    }
+
+    #[test]
+    fn test_count_by_rank() {
+        let tmp_dir = tempdir().unwrap();
+        std::fs::write(
+            tmp_dir.path().join("nodes.dmp"),
+            "1\t|\t1\t|\tno rank\t|\t\t|\t8\t|\t0\t|\t1\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|\n\
+             2\t|\t1\t|\tsuperkingdom\t|\t\t|\t0\t|\t0\t|\t11\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|\n\
+             561\t|\t2\t|\tgenus\t|\t\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n\
+             562\t|\t561\t|\tspecies\t|\tEC\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n\
+             1496\t|\t2\t|\tspecies\t|\tCD\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp_dir.path().join("names.dmp"),
+            "1\t|\troot\t|\t\t|\tscientific name\t|\n\
+             2\t|\tBacteria\t|\t\t|\tscientific name\t|\n\
+             561\t|\tEscherichia\t|\t\t|\tscientific name\t|\n\
+             562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|\n\
+             1496\t|\tClostridioides difficile\t|\t\t|\tscientific name\t|\n",
+        )
+        .unwrap();
+        let tax = load_taxonomy(tmp_dir.path().to_str().unwrap()).unwrap();
+        let assemblies: Vec<NCBIAssembly> = ["562", "561", "1496", "562"]
+            .into_iter()
+            .map(|taxid| NCBIAssembly {
+                taxid: taxid.to_string(),
+                ..Default::default()
+            })
+            .collect();
+
+        assert_eq!(
+            count_by_rank(&tax, &assemblies, TaxRank::Genus, &TaxonomyBackend::Ncbi),
+            vec![
+                ("561".to_string(), "Escherichia".to_string(), 3),
+                ("NA".to_string(), "unclassified".to_string(), 1),
+            ]
+        );
+        assert_eq!(parse_rank("Genus"), Ok(TaxRank::Genus));
+        assert!(parse_rank("not a rank").is_err());
+    }
+
+    #[test]
+    fn test_sort_assemblies() {
+        let assembly = |accession: &str, taxid: &str, assembly_level: &str| NCBIAssembly {
+            assembly_accession: accession.to_string(),
+            taxid: taxid.to_string(),
+            assembly_level: assembly_level.to_string(),
+            ..Default::default()
+        };
+        let assemblies = vec![
+            assembly("GCF_000000003.1", "562", "Contig"),
+            assembly("GCF_000000001.1", "1496", "Complete Genome"),
+            assembly("GCF_000000002.1", "562", "Complete Genome"),
+        ];
+        let level_preference: Vec<String> = ["Complete Genome", "Chromosome", "Scaffold", "Contig"]
+            .iter()
+            .map(|level| level.to_string())
+            .collect();
+        let sorted = |order: SortOrder| -> Vec<String> {
+            let mut assemblies = assemblies.clone();
+            sort_assemblies(&mut assemblies, &order, &level_preference);
+            assemblies
+                .into_iter()
+                .map(|assembly| assembly.assembly_accession)
+                .collect()
+        };
+
+        assert_eq!(
+            sorted(SortOrder::Taxid),
+            ["GCF_000000002.1", "GCF_000000003.1", "GCF_000000001.1"]
+        );
+        assert_eq!(
+            sorted(SortOrder::Accession),
+            ["GCF_000000001.1", "GCF_000000002.1", "GCF_000000003.1"]
+        );
+        assert_eq!(
+            sorted(SortOrder::Level),
+            ["GCF_000000001.1", "GCF_000000002.1", "GCF_000000003.1"]
+        );
+        assert_eq!(
+            sorted(SortOrder::None),
+            ["GCF_000000003.1", "GCF_000000001.1", "GCF_000000002.1"]
+        );
+    }
+
+    #[test]
+    fn test_limit_assemblies() {
+        let assembly = |accession: &str, level: &str, category: &str, date: &str| NCBIAssembly {
+            assembly_accession: accession.to_string(),
+            assembly_level: level.to_string(),
+            refseq_category: category.to_string(),
+            seq_rel_date: date.to_string(),
+            version_status: "latest".to_string(),
+            ..Default::default()
+        };
+        let assemblies = vec![
+            assembly(
+                "GCF_000000001.1",
+                "Contig",
+                "reference genome",
+                "2020/01/01",
+            ),
+            assembly("GCF_000000002.1", "Complete Genome", "na", "2019/01/01"),
+            assembly("GCF_000000003.1", "Complete Genome", "na", "2021/01/01"),
+            assembly(
+                "GCF_000000004.1",
+                "Complete Genome",
+                "representative genome",
+                "2018/01/01",
+            ),
+            NCBIAssembly {
+                version_status: "replaced".to_string(),
+                ..assembly(
+                    "GCF_000000005.1",
+                    "Complete Genome",
+                    "reference genome",
+                    "2022/01/01",
+                )
+            },
+        ];
+        let level_preference: Vec<String> = ["Complete Genome", "Chromosome", "Scaffold", "Contig"]
+            .iter()
+            .map(|level| level.to_string())
+            .collect();
+        let accessions = |limit: usize, priority: Option<&[String]>| -> Vec<String> {
+            limit_assemblies(assemblies.clone(), limit, priority)
+                .into_iter()
+                .map(|assembly| assembly.assembly_accession)
+                .collect()
+        };
+
+        assert_eq!(accessions(2, None), ["GCF_000000001.1", "GCF_000000002.1"]);
+        // the best two, in the order they came in
+        assert_eq!(
+            accessions(2, Some(&level_preference)),
+            ["GCF_000000003.1", "GCF_000000004.1"]
+        );
+        assert_eq!(
+            accessions(3, Some(&level_preference)),
+            ["GCF_000000002.1", "GCF_000000003.1", "GCF_000000004.1"]
+        );
+        assert_eq!(accessions(10, Some(&level_preference)).len(), 5);
+    }
+
+    #[test]
+    fn test_unique_by_taxid() {
+        let assembly = |accession: &str, taxid: &str, assembly_level: &str| NCBIAssembly {
+            assembly_accession: accession.to_string(),
+            taxid: taxid.to_string(),
+            assembly_level: assembly_level.to_string(),
+            ..Default::default()
+        };
+
+        let assemblies = vec![
+            assembly("GCF_000000001.1", "1", "Complete Genome"),
+            assembly("GCF_000000002.2", "1", "Contig"),
+            assembly("GCF_000000003.1", "2", "Contig"),
+            assembly("GCF_000000004.1", "2", "Chromosome"),
+            assembly("GCF_000000005.1", "2", "Chromosome"),
+        ];
+
+        let unique_accessions = |level_preference: &[&str]| -> Vec<String> {
+            let level_preference: Vec<String> = level_preference
+                .iter()
+                .map(|level| level.to_string())
+                .collect();
+            unique_by_taxid(assemblies.clone(), &level_preference)
+                .into_iter()
+                .map(|assembly| assembly.assembly_accession)
+                .collect()
+        };
+        let default_preference = ["Complete Genome", "Chromosome", "Scaffold", "Contig"];
+        assert_eq!(
+            unique_accessions(&default_preference),
+            vec!["GCF_000000002.2", "GCF_000000004.1"]
+        );
+        // the version still comes first
+        assert_eq!(
+            unique_accessions(&["Contig", "Chromosome"]),
+            vec!["GCF_000000002.2", "GCF_000000003.1"]
+        );
+
+        let level_preference: Vec<String> = default_preference
+            .iter()
+            .map(|level| level.to_string())
+            .collect();
+        let capped: Vec<String> = max_per_taxid(assemblies.clone(), 2, &level_preference)
+            .into_iter()
+            .map(|assembly| assembly.assembly_accession)
+            .collect();
+        assert_eq!(
+            capped,
+            vec![
+                "GCF_000000001.1",
+                "GCF_000000002.2",
+                "GCF_000000004.1",
+                "GCF_000000005.1"
+            ]
+        );
+
+        // GCF_000000002 stands for every version, and GCF_000000005.1 isn't in any cluster
+        let clusters: HashMap<String, String> = [
+            ("GCF_000000001.1", "a"),
+            ("GCF_000000002", "b"),
+            ("GCF_000000003.1", "a"),
+            ("GCF_000000004.1", "b"),
+        ]
+        .into_iter()
+        .map(|(accession, cluster)| (accession.to_string(), cluster.to_string()))
+        .collect();
+        let by_cluster = |keep_unclustered| {
+            let (kept, n_unclustered) = unique_by_cluster(
+                assemblies.clone(),
+                &clusters,
+                keep_unclustered,
+                &level_preference,
+            );
+            let accessions: Vec<String> = kept
+                .into_iter()
+                .map(|assembly| assembly.assembly_accession)
+                .collect();
+            (accessions, n_unclustered)
+        };
+        assert_eq!(
+            by_cluster(false),
+            (
+                vec!["GCF_000000001.1".to_string(), "GCF_000000002.2".to_string()],
+                1
+            )
+        );
+        assert_eq!(
+            by_cluster(true).0,
+            vec!["GCF_000000001.1", "GCF_000000002.2", "GCF_000000005.1"]
+        );
+    }
+}
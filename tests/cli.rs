@@ -0,0 +1,1983 @@
+use clap::Parser;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use gdl::Args;
+use httpmock::Method::{GET, HEAD};
+use httpmock::{Mock, MockServer};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::{tempdir, TempDir};
+
+const NODES_DMP: &str =
+    "1\t|\t1\t|\tno rank\t|\t\t|\t8\t|\t0\t|\t1\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|
+2\t|\t1\t|\tsuperkingdom\t|\t\t|\t0\t|\t0\t|\t11\t|\t0\t|\t0\t|\t0\t|\t0\t|\t0\t|\t\t|
+562\t|\t2\t|\tspecies\t|\tEC\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|
+1496\t|\t2\t|\tspecies\t|\tCD\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|
+316407\t|\t562\t|\tstrain\t|\tEC\t|\t0\t|\t1\t|\t11\t|\t1\t|\t0\t|\t1\t|\t1\t|\t0\t|\t\t|
+";
+
+const NAMES_DMP: &str = "1\t|\troot\t|\t\t|\tscientific name\t|
+2\t|\tBacteria\t|\tBacteria <bacteria>\t|\tscientific name\t|
+562\t|\tEscherichia coli\t|\t\t|\tscientific name\t|
+1496\t|\tClostridioides difficile\t|\t\t|\tscientific name\t|
+316407\t|\tEscherichia coli str. K-12 substr. W3110\t|\t\t|\tscientific name\t|
+";
+
+const ASSEMBLY_SUMMARY_HEADER: &str = "##  See ftp://ftp.ncbi.nlm.nih.gov/genomes/README_assembly_summary.txt for a description of the columns in this file.
+#assembly_accession\ttaxid\torganism_name\tassembly_level\tversion_status\tseq_rel_date\tftp_path\texcluded_from_refseq\trelation_to_type_material
+";
+
+// the gzipped contents of an assembly's fna
+fn genome(accession: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    write!(encoder, "{} genome", accession).unwrap();
+    encoder.finish().unwrap()
+}
+
+// taxdump.tar.gz with NODES_DMP and NAMES_DMP
+fn taxdump() -> Vec<u8> {
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (name, contents) in [("nodes.dmp", NODES_DMP), ("names.dmp", NAMES_DMP)] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive
+            .append_data(&mut header, name, contents.as_bytes())
+            .unwrap();
+    }
+    archive.into_inner().unwrap().finish().unwrap()
+}
+
+// a fake NCBI serving a taxdump, an assembly summary and the assemblies in it
+struct FakeNcbi {
+    server: MockServer,
+    dir: TempDir,
+}
+
+impl FakeNcbi {
+    fn start() -> Self {
+        FakeNcbi {
+            server: MockServer::start(),
+            dir: tempdir().unwrap(),
+        }
+    }
+
+    fn taxdump(&self) -> Mock<'_> {
+        self.delayed_taxdump(Duration::ZERO)
+    }
+
+    fn delayed_taxdump(&self, delay: Duration) -> Mock<'_> {
+        let taxdump = taxdump();
+        self.server.mock(|when, then| {
+            when.method(GET).path("/pub/taxonomy/taxdump.tar.gz");
+            then.status(200).body(taxdump).delay(delay);
+        })
+    }
+
+    // `assemblies` are (accession, taxid, assembly level)
+    fn assembly_summary(&self, assemblies: &[(&str, &str, &str)]) -> Mock<'_> {
+        self.delayed_assembly_summary(assemblies, Duration::ZERO)
+    }
+
+    fn delayed_assembly_summary(
+        &self,
+        assemblies: &[(&str, &str, &str)],
+        delay: Duration,
+    ) -> Mock<'_> {
+        let assemblies: Vec<_> = assemblies
+            .iter()
+            .map(|&(accession, taxid, assembly_level)| (accession, taxid, assembly_level, "latest"))
+            .collect();
+        self.assembly_summary_with_status(&assemblies, delay)
+    }
+
+    // `assemblies` are (accession, taxid, assembly level, version status)
+    fn assembly_summary_with_status(
+        &self,
+        assemblies: &[(&str, &str, &str, &str)],
+        delay: Duration,
+    ) -> Mock<'_> {
+        let summary = self.summary(assemblies);
+        self.server.mock(|when, then| {
+            when.method(GET).path("/assembly_summary_refseq.txt");
+            then.status(200).body(summary).delay(delay);
+        })
+    }
+
+    // `assemblies` are (accession, taxid, assembly level, version status)
+    fn summary(&self, assemblies: &[(&str, &str, &str, &str)]) -> String {
+        let mut summary = ASSEMBLY_SUMMARY_HEADER.to_string();
+        for (accession, taxid, assembly_level, version_status) in assemblies {
+            summary.push_str(&format!(
+                "{}\t{}\tsome organism\t{}\t{}\t2020/01/01\t{}\t\t{}\n",
+                accession,
+                taxid,
+                assembly_level,
+                version_status,
+                self.server.url(format!("/genomes/all/{}", accession)),
+                // complete genomes stand in for type strains
+                if *assembly_level == "Complete Genome" {
+                    "assembly from type material"
+                } else {
+                    "na"
+                }
+            ));
+        }
+        summary
+    }
+
+    fn assembly(&self, accession: &str, status: u16) -> Mock<'_> {
+        self.delayed_assembly(accession, status, Duration::ZERO)
+    }
+
+    fn delayed_assembly(&self, accession: &str, status: u16, delay: Duration) -> Mock<'_> {
+        self.server.mock(|when, then| {
+            when.method(GET).path(format!(
+                "/genomes/all/{}/{}_genomic.fna.gz",
+                accession, accession
+            ));
+            then.status(status).body(genome(accession)).delay(delay);
+        })
+    }
+
+    // a 200 that isn't the genome, like the error pages NCBI serves during maintenance
+    fn error_page(&self, accession: &str) -> Mock<'_> {
+        self.server.mock(|when, then| {
+            when.method(GET).path(format!(
+                "/genomes/all/{}/{}_genomic.fna.gz",
+                accession, accession
+            ));
+            then.status(200)
+                .body("<html><body>Service unavailable</body></html>");
+        })
+    }
+
+    fn checksums(&self, accession: &str, md5: &str) -> Mock<'_> {
+        self.server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/genomes/all/{}/md5checksums.txt", accession));
+            then.status(200)
+                .body(format!("{}  ./{}_genomic.fna.gz\n", md5, accession));
+        })
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.dir.path().join(name).to_string_lossy().into_owned()
+    }
+
+    fn run(&self, extra_args: &[&str]) {
+        let summary_url = self.server.url("/assembly_summary_refseq.txt");
+        let mut args = vec!["--assembly-summary-url", &summary_url];
+        args.extend_from_slice(extra_args);
+        self.run_with_cached_summaries(&args);
+    }
+
+    // uses whichever summaries are already in the cache directory
+    fn run_with_cached_summaries(&self, extra_args: &[&str]) {
+        let taxdump_url = self.server.url("/pub/taxonomy/taxdump.tar.gz");
+        let taxdump_path = self.path("taxdump");
+        let out_dir = self.path("out");
+        let cache_dir = self.dir.path().to_string_lossy().into_owned();
+
+        let mut args = vec![
+            "gdl",
+            "--taxdump-url",
+            &taxdump_url,
+            "--taxdump-path",
+            &taxdump_path,
+            "--cache-dir",
+            &cache_dir,
+            "--out-dir",
+            &out_dir,
+            "--parallel",
+            "2",
+        ];
+        args.extend_from_slice(extra_args);
+
+        gdl::run(Args::parse_from(args));
+    }
+
+    // failed downloads make the whole run fail once everything else is done
+    fn run_failing(&self, extra_args: &[&str]) {
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.run(extra_args)));
+        assert!(result.is_err(), "expected gdl to fail");
+    }
+
+    // runs the gdl binary instead, for what it prints to stdout
+    fn stdout(&self, extra_args: &[&str]) -> String {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_gdl"))
+            .args([
+                "--taxdump-url",
+                &self.server.url("/pub/taxonomy/taxdump.tar.gz"),
+                "--taxdump-path",
+                &self.path("taxdump"),
+                "--cache-dir",
+                &self.path(""),
+                "--assembly-summary-url",
+                &self.server.url("/assembly_summary_refseq.txt"),
+                "--out-dir",
+                &self.path("out"),
+            ])
+            .args(extra_args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "expected gdl to succeed");
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+#[test]
+fn test_downloads_assemblies_within_taxon() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Contig"),
+        ("GCF_000000003.1", "1496", "Complete Genome"),
+    ]);
+    let first = ncbi.assembly("GCF_000000001.1", 200);
+    let second = ncbi.assembly("GCF_000000002.1", 200);
+    let other = ncbi.assembly("GCF_000000003.1", 200);
+
+    ncbi.run(&["--tax-name", "Escherichia coli"]);
+
+    taxdump.assert();
+    summary.assert();
+    first.assert();
+    second.assert();
+    other.assert_hits(0);
+
+    let mut genome = String::new();
+    GzDecoder::new(std::fs::File::open(ncbi.path("out/GCF_000000001.1.fna.gz")).unwrap())
+        .read_to_string(&mut genome)
+        .unwrap();
+    assert_eq!(genome, "GCF_000000001.1 genome");
+}
+
+#[test]
+fn test_reuses_cached_summary_and_taxdump() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    ncbi.run(&["--tax-id", "562"]);
+    ncbi.run(&["--tax-id", "562"]);
+
+    taxdump.assert_hits(1);
+    summary.assert_hits(1);
+    assembly.assert_hits(2);
+    assert!(Path::new(&ncbi.path("assembly_summary_refseq.txt")).exists());
+    assert!(!Path::new(&ncbi.path("taxdump.tar.gz")).exists());
+}
+
+#[test]
+fn test_no_cache_refetches_summary_and_taxdump() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    ncbi.assembly("GCF_000000001.1", 200);
+
+    ncbi.run(&["--tax-id", "562", "--dry-run"]);
+    ncbi.run(&["--tax-id", "562", "--dry-run", "--no-cache"]);
+
+    taxdump.assert_hits(2);
+    summary.assert_hits(2);
+}
+
+#[test]
+fn test_missing_assembly_is_not_retried_or_saved() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+    ]);
+    let missing = ncbi.assembly("GCF_000000001.1", 404);
+    let present = ncbi.assembly("GCF_000000002.1", 200);
+
+    ncbi.run_failing(&["--tax-id", "562"]);
+
+    missing.assert_hits(1);
+    present.assert_hits(1);
+    assert!(!Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+    assert!(Path::new(&ncbi.path("out/GCF_000000002.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_server_errors_are_retried() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let unavailable = ncbi.assembly("GCF_000000001.1", 503);
+
+    ncbi.run_failing(&["--tax-id", "562", "--retries", "2"]);
+
+    unavailable.assert_hits(3);
+    assert!(!Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_json_errors_lists_every_failed_download() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+        ("GCF_000000003.1", "562", "Complete Genome"),
+    ]);
+    ncbi.assembly("GCF_000000001.1", 404);
+    ncbi.assembly("GCF_000000002.1", 503);
+    ncbi.assembly("GCF_000000003.1", 200);
+    let errors = ncbi.path("errors.jsonl");
+
+    ncbi.run_failing(&[
+        "--tax-id",
+        "562",
+        "--retries",
+        "1",
+        "--json-errors",
+        &errors,
+    ]);
+
+    let mut failures: Vec<serde_json::Value> = std::fs::read_to_string(&errors)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    failures.sort_by_key(|failure| failure["accession"].as_str().unwrap().to_string());
+    assert_eq!(failures.len(), 2);
+    for (failure, (accession, status, retries)) in failures
+        .iter()
+        .zip([("GCF_000000001.1", 404, 0), ("GCF_000000002.1", 503, 1)])
+    {
+        assert_eq!(failure["accession"], accession);
+        assert_eq!(
+            failure["url"],
+            ncbi.server.url(format!(
+                "/genomes/all/{}/{}_genomic.fna.gz",
+                accession, accession
+            ))
+        );
+        assert_eq!(failure["kind"], "http");
+        assert_eq!(failure["http_status"], status);
+        assert_eq!(failure["retries"], retries);
+    }
+}
+
+#[test]
+fn test_verify_skips_unchanged_files() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let md5 = format!("{:x}", md5::compute(genome("GCF_000000001.1")));
+    let checksums = ncbi.checksums("GCF_000000001.1", &md5);
+
+    ncbi.run(&["--tax-id", "562", "--verify"]);
+    ncbi.run(&["--tax-id", "562", "--verify"]);
+
+    checksums.assert_hits(2);
+    assembly.assert_hits(1);
+    assert!(Path::new(&ncbi.path("out/.gdl-md5-cache.tsv")).exists());
+}
+
+#[test]
+fn test_verify_skips_recompressed_files() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let md5 = format!("{:x}", md5::compute(genome("GCF_000000001.1")));
+    ncbi.checksums("GCF_000000001.1", &md5);
+
+    ncbi.run(&["--tax-id", "562", "--verify", "--recompress", "zstd"]);
+    ncbi.run(&["--tax-id", "562", "--verify", "--recompress", "zstd"]);
+
+    assembly.assert_hits(1);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.fna.zst")).exists());
+}
+
+#[test]
+fn test_verify_skips_bgzipped_files() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let md5 = format!("{:x}", md5::compute(genome("GCF_000000001.1")));
+    ncbi.checksums("GCF_000000001.1", &md5);
+
+    ncbi.run(&["--tax-id", "562", "--verify", "--bgzip"]);
+    ncbi.run(&["--tax-id", "562", "--verify", "--bgzip"]);
+
+    assembly.assert_hits(1);
+}
+
+#[test]
+fn test_verify_skips_annotated_files() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let md5 = format!("{:x}", md5::compute(genome("GCF_000000001.1")));
+    ncbi.checksums("GCF_000000001.1", &md5);
+
+    ncbi.run(&["--tax-id", "562", "--verify", "--annotate-headers"]);
+    ncbi.run(&["--tax-id", "562", "--verify", "--annotate-headers"]);
+
+    assembly.assert_hits(1);
+}
+
+#[test]
+fn test_verify_removes_mismatched_files() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    ncbi.assembly("GCF_000000001.1", 200);
+    ncbi.checksums("GCF_000000001.1", "00000000000000000000000000000000");
+
+    ncbi.run_failing(&["--tax-id", "562", "--verify"]);
+
+    assert!(!Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_downloads_summary_and_taxdump_concurrently() {
+    let ncbi = FakeNcbi::start();
+    let delay = Duration::from_secs(1);
+    ncbi.delayed_taxdump(delay);
+    ncbi.delayed_assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")], delay);
+
+    let started = Instant::now();
+    ncbi.run(&["--tax-id", "562", "--dry-run"]);
+
+    assert!(started.elapsed() < delay * 2);
+}
+
+#[test]
+fn test_type_material_only() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Contig"),
+    ]);
+    let type_strain = ncbi.assembly("GCF_000000001.1", 200);
+    let other = ncbi.assembly("GCF_000000002.1", 200);
+
+    ncbi.run(&["--tax-id", "562", "--type-material-only"]);
+
+    type_strain.assert_hits(1);
+    other.assert_hits(0);
+}
+
+#[test]
+fn test_error_pages_are_retried_and_not_saved() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let error_page = ncbi.error_page("GCF_000000001.1");
+
+    ncbi.run_failing(&["--tax-id", "562", "--retries", "1"]);
+
+    error_page.assert_hits(2);
+    assert!(!Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_email_is_sent_to_ncbi() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let identified = ncbi.server.mock(|when, then| {
+        when.method(GET)
+            .path("/genomes/all/GCF_000000001.1/GCF_000000001.1_genomic.fna.gz")
+            .header("from", "someone@example.org");
+        then.status(200).body(genome("GCF_000000001.1"));
+    });
+
+    ncbi.run(&["--tax-id", "562", "--email", "someone@example.org"]);
+
+    identified.assert_hits(1);
+}
+
+#[test]
+fn test_state_skips_completed_assemblies() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+    ]);
+    let completed = ncbi.assembly("GCF_000000001.1", 200);
+    let failed = ncbi.assembly("GCF_000000002.1", 404);
+    let state = ncbi.path("state.txt");
+
+    ncbi.run_failing(&["--tax-id", "562", "--state", &state]);
+    ncbi.run_failing(&["--tax-id", "562", "--state", &state]);
+
+    completed.assert_hits(1);
+    failed.assert_hits(2);
+    assert_eq!(
+        std::fs::read_to_string(&state).unwrap(),
+        "GCF_000000001.1\n"
+    );
+}
+
+#[test]
+fn test_accessions_without_a_taxon() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+        ("GCF_000000003.1", "1496", "Complete Genome"),
+    ]);
+    let first = ncbi.assembly("GCF_000000001.1", 200);
+    let second = ncbi.assembly("GCF_000000002.1", 200);
+    let third = ncbi.assembly("GCF_000000003.1", 200);
+    let accession_file = ncbi.path("accessions.txt");
+    std::fs::write(&accession_file, "# from a paper\nGCF_000000003\n").unwrap();
+
+    ncbi.run(&[
+        "--accession",
+        "GCF_000000001.1",
+        "--accession-file",
+        &accession_file,
+    ]);
+
+    first.assert_hits(1);
+    second.assert_hits(0);
+    third.assert_hits(1);
+    // without a taxon to filter by, the taxonomy isn't needed
+    taxdump.assert_hits(0);
+}
+
+#[test]
+fn test_suppressed_assemblies_are_excluded_by_default() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary_with_status(
+        &[
+            ("GCF_000000001.1", "562", "Complete Genome", "latest"),
+            ("GCF_000000002.1", "562", "Complete Genome", "suppressed"),
+        ],
+        Duration::ZERO,
+    );
+    let latest = ncbi.assembly("GCF_000000001.1", 200);
+    let suppressed = ncbi.assembly("GCF_000000002.1", 200);
+
+    ncbi.run(&["--tax-id", "562"]);
+    latest.assert_hits(1);
+    suppressed.assert_hits(0);
+
+    ncbi.run(&["--tax-id", "562", "--include-suppressed"]);
+    latest.assert_hits(2);
+    suppressed.assert_hits(1);
+}
+
+#[test]
+fn test_datasets_backend() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let package = ncbi.server.mock(|when, then| {
+        when.method(GET)
+            .path("/datasets/v2/genome/accession/GCF_000000001.1/download")
+            .query_param("include_annotation_type", "GENOME_FASTA");
+        then.status(200).body(b"PK\x03\x04 package");
+    });
+    let datasets_url = ncbi.server.url("/datasets/v2");
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--backend",
+        "datasets",
+        "--datasets-url",
+        &datasets_url,
+    ]);
+
+    package.assert_hits(1);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.zip")).exists());
+}
+
+#[test]
+fn test_api_key_is_sent_to_datasets() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let package = ncbi.server.mock(|when, then| {
+        when.method(GET)
+            .path("/datasets/v2/genome/accession/GCF_000000001.1/download")
+            .header("api-key", "secret");
+        then.status(200).body(b"PK\x03\x04 package");
+    });
+    let datasets_url = ncbi.server.url("/datasets/v2");
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--backend",
+        "datasets",
+        "--datasets-url",
+        &datasets_url,
+        "--api-key",
+        "secret",
+    ]);
+
+    package.assert_hits(1);
+}
+
+#[test]
+fn test_incomplete_taxdump_is_downloaded_again() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    std::fs::create_dir_all(ncbi.path("taxdump")).unwrap();
+    std::fs::write(ncbi.path("taxdump/nodes.dmp"), NODES_DMP).unwrap();
+    std::fs::write(ncbi.path("taxdump/names.dmp"), "").unwrap();
+
+    ncbi.run(&["--tax-id", "562", "--dry-run"]);
+
+    taxdump.assert_hits(1);
+    assert_eq!(
+        std::fs::read_to_string(ncbi.path("taxdump/names.dmp")).unwrap(),
+        NAMES_DMP
+    );
+}
+
+#[test]
+fn test_manifest_columns() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000002.1", "562", "Contig"),
+        ("GCF_000000001.1", "562", "Complete Genome"),
+    ]);
+    ncbi.assembly("GCF_000000001.1", 200);
+    ncbi.assembly("GCF_000000002.1", 200);
+    let manifest = ncbi.path("manifest.tsv");
+    let md5 = format!("{:x}", md5::compute(genome("GCF_000000001.1")));
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--manifest",
+        &manifest,
+        "--manifest-columns",
+        "path,assembly_level,md5",
+    ]);
+
+    let manifest = std::fs::read_to_string(manifest).unwrap();
+    let mut lines = manifest.lines();
+    assert_eq!(lines.next(), Some("path\tassembly_level\tmd5"));
+    assert_eq!(
+        lines.next(),
+        Some(format!("GCF_000000001.1.fna.gz\tComplete Genome\t{}", md5).as_str())
+    );
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("GCF_000000002.1.fna.gz\tContig\t"));
+    assert_eq!(lines.next(), None);
+}
+
+#[test]
+fn test_summary_columns_passthrough() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    ncbi.assembly("GCF_000000001.1", 200);
+    let manifest = ncbi.path("manifest.tsv");
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--manifest",
+        &manifest,
+        "--manifest-columns",
+        "path",
+        "--summary-columns-passthrough",
+        "relation_to_type_material,bioproject,assembly_accession",
+    ]);
+
+    let manifest = std::fs::read_to_string(manifest).unwrap();
+    let mut lines = manifest.lines();
+    assert_eq!(
+        lines.next(),
+        Some("path\trelation_to_type_material\tbioproject\tassembly_accession")
+    );
+    assert_eq!(
+        lines.next(),
+        Some("GCF_000000001.1.fna.gz\tassembly from type material\t\tGCF_000000001.1")
+    );
+    assert_eq!(lines.next(), None);
+}
+
+#[test]
+fn test_checkpoint_every() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Contig"),
+        ("GCF_000000003.1", "562", "Contig"),
+    ]);
+    ncbi.assembly("GCF_000000001.1", 200);
+    ncbi.assembly("GCF_000000002.1", 200);
+    ncbi.assembly("GCF_000000003.1", 200);
+    let manifest = ncbi.path("manifest.tsv");
+    let state = ncbi.path("state.txt");
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--manifest",
+        &manifest,
+        "--state",
+        &state,
+        "--checkpoint-every",
+        "2",
+    ]);
+
+    let manifest_contents = std::fs::read_to_string(&manifest).unwrap();
+    assert_eq!(manifest_contents.lines().count(), 4);
+    assert_eq!(std::fs::read_to_string(state).unwrap().lines().count(), 3);
+    assert!(!Path::new(&format!("{}.tmp", manifest)).exists());
+}
+
+#[test]
+fn test_only_missing_skips_assemblies_in_the_out_dir() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Contig"),
+    ]);
+    let first = ncbi.assembly("GCF_000000001.1", 200);
+    let second = ncbi.assembly("GCF_000000002.1", 200);
+
+    ncbi.run(&["--tax-id", "562", "--flat-filenames"]);
+    std::fs::remove_file(ncbi.path("out/GCF_000000002.1.fna.gz")).unwrap();
+    ncbi.run(&["--tax-id", "562", "--flat-filenames", "--only-missing"]);
+
+    first.assert_hits(1);
+    second.assert_hits(2);
+}
+
+#[test]
+fn test_format_fallback() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    // there's no gff (or faa), so the gff falls back to the fna
+    let fna = ncbi.assembly("GCF_000000001.1", 200);
+    let manifest = ncbi.path("manifest.tsv");
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--format",
+        "gff",
+        "--format-fallback",
+        "faa,fna",
+        "--manifest",
+        &manifest,
+        "--manifest-columns",
+        "accession,format",
+    ]);
+
+    fna.assert_hits(1);
+    assert_eq!(
+        std::fs::read_to_string(manifest).unwrap(),
+        "accession\tformat\nGCF_000000001.1\tfna\n"
+    );
+}
+
+#[test]
+fn test_max_runtime_stops_starting_downloads() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let state = ncbi.path("state.txt");
+
+    // loading the taxonomy alone takes longer than that
+    ncbi.run(&["--tax-id", "562", "--state", &state, "--max-runtime", "1ms"]);
+    assembly.assert_hits(0);
+
+    ncbi.run(&["--tax-id", "562", "--state", &state]);
+    assembly.assert_hits(1);
+    assert_eq!(std::fs::read_to_string(state).unwrap(), "GCF_000000001.1\n");
+}
+
+#[test]
+fn test_offline_needs_everything_on_disk() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    // nothing is cached yet
+    ncbi.run_failing(&["--tax-id", "562", "--offline"]);
+    summary.assert_hits(0);
+
+    ncbi.run(&["--tax-id", "562"]);
+    ncbi.run(&["--tax-id", "562", "--offline"]);
+
+    std::fs::remove_dir_all(ncbi.path("out")).unwrap();
+    ncbi.run_failing(&["--tax-id", "562", "--offline"]);
+    ncbi.run(&["--tax-id", "562", "--offline", "--dry-run"]);
+
+    taxdump.assert_hits(1);
+    summary.assert_hits(1);
+    assembly.assert_hits(1);
+}
+
+#[test]
+fn test_print_taxa_skips_the_assembly_summary() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    ncbi.run(&["--tax-id", "2", "--print-taxa"]);
+
+    taxdump.assert_hits(1);
+    summary.assert_hits(0);
+    assembly.assert_hits(0);
+}
+
+#[test]
+fn test_no_children_keeps_strains_of_a_species() {
+    // (args, expected hits for the assemblies of Bacteria, E. coli and an E. coli strain)
+    let cases: [(&[&str], [usize; 3]); 3] = [
+        (&["--tax-id", "2", "--no-children"], [1, 0, 0]),
+        (&["--tax-id", "562", "--no-children"], [0, 1, 1]),
+        (&["--tax-id", "562", "--species-exact"], [0, 1, 0]),
+    ];
+
+    for (args, expected_hits) in cases {
+        let ncbi = FakeNcbi::start();
+        ncbi.taxdump();
+        ncbi.assembly_summary(&[
+            ("GCF_000000001.1", "2", "Complete Genome"),
+            ("GCF_000000002.1", "562", "Complete Genome"),
+            ("GCF_000000003.1", "316407", "Complete Genome"),
+        ]);
+        let assemblies = [
+            ncbi.assembly("GCF_000000001.1", 200),
+            ncbi.assembly("GCF_000000002.1", 200),
+            ncbi.assembly("GCF_000000003.1", 200),
+        ];
+
+        ncbi.run(args);
+
+        for (assembly, hits) in assemblies.iter().zip(expected_hits) {
+            assembly.assert_hits(hits);
+        }
+    }
+}
+
+#[test]
+fn test_include_and_exclude_accession_files() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+        ("GCF_000000003.1", "1496", "Complete Genome"),
+        ("GCF_000000004.1", "1496", "Complete Genome"),
+    ]);
+    let assemblies = [
+        ncbi.assembly("GCF_000000001.1", 200),
+        ncbi.assembly("GCF_000000002.1", 200),
+        ncbi.assembly("GCF_000000003.1", 200),
+        ncbi.assembly("GCF_000000004.1", 200),
+    ];
+    let include_file = ncbi.path("include.txt");
+    std::fs::write(
+        &include_file,
+        "GCF_000000001\nGCF_000000002.1\nGCF_000000003.1\n",
+    )
+    .unwrap();
+    let exclude_file = ncbi.path("exclude.txt");
+    std::fs::write(&exclude_file, "# contaminated\nGCF_000000002.1\n").unwrap();
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--include-accession-file",
+        &include_file,
+        "--exclude-accession-file",
+        &exclude_file,
+    ]);
+
+    for (assembly, hits) in assemblies.iter().zip([1, 0, 0, 0]) {
+        assembly.assert_hits(hits);
+    }
+}
+
+#[test]
+fn test_verify_only_checks_the_manifest() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+    ]);
+    ncbi.assembly("GCF_000000001.1", 200);
+    let second = ncbi.assembly("GCF_000000002.1", 200);
+    let manifest = ncbi.path("manifest.tsv");
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--manifest",
+        &manifest,
+        "--manifest-columns",
+        "accession,path,md5",
+    ]);
+
+    ncbi.run(&[
+        "--verify-only",
+        "--manifest",
+        &manifest,
+        "--verify-parallel",
+        "2",
+    ]);
+    second.assert_hits(1);
+
+    let out_dir = Path::new(&ncbi.path("out")).to_path_buf();
+    std::fs::write(out_dir.join("GCF_000000001.1.fna.gz"), "corrupted").unwrap();
+    std::fs::remove_file(out_dir.join("GCF_000000002.1.fna.gz")).unwrap();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        ncbi.run(&["--verify-only", "--manifest", &manifest]);
+    }));
+    assert!(result.is_err());
+    second.assert_hits(1);
+}
+
+#[test]
+fn test_name_map() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000002.1", "1496", "Complete Genome"),
+        ("GCF_000000001.1", "562", "Complete Genome"),
+    ]);
+    let lineages = ncbi.path("lineages.tsv");
+    let tax_ids = ncbi.path("taxids.tsv");
+
+    ncbi.run(&["--tax-id", "2", "--dry-run", "--name-map", &lineages]);
+    ncbi.run(&[
+        "--tax-id",
+        "2",
+        "--dry-run",
+        "--name-map",
+        &tax_ids,
+        "--name-map-format",
+        "taxid",
+    ]);
+
+    assert_eq!(
+        std::fs::read_to_string(lineages).unwrap(),
+        "GCF_000000001.1\tBacteria; Escherichia coli\n\
+         GCF_000000002.1\tBacteria; Clostridioides difficile\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(tax_ids).unwrap(),
+        "GCF_000000001.1\t562\nGCF_000000002.1\t1496\n"
+    );
+}
+
+#[test]
+fn test_name_map_loads_the_taxonomy_for_accessions() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "1496", "Complete Genome"),
+    ]);
+    let lineages = ncbi.path("lineages.tsv");
+
+    ncbi.run(&[
+        "--accession",
+        "GCF_000000001.1",
+        "--dry-run",
+        "--name-map",
+        &lineages,
+    ]);
+
+    // the filters don't need it, but the name map does
+    taxdump.assert_hits(1);
+    assert_eq!(
+        std::fs::read_to_string(lineages).unwrap(),
+        "GCF_000000001.1\tBacteria; Escherichia coli\n"
+    );
+}
+
+#[test]
+fn test_seqid2taxid() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "1496", "Complete Genome"),
+    ]);
+    for (accession, fasta) in [
+        (
+            "GCF_000000001.1",
+            ">NC_000001.1 chromosome\nACGT\n>NC_000002.1 plasmid\nACGT\n",
+        ),
+        ("GCF_000000002.1", ">NZ_000003.1\nACGT\n"),
+    ] {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(fasta.as_bytes()).unwrap();
+        let body = encoder.finish().unwrap();
+        ncbi.server.mock(|when, then| {
+            when.method(GET).path(format!(
+                "/genomes/all/{}/{}_genomic.fna.gz",
+                accession, accession
+            ));
+            then.status(200).body(body);
+        });
+    }
+    let map = ncbi.path("seqid2taxid.map");
+
+    ncbi.run(&["--tax-id", "2", "--seqid2taxid", &map]);
+
+    assert_eq!(
+        std::fs::read_to_string(map).unwrap(),
+        "NC_000001.1\t562\nNC_000002.1\t562\nNZ_000003.1\t1496\n"
+    );
+}
+
+#[test]
+fn test_strict_stops_at_the_first_failure() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+        ("GCF_000000003.1", "562", "Complete Genome"),
+        ("GCF_000000004.1", "562", "Complete Genome"),
+    ]);
+    let missing = ncbi.assembly("GCF_000000001.1", 404);
+    let slow = ["GCF_000000002.1", "GCF_000000003.1", "GCF_000000004.1"]
+        .map(|accession| ncbi.delayed_assembly(accession, 200, Duration::from_millis(300)));
+
+    ncbi.run_failing(&["--tax-id", "562", "--strict"]);
+
+    missing.assert_hits(1);
+    // at most the one that the other thread started before the failure
+    assert!(slow.iter().map(|mock| mock.hits()).sum::<usize>() <= 1);
+}
+
+#[test]
+fn test_min_free_space_stops_downloads() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    ncbi.run_failing(&["--tax-id", "562", "--min-free-space", "1000000T"]);
+    assembly.assert_hits(0);
+
+    ncbi.run(&["--tax-id", "562", "--min-free-space", "1k"]);
+    assembly.assert_hits(1);
+}
+
+#[test]
+fn test_existing_taxdump_archive_is_extracted() {
+    let ncbi = FakeNcbi::start();
+    let download = ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let archive = ncbi.path("taxdump.tar.gz");
+    std::fs::write(&archive, taxdump()).unwrap();
+
+    ncbi.run(&["--tax-id", "562"]);
+
+    download.assert_hits(0);
+    assembly.assert_hits(1);
+    assert!(Path::new(&ncbi.path("taxdump/nodes.dmp")).exists());
+    // the archive was put there by the user, so it's left alone
+    assert!(Path::new(&archive).exists());
+}
+
+#[test]
+fn test_taxdump_path_can_be_the_archive() {
+    let ncbi = FakeNcbi::start();
+    let download = ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    // --taxdump-path is `taxdump`, which isn't named like an archive
+    std::fs::write(ncbi.path("taxdump"), taxdump()).unwrap();
+
+    ncbi.run(&["--tax-id", "562"]);
+
+    download.assert_hits(0);
+    assembly.assert_hits(1);
+    assert!(Path::new(&ncbi.path("taxdump.d/nodes.dmp")).exists());
+}
+
+#[test]
+fn test_unusable_out_dir_fails_before_downloading() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    // a file where the output directory should be
+    std::fs::write(ncbi.path("out"), "").unwrap();
+
+    ncbi.run_failing(&["--tax-id", "562"]);
+
+    taxdump.assert_hits(0);
+    summary.assert_hits(0);
+    assembly.assert_hits(0);
+}
+
+#[test]
+fn test_list_formats_available_skips_missing_formats() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let fna = ncbi.assembly("GCF_000000001.1", 200);
+    // only the fna is listed
+    let checksums = ncbi.checksums("GCF_000000001.1", "0123456789abcdef0123456789abcdef");
+    let gff = ncbi.server.mock(|when, then| {
+        when.method(GET)
+            .path("/genomes/all/GCF_000000001.1/GCF_000000001.1_genomic.gff.gz");
+        then.status(404);
+    });
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--format",
+        "fna",
+        "--format",
+        "gff",
+        "--list-formats-available",
+    ]);
+
+    checksums.assert_hits(1);
+    fna.assert_hits(1);
+    gff.assert_hits(0);
+}
+
+#[test]
+fn test_corrupted_taxdump_is_downloaded_again() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    std::fs::create_dir_all(ncbi.path("taxdump")).unwrap();
+    std::fs::write(ncbi.path("taxdump/nodes.dmp"), "not\ta\ttaxdump\n").unwrap();
+    std::fs::write(ncbi.path("taxdump/names.dmp"), NAMES_DMP).unwrap();
+
+    ncbi.run(&["--tax-id", "562", "--dry-run"]);
+
+    taxdump.assert_hits(1);
+    assert_eq!(
+        std::fs::read_to_string(ncbi.path("taxdump/nodes.dmp")).unwrap(),
+        NODES_DMP
+    );
+}
+
+#[test]
+fn test_clean() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    ncbi.assembly("GCF_000000001.1", 200);
+    ncbi.run(&["--tax-id", "562"]);
+    let summary = ncbi.path("assembly_summary_refseq.txt");
+    let genome = ncbi.path("out/GCF_000000001.1.fna.gz");
+    let partial = ncbi.path("out/GCF_000000002.1.fna.gz.part");
+    std::fs::write(&partial, "half a genome").unwrap();
+    assert!(Path::new(&summary).exists());
+
+    ncbi.run(&["clean", "--partial"]);
+    assert!(!Path::new(&partial).exists());
+    assert!(Path::new(&summary).exists());
+
+    ncbi.run(&["clean"]);
+    assert!(!Path::new(&summary).exists());
+    assert!(!Path::new(&ncbi.path("taxdump")).exists());
+    assert!(Path::new(&genome).exists());
+}
+
+#[test]
+fn test_tax_name_patterns() {
+    // (args, expected hits for the assemblies of E. coli, an E. coli strain and C. difficile)
+    let cases: [(&[&str], [usize; 3]); 2] = [
+        (&["--tax-name-glob", "Escherichia*"], [1, 1, 0]),
+        (
+            &["--tax-name-regex", "^(Escherichia coli|Clostridioides .*)$"],
+            [1, 1, 1],
+        ),
+    ];
+
+    for (args, expected_hits) in cases {
+        let ncbi = FakeNcbi::start();
+        ncbi.taxdump();
+        ncbi.assembly_summary(&[
+            ("GCF_000000001.1", "562", "Complete Genome"),
+            ("GCF_000000002.1", "316407", "Complete Genome"),
+            ("GCF_000000003.1", "1496", "Complete Genome"),
+        ]);
+        let assemblies = [
+            ncbi.assembly("GCF_000000001.1", 200),
+            ncbi.assembly("GCF_000000002.1", 200),
+            ncbi.assembly("GCF_000000003.1", 200),
+        ];
+
+        ncbi.run(args);
+
+        for (assembly, hits) in assemblies.iter().zip(expected_hits) {
+            assembly.assert_hits(hits);
+        }
+    }
+}
+
+#[test]
+fn test_extra_files_are_saved_and_missing_ones_are_not_fatal() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    ncbi.assembly("GCF_000000001.1", 200);
+    ncbi.checksums("GCF_000000001.1", "0123456789abcdef0123456789abcdef");
+    let stats = ncbi.server.mock(|when, then| {
+        when.method(GET)
+            .path("/genomes/all/GCF_000000001.1/GCF_000000001.1_assembly_stats.txt");
+        then.status(404);
+    });
+
+    ncbi.run(&["--tax-id", "562", "--extra-files", "md5,stats"]);
+
+    stats.assert_hits(1);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+    assert_eq!(
+        std::fs::read_to_string(ncbi.path("out/GCF_000000001.1.md5checksums.txt")).unwrap(),
+        "0123456789abcdef0123456789abcdef  ./GCF_000000001.1_genomic.fna.gz\n"
+    );
+    assert!(!Path::new(&ncbi.path("out/GCF_000000001.1.assembly_stats.txt")).exists());
+}
+
+#[test]
+fn test_stdout_needs_exactly_one_assembly() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+    ]);
+    let first = ncbi.assembly("GCF_000000001.1", 200);
+    let second = ncbi.assembly("GCF_000000002.1", 200);
+
+    ncbi.run_failing(&["--tax-id", "562", "--stdout"]);
+
+    first.assert_hits(0);
+    second.assert_hits(0);
+}
+
+#[test]
+fn test_stale_taxdump_is_only_downloaded_again_when_asked_to() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    std::fs::create_dir_all(ncbi.path("taxdump")).unwrap();
+    std::fs::write(ncbi.path("taxdump/nodes.dmp"), NODES_DMP).unwrap();
+    std::fs::write(ncbi.path("taxdump/names.dmp"), NAMES_DMP).unwrap();
+    let head = ncbi.server.mock(|when, then| {
+        when.method(HEAD).path("/pub/taxonomy/taxdump.tar.gz");
+        then.status(200)
+            .header("Last-Modified", "Fri, 01 Jan 2100 00:00:00 GMT");
+    });
+
+    ncbi.run(&["--tax-id", "562", "--dry-run"]);
+    taxdump.assert_hits(0);
+
+    ncbi.run(&["--tax-id", "562", "--dry-run", "--refresh-taxdump-if-stale"]);
+    head.assert_hits(2);
+    taxdump.assert_hits(1);
+    assert!(Path::new(&ncbi.path("taxdump/.gdl-extracted")).exists());
+}
+
+#[test]
+fn test_dry_run_writes_the_planned_manifest() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let manifest = ncbi.path("manifest.tsv");
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--dry-run",
+        "--manifest",
+        &manifest,
+        "--manifest-columns",
+        "accession,path,url",
+    ]);
+
+    assembly.assert_hits(0);
+    assert!(!Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+    assert_eq!(
+        std::fs::read_to_string(manifest).unwrap(),
+        format!(
+            "accession\tpath\turl\nGCF_000000001.1\tGCF_000000001.1.fna.gz\t{}\n",
+            ncbi.server
+                .url("/genomes/all/GCF_000000001.1/GCF_000000001.1_genomic.fna.gz")
+        )
+    );
+}
+
+#[test]
+fn test_source_all_downloads_from_both_summaries() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    let refseq = ncbi.assembly("GCF_000000001.1", 200);
+    let genbank = ncbi.assembly("GCA_000000002.1", 200);
+    for (source, accession) in [
+        ("refseq", "GCF_000000001.1"),
+        ("genbank", "GCA_000000002.1"),
+    ] {
+        std::fs::write(
+            ncbi.path(&format!("assembly_summary_{}.txt", source)),
+            ncbi.summary(&[(accession, "562", "Complete Genome", "latest")]),
+        )
+        .unwrap();
+    }
+    let manifest = ncbi.path("manifest.tsv");
+
+    ncbi.run_with_cached_summaries(&[
+        "--tax-id",
+        "562",
+        "--source",
+        "all",
+        "--source-subdirs",
+        "--flat-filenames",
+        "--manifest",
+        &manifest,
+        "--manifest-columns",
+        "accession,source,path",
+    ]);
+
+    refseq.assert_hits(1);
+    genbank.assert_hits(1);
+    assert!(Path::new(&ncbi.path("out/refseq/GCF_000000001.1.fna.gz")).exists());
+    assert!(Path::new(&ncbi.path("out/genbank/GCA_000000002.1.fna.gz")).exists());
+    assert_eq!(
+        std::fs::read_to_string(manifest).unwrap(),
+        "accession\tsource\tpath\n\
+         GCA_000000002.1\tgenbank\tgenbank/GCA_000000002.1.fna.gz\n\
+         GCF_000000001.1\trefseq\trefseq/GCF_000000001.1.fna.gz\n"
+    );
+}
+
+#[test]
+fn test_include_historical_also_filters_the_historical_summary() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    let latest = ncbi.assembly("GCF_000000002.1", 200);
+    let replaced = ncbi.assembly("GCF_000000001.1", 200);
+    for (name, accession, version_status) in [
+        ("refseq", "GCF_000000002.1", "latest"),
+        ("refseq_historical", "GCF_000000001.1", "replaced"),
+    ] {
+        std::fs::write(
+            ncbi.path(&format!("assembly_summary_{}.txt", name)),
+            ncbi.summary(&[(accession, "562", "Complete Genome", version_status)]),
+        )
+        .unwrap();
+    }
+
+    ncbi.run_with_cached_summaries(&["--tax-id", "562", "--flat-filenames"]);
+    latest.assert_hits(1);
+    replaced.assert_hits(0);
+
+    ncbi.run_with_cached_summaries(&[
+        "--tax-id",
+        "562",
+        "--flat-filenames",
+        "--include-historical",
+    ]);
+    replaced.assert_hits(1);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_versioned_accession_is_found_in_the_historical_summary() {
+    let ncbi = FakeNcbi::start();
+    let latest = ncbi.assembly("GCF_000000001.2", 200);
+    let replaced = ncbi.assembly("GCF_000000001.1", 200);
+    for (name, accession, version_status) in [
+        ("refseq", "GCF_000000001.2", "latest"),
+        ("refseq_historical", "GCF_000000001.1", "replaced"),
+    ] {
+        std::fs::write(
+            ncbi.path(&format!("assembly_summary_{}.txt", name)),
+            ncbi.summary(&[(accession, "562", "Complete Genome", version_status)]),
+        )
+        .unwrap();
+    }
+
+    // without a version, only the main summary is looked through
+    ncbi.run_with_cached_summaries(&["--accession", "GCF_000000001", "--flat-filenames"]);
+    latest.assert_hits(1);
+    replaced.assert_hits(0);
+
+    ncbi.run_with_cached_summaries(&["--accession", "GCF_000000001.1", "--flat-filenames"]);
+    latest.assert_hits(1);
+    replaced.assert_hits(1);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_interrupted_summary_download_is_resumed() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let summary = ncbi.summary(&[("GCF_000000001.1", "562", "Complete Genome", "latest")]);
+    let summary_path = ncbi.path("assembly_summary_refseq.txt");
+    std::fs::write(format!("{}.part", summary_path), &summary[..100]).unwrap();
+    std::fs::write(format!("{}.part.validator", summary_path), "\"v1\"").unwrap();
+    let rest = ncbi.server.mock(|when, then| {
+        when.method(GET)
+            .path("/assembly_summary_refseq.txt")
+            .header("range", "bytes=100-")
+            .header("if-range", "\"v1\"");
+        then.status(206)
+            .header(
+                "content-range",
+                format!("bytes 100-{}/{}", summary.len() - 1, summary.len()),
+            )
+            .body(&summary[100..]);
+    });
+
+    ncbi.run(&["--tax-id", "562"]);
+
+    rest.assert_hits(1);
+    assembly.assert_hits(1);
+    assert_eq!(std::fs::read_to_string(&summary_path).unwrap(), summary);
+    assert!(!Path::new(&format!("{}.part", summary_path)).exists());
+}
+
+#[test]
+fn test_stats_only_needs_the_summary() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Contig"),
+    ]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    ncbi.run(&["stats"]);
+    ncbi.run(&["stats"]);
+
+    // the second run uses the cached summary
+    summary.assert_hits(1);
+    taxdump.assert_hits(0);
+    assembly.assert_hits(0);
+    assert!(!Path::new(&ncbi.path("out")).exists());
+}
+
+#[test]
+fn test_assembly_summary_gz_keeps_the_summary_gzipped() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    ncbi.run(&["--tax-id", "562", "--assembly-summary-gz"]);
+    ncbi.run(&["--tax-id", "562", "--assembly-summary-gz"]);
+
+    // the second run reads the gzipped summary back
+    summary.assert_hits(1);
+    assembly.assert_hits(2);
+    let cached = std::fs::read(ncbi.path("assembly_summary_refseq.txt.gz")).unwrap();
+    assert_eq!(cached[..2], [0x1f, 0x8b]);
+    assert!(!Path::new(&ncbi.path("assembly_summary_refseq.txt")).exists());
+    assert!(!Path::new(&ncbi.path("assembly_summary_refseq.txt.part")).exists());
+}
+
+#[test]
+fn test_print_plan_downloads_nothing() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    ncbi.run(&["--tax-name", "Escherichia coli", "--print-plan"]);
+
+    taxdump.assert_hits(0);
+    summary.assert_hits(0);
+    assembly.assert_hits(0);
+    assert!(!Path::new(&ncbi.path("out")).exists());
+}
+
+#[test]
+fn test_version_info_describes_the_cached_sources() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "1496", "Contig"),
+    ]);
+    let _assembly = ncbi.assembly("GCF_000000001.1", 200);
+    ncbi.run(&["--tax-id", "562"]);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_gdl"))
+        .args([
+            "--taxdump-url",
+            &ncbi.server.url("/pub/taxonomy/taxdump.tar.gz"),
+            "--taxdump-path",
+            &ncbi.path("taxdump"),
+            "--cache-dir",
+            &ncbi.path(""),
+            "--assembly-summary-url",
+            &ncbi.server.url("/assembly_summary_refseq.txt"),
+            "--version-info",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let info = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = info.lines().collect();
+    assert_eq!(lines[0], format!("version: {}", env!("CARGO_PKG_VERSION")));
+    assert!(lines.contains(&"taxonomy: ncbi"));
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("taxdump_extracted: ") && !line.ends_with("not extracted")));
+    assert!(lines.contains(&"refseq_rows: 2"));
+    taxdump.assert_hits(1);
+    summary.assert_hits(1);
+}
+
+#[test]
+fn test_update_downloads_the_caches_again() {
+    let ncbi = FakeNcbi::start();
+    let taxdump = ncbi.taxdump();
+    let summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    ncbi.run(&["update"]);
+    ncbi.run(&["refresh"]);
+
+    taxdump.assert_hits(2);
+    summary.assert_hits(2);
+    assembly.assert_hits(0);
+    assert!(Path::new(&ncbi.path("taxdump/names.dmp")).exists());
+    assert!(Path::new(&ncbi.path("assembly_summary_refseq.txt")).exists());
+}
+
+#[test]
+fn test_continue_on_taxon_error_skips_unresolvable_taxa() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000003.1", "1496", "Complete Genome"),
+    ]);
+    let first = ncbi.assembly("GCF_000000001.1", 200);
+    let other = ncbi.assembly("GCF_000000003.1", 200);
+
+    // a tax ID that isn't in the taxonomy stops the run by default
+    ncbi.run_failing(&["--tax-id", "562,999999"]);
+    first.assert_hits(0);
+
+    ncbi.run(&["--tax-id", "562,999999", "--continue-on-taxon-error"]);
+    first.assert();
+    other.assert_hits(0);
+
+    // with nothing left to download, it still stops
+    ncbi.run_failing(&["--tax-id", "999999", "--continue-on-taxon-error"]);
+}
+
+#[test]
+fn test_shard_size_spreads_assemblies_over_directories() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[
+        ("GCF_000000002.1", "562", "Complete Genome"),
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000003.1", "562", "Contig"),
+    ]);
+    let _first = ncbi.assembly("GCF_000000001.1", 200);
+    let _second = ncbi.assembly("GCF_000000002.1", 200);
+    let _third = ncbi.assembly("GCF_000000003.1", 200);
+    let manifest = ncbi.path("manifest.tsv");
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--shard-size",
+        "2",
+        "--manifest",
+        &manifest,
+        "--manifest-columns",
+        "accession,path",
+    ]);
+
+    assert!(Path::new(&ncbi.path("out/000/GCF_000000001.1.fna.gz")).exists());
+    assert!(Path::new(&ncbi.path("out/000/GCF_000000002.1.fna.gz")).exists());
+    assert!(Path::new(&ncbi.path("out/001/GCF_000000003.1.fna.gz")).exists());
+    let manifest = std::fs::read_to_string(manifest).unwrap();
+    assert!(manifest.contains("GCF_000000003.1\t001/GCF_000000003.1.fna.gz"));
+}
+
+#[test]
+fn test_check_genome_length_against_assembly_stats() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+    ]);
+    let _first = ncbi.assembly("GCF_000000001.1", 200);
+    let _second = ncbi.assembly("GCF_000000002.1", 200);
+    // the fake genomes are a single line like `GCF_000000001.1 genome`, 21 bases without the space
+    for (accession, length) in [("GCF_000000001.1", 21), ("GCF_000000002.1", 5_000_000)] {
+        ncbi.server.mock(|when, then| {
+            when.method(GET).path(format!(
+                "/genomes/all/{}/{}_assembly_stats.txt",
+                accession, accession
+            ));
+            then.status(200)
+                .body(format!("all\tall\tall\tall\ttotal-length\t{}\n", length));
+        });
+    }
+
+    ncbi.run_failing(&["--tax-id", "562", "--check-genome-length"]);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+    assert!(!Path::new(&ncbi.path("out/GCF_000000002.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_exec_runs_for_every_downloaded_file() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+    ]);
+    let _first = ncbi.assembly("GCF_000000001.1", 200);
+    let _second = ncbi.assembly("GCF_000000002.1", 200);
+    let exec = "case {} in *000000002*) exit 3;; esac; cp {} {}.copy";
+
+    // a failing command is only a warning
+    ncbi.run(&["--tax-id", "562", "--exec", exec]);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz.copy")).exists());
+    assert!(Path::new(&ncbi.path("out/GCF_000000002.1.fna.gz")).exists());
+    assert!(!Path::new(&ncbi.path("out/GCF_000000002.1.fna.gz.copy")).exists());
+
+    ncbi.run_failing(&["--tax-id", "562", "--exec", exec, "--strict"]);
+}
+
+#[test]
+fn test_tax_id_output_writes_the_resolved_taxa() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[("GCF_000000001.1", "316407", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+
+    let output = ncbi.path("taxa.txt");
+    ncbi.run(&["--tax-id", "562", "--tax-id-output", &output]);
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "562\n316407\n");
+    assembly.assert_hits(1);
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--tax-id-output",
+        &output,
+        "--tax-id-output-names",
+    ]);
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "562\tEscherichia coli\tspecies\tEscherichia coli\n\
+         316407\tEscherichia coli str. K-12 substr. W3110\tstrain\t\
+         Escherichia coli; Escherichia coli str. K-12 substr. W3110\n"
+    );
+}
+
+#[test]
+fn test_assembly_summary_path_reads_stdin() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let other = ncbi.assembly("GCF_000000002.1", 200);
+    // e.g. pre-filtered with grep
+    let summary = ncbi.summary(&[("GCF_000000001.1", "562", "Complete Genome", "latest")]);
+
+    let mut gdl = std::process::Command::new(env!("CARGO_BIN_EXE_gdl"))
+        .args([
+            "--taxdump-url",
+            &ncbi.server.url("/pub/taxonomy/taxdump.tar.gz"),
+            "--taxdump-path",
+            &ncbi.path("taxdump"),
+            "--cache-dir",
+            &ncbi.path(""),
+            "--out-dir",
+            &ncbi.path("out"),
+            "--source",
+            "none",
+            "--assembly-summary-path",
+            "-",
+            "--tax-id",
+            "562",
+        ])
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    gdl.stdin
+        .take()
+        .unwrap()
+        .write_all(summary.as_bytes())
+        .unwrap();
+    assert!(gdl.wait().unwrap().success());
+
+    assembly.assert_hits(1);
+    other.assert_hits(0);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_only_formats_present_lists_without_downloading() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    let assembly = ncbi.assembly("GCF_000000001.1", 200);
+    let checksums = ncbi.checksums("GCF_000000001.1", "0123456789abcdef0123456789abcdef");
+
+    ncbi.run(&["--tax-id", "562", "--only-formats-present"]);
+
+    checksums.assert_hits(1);
+    assembly.assert_hits(0);
+    assert!(!Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_stream_downloads_downloads_while_filtering() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "316407", "Contig"),
+        ("GCF_000000003.1", "1496", "Complete Genome"),
+    ]);
+    let first = ncbi.assembly("GCF_000000001.1", 200);
+    let second = ncbi.assembly("GCF_000000002.1", 200);
+    let other = ncbi.assembly("GCF_000000003.1", 200);
+
+    ncbi.run(&["--tax-id", "562", "--stream-downloads"]);
+
+    first.assert_hits(1);
+    second.assert_hits(1);
+    other.assert_hits(0);
+    assert!(Path::new(&ncbi.path("out/GCF_000000001.1.fna.gz")).exists());
+    assert!(Path::new(&ncbi.path("out/GCF_000000002.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_stream_downloads_rejects_options_it_cant_honor() {
+    for option in [
+        &["--max-runtime", "1h"][..],
+        &["--min-free-space", "50G"],
+        &["--strict"],
+        &["--extra-files", "md5"],
+    ] {
+        let mut args = vec!["gdl", "--tax-id", "562", "--stream-downloads"];
+        args.extend_from_slice(option);
+        let error = Args::try_parse_from(args).unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+}
+
+#[test]
+fn test_stream_downloads_fails_once_everything_has_been_tried() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+    ]);
+    let _missing = ncbi.assembly("GCF_000000001.1", 404);
+    let found = ncbi.assembly("GCF_000000002.1", 200);
+
+    ncbi.run_failing(&["--tax-id", "562", "--stream-downloads", "--retries", "0"]);
+
+    found.assert_hits(1);
+    assert!(Path::new(&ncbi.path("out/GCF_000000002.1.fna.gz")).exists());
+}
+
+#[test]
+fn test_jsonl_isnt_streamed_with_limit() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Contig"),
+        ("GCF_000000003.1", "562", "Scaffold"),
+    ]);
+
+    let jsonl = ncbi.stdout(&["--tax-id", "562", "--dry-run", "--jsonl", "--limit", "1"]);
+    let lines: Vec<&str> = jsonl.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].contains("GCF_000000001.1"));
+}
+
+#[test]
+fn test_jsonl_isnt_streamed_with_cluster_file_or_flat_filenames() {
+    let ncbi = FakeNcbi::start();
+    let _taxdump = ncbi.taxdump();
+    let _summary = ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Contig"),
+        ("GCF_000000002.1", "562", "Contig"),
+    ]);
+    let clusters = ncbi.path("clusters.tsv");
+    std::fs::write(&clusters, "GCF_000000001.1\tc1\nGCF_000000002.1\tc1\n").unwrap();
+
+    let jsonl = ncbi.stdout(&[
+        "--tax-id",
+        "562",
+        "--dry-run",
+        "--jsonl",
+        "--cluster-file",
+        &clusters,
+    ]);
+    assert_eq!(jsonl.lines().count(), 1);
+
+    // the summary lists GCF_000000002.1 twice, but it's only written once
+    let jsonl = ncbi.stdout(&[
+        "--tax-id",
+        "562",
+        "--dry-run",
+        "--jsonl",
+        "--flat-filenames",
+    ]);
+    assert_eq!(jsonl.lines().count(), 2);
+}
+
+// the sorted names of the entries in an (uncompressed) --tar archive
+fn tar_entries(path: &str) -> Vec<String> {
+    let mut entries: Vec<String> = tar::Archive::new(std::fs::File::open(path).unwrap())
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            entry
+                .unwrap()
+                .path()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+#[test]
+fn test_tar_entries_match_the_manifest() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[("GCF_000000001.1", "562", "Complete Genome")]);
+    ncbi.assembly("GCF_000000001.1", 200);
+    ncbi.server.mock(|when, then| {
+        when.method(GET)
+            .path("/genomes/all/GCF_000000001.1/GCF_000000001.1_genomic.gbff.gz");
+        then.status(200).body(genome("GCF_000000001.1"));
+    });
+    let archive = ncbi.path("assemblies.tar");
+    let manifest = ncbi.path("manifest.tsv");
+
+    ncbi.run(&[
+        "--tax-id",
+        "562",
+        "--format",
+        "fna",
+        "--format",
+        "gbff",
+        "--tar",
+        &archive,
+        "--manifest",
+        &manifest,
+        "--manifest-columns",
+        "path",
+    ]);
+
+    let entries = tar_entries(&archive);
+    let manifest = std::fs::read_to_string(manifest).unwrap();
+    let mut paths: Vec<&str> = manifest.lines().skip(1).collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        ["fna/GCF_000000001.1.fna.gz", "gbff/GCF_000000001.1.gbff.gz"]
+    );
+    assert_eq!(entries, paths);
+}
+
+#[test]
+fn test_tar_keeps_shard_and_source_directories() {
+    let ncbi = FakeNcbi::start();
+    ncbi.taxdump();
+    ncbi.assembly_summary(&[
+        ("GCF_000000001.1", "562", "Complete Genome"),
+        ("GCF_000000002.1", "562", "Complete Genome"),
+        ("GCF_000000003.1", "562", "Contig"),
+    ]);
+    for accession in ["GCF_000000001.1", "GCF_000000002.1", "GCF_000000003.1"] {
+        ncbi.assembly(accession, 200);
+    }
+    let sharded = ncbi.path("sharded.tar");
+
+    ncbi.run(&["--tax-id", "562", "--shard-size", "2", "--tar", &sharded]);
+
+    assert_eq!(
+        tar_entries(&sharded),
+        [
+            "000/GCF_000000001.1.fna.gz",
+            "000/GCF_000000002.1.fna.gz",
+            "001/GCF_000000003.1.fna.gz"
+        ]
+    );
+
+    // next to the refseq summary the first run downloaded
+    std::fs::write(
+        ncbi.path("assembly_summary_genbank.txt"),
+        ncbi.summary(&[("GCA_000000004.1", "562", "Complete Genome", "latest")]),
+    )
+    .unwrap();
+    ncbi.assembly("GCA_000000004.1", 200);
+    let by_source = ncbi.path("by_source.tar");
+    ncbi.run_with_cached_summaries(&[
+        "--tax-id",
+        "562",
+        "--source",
+        "all",
+        "--source-subdirs",
+        "--flat-filenames",
+        "--tar",
+        &by_source,
+    ]);
+
+    assert_eq!(
+        tar_entries(&by_source),
+        [
+            "genbank/GCA_000000004.1.fna.gz",
+            "refseq/GCF_000000001.1.fna.gz",
+            "refseq/GCF_000000002.1.fna.gz",
+            "refseq/GCF_000000003.1.fna.gz"
+        ]
+    );
+}